@@ -39,6 +39,13 @@ fn bench_engine<E>(
                         bf::engine::StopState::HasOutput(ch) => {
                             black_box(ch);
                         }
+                        bf::engine::StopState::Breakpoint => unreachable!("No breakpoint is set"),
+                        bf::engine::StopState::EndOfInput => {
+                            unreachable!("The input is never closed")
+                        }
+                        bf::engine::StopState::Diverged => {
+                            unreachable!("The examples always halt")
+                        }
                     }
                 }
             })
@@ -19,17 +19,28 @@ fn default_empty() -> Either<Vec<u8>, String> {
     Left(vec![])
 }
 
+/// Steps an example can take before being considered non halting
+const DEFAULT_MAX_STEPS: u64 = 100_000_000;
+
+fn default_max_steps() -> u64 {
+    DEFAULT_MAX_STEPS
+}
+
 #[derive(Debug, Deserialize)]
 struct IOExample {
     #[serde(default = "default_empty", with = "either::serde_untagged")]
     r#in: Either<Vec<u8>, String>,
     #[serde(with = "either::serde_untagged")]
     out: Either<Vec<u8>, String>,
+    #[serde(default = "default_max_steps")]
+    max_steps: u64,
 }
 
 static ENGINES: &[(&str, &str)] = &[
     ("raw", "bf::engine::raw::Engine"),
     ("ir", "bf::engine::ir::Engine"),
+    ("threaded", "bf::engine::threaded::Engine"),
+    ("closure", "bf::engine::closure::Engine"),
 ];
 
 fn test_fns() -> proc_macro2::TokenStream {
@@ -41,10 +52,24 @@ fn test_fns() -> proc_macro2::TokenStream {
         quote!(
             #[test]
             fn #name () {
-                super::super::test_engine::<#path>(super::CODE, super::super::IOExample {input: INPUT, output: OUTPUT})
+                super::super::test_engine::<#path>(super::CODE, super::super::IOExample {input: INPUT, output: OUTPUT, max_steps: MAX_STEPS})
             }
         ).to_tokens(&mut tokens)
     }
+    quote!(
+        #[test]
+        fn wasm() {
+            super::super::test_wasm(
+                super::CODE,
+                super::super::IOExample {
+                    input: INPUT,
+                    output: OUTPUT,
+                    max_steps: MAX_STEPS,
+                },
+            )
+        }
+    )
+    .to_tokens(&mut tokens);
     tokens
 }
 
@@ -78,9 +103,22 @@ impl ToTokens for AsTest<&Example> {
         let code = &self.0.code;
         quote!(
             static CODE: &str = #code;
+
+            #[test]
+            fn optimizer() {
+                super::test_optimizer(CODE)
+            }
         )
         .to_tokens(tokens);
-        for (name, IOExample { r#in, out }) in &self.0.io {
+        for (
+            name,
+            IOExample {
+                r#in,
+                out,
+                max_steps,
+            },
+        ) in &self.0.io
+        {
             let [r#in, out] = [r#in, out].map(|b| {
                 b.as_ref()
                     .map_either(Vec::as_slice, String::as_bytes)
@@ -91,6 +129,7 @@ impl ToTokens for AsTest<&Example> {
                 mod #name {
                     static INPUT: &[u8] = &[#(# r#in),*];
                     static OUTPUT: &[u8] = &[#(# out),*];
+                    static MAX_STEPS: u64 = #max_steps;
 
                     #tests
                 }
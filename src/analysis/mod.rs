@@ -0,0 +1,3 @@
+//! Analyses of ir programs, beyond the ones the optimizer needs
+
+pub mod symexec;
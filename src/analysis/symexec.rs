@@ -0,0 +1,835 @@
+//! Symbolic execution of ir programs
+//!
+//! The bytes of input are unknowns, and the cells hold polynomials in them, with wrapping
+//! coefficients. When a loop or an if tests a cell whose value is not known the execution
+//! forks, and each path remembers the conditions it took. Two programs are compared by running
+//! the second one along each path of the first: on every pair of paths that can be taken
+//! together, the two must end in the same way and output the same values.
+//!
+//! Whether two polynomials differ on the inputs satisfying the conditions is decided by trying
+//! all the values of the unknowns left, so only paths with a few of them can be decided. The
+//! conditions fixing an unknown to a single value are solved as soon as they are taken, so most
+//! paths keep few unknowns.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    num::NonZeroU8,
+};
+
+use thiserror::Error;
+
+use crate::{
+    engine::RTError,
+    ir::{
+        check::{Outcome, RunEnd},
+        Add, If, Input, Loop, Mul, Node, Output, Print, Program, Set, Shift, SwitchTape,
+    },
+};
+
+/// Limits of a symbolic execution
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Bounds {
+    /// Nodes run by each program along a path
+    pub max_steps: u64,
+    /// Longest input tried
+    pub max_inputs: usize,
+    /// Forks allowed to each run of a program
+    pub max_paths: usize,
+    /// Unknowns whose values are all tried, to decide a path
+    pub max_unknowns: usize,
+}
+impl Default for Bounds {
+    fn default() -> Self {
+        Self {
+            max_steps: 10_000,
+            max_inputs: 4,
+            max_paths: 10_000,
+            max_unknowns: 2,
+        }
+    }
+}
+
+/// Summary of an equivalence check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Report {
+    /// Pairs of paths on which the programs were proved to behave the same
+    pub proved: usize,
+    /// Pairs of paths that ran out of steps, or with too many unknowns to be decided
+    pub inconclusive: usize,
+}
+impl Report {
+    /// Check if the programs were proved to behave the same on all the inputs within the bounds
+    pub fn is_complete(&self) -> bool {
+        self.inconclusive == 0
+    }
+}
+
+/// The programs behave differently on an input
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Error)]
+#[error("The programs differ on input {input:?}")]
+pub struct Mismatch {
+    pub input: Vec<u8>,
+    pub first: Outcome,
+    pub second: Outcome,
+}
+impl Mismatch {
+    /// One of the programs accessed a cell, or a tape, before the first one
+    ///
+    /// The optimizer is free to move or drop these accesses, so the comparisons with the
+    /// unoptimized program skip these mismatches.
+    pub fn is_negative_tape_access(&self) -> bool {
+        [&self.first, &self.second].iter().any(|outcome| {
+            matches!(
+                outcome.end,
+                RunEnd::Error(RTError::MemNegativeOut | RTError::TapeNegativeOut)
+            )
+        })
+    }
+}
+
+/// Check that two programs have the same output on every input of at most
+/// `bounds.max_inputs` bytes
+///
+/// The programs are run on wrapping cells and a zeroed tape, as the optimizer assumes. A path
+/// that does not end within the bounds is counted as inconclusive, as the ones that could not be
+/// decided.
+pub fn check_equivalent(
+    first: &Program,
+    second: &Program,
+    bounds: Bounds,
+) -> Result<Report, Mismatch> {
+    let mut report = Report::default();
+    for first in Executor::run(first, Context::default(), &bounds) {
+        if first.end == RunEnd::OutOfSteps {
+            report.inconclusive += 1;
+            continue;
+        }
+        for second in Executor::run(second, first.context.clone(), &bounds) {
+            compare(&first, &second, &bounds, &mut report)?
+        }
+    }
+    Ok(report)
+}
+
+/// Compare two paths, the second taken under the conditions of the first
+fn compare(
+    first: &Ended,
+    second: &Ended,
+    bounds: &Bounds,
+    report: &mut Report,
+) -> Result<(), Mismatch> {
+    if second.end == RunEnd::OutOfSteps {
+        report.inconclusive += 1;
+        return Ok(());
+    }
+    let context = &second.context;
+    let differ: Vec<Poly> =
+        if first.end != second.end || first.machine.output.len() != second.machine.output.len() {
+            vec![Poly::constant(1)]
+        } else {
+            first
+                .machine
+                .output
+                .iter()
+                .zip(&second.machine.output)
+                .map(|(a, b)| {
+                    let mut diff = a.substitute(&context.assigned);
+                    diff.add_scaled(&b.substitute(&context.assigned), 255);
+                    diff
+                })
+                .filter(|diff| diff.known() != Some(0))
+                .collect()
+        };
+    if differ.is_empty() {
+        report.proved += 1;
+        return Ok(());
+    }
+    match context.solve(&differ, bounds.max_unknowns) {
+        // no input taking those paths makes them differ
+        Ok(None) => report.proved += 1,
+        Ok(Some(values)) => {
+            let len = context.len.unwrap_or(context.available);
+            let input: Vec<u8> = (0..len)
+                .map(|i| {
+                    let value = context.assigned.get(&i).or(values.get(&i));
+                    value.copied().unwrap_or(0)
+                })
+                .collect();
+            let values = context.assigned.iter().chain(&values);
+            let values: BTreeMap<_, _> = values.map(|(&i, &v)| (i, v)).collect();
+            return Err(Mismatch {
+                first: first.outcome(&values),
+                second: second.outcome(&values),
+                input,
+            });
+        }
+        Err(TooManyUnknowns) => report.inconclusive += 1,
+    }
+    Ok(())
+}
+
+/// Polynomial in the bytes of input, with wrapping coefficients
+///
+/// Each monomial is the sorted list of the inputs it multiplies, with repetitions.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+struct Poly(BTreeMap<Vec<usize>, NonZeroU8>);
+impl Poly {
+    fn constant(value: u8) -> Self {
+        let mut poly = Poly::default();
+        poly.add_term(vec![], value);
+        poly
+    }
+    fn input(index: usize) -> Self {
+        let mut poly = Poly::default();
+        poly.add_term(vec![index], 1);
+        poly
+    }
+
+    fn add_term(&mut self, monomial: Vec<usize>, coeff: u8) {
+        let coeff = self
+            .0
+            .get(&monomial)
+            .map_or(0, |c| c.get())
+            .wrapping_add(coeff);
+        match NonZeroU8::new(coeff) {
+            Some(coeff) => self.0.insert(monomial, coeff),
+            None => self.0.remove(&monomial),
+        };
+    }
+    /// Add `factor * other` to this polynomial
+    fn add_scaled(&mut self, other: &Poly, factor: u8) {
+        for (monomial, coeff) in &other.0 {
+            self.add_term(monomial.clone(), coeff.get().wrapping_mul(factor))
+        }
+    }
+    fn mul(&self, other: &Poly) -> Poly {
+        let mut product = Poly::default();
+        for (a, ca) in &self.0 {
+            for (b, cb) in &other.0 {
+                let mut monomial = [&a[..], &b[..]].concat();
+                monomial.sort_unstable();
+                product.add_term(monomial, ca.get().wrapping_mul(cb.get()))
+            }
+        }
+        product
+    }
+
+    /// The value of the polynomial, if it does not depend on the input
+    fn known(&self) -> Option<u8> {
+        match self.0.iter().next() {
+            None => Some(0),
+            Some((monomial, coeff)) if monomial.is_empty() && self.0.len() == 1 => {
+                Some(coeff.get())
+            }
+            Some(_) => None,
+        }
+    }
+    fn unknowns(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.keys().flatten().copied()
+    }
+
+    /// Replace the unknowns in `values` with their value
+    fn substitute(&self, values: &BTreeMap<usize, u8>) -> Poly {
+        let mut result = Poly::default();
+        for (monomial, coeff) in &self.0 {
+            let mut coeff = coeff.get();
+            let mut rest = vec![];
+            for unknown in monomial {
+                match values.get(unknown) {
+                    Some(value) => coeff = coeff.wrapping_mul(*value),
+                    None => rest.push(*unknown),
+                }
+            }
+            result.add_term(rest, coeff)
+        }
+        result
+    }
+    /// Value of the polynomial, if all the unknowns are in `values`
+    fn eval(&self, values: &BTreeMap<usize, u8>) -> Option<u8> {
+        self.substitute(values).known()
+    }
+
+    /// The only value of an unknown that makes the polynomial zero, if it is `a*x + b` with an
+    /// odd `a`
+    fn root(&self) -> Option<(usize, u8)> {
+        let mut constant = 0;
+        let mut linear = None;
+        for (monomial, coeff) in &self.0 {
+            match monomial[..] {
+                [] => constant = coeff.get(),
+                [unknown] if coeff.get() % 2 == 1 && linear.is_none() => {
+                    linear = Some((unknown, coeff.get()))
+                }
+                _ => return None,
+            }
+        }
+        let (unknown, coeff) = linear?;
+        // inverse modulo 256 by newton iteration, each step doubling the correct bits
+        let mut inverse = coeff;
+        for _ in 0..3 {
+            inverse = inverse.wrapping_mul(2u8.wrapping_sub(coeff.wrapping_mul(inverse)))
+        }
+        Some((unknown, constant.wrapping_neg().wrapping_mul(inverse)))
+    }
+}
+
+/// The unknowns left on a path are too many to try all their values
+#[derive(Debug, Clone, Copy)]
+struct TooManyUnknowns;
+
+/// What is known of the input along a path
+#[derive(Debug, Clone, Default)]
+struct Context {
+    /// The unknowns fixed by the conditions
+    assigned: BTreeMap<usize, u8>,
+    /// Values the unknowns cannot take
+    excluded: BTreeMap<usize, BTreeSet<u8>>,
+    /// Bytes of input surely there
+    available: usize,
+    /// Length of the input, if it was seen ending
+    len: Option<usize>,
+    /// Polynomials that are zero (`true`) or not zero (`false`) along the path
+    conditions: Vec<(Poly, bool)>,
+}
+impl Context {
+    /// Add a condition
+    ///
+    /// Return `false` if it contradicts the others
+    fn assume(&mut self, poly: Poly, zero: bool) -> bool {
+        let poly = poly.substitute(&self.assigned);
+        if let Some(value) = poly.known() {
+            return (value == 0) == zero;
+        }
+        match poly.root() {
+            Some((unknown, value)) if zero => self.assign(unknown, value),
+            Some((unknown, value)) => {
+                let excluded = self.excluded.entry(unknown).or_default();
+                excluded.insert(value);
+                match excluded.len() {
+                    256 => false,
+                    255 => {
+                        let value = (0..=u8::MAX).find(|v| !excluded.contains(v)).unwrap();
+                        self.assign(unknown, value)
+                    }
+                    _ => true,
+                }
+            }
+            None => {
+                self.conditions.push((poly, zero));
+                true
+            }
+        }
+    }
+
+    /// Fix the value of an unknown
+    ///
+    /// Return `false` if it contradicts the conditions
+    fn assign(&mut self, unknown: usize, value: u8) -> bool {
+        if self
+            .excluded
+            .remove(&unknown)
+            .is_some_and(|excluded| excluded.contains(&value))
+        {
+            return false;
+        }
+        self.assigned.insert(unknown, value);
+        // the other conditions might now be decided, or fix other unknowns
+        for (poly, zero) in std::mem::take(&mut self.conditions) {
+            if !self.assume(poly, zero) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// The condition `poly == 0`, if it was already taken
+    fn decided(&self, poly: &Poly) -> Option<bool> {
+        self.conditions
+            .iter()
+            .find_map(|(condition, zero)| (condition == poly).then_some(*zero))
+    }
+
+    /// Find values of the unknowns satisfying the conditions, and making one of the polynomials
+    /// in `differ` not zero, if any is given
+    ///
+    /// `Ok(None)` if there are none.
+    fn solve(
+        &self,
+        differ: &[Poly],
+        max_unknowns: usize,
+    ) -> Result<Option<BTreeMap<usize, u8>>, TooManyUnknowns> {
+        let differ: Vec<_> = differ
+            .iter()
+            .map(|poly| poly.substitute(&self.assigned))
+            .collect();
+        let unknowns: BTreeSet<usize> = self
+            .conditions
+            .iter()
+            .map(|(poly, _)| poly)
+            .chain(&differ)
+            .flat_map(Poly::unknowns)
+            .collect();
+        if unknowns.len() > max_unknowns {
+            return Err(TooManyUnknowns);
+        }
+        let mut values: BTreeMap<usize, u8> = unknowns.iter().map(|&i| (i, 0)).collect();
+        loop {
+            let satisfied = values.iter().all(|(unknown, value)| {
+                self.excluded
+                    .get(unknown)
+                    .is_none_or(|excluded| !excluded.contains(value))
+            }) && self
+                .conditions
+                .iter()
+                .all(|(poly, zero)| (poly.eval(&values) == Some(0)) == *zero);
+            if satisfied
+                && (differ.is_empty() || differ.iter().any(|poly| poly.eval(&values) != Some(0)))
+            {
+                return Ok(Some(values));
+            }
+            // next combination, as an odometer
+            let mut carry = true;
+            for value in values.values_mut() {
+                if !carry {
+                    break;
+                }
+                (*value, carry) = value.overflowing_add(1);
+            }
+            if carry {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// State of a program along a path
+#[derive(Debug, Clone, Default)]
+struct Machine {
+    /// The cells not in the map are zero
+    memory: BTreeMap<(usize, isize), Poly>,
+    tape: usize,
+    pos: isize,
+    output: Vec<Poly>,
+    /// Bytes of input read
+    inputs: usize,
+    steps: u64,
+}
+impl Machine {
+    fn cell(&self, offset: isize) -> Result<(usize, isize), RTError> {
+        if self.pos + offset < 0 {
+            return Err(RTError::MemNegativeOut);
+        }
+        Ok((self.tape, self.pos + offset))
+    }
+
+    fn get(&self, offset: isize, context: &Context) -> Result<Poly, RTError> {
+        let cell = self.cell(offset)?;
+        Ok(self
+            .memory
+            .get(&cell)
+            .map_or_else(Poly::default, |poly| poly.substitute(&context.assigned)))
+    }
+
+    fn set(&mut self, offset: isize, poly: Poly) -> Result<(), RTError> {
+        let cell = self.cell(offset)?;
+        self.memory.insert(cell, poly);
+        Ok(())
+    }
+
+    /// Add `factor * poly` to a cell
+    fn add(&mut self, offset: isize, poly: &Poly, factor: u8) -> Result<(), RTError> {
+        let cell = self.cell(offset)?;
+        self.memory
+            .entry(cell)
+            .or_default()
+            .add_scaled(poly, factor);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Path {
+    context: Context,
+    machine: Machine,
+}
+
+/// A path that stopped
+#[derive(Debug, Clone)]
+struct Ended {
+    context: Context,
+    machine: Machine,
+    end: RunEnd,
+}
+impl Ended {
+    /// What the program does on the input with the given values
+    fn outcome(&self, values: &BTreeMap<usize, u8>) -> Outcome {
+        Outcome {
+            output: self
+                .machine
+                .output
+                .iter()
+                .map(|poly| poly.eval(values).unwrap_or(0))
+                .collect(),
+            end: self.end,
+        }
+    }
+}
+
+/// Symbolic run of a single program
+struct Executor<'b> {
+    bounds: &'b Bounds,
+    forks: usize,
+    ended: Vec<Ended>,
+}
+impl Executor<'_> {
+    /// Run the program along all the paths compatible with `context`
+    fn run(program: &Program, context: Context, bounds: &Bounds) -> Vec<Ended> {
+        let mut executor = Executor {
+            bounds,
+            forks: 0,
+            ended: vec![],
+        };
+        let start = Path {
+            context,
+            machine: Machine::default(),
+        };
+        for path in executor.run_block(&program.0 .0, vec![start]) {
+            executor.end(path, RunEnd::Halted)
+        }
+        executor.ended
+    }
+
+    fn end(&mut self, path: Path, end: RunEnd) {
+        self.ended.push(Ended {
+            context: path.context,
+            machine: path.machine,
+            end,
+        })
+    }
+
+    fn run_block(&mut self, nodes: &[Node], mut paths: Vec<Path>) -> Vec<Path> {
+        for node in nodes {
+            let mut next = vec![];
+            for path in paths {
+                next.extend(self.run_node(node, path))
+            }
+            paths = next
+        }
+        paths
+    }
+
+    /// Run a node, returning the paths that continue after it
+    fn run_node(&mut self, node: &Node, mut path: Path) -> Vec<Path> {
+        if path.machine.steps == self.bounds.max_steps {
+            self.end(path, RunEnd::OutOfSteps);
+            return vec![];
+        }
+        path.machine.steps += 1;
+        if matches!(node, Node::Input(_) | Node::InputDiscard) {
+            match self.read(path) {
+                Some(read) => path = read,
+                None => return vec![],
+            }
+        }
+        let Path { context, machine } = &mut path;
+        let result = match node {
+            Node::Noop => Ok(()),
+            Node::Shift(Shift { amount }) => {
+                machine.pos += amount.get();
+                Ok(())
+            }
+            Node::Add(Add { amount, offset }) => {
+                machine.add(*offset, &Poly::constant(amount.get()), 1)
+            }
+            Node::Set(Set { value, offset }) => machine.set(*offset, Poly::constant(*value)),
+            Node::Output(Output { offset }) => machine.get(*offset, context).map(|poly| {
+                machine.output.push(poly);
+            }),
+            Node::Print(Print { bytes }) => {
+                machine
+                    .output
+                    .extend(bytes.iter().map(|b| Poly::constant(*b)));
+                Ok(())
+            }
+            Node::Input(Input { offset }) => {
+                let byte = Poly::input(machine.inputs).substitute(&context.assigned);
+                machine.inputs += 1;
+                machine.set(*offset, byte)
+            }
+            Node::InputDiscard => {
+                machine.inputs += 1;
+                Ok(())
+            }
+            Node::SwitchTape(SwitchTape { amount }) => {
+                match machine.tape.checked_add_signed(amount.get()) {
+                    Some(tape) => {
+                        machine.tape = tape;
+                        Ok(())
+                    }
+                    None => Err(RTError::TapeNegativeOut),
+                }
+            }
+            Node::Mul(Mul { offset, targets }) => run_mul(machine, context, *offset, targets),
+            Node::Diverge => {
+                self.end(path, RunEnd::OutOfSteps);
+                return vec![];
+            }
+            Node::If(If { body, offset }) => {
+                let (zero, nonzero) = match self.test(path, *offset) {
+                    Some(branches) => branches,
+                    None => return vec![],
+                };
+                let mut paths: Vec<_> = zero.into_iter().collect();
+                if let Some(path) = nonzero {
+                    paths.extend(self.run_block(&body.0, vec![path]))
+                }
+                return paths;
+            }
+            Node::Loop(Loop { body, offset }) => {
+                let mut paths = vec![];
+                let mut testing = vec![path];
+                while let Some(path) = testing.pop() {
+                    let Some((zero, nonzero)) = self.test(path, *offset) else {
+                        continue;
+                    };
+                    paths.extend(zero);
+                    if let Some(mut path) = nonzero {
+                        // each iteration is a step
+                        if path.machine.steps == self.bounds.max_steps {
+                            self.end(path, RunEnd::OutOfSteps);
+                            continue;
+                        }
+                        path.machine.steps += 1;
+                        testing.extend(self.run_block(&body.0, vec![path]))
+                    }
+                }
+                return paths;
+            }
+        };
+        match result {
+            Ok(()) => vec![path],
+            Err(err) => {
+                self.end(path, RunEnd::Error(err));
+                vec![]
+            }
+        }
+    }
+
+    /// Split a path on the input having another byte or not
+    ///
+    /// The path where the input ended is stopped, the other one is returned.
+    fn read(&mut self, mut path: Path) -> Option<Path> {
+        let Path { context, machine } = &mut path;
+        if machine.inputs < context.available {
+            return Some(path);
+        }
+        if context.len.is_none() && machine.inputs == self.bounds.max_inputs {
+            context.len = Some(machine.inputs)
+        }
+        if context.len.is_some() {
+            self.end(path, RunEnd::NeedInput);
+            return None;
+        }
+        if self.forks == self.bounds.max_paths {
+            self.end(path, RunEnd::OutOfSteps);
+            return None;
+        }
+        self.forks += 1;
+        let mut ended = path.clone();
+        ended.context.len = Some(ended.machine.inputs);
+        self.end(ended, RunEnd::NeedInput);
+        path.context.available += 1;
+        Some(path)
+    }
+
+    /// Split a path on the cell at `offset` being zero or not
+    ///
+    /// Either of the paths is missing if it cannot be taken. `None` if the path ended.
+    fn test(&mut self, path: Path, offset: isize) -> Option<(Option<Path>, Option<Path>)> {
+        let poly = match path.machine.get(offset, &path.context) {
+            Ok(poly) => poly,
+            Err(err) => {
+                self.end(path, RunEnd::Error(err));
+                return None;
+            }
+        };
+        let decided = poly
+            .known()
+            .map(|value| value == 0)
+            .or_else(|| path.context.decided(&poly));
+        match decided {
+            Some(true) => return Some((Some(path), None)),
+            Some(false) => return Some((None, Some(path))),
+            None => (),
+        }
+        if self.forks == self.bounds.max_paths {
+            self.end(path, RunEnd::OutOfSteps);
+            return None;
+        }
+        self.forks += 1;
+        let branch =
+            |mut path: Path, zero: bool| path.context.assume(poly.clone(), zero).then_some(path);
+        Some((branch(path.clone(), true), branch(path, false)))
+    }
+}
+
+/// Run a multiplication, as described in [`Mul`]
+fn run_mul(
+    machine: &mut Machine,
+    context: &Context,
+    offset: isize,
+    targets: &[(isize, crate::ir::Affine)],
+) -> Result<(), RTError> {
+    let n = machine.get(offset, context)?;
+    if n.known() == Some(0) {
+        return Ok(());
+    }
+    let mut increments = vec![];
+    for (target, expr) in targets {
+        let mut value = Poly::constant(expr.constant);
+        for (cell, coeff) in &expr.terms {
+            value.add_scaled(&machine.get(*cell, context)?, coeff.get())
+        }
+        increments.push((*target, n.mul(&value)))
+    }
+    for (target, increment) in increments {
+        machine.add(target, &increment, 1)?
+    }
+    machine.set(offset, Poly::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_equivalent, Bounds};
+    use crate::ir::{
+        check::{
+            random::{self, Lcg},
+            RunEnd,
+        },
+        passes::OptLevel,
+        Program,
+    };
+
+    fn unoptimized(source: &str) -> Program {
+        Program::from_raw_with(source.parse().unwrap(), OptLevel::O0)
+    }
+
+    #[test]
+    fn optimizer_is_equivalent() {
+        for source in [
+            ",[.,]",
+            ",[->+>+<<]>.>.",
+            ",>,<[->+<]>.",
+            ",[->+++<]>[-<++>]<.",
+            ",>+++++[<-->-]<[[-]>+<]>.",
+            "++++++++[>++++++++<-]>+.+.+.",
+            ",[>+<-[>+<-[-]]]>.",
+        ] {
+            let raw = unoptimized(source);
+            let optimized: Program = source.parse().unwrap();
+            match check_equivalent(&raw, &optimized, Bounds::default()) {
+                Ok(report) => assert!(report.proved > 0, "Nothing proved on `{source}`"),
+                Err(err) if err.is_negative_tape_access() => {}
+                Err(err) => panic!("`{source}` was optimized wrong: {err}: {err:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn decides_all_paths() {
+        // the raw program forks on each iteration, but each fork fixes the input
+        let report = check_equivalent(
+            &unoptimized(",[->++<]>."),
+            &",[->++<]>.".parse().unwrap(),
+            Bounds::default(),
+        )
+        .unwrap();
+        assert!(report.is_complete());
+        // one path for each value, and one for the empty input
+        assert_eq!(report.proved, 257);
+        // the sum of two inputs is equal as a polynomial
+        let report = check_equivalent(
+            &unoptimized(",>,[-<+>]<."),
+            &",>,<[->+<]>.".parse().unwrap(),
+            Bounds::default(),
+        )
+        .unwrap();
+        assert!(report.is_complete());
+    }
+
+    #[test]
+    fn finds_differences() {
+        let err = check_equivalent(
+            &",[->++<]>.".parse().unwrap(),
+            &",[->+++<]>.".parse().unwrap(),
+            Bounds::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err.first.output, [err.input[0].wrapping_mul(2)]);
+        assert_eq!(err.second.output, [err.input[0].wrapping_mul(3)]);
+        // only the inputs equal to 7 are printed by the second
+        let err = check_equivalent(
+            &unoptimized(",."),
+            &unoptimized(",>+++++++[<->-]<[+++++++.[-]]"),
+            Bounds::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err.input, [7]);
+        // the second asks for more input
+        let err = check_equivalent(&unoptimized(",."), &unoptimized(",.,"), Bounds::default())
+            .unwrap_err();
+        assert_eq!(err.second.end, RunEnd::NeedInput);
+    }
+
+    /// Compare random programs with their optimized version
+    #[test]
+    fn random_programs() {
+        let mut rng = Lcg::new(0);
+        let (mut complete, mut total) = (0, 0);
+        for _ in 0..400 {
+            let source = random::source(&mut rng, 24);
+            let raw = unoptimized(&source);
+            let optimized: Program = source.parse().unwrap();
+            match check_equivalent(&raw, &optimized, random::BOUNDS) {
+                Ok(report) => {
+                    total += 1;
+                    complete += report.is_complete() as usize
+                }
+                Err(err) if err.is_negative_tape_access() => {}
+                Err(err) => panic!("`{source}`: {err}: {err:?}\n{optimized}"),
+            }
+        }
+        // most programs are small enough to be decided on every path
+        assert!(complete * 2 > total, "Only {complete} of {total} decided")
+    }
+
+    #[test]
+    fn bounded() {
+        // the loop never ends, so nothing can be said
+        let report = check_equivalent(
+            &unoptimized("+[]"),
+            &unoptimized("+[>]"),
+            Bounds {
+                max_steps: 100,
+                ..Bounds::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(report.proved, 0);
+        assert!(!report.is_complete());
+        // the product and the sum of two inputs differ, but only trying their values shows it
+        let product: Program = ",>,<[->[->+>+<<]>>[-<<+>>]<<<]>>.".parse().unwrap();
+        let sum: Program = ",>,[-<+>]<.".parse().unwrap();
+        let report = check_equivalent(
+            &product,
+            &sum,
+            Bounds {
+                max_unknowns: 0,
+                ..Bounds::default()
+            },
+        )
+        .unwrap();
+        assert!(!report.is_complete());
+        assert!(check_equivalent(&product, &sum, Bounds::default()).is_err());
+    }
+}
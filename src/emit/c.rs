@@ -0,0 +1,211 @@
+//! C backend
+//!
+//! Lower the ir to a standalone C source, buildable with any C compiler
+
+use std::fmt::Write;
+
+use crate::ir::{
+    Add, Affine, Block, If, Input, Loop, Mul, Node, Output, Print, Program, Set, Shift, SwitchTape,
+};
+
+/// Options of the generated code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Options {
+    /// Number of cells in each tape
+    pub tape_size: usize,
+    /// Number of tapes, used only if the program switches tape
+    pub tapes: usize,
+    /// Check every memory access against the tape limits
+    pub bounds_checks: bool,
+}
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            tape_size: 65536,
+            tapes: 16,
+            bounds_checks: false,
+        }
+    }
+}
+
+/// Lower a program to a standalone C source
+///
+/// The memory is a fixed array of `unsigned char`, and the pointer starts at its beginning.
+/// Reading at the end of the input leaves the cell unchanged.
+pub fn emit(program: &Program, options: &Options) -> String {
+    let tapes = if program.stats().tape_switches > 0 {
+        options.tapes
+    } else {
+        1
+    };
+
+    let mut out = String::new();
+    writeln!(out, "/* Generated by bf */").unwrap();
+    writeln!(out, "#include <stdio.h>").unwrap();
+    writeln!(out, "#include <stdlib.h>").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "#define TAPE_SIZE {}", options.tape_size).unwrap();
+    writeln!(out, "#define TAPES {tapes}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "static unsigned char tapes[TAPES][TAPE_SIZE];").unwrap();
+    writeln!(out).unwrap();
+    if options.bounds_checks || tapes > 1 {
+        writeln!(out, "static void fail(const char *msg) {{").unwrap();
+        writeln!(out, "    fflush(stdout);").unwrap();
+        writeln!(out, "    fprintf(stderr, \"Runtime error: %s\\n\", msg);").unwrap();
+        writeln!(out, "    exit(1);").unwrap();
+        writeln!(out, "}}").unwrap();
+        writeln!(out).unwrap();
+    }
+    if options.bounds_checks {
+        writeln!(
+            out,
+            "static unsigned char *checked(unsigned char *t, long pos) {{"
+        )
+        .unwrap();
+        writeln!(out, "    if (pos < 0)").unwrap();
+        writeln!(
+            out,
+            "        fail(\"The memory pointer exited the memory from below\");"
+        )
+        .unwrap();
+        writeln!(out, "    if (pos >= TAPE_SIZE)").unwrap();
+        writeln!(
+            out,
+            "        fail(\"The memory pointer exited the memory from above\");"
+        )
+        .unwrap();
+        writeln!(out, "    return &t[pos];").unwrap();
+        writeln!(out, "}}").unwrap();
+        writeln!(out, "#define CELL(o) (*checked(t, p + (o)))").unwrap();
+    } else {
+        writeln!(out, "#define CELL(o) (t[p + (o)])").unwrap();
+    }
+    writeln!(out).unwrap();
+    writeln!(out, "int main(void) {{").unwrap();
+    writeln!(out, "    long tape = 0;").unwrap();
+    writeln!(out, "    unsigned char *t = tapes[tape];").unwrap();
+    writeln!(out, "    long p = 0;").unwrap();
+    writeln!(out, "    int c;").unwrap();
+    emit_block(&mut out, &program.0, 1);
+    writeln!(out, "    (void)tape;").unwrap();
+    writeln!(out, "    (void)c;").unwrap();
+    writeln!(out, "    return 0;").unwrap();
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+fn emit_block(out: &mut String, block: &Block, depth: usize) {
+    for node in &block.0 {
+        emit_node(out, node, depth)
+    }
+}
+
+fn emit_node(out: &mut String, node: &Node, depth: usize) {
+    let indent = "    ".repeat(depth);
+    match node {
+        Node::Noop => (),
+        Node::Shift(Shift { amount }) => writeln!(out, "{indent}p += {amount};").unwrap(),
+        Node::Add(Add { amount, offset }) => {
+            writeln!(out, "{indent}CELL({offset}) += {amount};").unwrap()
+        }
+        Node::Output(Output { offset }) => {
+            writeln!(out, "{indent}putchar(CELL({offset}));").unwrap()
+        }
+        Node::Input(Input { offset }) => {
+            writeln!(out, "{indent}fflush(stdout);").unwrap();
+            writeln!(out, "{indent}if ((c = getchar()) != EOF)").unwrap();
+            writeln!(out, "{indent}    CELL({offset}) = (unsigned char)c;").unwrap();
+        }
+        Node::InputDiscard => {
+            writeln!(out, "{indent}fflush(stdout);").unwrap();
+            writeln!(out, "{indent}getchar();").unwrap();
+        }
+        Node::Loop(Loop { body, offset }) => {
+            writeln!(out, "{indent}while (CELL({offset})) {{").unwrap();
+            emit_block(out, body, depth + 1);
+            writeln!(out, "{indent}}}").unwrap();
+        }
+        Node::If(If { body, offset }) => {
+            writeln!(out, "{indent}if (CELL({offset})) {{").unwrap();
+            emit_block(out, body, depth + 1);
+            writeln!(out, "{indent}}}").unwrap();
+        }
+        Node::Set(Set { value, offset }) => {
+            writeln!(out, "{indent}CELL({offset}) = {value};").unwrap()
+        }
+        Node::Diverge => {
+            writeln!(out, "{indent}fflush(stdout);").unwrap();
+            writeln!(out, "{indent}for (;;) {{}}").unwrap();
+        }
+        Node::Print(Print { bytes }) => {
+            // octal escapes, as hex ones do not stop after two digits
+            let literal: String = bytes.iter().map(|b| format!("\\{b:03o}")).collect();
+            writeln!(
+                out,
+                "{indent}fwrite(\"{literal}\", 1, {}, stdout);",
+                bytes.len()
+            )
+            .unwrap()
+        }
+        Node::Mul(Mul { offset, targets }) if targets.is_empty() => {
+            writeln!(out, "{indent}CELL({offset}) = 0;").unwrap()
+        }
+        Node::Mul(Mul { offset, targets }) => {
+            writeln!(out, "{indent}if (CELL({offset})) {{").unwrap();
+            writeln!(out, "{indent}    unsigned char n = CELL({offset});").unwrap();
+            // all the expressions are evaluated before any cell is modified
+            for (i, (_, expr)) in targets.iter().enumerate() {
+                writeln!(
+                    out,
+                    "{indent}    unsigned char d{i} = {};",
+                    expression(expr)
+                )
+                .unwrap();
+            }
+            for (i, (target, _)) in targets.iter().enumerate() {
+                writeln!(out, "{indent}    CELL({target}) += n * d{i};").unwrap();
+            }
+            writeln!(out, "{indent}    CELL({offset}) = 0;").unwrap();
+            writeln!(out, "{indent}}}").unwrap();
+        }
+        Node::SwitchTape(SwitchTape { amount }) => {
+            writeln!(out, "{indent}tape += {amount};").unwrap();
+            writeln!(out, "{indent}if (tape < 0)").unwrap();
+            writeln!(
+                out,
+                "{indent}    fail(\"The tape selector went before the first tape\");"
+            )
+            .unwrap();
+            writeln!(out, "{indent}if (tape >= TAPES)").unwrap();
+            writeln!(
+                out,
+                "{indent}    fail(\"The tape selector went after the last tape\");"
+            )
+            .unwrap();
+            writeln!(out, "{indent}t = tapes[tape];").unwrap();
+        }
+    }
+}
+
+/// C expression computing an affine combination of cells
+fn expression(expr: &Affine) -> String {
+    let mut res = format!("{}", expr.constant);
+    for (offset, coeff) in &expr.terms {
+        write!(res, " + {coeff} * CELL({offset})").unwrap();
+    }
+    format!("(unsigned char)({res})")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{emit, Options};
+
+    #[test]
+    fn emit_loops() {
+        let program = ",[->+++<]>.".parse().unwrap();
+        let code = emit(&program, &Options::default());
+        assert!(code.contains("int main(void)"));
+        assert!(code.contains("#define TAPES 1\n"));
+    }
+}
@@ -0,0 +1,329 @@
+//! LLVM backend
+//!
+//! Lower the ir to textual LLVM-IR, that can be compiled by `clang` or `llc` or run with `lli`.
+//! The module uses opaque pointers and links against the C library for the input and output.
+//!
+//! The crate does not bind to LLVM: programs are not compiled just in time, and object files are
+//! made by passing the output to the LLVM tools.
+
+use std::fmt::Write;
+
+use crate::ir::{
+    Add, Affine, Block, If, Input, Loop, Mul, Node, Output, Print, Program, Set, Shift, SwitchTape,
+};
+
+/// Number of cells in each tape
+const TAPE_SIZE: usize = 65536;
+/// Number of tapes, used only if the program switches tape
+const TAPES: usize = 16;
+
+/// Error messages, with the name of the global holding them
+const TAPE_BEFORE: (&str, &str) = (
+    "tape_before",
+    "Runtime error: The tape selector went before the first tape\n",
+);
+const TAPE_AFTER: (&str, &str) = (
+    "tape_after",
+    "Runtime error: The tape selector went after the last tape\n",
+);
+
+/// Lower a program to LLVM-IR
+///
+/// The memory accesses are not checked, while going out of the tapes stops the program with an error.
+/// Reading at the end of the input leaves the cell unchanged.
+pub fn emit(program: &Program) -> String {
+    let tapes = if program.stats().tape_switches > 0 {
+        TAPES
+    } else {
+        1
+    };
+
+    let mut builder = Builder {
+        out: String::new(),
+        next: 0,
+    };
+    let out = &mut builder.out;
+    writeln!(out, "; Generated by bf").unwrap();
+    writeln!(
+        out,
+        "@tapes = internal global [{} x i8] zeroinitializer",
+        tapes * TAPE_SIZE
+    )
+    .unwrap();
+    for (name, msg) in [TAPE_BEFORE, TAPE_AFTER] {
+        writeln!(
+            out,
+            "@{name} = private constant [{} x i8] c\"{}\\0A\"",
+            msg.len(),
+            msg.trim_end()
+        )
+        .unwrap();
+    }
+    writeln!(out).unwrap();
+    writeln!(out, "declare i32 @getchar()").unwrap();
+    writeln!(out, "declare i32 @putchar(i32)").unwrap();
+    writeln!(out, "declare i32 @fflush(ptr)").unwrap();
+    writeln!(out, "declare i64 @write(i32, ptr, i64)").unwrap();
+    writeln!(out, "declare void @exit(i32)").unwrap();
+    writeln!(out).unwrap();
+    writeln!(
+        out,
+        "define internal void @fail(ptr %msg, i64 %len) cold noreturn {{"
+    )
+    .unwrap();
+    writeln!(out, "  call i32 @fflush(ptr null)").unwrap();
+    writeln!(out, "  call i64 @write(i32 2, ptr %msg, i64 %len)").unwrap();
+    writeln!(out, "  call void @exit(i32 1)").unwrap();
+    writeln!(out, "  unreachable").unwrap();
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "define i32 @main() {{").unwrap();
+    writeln!(out, "entry:").unwrap();
+    writeln!(out, "  %p = alloca i64").unwrap();
+    writeln!(out, "  %tape = alloca i64").unwrap();
+    writeln!(out, "  store i64 0, ptr %p").unwrap();
+    writeln!(out, "  store i64 0, ptr %tape").unwrap();
+    builder.block(&program.0);
+    let out = &mut builder.out;
+    writeln!(out, "  call i32 @fflush(ptr null)").unwrap();
+    writeln!(out, "  ret i32 0").unwrap();
+    writeln!(out, "}}").unwrap();
+    builder.out
+}
+
+struct Builder {
+    out: String,
+    /// Next free number for temporaries and labels
+    next: usize,
+}
+
+impl Builder {
+    fn fresh(&mut self) -> usize {
+        self.next += 1;
+        self.next
+    }
+
+    fn block(&mut self, block: &Block) {
+        for node in &block.0 {
+            self.node(node)
+        }
+    }
+
+    fn node(&mut self, node: &Node) {
+        match node {
+            Node::Noop => (),
+            Node::Shift(Shift { amount }) => self.move_pointer(amount.get() as i64),
+            Node::Add(Add { amount, offset }) => {
+                let (ptr, value) = self.load(*offset);
+                let sum = self.fresh();
+                writeln!(self.out, "  %t{sum} = add i8 %t{value}, {amount}").unwrap();
+                writeln!(self.out, "  store i8 %t{sum}, ptr %t{ptr}").unwrap();
+            }
+            Node::Output(Output { offset }) => {
+                let (_, value) = self.load(*offset);
+                let ch = self.fresh();
+                writeln!(self.out, "  %t{ch} = zext i8 %t{value} to i32").unwrap();
+                writeln!(self.out, "  call i32 @putchar(i32 %t{ch})").unwrap();
+            }
+            Node::Input(Input { offset }) => {
+                let label = self.fresh();
+                let ch = self.fresh();
+                let eof = self.fresh();
+                writeln!(self.out, "  call i32 @fflush(ptr null)").unwrap();
+                writeln!(self.out, "  %t{ch} = call i32 @getchar()").unwrap();
+                writeln!(self.out, "  %t{eof} = icmp slt i32 %t{ch}, 0").unwrap();
+                writeln!(
+                    self.out,
+                    "  br i1 %t{eof}, label %end{label}, label %read{label}"
+                )
+                .unwrap();
+                writeln!(self.out, "read{label}:").unwrap();
+                let ptr = self.address(*offset);
+                let byte = self.fresh();
+                writeln!(self.out, "  %t{byte} = trunc i32 %t{ch} to i8").unwrap();
+                writeln!(self.out, "  store i8 %t{byte}, ptr %t{ptr}").unwrap();
+                writeln!(self.out, "  br label %end{label}").unwrap();
+                writeln!(self.out, "end{label}:").unwrap();
+            }
+            Node::InputDiscard => {
+                writeln!(self.out, "  call i32 @fflush(ptr null)").unwrap();
+                writeln!(self.out, "  call i32 @getchar()").unwrap();
+            }
+            Node::Loop(Loop { body, offset }) => {
+                let label = self.fresh();
+                writeln!(self.out, "  br label %cond{label}").unwrap();
+                writeln!(self.out, "cond{label}:").unwrap();
+                let (_, value) = self.load(*offset);
+                let nonzero = self.fresh();
+                writeln!(self.out, "  %t{nonzero} = icmp ne i8 %t{value}, 0").unwrap();
+                writeln!(
+                    self.out,
+                    "  br i1 %t{nonzero}, label %body{label}, label %end{label}"
+                )
+                .unwrap();
+                writeln!(self.out, "body{label}:").unwrap();
+                self.block(body);
+                writeln!(self.out, "  br label %cond{label}").unwrap();
+                writeln!(self.out, "end{label}:").unwrap();
+            }
+            Node::Mul(Mul { offset, targets }) => {
+                let label = self.fresh();
+                let (counter, n) = self.load(*offset);
+                let nonzero = self.fresh();
+                writeln!(self.out, "  %t{nonzero} = icmp ne i8 %t{n}, 0").unwrap();
+                writeln!(
+                    self.out,
+                    "  br i1 %t{nonzero}, label %mul{label}, label %end{label}"
+                )
+                .unwrap();
+                writeln!(self.out, "mul{label}:").unwrap();
+                // all the expressions are evaluated before any cell is modified
+                let factors: Vec<_> = targets
+                    .iter()
+                    .map(|(_, expr)| self.expression(expr))
+                    .collect();
+                for ((target, _), factor) in targets.iter().zip(factors) {
+                    let (ptr, value) = self.load(*target);
+                    let product = self.fresh();
+                    let sum = self.fresh();
+                    writeln!(self.out, "  %t{product} = mul i8 {factor}, %t{n}").unwrap();
+                    writeln!(self.out, "  %t{sum} = add i8 %t{value}, %t{product}").unwrap();
+                    writeln!(self.out, "  store i8 %t{sum}, ptr %t{ptr}").unwrap();
+                }
+                writeln!(self.out, "  store i8 0, ptr %t{counter}").unwrap();
+                writeln!(self.out, "  br label %end{label}").unwrap();
+                writeln!(self.out, "end{label}:").unwrap();
+            }
+            Node::If(If { body, offset }) => {
+                let label = self.fresh();
+                let (_, value) = self.load(*offset);
+                let nonzero = self.fresh();
+                writeln!(self.out, "  %t{nonzero} = icmp ne i8 %t{value}, 0").unwrap();
+                writeln!(
+                    self.out,
+                    "  br i1 %t{nonzero}, label %then{label}, label %end{label}"
+                )
+                .unwrap();
+                writeln!(self.out, "then{label}:").unwrap();
+                self.block(body);
+                writeln!(self.out, "  br label %end{label}").unwrap();
+                writeln!(self.out, "end{label}:").unwrap();
+            }
+            Node::Set(Set { value, offset }) => {
+                let ptr = self.address(*offset);
+                writeln!(self.out, "  store i8 {value}, ptr %t{ptr}").unwrap();
+            }
+            Node::Diverge => {
+                let label = self.fresh();
+                writeln!(self.out, "  call i32 @fflush(ptr null)").unwrap();
+                writeln!(self.out, "  br label %hang{label}").unwrap();
+                writeln!(self.out, "hang{label}:").unwrap();
+                writeln!(self.out, "  br label %hang{label}").unwrap();
+                // the code after is unreachable, but still needs a block
+                writeln!(self.out, "dead{label}:").unwrap();
+            }
+            Node::Print(Print { bytes }) => {
+                for byte in bytes {
+                    writeln!(self.out, "  call i32 @putchar(i32 {byte})").unwrap();
+                }
+            }
+            Node::SwitchTape(SwitchTape { amount }) => {
+                let label = self.fresh();
+                let old = self.fresh();
+                let new = self.fresh();
+                let before = self.fresh();
+                let after = self.fresh();
+                writeln!(self.out, "  %t{old} = load i64, ptr %tape").unwrap();
+                writeln!(self.out, "  %t{new} = add i64 %t{old}, {amount}").unwrap();
+                writeln!(self.out, "  store i64 %t{new}, ptr %tape").unwrap();
+                writeln!(self.out, "  %t{before} = icmp slt i64 %t{new}, 0").unwrap();
+                writeln!(
+                    self.out,
+                    "  br i1 %t{before}, label %before{label}, label %check{label}"
+                )
+                .unwrap();
+                writeln!(self.out, "before{label}:").unwrap();
+                self.fail(TAPE_BEFORE);
+                writeln!(self.out, "check{label}:").unwrap();
+                writeln!(self.out, "  %t{after} = icmp sge i64 %t{new}, {TAPES}").unwrap();
+                writeln!(
+                    self.out,
+                    "  br i1 %t{after}, label %after{label}, label %end{label}"
+                )
+                .unwrap();
+                writeln!(self.out, "after{label}:").unwrap();
+                self.fail(TAPE_AFTER);
+                writeln!(self.out, "end{label}:").unwrap();
+                self.move_pointer(amount.get() as i64 * TAPE_SIZE as i64);
+            }
+        }
+    }
+
+    fn fail(&mut self, (name, msg): (&str, &str)) {
+        writeln!(
+            self.out,
+            "  call void @fail(ptr @{name}, i64 {})",
+            msg.len()
+        )
+        .unwrap();
+        writeln!(self.out, "  unreachable").unwrap();
+    }
+
+    fn move_pointer(&mut self, amount: i64) {
+        let old = self.fresh();
+        let new = self.fresh();
+        writeln!(self.out, "  %t{old} = load i64, ptr %p").unwrap();
+        writeln!(self.out, "  %t{new} = add i64 %t{old}, {amount}").unwrap();
+        writeln!(self.out, "  store i64 %t{new}, ptr %p").unwrap();
+    }
+
+    /// Compute the address of the cell at the given offset
+    fn address(&mut self, offset: isize) -> usize {
+        let p = self.fresh();
+        let pos = self.fresh();
+        let ptr = self.fresh();
+        writeln!(self.out, "  %t{p} = load i64, ptr %p").unwrap();
+        writeln!(self.out, "  %t{pos} = add i64 %t{p}, {offset}").unwrap();
+        writeln!(
+            self.out,
+            "  %t{ptr} = getelementptr inbounds i8, ptr @tapes, i64 %t{pos}"
+        )
+        .unwrap();
+        ptr
+    }
+
+    /// Load the cell at the given offset, returning its address and its value
+    fn load(&mut self, offset: isize) -> (usize, usize) {
+        let ptr = self.address(offset);
+        let value = self.fresh();
+        writeln!(self.out, "  %t{value} = load i8, ptr %t{ptr}").unwrap();
+        (ptr, value)
+    }
+
+    /// Compute an affine combination of cells, returning the operand holding it
+    fn expression(&mut self, expr: &Affine) -> String {
+        let mut acc = expr.constant.to_string();
+        for (offset, coeff) in &expr.terms {
+            let (_, value) = self.load(*offset);
+            let product = self.fresh();
+            let sum = self.fresh();
+            writeln!(self.out, "  %t{product} = mul i8 %t{value}, {coeff}").unwrap();
+            writeln!(self.out, "  %t{sum} = add i8 {acc}, %t{product}").unwrap();
+            acc = format!("%t{sum}");
+        }
+        acc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::emit;
+
+    #[test]
+    fn emit_loops() {
+        let program = ",[->+++<]>.".parse().unwrap();
+        let code = emit(&program);
+        assert!(code.contains("define i32 @main()"));
+        assert!(code.contains("@tapes = internal global [65536 x i8]"));
+    }
+}
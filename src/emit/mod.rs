@@ -0,0 +1,9 @@
+//! Code generators, lowering the ir to other languages
+
+pub mod c;
+#[cfg(feature = "llvm")]
+pub mod llvm;
+pub mod raw;
+pub mod rust;
+pub mod wasm;
+pub mod x86_64;
@@ -0,0 +1,269 @@
+//! Brainfuck backend
+//!
+//! Lower the ir back to plain brainfuck. Sets become clear loops followed by additions, and the
+//! offsets become moves of the pointer. The constant prints and the multiplications by a cell need
+//! scratch cells, so in programs that use them each cell of the ir is followed on the tape by two
+//! scratch cells, that are zero between any two nodes.
+
+use std::{collections::BTreeSet, iter};
+
+use crate::{
+    ir::{Add, Block, If, Input, Loop, Mul, Node, Output, Print, Program, Set, Shift, SwitchTape},
+    raw::{self, Instruction},
+};
+
+/// Cells of the tape for each cell of the ir, if scratch cells are needed
+const SPREAD: isize = 3;
+
+/// Lower a program to brainfuck
+///
+/// The program uses the [`raw::Dialect::MultiTape`] dialect only if the ir switches tape.
+pub fn emit(program: &Program) -> raw::Program {
+    let mut lowering = Lowering {
+        code: vec![],
+        stride: if needs_scratch(&program.0) { SPREAD } else { 1 },
+        cursor: 0,
+    };
+    lowering.block(&program.0);
+    raw::Program::from_instrs(lowering.code).expect("The loops are always closed")
+}
+
+fn needs_scratch(block: &Block) -> bool {
+    block.0.iter().any(|node| match node {
+        Node::Print(_) | Node::InputDiscard => true,
+        Node::Mul(Mul { targets, .. }) => targets.iter().any(|(_, expr)| !expr.is_constant()),
+        Node::Loop(Loop { body, .. }) | Node::If(If { body, .. }) => needs_scratch(body),
+        _ => false,
+    })
+}
+
+struct Lowering {
+    code: Vec<Instruction>,
+    /// Cells of the tape for each cell of the ir
+    stride: isize,
+    /// Position of the pointer on the tape, relative to where the pointer of the ir is mapped
+    cursor: isize,
+}
+
+impl Lowering {
+    /// Move the pointer to the cell at `offset`, or to one of its scratch cells
+    fn goto(&mut self, offset: isize, scratch: isize) {
+        let target = offset * self.stride + scratch;
+        let instr = if target > self.cursor {
+            Instruction::ShiftRight
+        } else {
+            Instruction::ShiftLeft
+        };
+        self.code
+            .extend(iter::repeat_n(instr, target.abs_diff(self.cursor)));
+        self.cursor = target;
+    }
+
+    /// Add to the current cell, counting down if it is shorter
+    fn add(&mut self, amount: u8) {
+        if amount <= 128 {
+            self.code
+                .extend(iter::repeat_n(Instruction::Add, amount as usize))
+        } else {
+            self.code.extend(iter::repeat_n(
+                Instruction::Sub,
+                amount.wrapping_neg() as usize,
+            ))
+        }
+    }
+
+    fn open(&mut self, offset: isize, scratch: isize) {
+        self.goto(offset, scratch);
+        self.code.push(Instruction::OpenLoop)
+    }
+    fn close(&mut self, offset: isize, scratch: isize) {
+        self.goto(offset, scratch);
+        self.code.push(Instruction::CloseLoop)
+    }
+
+    /// Move the value of a cell into another, clearing it
+    fn transfer(&mut self, from: (isize, isize), to: (isize, isize)) {
+        self.open(from.0, from.1);
+        self.add(u8::MAX);
+        self.goto(to.0, to.1);
+        self.add(1);
+        self.close(from.0, from.1)
+    }
+
+    fn block(&mut self, block: &Block) {
+        for node in &block.0 {
+            self.node(node)
+        }
+    }
+
+    fn node(&mut self, node: &Node) {
+        match node {
+            Node::Noop => (),
+            Node::Shift(Shift { amount }) => self.cursor -= amount.get() * self.stride,
+            Node::Add(Add { amount, offset }) => {
+                self.goto(*offset, 0);
+                self.add(amount.get())
+            }
+            Node::Output(Output { offset }) => {
+                self.goto(*offset, 0);
+                self.code.push(Instruction::Output)
+            }
+            Node::Input(Input { offset }) => {
+                self.goto(*offset, 0);
+                self.code.push(Instruction::Input)
+            }
+            // reading in a scratch cell, then clearing it
+            Node::InputDiscard => {
+                self.goto(self.cursor.div_euclid(self.stride), 1);
+                self.code.extend([
+                    Instruction::Input,
+                    Instruction::OpenLoop,
+                    Instruction::Sub,
+                    Instruction::CloseLoop,
+                ])
+            }
+            // the body of an if always clears the cell, so it can run as a loop
+            Node::Loop(Loop { body, offset }) | Node::If(If { body, offset }) => {
+                self.open(*offset, 0);
+                self.block(body);
+                self.close(*offset, 0)
+            }
+            Node::Mul(mul) => self.mul(mul),
+            Node::SwitchTape(SwitchTape { amount }) => {
+                let instr = if amount.get() > 0 {
+                    Instruction::NextTape
+                } else {
+                    Instruction::PrevTape
+                };
+                self.code
+                    .extend(iter::repeat_n(instr, amount.get().unsigned_abs()))
+            }
+            Node::Set(Set { value, offset }) => {
+                self.goto(*offset, 0);
+                self.code.extend([
+                    Instruction::OpenLoop,
+                    Instruction::Sub,
+                    Instruction::CloseLoop,
+                ]);
+                self.add(*value)
+            }
+            Node::Print(Print { bytes }) => {
+                self.goto(self.cursor.div_euclid(self.stride), 1);
+                let mut current = 0u8;
+                for &byte in bytes {
+                    self.add(byte.wrapping_sub(current));
+                    self.code.push(Instruction::Output);
+                    current = byte;
+                }
+                self.add(current.wrapping_neg())
+            }
+            // if the cell is not zero the first loop never ends, otherwise the second one
+            Node::Diverge => self.code.extend([
+                Instruction::OpenLoop,
+                Instruction::CloseLoop,
+                Instruction::Add,
+                Instruction::OpenLoop,
+                Instruction::CloseLoop,
+            ]),
+        }
+    }
+
+    fn mul(&mut self, Mul { offset, targets }: &Mul) {
+        let offset = *offset;
+        if targets.iter().all(|(_, expr)| expr.is_constant()) {
+            self.open(offset, 0);
+            self.add(u8::MAX);
+            for (target, expr) in targets {
+                if expr.constant != 0 {
+                    self.goto(*target, 0);
+                    self.add(expr.constant)
+                }
+            }
+            self.close(offset, 0);
+            return;
+        }
+        // The products are accumulated in the first scratch cell of each target, so all the
+        // expressions read the cells before the multiplication. The scratch cells of the counter
+        // keep the factors while they are counted down.
+        let cells: BTreeSet<isize> = targets
+            .iter()
+            .flat_map(|(_, expr)| expr.terms.keys().copied())
+            .collect();
+        for cell in cells {
+            assert_ne!(cell, offset, "Multiplications never read their counter");
+            self.open(cell, 0);
+            self.add(u8::MAX);
+            self.goto(offset, 1);
+            self.add(1);
+            self.open(offset, 0);
+            self.add(u8::MAX);
+            self.goto(offset, 2);
+            self.add(1);
+            for (target, expr) in targets {
+                if expr.coefficient(cell) != 0 {
+                    self.goto(*target, 1);
+                    self.add(expr.coefficient(cell))
+                }
+            }
+            self.close(offset, 0);
+            self.transfer((offset, 2), (offset, 0));
+            self.close(cell, 0);
+            self.transfer((offset, 1), (cell, 0));
+        }
+        self.open(offset, 0);
+        self.add(u8::MAX);
+        for (target, expr) in targets {
+            if expr.constant != 0 {
+                self.goto(*target, 1);
+                self.add(expr.constant)
+            }
+        }
+        self.close(offset, 0);
+        for (target, _) in targets {
+            self.transfer((*target, 1), (*target, 0))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{emit, needs_scratch};
+    use crate::{
+        engine::{Engine, ProgrammableEngine},
+        ir::Program,
+    };
+
+    fn run(src: &str, input: &[u8]) -> Vec<u8> {
+        let mut input = input.iter().copied();
+        let mut output = vec![];
+        <crate::engine::raw::Engine>::new_from_str(src)
+            .unwrap()
+            .run_with_io(&mut || input.next(), &mut |ch| output.push(ch))
+            .unwrap();
+        output
+    }
+
+    #[test]
+    fn roundtrip() {
+        for (src, input) in [
+            (",[->+++<]>.", &[5][..]),
+            // the print needs a scratch cell
+            ("++++++++[>++++++++<-]>+.+.,.", b"x"),
+            // multiplication of two inputs
+            (",>,<[->[->+>+<<]>>[-<<+>>]<<<]>>.", &[6, 7]),
+            // the first input is discarded in a scratch cell
+            (",[-]+.,.", b"ab"),
+        ] {
+            let program: Program = src.parse().unwrap();
+            let lowered = emit(&program);
+            assert_eq!(
+                run(&lowered.to_string(), input),
+                run(src, input),
+                "{src} was lowered to {lowered}"
+            );
+        }
+        // the multiplication by a cell is lowered too
+        let program: Program = ",>,<[->[->+>+<<]>>[-<<+>>]<<<]>>.".parse().unwrap();
+        assert!(needs_scratch(&program.0));
+    }
+}
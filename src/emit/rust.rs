@@ -0,0 +1,190 @@
+//! Rust backend
+//!
+//! Lower the ir to a single Rust source file, buildable with `rustc`
+
+use std::fmt::Write;
+
+use crate::ir::{
+    Add, Affine, Block, If, Input, Loop, Mul, Node, Output, Print, Program, Set, Shift, SwitchTape,
+};
+
+/// Number of cells in each tape
+const TAPE_SIZE: usize = 65536;
+/// Number of tapes, used only if the program switches tape
+const TAPES: usize = 16;
+
+/// Lower a program to a standalone Rust source
+///
+/// Accesses outside the memory panic, and reading at the end of the input leaves the cell unchanged.
+pub fn emit(program: &Program) -> String {
+    let tapes = if program.stats().tape_switches > 0 {
+        TAPES
+    } else {
+        1
+    };
+
+    let mut out = String::new();
+    writeln!(out, "// Generated by bf").unwrap();
+    writeln!(out, "#![allow(unused)]").unwrap();
+    writeln!(out, "use std::io::{{Read, Write}};").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "const TAPE_SIZE: usize = {TAPE_SIZE};").unwrap();
+    writeln!(out, "const TAPES: isize = {tapes};").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "macro_rules! cell {{").unwrap();
+    writeln!(
+        out,
+        "    ($tapes:ident, $tape:ident, $p:ident, $o:expr) => {{"
+    )
+    .unwrap();
+    writeln!(out, "        $tapes[$tape as usize][($p + $o) as usize]").unwrap();
+    writeln!(out, "    }};").unwrap();
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "fn main() {{").unwrap();
+    writeln!(
+        out,
+        "    let mut tapes = vec![vec![0u8; TAPE_SIZE]; TAPES as usize];"
+    )
+    .unwrap();
+    writeln!(out, "    let mut tape: isize = 0;").unwrap();
+    writeln!(out, "    let mut p: isize = 0;").unwrap();
+    writeln!(out, "    let mut input = std::io::stdin().lock();").unwrap();
+    writeln!(out, "    let mut output = std::io::stdout().lock();").unwrap();
+    writeln!(out, "    let mut byte = [0u8];").unwrap();
+    emit_block(&mut out, &program.0, 1);
+    writeln!(out, "    output.flush().unwrap();").unwrap();
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+fn emit_block(out: &mut String, block: &Block, depth: usize) {
+    for node in &block.0 {
+        emit_node(out, node, depth)
+    }
+}
+
+fn emit_node(out: &mut String, node: &Node, depth: usize) {
+    let indent = "    ".repeat(depth);
+    match node {
+        Node::Noop => (),
+        Node::Shift(Shift { amount }) => writeln!(out, "{indent}p += {amount};").unwrap(),
+        Node::Add(Add { amount, offset }) => writeln!(
+            out,
+            "{indent}{} = {}.wrapping_add({amount});",
+            cell(*offset),
+            cell(*offset)
+        )
+        .unwrap(),
+        Node::Output(Output { offset }) => writeln!(
+            out,
+            "{indent}output.write_all(&[{}]).unwrap();",
+            cell(*offset)
+        )
+        .unwrap(),
+        Node::Input(Input { offset }) => {
+            writeln!(out, "{indent}output.flush().unwrap();").unwrap();
+            writeln!(out, "{indent}if input.read_exact(&mut byte).is_ok() {{").unwrap();
+            writeln!(out, "{indent}    {} = byte[0];", cell(*offset)).unwrap();
+            writeln!(out, "{indent}}}").unwrap();
+        }
+        Node::InputDiscard => {
+            writeln!(out, "{indent}output.flush().unwrap();").unwrap();
+            writeln!(out, "{indent}let _ = input.read_exact(&mut byte);").unwrap();
+        }
+        Node::Loop(Loop { body, offset }) => {
+            writeln!(out, "{indent}while {} != 0 {{", cell(*offset)).unwrap();
+            emit_block(out, body, depth + 1);
+            writeln!(out, "{indent}}}").unwrap();
+        }
+        Node::If(If { body, offset }) => {
+            writeln!(out, "{indent}if {} != 0 {{", cell(*offset)).unwrap();
+            emit_block(out, body, depth + 1);
+            writeln!(out, "{indent}}}").unwrap();
+        }
+        Node::Set(Set { value, offset }) => {
+            writeln!(out, "{indent}{} = {value};", cell(*offset)).unwrap()
+        }
+        Node::Diverge => {
+            writeln!(out, "{indent}output.flush().unwrap();").unwrap();
+            writeln!(out, "{indent}loop {{ std::thread::park() }}").unwrap();
+        }
+        Node::Print(Print { bytes }) => writeln!(
+            out,
+            "{indent}output.write_all(b\"{}\").unwrap();",
+            bytes.escape_ascii()
+        )
+        .unwrap(),
+        Node::Mul(Mul { offset, targets }) if targets.is_empty() => {
+            writeln!(out, "{indent}{} = 0;", cell(*offset)).unwrap()
+        }
+        Node::Mul(Mul { offset, targets }) => {
+            writeln!(out, "{indent}let n = {};", cell(*offset)).unwrap();
+            writeln!(out, "{indent}if n != 0 {{").unwrap();
+            // all the expressions are evaluated before any cell is modified
+            for (i, (_, expr)) in targets.iter().enumerate() {
+                writeln!(out, "{indent}    let d{i} = {};", expression(expr)).unwrap();
+            }
+            for (i, (target, _)) in targets.iter().enumerate() {
+                writeln!(
+                    out,
+                    "{indent}    {} = {}.wrapping_add(n.wrapping_mul(d{i}));",
+                    cell(*target),
+                    cell(*target)
+                )
+                .unwrap();
+            }
+            writeln!(out, "{indent}    {} = 0;", cell(*offset)).unwrap();
+            writeln!(out, "{indent}}}").unwrap();
+        }
+        Node::SwitchTape(SwitchTape { amount }) => {
+            writeln!(out, "{indent}tape += {amount};").unwrap();
+            writeln!(out, "{indent}if tape < 0 {{").unwrap();
+            writeln!(
+                out,
+                "{indent}    panic!(\"The tape selector went before the first tape\");"
+            )
+            .unwrap();
+            writeln!(out, "{indent}}}").unwrap();
+            writeln!(out, "{indent}if tape >= TAPES {{").unwrap();
+            writeln!(
+                out,
+                "{indent}    panic!(\"The tape selector went after the last tape\");"
+            )
+            .unwrap();
+            writeln!(out, "{indent}}}").unwrap();
+        }
+    }
+}
+
+/// Rust place expression of the cell at the given offset
+fn cell(offset: isize) -> String {
+    format!("cell!(tapes, tape, p, {offset})")
+}
+
+/// Rust expression computing an affine combination of cells
+fn expression(expr: &Affine) -> String {
+    let mut res = format!("{}u8", expr.constant);
+    for (offset, coeff) in &expr.terms {
+        write!(
+            res,
+            ".wrapping_add({}.wrapping_mul({coeff}))",
+            cell(*offset)
+        )
+        .unwrap();
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::emit;
+
+    #[test]
+    fn emit_loops() {
+        let program = ",[->+++<]>.".parse().unwrap();
+        let code = emit(&program);
+        assert!(code.contains("fn main()"));
+        assert!(code.contains("const TAPES: isize = 1;"));
+    }
+}
@@ -0,0 +1,347 @@
+//! WebAssembly backend
+//!
+//! Lower the ir to a binary `.wasm` module. The module imports `env.read_byte`, returning the
+//! next input byte or `-1` at the end of the input, and `env.write_byte`, and exports the
+//! function `run` and its `memory`.
+
+use crate::ir::{
+    Add, Affine, Block, If, Input, Loop, Mul, Node, Output, Print, Program, Set, Shift, SwitchTape,
+};
+
+/// Number of cells in each tape. This is also the size of a wasm page.
+const TAPE_SIZE: usize = 65536;
+/// Number of tapes, used only if the program switches tape
+const TAPES: usize = 16;
+
+/// Opcodes used by the lowering
+mod op {
+    pub const UNREACHABLE: u8 = 0x00;
+    pub const BLOCK: u8 = 0x02;
+    pub const LOOP: u8 = 0x03;
+    pub const IF: u8 = 0x04;
+    pub const END: u8 = 0x0b;
+    pub const BR: u8 = 0x0c;
+    pub const BR_IF: u8 = 0x0d;
+    pub const CALL: u8 = 0x10;
+    pub const DROP: u8 = 0x1a;
+    pub const LOCAL_GET: u8 = 0x20;
+    pub const LOCAL_SET: u8 = 0x21;
+    pub const LOCAL_TEE: u8 = 0x22;
+    pub const I32_LOAD8_U: u8 = 0x2d;
+    pub const I32_STORE8: u8 = 0x3a;
+    pub const I32_CONST: u8 = 0x41;
+    pub const I32_EQZ: u8 = 0x45;
+    pub const I32_LT_S: u8 = 0x48;
+    pub const I32_GE_S: u8 = 0x4e;
+    pub const I32_ADD: u8 = 0x6a;
+    pub const I32_MUL: u8 = 0x6c;
+
+    /// Block type of blocks with no results
+    pub const EMPTY: u8 = 0x40;
+    /// Value type of 32 bit integers
+    pub const I32: u8 = 0x7f;
+    /// Type constructor of functions
+    pub const FUNC: u8 = 0x60;
+}
+
+/// Index of the imported `read_byte`
+const READ_BYTE: u32 = 0;
+/// Index of the imported `write_byte`
+const WRITE_BYTE: u32 = 1;
+/// Index of the exported `run`
+const RUN: u32 = 2;
+
+/// Address of the current cell
+const LOCAL_P: u32 = 0;
+/// Selected tape
+const LOCAL_TAPE: u32 = 1;
+/// Counter of a multiplication
+const LOCAL_N: u32 = 2;
+/// Last read byte
+const LOCAL_C: u32 = 3;
+/// First of the temporaries holding the multiplication factors
+const LOCAL_D: u32 = 4;
+
+/// Lower a program to a WebAssembly module
+///
+/// Accesses outside the memory trap, and reading at the end of the input leaves the cell unchanged.
+pub fn emit(program: &Program) -> Vec<u8> {
+    let tapes = if program.stats().tape_switches > 0 {
+        TAPES
+    } else {
+        1
+    };
+
+    let mut module = b"\0asm".to_vec();
+    module.extend(1u32.to_le_bytes());
+
+    // types: read_byte, write_byte, run
+    let mut types = vec![];
+    uleb(&mut types, 3);
+    types.extend([op::FUNC, 0, 1, op::I32]);
+    types.extend([op::FUNC, 1, op::I32, 0]);
+    types.extend([op::FUNC, 0, 0]);
+    section(&mut module, 1, &types);
+
+    let mut imports = vec![];
+    uleb(&mut imports, 2);
+    for (name, typ) in [("read_byte", READ_BYTE), ("write_byte", WRITE_BYTE)] {
+        string(&mut imports, "env");
+        string(&mut imports, name);
+        imports.push(0x00);
+        uleb(&mut imports, typ);
+    }
+    section(&mut module, 2, &imports);
+
+    let mut functions = vec![];
+    uleb(&mut functions, 1);
+    uleb(&mut functions, 2);
+    section(&mut module, 3, &functions);
+
+    let mut memories = vec![];
+    uleb(&mut memories, 1);
+    memories.push(0x00);
+    uleb(&mut memories, (tapes * TAPE_SIZE / 65536) as u32);
+    section(&mut module, 5, &memories);
+
+    let mut exports = vec![];
+    uleb(&mut exports, 2);
+    string(&mut exports, "run");
+    exports.push(0x00);
+    uleb(&mut exports, RUN);
+    string(&mut exports, "memory");
+    exports.push(0x02);
+    uleb(&mut exports, 0);
+    section(&mut module, 7, &exports);
+
+    let mut body = vec![];
+    let mut temporaries = 0;
+    emit_block(&mut body, &program.0, &mut temporaries);
+    body.push(op::END);
+    let mut function = vec![];
+    uleb(&mut function, 1);
+    uleb(&mut function, LOCAL_D + temporaries);
+    function.push(op::I32);
+    function.extend(body);
+    let mut code = vec![];
+    uleb(&mut code, 1);
+    uleb(&mut code, function.len() as u32);
+    code.extend(function);
+    section(&mut module, 10, &code);
+
+    module
+}
+
+fn emit_block(out: &mut Vec<u8>, block: &Block, temporaries: &mut u32) {
+    for node in &block.0 {
+        emit_node(out, node, temporaries)
+    }
+}
+
+fn emit_node(out: &mut Vec<u8>, node: &Node, temporaries: &mut u32) {
+    match node {
+        Node::Noop => (),
+        Node::Shift(Shift { amount }) => {
+            local(out, op::LOCAL_GET, LOCAL_P);
+            i32_const(out, amount.get() as i64);
+            out.push(op::I32_ADD);
+            local(out, op::LOCAL_SET, LOCAL_P);
+        }
+        Node::Add(Add { amount, offset }) => {
+            address(out, *offset);
+            load(out, *offset);
+            i32_const(out, amount.get() as i64);
+            out.push(op::I32_ADD);
+            store(out);
+        }
+        Node::Output(Output { offset }) => {
+            load(out, *offset);
+            call(out, WRITE_BYTE);
+        }
+        Node::Input(Input { offset }) => {
+            call(out, READ_BYTE);
+            local(out, op::LOCAL_TEE, LOCAL_C);
+            i32_const(out, 0);
+            out.push(op::I32_GE_S);
+            out.extend([op::IF, op::EMPTY]);
+            address(out, *offset);
+            local(out, op::LOCAL_GET, LOCAL_C);
+            store(out);
+            out.push(op::END);
+        }
+        Node::InputDiscard => {
+            call(out, READ_BYTE);
+            out.push(op::DROP);
+        }
+        Node::Loop(Loop { body, offset }) => {
+            out.extend([op::BLOCK, op::EMPTY, op::LOOP, op::EMPTY]);
+            load(out, *offset);
+            out.push(op::I32_EQZ);
+            out.extend([op::BR_IF, 1]);
+            emit_block(out, body, temporaries);
+            out.extend([op::BR, 0, op::END, op::END]);
+        }
+        Node::Mul(Mul { offset, targets }) => {
+            *temporaries = (*temporaries).max(targets.len() as u32);
+            load(out, *offset);
+            local(out, op::LOCAL_TEE, LOCAL_N);
+            out.extend([op::IF, op::EMPTY]);
+            // all the expressions are evaluated before any cell is modified
+            for (i, (_, expr)) in targets.iter().enumerate() {
+                expression(out, expr);
+                local(out, op::LOCAL_SET, LOCAL_D + i as u32);
+            }
+            for (i, (target, _)) in targets.iter().enumerate() {
+                address(out, *target);
+                load(out, *target);
+                local(out, op::LOCAL_GET, LOCAL_D + i as u32);
+                local(out, op::LOCAL_GET, LOCAL_N);
+                out.push(op::I32_MUL);
+                out.push(op::I32_ADD);
+                store(out);
+            }
+            address(out, *offset);
+            i32_const(out, 0);
+            store(out);
+            out.push(op::END);
+        }
+        Node::If(If { body, offset }) => {
+            load(out, *offset);
+            out.extend([op::IF, op::EMPTY]);
+            emit_block(out, body, temporaries);
+            out.push(op::END);
+        }
+        Node::Set(Set { value, offset }) => {
+            address(out, *offset);
+            i32_const(out, *value as i64);
+            store(out);
+        }
+        Node::Diverge => out.extend([op::LOOP, op::EMPTY, op::BR, 0, op::END]),
+        Node::Print(Print { bytes }) => {
+            for &byte in bytes {
+                i32_const(out, byte as i64);
+                call(out, WRITE_BYTE);
+            }
+        }
+        Node::SwitchTape(SwitchTape { amount }) => {
+            local(out, op::LOCAL_GET, LOCAL_TAPE);
+            i32_const(out, amount.get() as i64);
+            out.push(op::I32_ADD);
+            local(out, op::LOCAL_TEE, LOCAL_TAPE);
+            i32_const(out, 0);
+            out.push(op::I32_LT_S);
+            out.extend([op::IF, op::EMPTY, op::UNREACHABLE, op::END]);
+            local(out, op::LOCAL_GET, LOCAL_TAPE);
+            i32_const(out, TAPES as i64);
+            out.push(op::I32_GE_S);
+            out.extend([op::IF, op::EMPTY, op::UNREACHABLE, op::END]);
+            local(out, op::LOCAL_GET, LOCAL_P);
+            i32_const(out, amount.get() as i64 * TAPE_SIZE as i64);
+            out.push(op::I32_ADD);
+            local(out, op::LOCAL_SET, LOCAL_P);
+        }
+    }
+}
+
+/// Compute an affine combination of cells
+fn expression(out: &mut Vec<u8>, expr: &Affine) {
+    i32_const(out, expr.constant as i64);
+    for (offset, coeff) in &expr.terms {
+        load(out, *offset);
+        i32_const(out, coeff.get() as i64);
+        out.push(op::I32_MUL);
+        out.push(op::I32_ADD);
+    }
+}
+
+/// Push the address of the cell at the given offset
+fn address(out: &mut Vec<u8>, offset: isize) {
+    local(out, op::LOCAL_GET, LOCAL_P);
+    if offset != 0 {
+        i32_const(out, offset as i64);
+        out.push(op::I32_ADD);
+    }
+}
+
+/// Push the value of the cell at the given offset
+fn load(out: &mut Vec<u8>, offset: isize) {
+    address(out, offset);
+    out.extend([op::I32_LOAD8_U, 0, 0]);
+}
+
+/// Store a value at the address below it on the stack
+fn store(out: &mut Vec<u8>) {
+    out.extend([op::I32_STORE8, 0, 0]);
+}
+
+fn local(out: &mut Vec<u8>, op: u8, idx: u32) {
+    out.push(op);
+    uleb(out, idx);
+}
+
+fn call(out: &mut Vec<u8>, func: u32) {
+    out.push(op::CALL);
+    uleb(out, func);
+}
+
+fn i32_const(out: &mut Vec<u8>, value: i64) {
+    out.push(op::I32_CONST);
+    sleb(out, value);
+}
+
+fn section(module: &mut Vec<u8>, id: u8, content: &[u8]) {
+    module.push(id);
+    uleb(module, content.len() as u32);
+    module.extend(content);
+}
+
+fn string(out: &mut Vec<u8>, s: &str) {
+    uleb(out, s.len() as u32);
+    out.extend(s.as_bytes());
+}
+
+fn uleb(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{emit, sleb, uleb};
+
+    #[test]
+    fn leb128() {
+        let mut out = vec![];
+        uleb(&mut out, 624485);
+        assert_eq!(out, [0xe5, 0x8e, 0x26]);
+        out.clear();
+        sleb(&mut out, -123456);
+        assert_eq!(out, [0xc0, 0xbb, 0x78]);
+    }
+
+    #[test]
+    fn emit_loops() {
+        let program = ",[->+++<]>.".parse().unwrap();
+        let module = emit(&program);
+        assert!(module.starts_with(b"\0asm\x01\0\0\0"));
+    }
+}
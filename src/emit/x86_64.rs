@@ -0,0 +1,252 @@
+//! x86-64 backend
+//!
+//! Lower the ir to GNU assembly for x86-64 Linux. The result does not need the C library, and can
+//! be turned into an executable with `cc -nostdlib -static`.
+
+use std::fmt::Write;
+
+use crate::ir::{
+    Add, Affine, Block, If, Input, Loop, Mul, Node, Output, Print, Program, Set, Shift, SwitchTape,
+};
+
+/// Number of cells in each tape
+const TAPE_SIZE: usize = 65536;
+/// Number of tapes, used only if the program switches tape
+const TAPES: usize = 16;
+/// Size of the output buffer
+const OUT_BUF: usize = 4096;
+
+/// Support routines. `rbx` points to the current cell, `r12` is the length of the output buffer
+/// and `r13` is the selected tape.
+const RUNTIME: &str = r#"
+# write the byte in al
+put:
+    lea rdi, [rip + out_buf]
+    mov [rdi + r12], al
+    inc r12
+    cmp r12, OUT_BUF
+    je flush
+    ret
+
+# empty the output buffer
+flush:
+    lea rsi, [rip + out_buf]
+1:
+    test r12, r12
+    jle 2f
+    mov eax, 1
+    mov edi, 1
+    mov rdx, r12
+    syscall
+    test rax, rax
+    jle 2f
+    add rsi, rax
+    sub r12, rax
+    jmp 1b
+2:
+    xor r12d, r12d
+    ret
+
+# read a byte in eax, or -1 at the end of the input
+get:
+    call flush
+    xor eax, eax
+    xor edi, edi
+    lea rsi, [rip + in_byte]
+    mov edx, 1
+    syscall
+    cmp rax, 1
+    jne 1f
+    movzx eax, byte ptr [rip + in_byte]
+    ret
+1:
+    mov eax, -1
+    ret
+
+# print the message in rsi, long rdx, and exit with an error
+fail:
+    push rsi
+    push rdx
+    call flush
+    pop rdx
+    pop rsi
+    mov eax, 1
+    mov edi, 2
+    syscall
+    mov eax, 60
+    mov edi, 1
+    syscall
+
+tape_before:
+    lea rsi, [rip + tape_before_msg]
+    mov edx, offset tape_before_len
+    jmp fail
+
+tape_after:
+    lea rsi, [rip + tape_after_msg]
+    mov edx, offset tape_after_len
+    jmp fail
+
+    .section .rodata
+tape_before_msg:
+    .ascii "Runtime error: The tape selector went before the first tape\n"
+    .set tape_before_len, . - tape_before_msg
+tape_after_msg:
+    .ascii "Runtime error: The tape selector went after the last tape\n"
+    .set tape_after_len, . - tape_after_msg
+
+    .bss
+in_byte:
+    .zero 1
+out_buf:
+    .zero OUT_BUF
+    .balign 64
+tapes:
+    .zero TAPE_SIZE * TAPES
+"#;
+
+/// Lower a program to x86-64 assembly
+///
+/// The memory accesses are not checked, while going out of the tapes stops the program with an error.
+/// Reading at the end of the input leaves the cell unchanged.
+pub fn emit(program: &Program) -> String {
+    let tapes = if program.stats().tape_switches > 0 {
+        TAPES
+    } else {
+        1
+    };
+
+    let mut out = String::new();
+    writeln!(out, "# Generated by bf").unwrap();
+    writeln!(out, "    .intel_syntax noprefix").unwrap();
+    writeln!(out, "    .set TAPE_SIZE, {TAPE_SIZE}").unwrap();
+    writeln!(out, "    .set TAPES, {tapes}").unwrap();
+    writeln!(out, "    .set OUT_BUF, {OUT_BUF}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "    .text").unwrap();
+    writeln!(out, "    .globl _start").unwrap();
+    writeln!(out, "_start:").unwrap();
+    writeln!(out, "    lea rbx, [rip + tapes]").unwrap();
+    writeln!(out, "    xor r12d, r12d").unwrap();
+    writeln!(out, "    xor r13d, r13d").unwrap();
+    let mut labels = 0;
+    emit_block(&mut out, &program.0, &mut labels);
+    writeln!(out, "    call flush").unwrap();
+    writeln!(out, "    mov eax, 60").unwrap();
+    writeln!(out, "    xor edi, edi").unwrap();
+    writeln!(out, "    syscall").unwrap();
+    out.push_str(RUNTIME);
+    out
+}
+
+fn emit_block(out: &mut String, block: &Block, labels: &mut usize) {
+    for node in &block.0 {
+        emit_node(out, node, labels)
+    }
+}
+
+fn emit_node(out: &mut String, node: &Node, labels: &mut usize) {
+    match node {
+        Node::Noop => (),
+        Node::Shift(Shift { amount }) => writeln!(out, "    add rbx, {amount}").unwrap(),
+        Node::Add(Add { amount, offset }) => {
+            writeln!(out, "    add byte ptr [rbx + {offset}], {amount}").unwrap()
+        }
+        Node::Output(Output { offset }) => {
+            writeln!(out, "    mov al, [rbx + {offset}]").unwrap();
+            writeln!(out, "    call put").unwrap();
+        }
+        Node::Input(Input { offset }) => {
+            writeln!(out, "    call get").unwrap();
+            writeln!(out, "    test eax, eax").unwrap();
+            writeln!(out, "    js 1f").unwrap();
+            writeln!(out, "    mov [rbx + {offset}], al").unwrap();
+            writeln!(out, "1:").unwrap();
+        }
+        Node::InputDiscard => writeln!(out, "    call get").unwrap(),
+        Node::Loop(Loop { body, offset }) => {
+            let label = *labels;
+            *labels += 1;
+            writeln!(out, "    cmp byte ptr [rbx + {offset}], 0").unwrap();
+            writeln!(out, "    je .Lend{label}").unwrap();
+            writeln!(out, ".Lstart{label}:").unwrap();
+            emit_block(out, body, labels);
+            writeln!(out, "    cmp byte ptr [rbx + {offset}], 0").unwrap();
+            writeln!(out, "    jne .Lstart{label}").unwrap();
+            writeln!(out, ".Lend{label}:").unwrap();
+        }
+        Node::Mul(Mul { offset, targets }) => {
+            let label = *labels;
+            *labels += 1;
+            writeln!(out, "    movzx ecx, byte ptr [rbx + {offset}]").unwrap();
+            writeln!(out, "    test ecx, ecx").unwrap();
+            writeln!(out, "    je .Lend{label}").unwrap();
+            // all the expressions are evaluated before any cell is modified
+            for (_, expr) in targets {
+                expression(out, expr);
+                writeln!(out, "    push rdx").unwrap();
+            }
+            for (target, _) in targets.iter().rev() {
+                writeln!(out, "    pop rdx").unwrap();
+                writeln!(out, "    imul edx, ecx").unwrap();
+                writeln!(out, "    add [rbx + {target}], dl").unwrap();
+            }
+            writeln!(out, "    mov byte ptr [rbx + {offset}], 0").unwrap();
+            writeln!(out, ".Lend{label}:").unwrap();
+        }
+        Node::If(If { body, offset }) => {
+            let label = *labels;
+            *labels += 1;
+            writeln!(out, "    cmp byte ptr [rbx + {offset}], 0").unwrap();
+            writeln!(out, "    je .Lend{label}").unwrap();
+            emit_block(out, body, labels);
+            writeln!(out, ".Lend{label}:").unwrap();
+        }
+        Node::Set(Set { value, offset }) => {
+            writeln!(out, "    mov byte ptr [rbx + {offset}], {value}").unwrap()
+        }
+        Node::Diverge => {
+            let label = *labels;
+            *labels += 1;
+            writeln!(out, "    call flush").unwrap();
+            writeln!(out, ".Lhang{label}:").unwrap();
+            writeln!(out, "    jmp .Lhang{label}").unwrap();
+        }
+        Node::Print(Print { bytes }) => {
+            for byte in bytes {
+                writeln!(out, "    mov al, {byte}").unwrap();
+                writeln!(out, "    call put").unwrap();
+            }
+        }
+        Node::SwitchTape(SwitchTape { amount }) => {
+            writeln!(out, "    add r13, {amount}").unwrap();
+            writeln!(out, "    js tape_before").unwrap();
+            writeln!(out, "    cmp r13, TAPES").unwrap();
+            writeln!(out, "    jge tape_after").unwrap();
+            writeln!(out, "    add rbx, {}", amount.get() * TAPE_SIZE as isize).unwrap();
+        }
+    }
+}
+
+/// Compute an affine combination of cells in `edx`
+fn expression(out: &mut String, expr: &Affine) {
+    writeln!(out, "    mov edx, {}", expr.constant).unwrap();
+    for (offset, coeff) in &expr.terms {
+        writeln!(out, "    movzx eax, byte ptr [rbx + {offset}]").unwrap();
+        writeln!(out, "    imul eax, eax, {coeff}").unwrap();
+        writeln!(out, "    add edx, eax").unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::emit;
+
+    #[test]
+    fn emit_loops() {
+        let program = ",[->+++<]>.".parse().unwrap();
+        let code = emit(&program);
+        assert!(code.contains("_start:"));
+        assert!(code.contains(".set TAPES, 1\n"));
+    }
+}
@@ -0,0 +1,78 @@
+//! Driving engines with async input and output
+//!
+//! The engine runs synchronously between two exchanges of data, and yields to the executor while
+//! waiting for input or writing output.
+
+use std::io;
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use thiserror::Error;
+
+use super::{Engine, RTError, StopState};
+
+/// Error while running an engine with async input and output
+#[derive(Debug, Error)]
+pub enum AsyncRunError {
+    #[error(transparent)]
+    Runtime(#[from] RTError),
+    #[error("Error during input or output")]
+    IO(#[from] io::Error),
+}
+
+/// Run the engine, reading from `input` and writing to `output`
+///
+/// The output is buffered, and flushed before waiting for input.
+/// Stops when the engine halts, reaches a breakpoint or reads past the closed input, or with
+/// [`StopState::NeedInput`] at the end of the input.
+pub async fn run<E, R, W>(
+    engine: &mut E,
+    input: &mut R,
+    output: &mut W,
+) -> Result<StopState, AsyncRunError>
+where
+    E: Engine,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = vec![];
+    let stop = loop {
+        match engine.run()? {
+            stop @ (StopState::Halted
+            | StopState::Breakpoint
+            | StopState::EndOfInput
+            | StopState::Diverged) => break stop,
+            StopState::NeedInput => {
+                output.write_all(&buffer).await?;
+                output.flush().await?;
+                buffer.clear();
+                let mut ch = [0];
+                if input.read(&mut ch).await? == 0 {
+                    break StopState::NeedInput;
+                }
+                engine.give_input(ch[0]);
+            }
+            StopState::HasOutput(ch) => buffer.push(ch),
+        }
+    };
+    output.write_all(&buffer).await?;
+    output.flush().await?;
+    Ok(stop)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, io::Cursor};
+
+    use super::run;
+    use crate::engine::{ProgrammableEngine, StopState};
+
+    #[test]
+    fn echo() {
+        let mut engine = crate::engine::ir::Engine::new_from_str(",[.,]").unwrap();
+        let mut input = Cursor::new(b"abc".to_vec());
+        let mut output = Cursor::new(vec![]);
+        let stop = block_on(run(&mut engine, &mut input, &mut output)).unwrap();
+        assert_eq!(stop, StopState::NeedInput);
+        assert_eq!(output.into_inner(), b"abc");
+    }
+}
@@ -0,0 +1,262 @@
+//! Running engines on a worker thread
+//!
+//! [`spawn`] moves an engine to its own thread, and returns a [`Handle`] that exchanges input and
+//! output with it through channels. The run can be paused, resumed and cancelled at any time, as
+//! the worker checks for commands between slices of steps.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Condvar, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use static_assertions::assert_impl_all;
+
+use super::{Engine, RTError, State, StopState};
+
+// The engines and the programs can be moved to a worker
+assert_impl_all!(super::raw::Engine: Send);
+assert_impl_all!(super::ir::Engine: Send);
+assert_impl_all!(super::threaded::Engine: Send);
+assert_impl_all!(super::closure::Engine: Send);
+assert_impl_all!(crate::raw::Program: Send, Sync);
+assert_impl_all!(crate::ir::Program: Send, Sync);
+
+/// Steps run between two checks for commands
+const SLICE: u64 = 1 << 16;
+
+/// How a background run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Exit {
+    /// The engine stopped by itself
+    ///
+    /// [`StopState::NeedInput`] means the input was closed, but the engine cannot be told so.
+    Stopped(StopState),
+    /// The run was cancelled with [`Handle::cancel`], or the handle was dropped
+    Cancelled,
+}
+
+/// Messages from the handle to the worker
+enum Message {
+    Input(Vec<u8>),
+    CloseInput,
+    /// Check the flags again
+    Wake,
+}
+
+#[derive(Debug, Default)]
+struct Flags {
+    paused: bool,
+    cancelled: bool,
+}
+
+/// Flags shared between the handle and the worker
+#[derive(Debug, Default)]
+struct Control {
+    flags: Mutex<Flags>,
+    changed: Condvar,
+}
+
+impl Control {
+    fn update(&self, f: impl FnOnce(&mut Flags)) {
+        f(&mut self
+            .flags
+            .lock()
+            .expect("The lock should never be poisoned"));
+        self.changed.notify_all()
+    }
+
+    /// Wait until the run is not paused. Returns `true` if it was cancelled
+    fn wait(&self) -> bool {
+        let flags = self
+            .changed
+            .wait_while(
+                self.flags
+                    .lock()
+                    .expect("The lock should never be poisoned"),
+                |flags| flags.paused && !flags.cancelled,
+            )
+            .expect("The lock should never be poisoned");
+        flags.cancelled
+    }
+}
+
+/// Handle to an engine running on a worker thread
+///
+/// Dropping the handle cancels the run.
+#[derive(Debug)]
+pub struct Handle<E> {
+    control: Arc<Control>,
+    input: Sender<Message>,
+    output: Receiver<u8>,
+    worker: Option<JoinHandle<(E, Result<Exit, RTError>)>>,
+}
+
+/// Run the engine on a new thread
+pub fn spawn<E>(engine: E) -> Handle<E>
+where
+    E: Engine + Send + 'static,
+{
+    let control = Arc::<Control>::default();
+    let (input, messages) = mpsc::channel();
+    let (outputs, output) = mpsc::channel();
+    let worker = {
+        let control = control.clone();
+        thread::spawn(move || work(engine, &control, &messages, &outputs))
+    };
+    Handle {
+        control,
+        input,
+        output,
+        worker: Some(worker),
+    }
+}
+
+fn work<E: Engine>(
+    mut engine: E,
+    control: &Control,
+    messages: &Receiver<Message>,
+    output: &Sender<u8>,
+) -> (E, Result<Exit, RTError>) {
+    // input sent and not yet taken by the engine
+    let mut pending = VecDeque::new();
+    let end = 'run: loop {
+        if control.wait() {
+            break Ok(Exit::Cancelled);
+        }
+        let state = match engine.step_n(SLICE) {
+            Ok((state, _)) => state,
+            Err(err) => break Err(err),
+        };
+        match state {
+            State::Running => (),
+            State::Stopped(StopState::HasOutput(ch)) => {
+                // the output is dropped if nobody is listening
+                let _ = output.send(ch);
+            }
+            State::Stopped(StopState::NeedInput) => loop {
+                let taken = engine.give_input_slice(pending.make_contiguous());
+                if taken > 0 {
+                    pending.drain(..taken);
+                    continue 'run;
+                }
+                match messages.recv() {
+                    Ok(Message::Input(input)) => pending.extend(input),
+                    Ok(Message::CloseInput) => {
+                        if engine.close_input() {
+                            continue 'run;
+                        }
+                        break 'run Ok(Exit::Stopped(StopState::NeedInput));
+                    }
+                    Ok(Message::Wake) => {
+                        if control.wait() {
+                            break 'run Ok(Exit::Cancelled);
+                        }
+                    }
+                    Err(_) => break 'run Ok(Exit::Cancelled),
+                }
+            },
+            State::Stopped(stop) => break Ok(Exit::Stopped(stop)),
+        }
+    };
+    (engine, end)
+}
+
+impl<E> Handle<E> {
+    /// Send input to the engine
+    ///
+    /// Returns `false` if the run already ended
+    pub fn send(&self, input: &[u8]) -> bool {
+        self.input.send(Message::Input(input.to_vec())).is_ok()
+    }
+
+    /// Declare that no more input will be sent. See [`Engine::close_input`]
+    pub fn close_input(&self) {
+        let _ = self.input.send(Message::CloseInput);
+    }
+
+    /// Output of the engine
+    ///
+    /// The channel is disconnected when the run ends, so it can be iterated until then.
+    pub fn output(&self) -> &Receiver<u8> {
+        &self.output
+    }
+
+    /// Pause the run. It can be continued with [`Handle::resume`]
+    ///
+    /// The engine stops at the end of the current slice of steps.
+    pub fn pause(&self) {
+        self.control.update(|flags| flags.paused = true)
+    }
+
+    /// Continue a paused run
+    pub fn resume(&self) {
+        self.control.update(|flags| flags.paused = false)
+    }
+
+    /// Stop the run, even if paused or waiting for input
+    pub fn cancel(&self) {
+        self.control.update(|flags| flags.cancelled = true);
+        let _ = self.input.send(Message::Wake);
+    }
+
+    /// Check if the run ended
+    pub fn is_finished(&self) -> bool {
+        self.worker.as_ref().is_none_or(JoinHandle::is_finished)
+    }
+
+    /// Wait for the run to end, and take back the engine
+    ///
+    /// # Panics
+    /// If the engine panicked
+    pub fn join(mut self) -> (E, Result<Exit, RTError>) {
+        self.worker
+            .take()
+            .expect("The worker is taken only on join")
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    }
+}
+
+impl<E> Drop for Handle<E> {
+    fn drop(&mut self) {
+        if self.worker.is_some() {
+            self.cancel()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{spawn, Exit};
+    use crate::engine::{ProgrammableEngine, StopState};
+
+    #[test]
+    fn echo() {
+        let handle = spawn(crate::engine::closure::Engine::new_from_str(",[+.,]").unwrap());
+        assert!(handle.send(b"ab"));
+        assert_eq!(handle.output().recv(), Ok(b'b'));
+        assert_eq!(handle.output().recv(), Ok(b'c'));
+        handle.close_input();
+        let (_, end) = handle.join();
+        assert_eq!(end, Ok(Exit::Stopped(StopState::EndOfInput)));
+    }
+
+    #[test]
+    fn cancel() {
+        let handle = spawn(<crate::engine::raw::Engine>::new_from_str("+[]").unwrap());
+        handle.pause();
+        handle.resume();
+        handle.cancel();
+        let (_, end) = handle.join();
+        assert_eq!(end, Ok(Exit::Cancelled));
+
+        // waiting for input
+        let handle = spawn(crate::engine::ir::Engine::new_from_str(",").unwrap());
+        handle.cancel();
+        assert_eq!(handle.join().1, Ok(Exit::Cancelled));
+    }
+}
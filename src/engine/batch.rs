@@ -0,0 +1,84 @@
+//! Running a program on many inputs in parallel
+//!
+//! Each input gets its own clone of a template engine, so the program is decoded only once and
+//! shared between the threads.
+
+use rayon::prelude::*;
+
+use crate::ir;
+
+use super::{limit::StepLimit, Engine, ProgrammableEngine, RTError, StopState};
+
+/// Result of running a program on one input
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Outcome {
+    /// The output emitted, up to the end of the run
+    pub output: Vec<u8>,
+    /// How the run ended. [`StopState::NeedInput`] if the input was exhausted
+    pub end: Result<StopState, RTError>,
+}
+
+/// Run a clone of `engine` on each input, in parallel
+///
+/// If `max_steps` is given, each run fails with [`RTError::StepLimitExceeded`] after that many
+/// steps. The outcomes are in the same order as the inputs.
+pub fn run<E, I>(engine: &E, inputs: &[I], max_steps: Option<u64>) -> Vec<Outcome>
+where
+    E: Engine + Clone + Send + Sync,
+    I: AsRef<[u8]> + Sync,
+{
+    inputs
+        .par_iter()
+        .map(|input| {
+            let mut input = input.as_ref().iter().copied();
+            let mut output = vec![];
+            let end = match max_steps {
+                Some(limit) => StepLimit::new(engine.clone(), limit)
+                    .run_with_io(&mut || input.next(), &mut |ch| output.push(ch)),
+                None => engine
+                    .clone()
+                    .run_with_io(&mut || input.next(), &mut |ch| output.push(ch)),
+            };
+            Outcome { output, end }
+        })
+        .collect()
+}
+
+/// Run a compiled program on each input, in parallel, with the threaded engine
+pub fn run_program<I>(program: ir::Program, inputs: &[I], max_steps: Option<u64>) -> Vec<Outcome>
+where
+    I: AsRef<[u8]> + Sync,
+{
+    run(&super::threaded::Engine::new(program), inputs, max_steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run_program, Outcome};
+    use crate::engine::{RTError, StopState};
+
+    #[test]
+    fn batch() {
+        // the last loop never ends, but moves along the tape so it is not detected as diverging
+        let program = crate::ir::Program::from_raw(",[.,]+[>+]".parse().unwrap());
+        let inputs = [&b"ab\0"[..], b"", b"abc"];
+        let outcomes = run_program(program, &inputs, Some(100));
+        assert_eq!(
+            outcomes,
+            [
+                Outcome {
+                    output: b"ab".to_vec(),
+                    end: Err(RTError::StepLimitExceeded)
+                },
+                Outcome {
+                    output: vec![],
+                    end: Ok(StopState::NeedInput)
+                },
+                Outcome {
+                    output: b"abc".to_vec(),
+                    end: Ok(StopState::NeedInput)
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,193 @@
+//! Stopping an engine at interesting points
+//!
+//! Breakpoints are handled by a wrapper, so the engines that are not debugged pay nothing for
+//! them.
+
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use super::{coverage::Coverage, mem::Memory, stats::Stats, Engine, RTError, State, StopState};
+
+/// A point where the engine stops with [`StopState::Breakpoint`]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Breakpoint {
+    /// Before executing the instruction at this position, as given by [`Engine::position`]
+    Position(Vec<usize>),
+    /// Right after the engine reads an input
+    Input,
+    /// Right after the engine emits an output
+    Output,
+}
+
+/// Engine that stops at the breakpoints
+///
+/// Stopping at a breakpoint does not advance the engine. The next step continues from where the
+/// engine stopped, without stopping again on the same breakpoint.
+///
+/// Input is given to the engine one byte at a time, so that each read can be noticed.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Breakpoints<E> {
+    engine: E,
+    breakpoints: BTreeSet<Breakpoint>,
+    /// An I/O breakpoint was hit, and must be reported on the next step
+    pending: bool,
+    /// The engine was stopped before the current position, and must not stop there again
+    resuming: bool,
+}
+
+impl<E> Breakpoints<E> {
+    /// Wrap the engine, with no breakpoints set
+    pub fn new(engine: E) -> Self {
+        Self {
+            engine,
+            breakpoints: BTreeSet::new(),
+            pending: false,
+            resuming: false,
+        }
+    }
+
+    /// The breakpoints that are set
+    pub fn breakpoints(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.breakpoints.iter()
+    }
+
+    /// Remove the breakpoints, returning the engine
+    pub fn into_inner(self) -> E {
+        self.engine
+    }
+}
+
+impl<E: Engine> Engine for Breakpoints<E> {
+    type Cell = E::Cell;
+
+    fn step(&mut self) -> Result<State, RTError> {
+        if std::mem::take(&mut self.pending) {
+            return Ok(State::Stopped(StopState::Breakpoint));
+        }
+        if !std::mem::take(&mut self.resuming)
+            && self
+                .breakpoints
+                .contains(&Breakpoint::Position(self.engine.position()))
+        {
+            self.resuming = true;
+            return Ok(State::Stopped(StopState::Breakpoint));
+        }
+        let had_input = self.engine.has_input();
+        let state = self.engine.step()?;
+        self.pending = match state {
+            State::Stopped(StopState::HasOutput(_)) => {
+                self.breakpoints.contains(&Breakpoint::Output)
+            }
+            _ => {
+                had_input
+                    && !self.engine.has_input()
+                    && self.breakpoints.contains(&Breakpoint::Input)
+            }
+        };
+        Ok(state)
+    }
+
+    fn input(&self) -> Option<u8> {
+        self.engine.input()
+    }
+
+    fn give_input(&mut self, input: u8) -> Option<u8> {
+        self.engine.give_input(input)
+    }
+
+    fn try_give_input(&mut self, input: u8) -> Result<(), u8> {
+        self.engine.try_give_input(input)
+    }
+
+    fn close_input(&mut self) -> bool {
+        self.engine.close_input()
+    }
+
+    fn memory(&self) -> &Memory<E::Cell> {
+        self.engine.memory()
+    }
+
+    fn memory_mut(&mut self) -> &mut Memory<E::Cell> {
+        self.engine.memory_mut()
+    }
+
+    fn tape(&self) -> usize {
+        self.engine.tape()
+    }
+
+    fn pointer(&self) -> isize {
+        self.engine.pointer()
+    }
+
+    fn set_pointer(&mut self, pointer: isize) {
+        self.engine.set_pointer(pointer)
+    }
+
+    fn position(&self) -> Vec<usize> {
+        self.engine.position()
+    }
+
+    fn instruction(&self) -> Option<String> {
+        self.engine.instruction()
+    }
+
+    /// Reset the engine. The breakpoints are kept
+    fn reset(&mut self) {
+        self.engine.reset();
+        self.pending = false;
+        self.resuming = false;
+    }
+
+    fn collect_stats(&mut self) -> bool {
+        self.engine.collect_stats()
+    }
+
+    fn stats(&self) -> Option<&Stats> {
+        self.engine.stats()
+    }
+
+    fn collect_coverage(&mut self) -> bool {
+        self.engine.collect_coverage()
+    }
+
+    fn coverage(&self) -> Option<&Coverage> {
+        self.engine.coverage()
+    }
+
+    fn set_breakpoint(&mut self, breakpoint: Breakpoint) -> bool {
+        self.breakpoints.insert(breakpoint);
+        true
+    }
+
+    fn clear_breakpoint(&mut self, breakpoint: &Breakpoint) -> bool {
+        self.breakpoints.remove(breakpoint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Breakpoint, Breakpoints};
+    use crate::engine::{Engine, ProgrammableEngine, StopState};
+
+    #[test]
+    fn breakpoints() {
+        let engine = <crate::engine::raw::Engine>::new_from_str(",+.+.").unwrap();
+        let mut engine = Breakpoints::new(engine);
+        assert!(engine.set_breakpoint(Breakpoint::Position(vec![3])));
+        assert!(engine.set_breakpoint(Breakpoint::Input));
+        assert_eq!(engine.run().unwrap(), StopState::NeedInput);
+        engine.give_input(b'a');
+        // stopping after the read
+        assert_eq!(engine.run().unwrap(), StopState::Breakpoint);
+        assert_eq!(engine.position(), [1]);
+        assert_eq!(engine.run().unwrap(), StopState::HasOutput(b'b'));
+        // stopping before the second `+`
+        assert_eq!(engine.run().unwrap(), StopState::Breakpoint);
+        assert_eq!(engine.position(), [3]);
+        assert!(engine.clear_breakpoint(&Breakpoint::Position(vec![3])));
+        assert!(!engine.clear_breakpoint(&Breakpoint::Output));
+        assert_eq!(engine.run().unwrap(), StopState::HasOutput(b'c'));
+        assert_eq!(engine.run().unwrap(), StopState::Halted);
+    }
+}
@@ -0,0 +1,354 @@
+//! Memory cells of different widths
+//!
+//! The optimizations assume byte cells, so only the raw engine can run with wider ones.
+//! With the `bignum` feature the cells can also be unbounded integers.
+
+use std::{
+    fmt::{Debug, Display},
+    hash::Hash,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// A memory cell, wrapping around if bounded
+pub trait Cell:
+    Clone + Default + Eq + Ord + Hash + Debug + Display + Serialize + DeserializeOwned + 'static
+{
+    /// Width of the cell in bytes. `None` if the cell is unbounded
+    const BYTES: Option<usize>;
+    /// The empty cell
+    const ZERO: &'static Self;
+
+    fn wrapping_add(&self, other: &Self) -> Self;
+    fn wrapping_sub(&self, other: &Self) -> Self;
+    fn saturating_add(&self, other: &Self) -> Self;
+    fn saturating_sub(&self, other: &Self) -> Self;
+    fn checked_add(&self, other: &Self) -> Option<Self>;
+    fn checked_sub(&self, other: &Self) -> Option<Self>;
+
+    /// Convert from a value, keeping only the bits that fit in the cell
+    fn from_u64(value: u64) -> Self;
+    /// Convert to a value, keeping only the lowest 64 bits in two's complement
+    fn to_u64(&self) -> u64;
+
+    /// Convert from a string of decimal digits, optionally preceded by a `-`, wrapping around
+    /// if it does not fit
+    fn from_decimal(digits: &[u8]) -> Self {
+        let (negative, digits) = match digits {
+            [b'-', digits @ ..] => (true, digits),
+            digits => (false, digits),
+        };
+        let value = digits.iter().fold(0u64, |value, digit| {
+            value.wrapping_mul(10).wrapping_add((digit - b'0').into())
+        });
+        Self::from_u64(if negative {
+            value.wrapping_neg()
+        } else {
+            value
+        })
+    }
+}
+
+macro_rules! impl_cell {
+    ($($t:ty),*) => {$(
+        impl Cell for $t {
+            const BYTES: Option<usize> = Some(std::mem::size_of::<$t>());
+            const ZERO: &'static Self = &0;
+
+            #[inline]
+            fn wrapping_add(&self, other: &Self) -> Self {
+                <$t>::wrapping_add(*self, *other)
+            }
+            #[inline]
+            fn wrapping_sub(&self, other: &Self) -> Self {
+                <$t>::wrapping_sub(*self, *other)
+            }
+            #[inline]
+            fn saturating_add(&self, other: &Self) -> Self {
+                <$t>::saturating_add(*self, *other)
+            }
+            #[inline]
+            fn saturating_sub(&self, other: &Self) -> Self {
+                <$t>::saturating_sub(*self, *other)
+            }
+            #[inline]
+            fn checked_add(&self, other: &Self) -> Option<Self> {
+                <$t>::checked_add(*self, *other)
+            }
+            #[inline]
+            fn checked_sub(&self, other: &Self) -> Option<Self> {
+                <$t>::checked_sub(*self, *other)
+            }
+            #[inline]
+            fn from_u64(value: u64) -> Self {
+                value as $t
+            }
+            #[inline]
+            fn to_u64(&self) -> u64 {
+                *self as u64
+            }
+        }
+    )*};
+}
+impl_cell!(u8, u16, u32, i8, i16, i32);
+
+/// Unbounded cells, that can also go below zero
+#[cfg(feature = "bignum")]
+impl Cell for num_bigint::BigInt {
+    const BYTES: Option<usize> = None;
+    const ZERO: &'static Self = &num_bigint::BigInt::ZERO;
+
+    fn wrapping_add(&self, other: &Self) -> Self {
+        self + other
+    }
+    fn wrapping_sub(&self, other: &Self) -> Self {
+        self - other
+    }
+    fn saturating_add(&self, other: &Self) -> Self {
+        self + other
+    }
+    fn saturating_sub(&self, other: &Self) -> Self {
+        self - other
+    }
+    fn checked_add(&self, other: &Self) -> Option<Self> {
+        Some(self + other)
+    }
+    fn checked_sub(&self, other: &Self) -> Option<Self> {
+        Some(self - other)
+    }
+    fn from_u64(value: u64) -> Self {
+        value.into()
+    }
+    fn to_u64(&self) -> u64 {
+        let low = self.iter_u64_digits().next().unwrap_or(0);
+        match self.sign() {
+            num_bigint::Sign::Minus => (!low).wrapping_add(1),
+            _ => low,
+        }
+    }
+    fn from_decimal(digits: &[u8]) -> Self {
+        num_bigint::BigInt::parse_bytes(digits, 10).expect("Only digits are accumulated")
+    }
+}
+
+/// What `+` and `-` do when the cell cannot hold the result
+///
+/// Only the raw and the ir engines support policies other than wrapping.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Overflow {
+    /// Wrap around to the other end
+    #[default]
+    Wrap,
+    /// Stop at the largest or smallest value
+    Saturate,
+    /// Stop the program with [`RTError::CellOverflow`](super::RTError::CellOverflow)
+    Trap,
+}
+
+impl Overflow {
+    /// Add `amount` to `cell`. Returns `None` if the addition traps
+    pub fn add<C: Cell>(self, cell: &C, amount: &C) -> Option<C> {
+        match self {
+            Overflow::Wrap => Some(cell.wrapping_add(amount)),
+            Overflow::Saturate => Some(cell.saturating_add(amount)),
+            Overflow::Trap => cell.checked_add(amount),
+        }
+    }
+
+    /// Subtract `amount` from `cell`. Returns `None` if the subtraction traps
+    pub fn sub<C: Cell>(self, cell: &C, amount: &C) -> Option<C> {
+        match self {
+            Overflow::Wrap => Some(cell.wrapping_sub(amount)),
+            Overflow::Saturate => Some(cell.saturating_sub(amount)),
+            Overflow::Trap => cell.checked_sub(amount),
+        }
+    }
+
+    /// Add a signed amount to a byte cell, as the ir does
+    pub fn add_signed(self, cell: u8, amount: i8) -> Option<u8> {
+        if amount >= 0 {
+            self.add(&cell, &amount.unsigned_abs())
+        } else {
+            self.sub(&cell, &amount.unsigned_abs())
+        }
+    }
+}
+
+/// How `.` and `,` convert between cells and bytes
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum CellIo {
+    /// A single byte: the output is truncated, the input zero extended
+    #[default]
+    Truncate,
+    /// All the bytes of the cell, least significant first.
+    /// Unbounded cells use a single byte, as in [`CellIo::Truncate`]
+    LittleEndian,
+    /// All the bytes of the cell, most significant first.
+    /// Unbounded cells use a single byte, as in [`CellIo::Truncate`]
+    BigEndian,
+    /// Decimal numbers, negative for signed cells. The output is followed by a newline, the
+    /// input is terminated by any non digit. The input wraps around if the number does not fit
+    /// in the cell
+    Numeric,
+}
+
+impl CellIo {
+    /// Bytes emitted when outputting `cell`
+    pub fn encode<C: Cell>(self, cell: &C) -> Vec<u8> {
+        let value = cell.to_u64();
+        match (self, C::BYTES) {
+            (CellIo::Truncate, _) | (CellIo::LittleEndian | CellIo::BigEndian, None) => {
+                vec![value as u8]
+            }
+            (CellIo::LittleEndian, Some(bytes)) => value.to_le_bytes()[..bytes].to_vec(),
+            (CellIo::BigEndian, Some(bytes)) => value.to_be_bytes()[8 - bytes..].to_vec(),
+            (CellIo::Numeric, _) => format!("{cell}\n").into_bytes(),
+        }
+    }
+
+    /// Feed an input byte, returning the cell when it is complete
+    ///
+    /// `partial` holds the bytes received so far, and is cleared when the cell is returned
+    pub fn decode<C: Cell>(self, partial: &mut Vec<u8>, byte: u8) -> Option<C> {
+        match (self, C::BYTES) {
+            (CellIo::Truncate, _) | (CellIo::LittleEndian | CellIo::BigEndian, None) => {
+                Some(C::from_u64(byte.into()))
+            }
+            (CellIo::LittleEndian | CellIo::BigEndian, Some(width)) => {
+                partial.push(byte);
+                if partial.len() < width {
+                    return None;
+                }
+                let mut bytes = [0; 8];
+                if self == CellIo::LittleEndian {
+                    bytes[..width].copy_from_slice(partial);
+                    partial.clear();
+                    Some(C::from_u64(u64::from_le_bytes(bytes)))
+                } else {
+                    bytes[8 - width..].copy_from_slice(partial);
+                    partial.clear();
+                    Some(C::from_u64(u64::from_be_bytes(bytes)))
+                }
+            }
+            (CellIo::Numeric, _) if byte.is_ascii_digit() || byte == b'-' && partial.is_empty() => {
+                partial.push(byte);
+                None
+            }
+            // separators before the number are skipped, as a sign with no digits
+            (CellIo::Numeric, _) if matches!(partial.as_slice(), [] | [b'-']) => {
+                partial.clear();
+                None
+            }
+            (CellIo::Numeric, _) => {
+                let cell = C::from_decimal(partial);
+                partial.clear();
+                Some(cell)
+            }
+        }
+    }
+
+    /// Complete the cell pending when the input ends
+    ///
+    /// Only a number can end with the input: the bytes of an incomplete cell are left in
+    /// `partial`
+    pub fn finish<C: Cell>(self, partial: &mut Vec<u8>) -> Option<C> {
+        match (self, partial.as_slice()) {
+            (CellIo::Numeric, [] | [b'-'])
+            | (CellIo::Truncate | CellIo::LittleEndian | CellIo::BigEndian, _) => None,
+            (CellIo::Numeric, _) => {
+                let cell = C::from_decimal(partial);
+                partial.clear();
+                Some(cell)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CellIo;
+
+    #[test]
+    fn roundtrip() {
+        for io in [CellIo::LittleEndian, CellIo::BigEndian, CellIo::Numeric] {
+            let mut partial = vec![];
+            let mut decoded = None;
+            for byte in io.encode(&0x1234u16) {
+                assert_eq!(decoded, None);
+                decoded = io.decode::<u16>(&mut partial, byte);
+            }
+            assert_eq!(decoded, Some(0x1234));
+            assert!(partial.is_empty());
+        }
+        assert_eq!(CellIo::LittleEndian.encode(&0x1234u16), [0x34, 0x12]);
+        assert_eq!(CellIo::BigEndian.encode(&0x1234u16), [0x12, 0x34]);
+        assert_eq!(CellIo::Truncate.encode(&0x1234u16), [0x34]);
+        // wrapping around
+        let mut partial = vec![];
+        for byte in *b"  65537" {
+            assert_eq!(CellIo::Numeric.decode::<u16>(&mut partial, byte), None);
+        }
+        assert_eq!(CellIo::Numeric.decode::<u16>(&mut partial, b' '), Some(1));
+        // ended by the input
+        for byte in *b"-12" {
+            assert_eq!(CellIo::Numeric.decode::<i16>(&mut partial, byte), None);
+        }
+        assert_eq!(CellIo::Numeric.finish::<i16>(&mut partial), Some(-12));
+        assert!(partial.is_empty());
+        assert_eq!(CellIo::Numeric.decode::<u16>(&mut partial, b'-'), None);
+        assert_eq!(CellIo::Numeric.finish::<u16>(&mut partial), None);
+        assert_eq!(CellIo::LittleEndian.decode::<u16>(&mut vec![], 1), None);
+        assert_eq!(CellIo::LittleEndian.finish::<u16>(&mut vec![1]), None);
+    }
+
+    #[cfg(feature = "bignum")]
+    #[test]
+    fn unbounded() {
+        use super::Cell;
+        use num_bigint::BigInt;
+
+        let big = BigInt::from_u64(1) << 100;
+        let mut partial = vec![];
+        for byte in CellIo::Numeric.encode(&big) {
+            if let Some(decoded) = CellIo::Numeric.decode::<BigInt>(&mut partial, byte) {
+                assert_eq!(decoded, big)
+            }
+        }
+        // truncated as a two's complement number
+        let minus_one = BigInt::ZERO.wrapping_sub(&BigInt::from_u64(1));
+        assert_eq!(CellIo::Truncate.encode(&minus_one), [0xff]);
+        assert_eq!(CellIo::LittleEndian.encode(&(big + 2)), [2]);
+    }
+
+    #[test]
+    fn signed() {
+        assert_eq!(CellIo::Numeric.encode(&-5i8), b"-5\n");
+        assert_eq!(CellIo::Truncate.encode(&-1i16), [0xff]);
+        assert_eq!(CellIo::LittleEndian.encode(&-2i16), [0xfe, 0xff]);
+        let mut partial = vec![];
+        let decoded: Vec<_> = b"- -300 -12,"
+            .iter()
+            .filter_map(|&byte| CellIo::Numeric.decode::<i8>(&mut partial, byte))
+            .collect();
+        // -300 wraps around to -44
+        assert_eq!(decoded, [-44, -12]);
+    }
+
+    #[test]
+    fn overflow() {
+        use super::Overflow;
+
+        assert_eq!(Overflow::Wrap.add(&250u8, &10), Some(4));
+        assert_eq!(Overflow::Saturate.add(&250u8, &10), Some(255));
+        assert_eq!(Overflow::Trap.add(&250u8, &10), None);
+        assert_eq!(Overflow::Saturate.sub(&-120i8, &10), Some(-128));
+        assert_eq!(Overflow::Trap.add_signed(3, -3), Some(0));
+        assert_eq!(Overflow::Trap.add_signed(3, -4), None);
+    }
+}
@@ -0,0 +1,471 @@
+//! Engine running ir brainfuck compiled into closures
+//!
+//! Every part of the program that does no input or output is compiled once into nested closures,
+//! that then run in a single step. The loops containing input or output are kept as jumps, so the
+//! engine can still stop to exchange data.
+//!
+//! A step does a bounded number of iterations of the compiled loops, so that a loop that
+//! never ends can still be stopped. Each compiled loop and if is also laid out as jumps after the
+//! end of the program, and the engine continues from the head of the loop that ran out of
+//! iterations.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+use crate::ir::{self, Add, Block, If, Input, Loop, Mul, Output, Print, Set, Shift, SwitchTape};
+
+use super::{mem::Memory, Eof, ProgrammableEngine, RTError, State, StopState};
+
+/// State touched by the compiled code
+#[derive(Debug, Clone)]
+struct Machine {
+    /// One memory for each tape. Tapes are added when first selected
+    tapes: Vec<Memory>,
+    /// Selected tape
+    tape: usize,
+    mp: isize,
+    /// Maximum number of cells of each tape
+    memory_limit: usize,
+    /// Iterations of the compiled loops left in this step
+    iterations: u32,
+}
+
+impl Machine {
+    #[inline]
+    fn get(&self, offset: isize) -> Result<u8, RTError> {
+        let mp = self.mp + offset;
+        if mp < 0 {
+            Err(RTError::MemNegativeOut)
+        } else {
+            Ok(*self.tapes[self.tape].get(mp as usize))
+        }
+    }
+
+    #[inline]
+    fn set(&mut self, offset: isize, value: u8) -> Result<(), RTError> {
+        let mp = self.mp + offset;
+        if mp < 0 {
+            Err(RTError::MemNegativeOut)
+        } else {
+            self.tapes[self.tape].set_limited(mp, value, self.memory_limit)
+        }
+    }
+}
+
+/// Loop iterations the compiled code can do in a single step
+const ITERATIONS_PER_STEP: u32 = 1 << 16;
+
+/// Why the compiled code stopped before its end
+enum Interrupt {
+    Error(RTError),
+    /// The iterations of the step ran out at the head of the loop with the given id
+    Yield(usize),
+}
+
+impl From<RTError> for Interrupt {
+    fn from(err: RTError) -> Self {
+        Interrupt::Error(err)
+    }
+}
+
+/// Compiled code without input or output
+///
+/// The code is shared between the clones of the engine, as all the state is in the [`Machine`]
+type Code = Arc<dyn Fn(&mut Machine) -> Result<(), Interrupt> + Send + Sync>;
+
+#[derive(Clone)]
+enum Op {
+    Run(Code),
+    Output(isize),
+    /// Output a constant byte
+    Print(u8),
+    Diverge,
+    Input(isize),
+    InputDiscard,
+    JumpIfZero(isize, usize),
+    JumpIfNonZero(isize, usize),
+    Jump(usize),
+    Halt,
+}
+
+#[derive(Clone)]
+pub struct Engine {
+    /// Decoded program, shared between the clones of the engine
+    ops: Arc<[Op]>,
+    /// Where the jumps of each compiled loop start, by id
+    resumes: Arc<[usize]>,
+    ip: usize,
+    machine: Machine,
+    /// Input received and not yet read
+    input: VecDeque<u8>,
+    /// No more input will be received
+    input_closed: bool,
+    /// What `,` does after the input was closed
+    eof: Eof,
+}
+
+/// Check if a node does input or output, or stops the engine
+fn has_io(node: &ir::Node) -> bool {
+    match node {
+        ir::Node::Output(_)
+        | ir::Node::Input(_)
+        | ir::Node::InputDiscard
+        | ir::Node::Print(_)
+        | ir::Node::Diverge => true,
+        ir::Node::Loop(Loop { body, .. }) | ir::Node::If(If { body, .. }) => {
+            body.0.iter().any(has_io)
+        }
+        _ => false,
+    }
+}
+
+/// Translation of a program into ops
+#[derive(Default)]
+struct Decoder<'p> {
+    ops: Vec<Op>,
+    /// Compiled code of the nodes, by their address
+    codes: HashMap<*const ir::Node, Code>,
+    /// Id of the compiled loops, by their address
+    loops: HashMap<*const ir::Node, usize>,
+    /// Where the jumps of each compiled loop start, by id
+    resumes: Vec<usize>,
+    /// Compiled loops and ifs still to be laid out as jumps, with the op that follows them
+    pending: Vec<(&'p ir::Node, usize)>,
+}
+
+impl<'p> Decoder<'p> {
+    fn compile_block(&mut self, nodes: impl IntoIterator<Item = &'p ir::Node>) -> Code {
+        let codes: Vec<_> = nodes
+            .into_iter()
+            .filter_map(|node| self.compile(node))
+            .collect();
+        Arc::new(move |m| codes.iter().try_for_each(|code| code(m)))
+    }
+
+    fn compile(&mut self, node: &'p ir::Node) -> Option<Code> {
+        let code: Code = match node {
+            ir::Node::Noop => return None,
+            ir::Node::Output(_)
+            | ir::Node::Input(_)
+            | ir::Node::InputDiscard
+            | ir::Node::Print(_)
+            | ir::Node::Diverge => {
+                unreachable!("Nodes doing input or output are never compiled")
+            }
+            &ir::Node::Shift(Shift { amount }) => Arc::new(move |m| {
+                m.mp += amount.get();
+                Ok(())
+            }),
+            &ir::Node::Add(Add { amount, offset }) => {
+                Arc::new(move |m| Ok(m.set(offset, m.get(offset)?.wrapping_add(amount.get()))?))
+            }
+            ir::Node::Loop(Loop { body, offset }) => {
+                let offset = *offset;
+                let id = self.resumes.len();
+                self.resumes.push(0);
+                self.loops.insert(node, id);
+                let body = self.compile_block(&body.0);
+                Arc::new(move |m| {
+                    while m.get(offset)? != 0 {
+                        if m.iterations == 0 {
+                            return Err(Interrupt::Yield(id));
+                        }
+                        m.iterations -= 1;
+                        body(m)?
+                    }
+                    Ok(())
+                })
+            }
+            ir::Node::If(If { body, offset }) => {
+                let offset = *offset;
+                let body = self.compile_block(&body.0);
+                Arc::new(move |m| {
+                    if m.get(offset)? != 0 {
+                        body(m)?
+                    }
+                    Ok(())
+                })
+            }
+            ir::Node::Mul(Mul { offset, targets }) => {
+                let offset = *offset;
+                let targets = targets.clone();
+                Arc::new(move |m| {
+                    let n = m.get(offset)?;
+                    if n != 0 {
+                        let increments = targets
+                            .iter()
+                            .map(|(target, expr)| Ok((*target, expr.eval(|o| m.get(o))?)))
+                            .collect::<Result<Vec<_>, RTError>>()?;
+                        for (target, increment) in increments {
+                            m.set(
+                                target,
+                                m.get(target)?.wrapping_add(n.wrapping_mul(increment)),
+                            )?;
+                        }
+                        m.set(offset, 0)?;
+                    }
+                    Ok(())
+                })
+            }
+            &ir::Node::Set(Set { value, offset }) => Arc::new(move |m| Ok(m.set(offset, value)?)),
+            &ir::Node::SwitchTape(SwitchTape { amount }) => Arc::new(move |m| {
+                m.tape = m
+                    .tape
+                    .checked_add_signed(amount.get())
+                    .ok_or(RTError::TapeNegativeOut)?;
+                if m.tape >= m.tapes.len() {
+                    m.tapes.resize_with(m.tape + 1, Memory::new)
+                }
+                Ok(())
+            }),
+        };
+        self.codes.insert(node, code.clone());
+        Some(code)
+    }
+
+    /// Run a node that does no input or output as a single op
+    fn run(&mut self, node: &'p ir::Node) {
+        let code = match self.codes.get(&(node as *const _)) {
+            Some(code) => code.clone(),
+            None => match self.compile(node) {
+                Some(code) => code,
+                None => return,
+            },
+        };
+        self.ops.push(Op::Run(code));
+        if matches!(node, ir::Node::Loop(_) | ir::Node::If(_)) {
+            self.pending.push((node, self.ops.len()))
+        }
+    }
+
+    /// Decode a block, compiling the nodes that do no input or output
+    fn decode(&mut self, block: &'p Block) {
+        for node in &block.0 {
+            match node {
+                node if !has_io(node) => self.run(node),
+                &ir::Node::Output(Output { offset }) => self.ops.push(Op::Output(offset)),
+                &ir::Node::Input(Input { offset }) => self.ops.push(Op::Input(offset)),
+                ir::Node::InputDiscard => self.ops.push(Op::InputDiscard),
+                ir::Node::Diverge => self.ops.push(Op::Diverge),
+                ir::Node::Print(Print { bytes }) => {
+                    self.ops.extend(bytes.iter().map(|&b| Op::Print(b)))
+                }
+                ir::Node::Loop(Loop { body, offset }) => {
+                    let start = self.ops.len();
+                    self.ops.push(Op::JumpIfZero(*offset, 0));
+                    self.decode(body);
+                    self.ops.push(Op::JumpIfNonZero(*offset, start + 1));
+                    self.ops[start] = Op::JumpIfZero(*offset, self.ops.len());
+                }
+                ir::Node::If(If { body, offset }) => {
+                    let start = self.ops.len();
+                    self.ops.push(Op::JumpIfZero(*offset, 0));
+                    self.decode(body);
+                    self.ops[start] = Op::JumpIfZero(*offset, self.ops.len());
+                }
+                other => unreachable!("{other:?} does no input or output"),
+            }
+        }
+    }
+
+    /// Lay out as jumps the compiled loops and ifs, so the engine can continue them after
+    /// they ran out of iterations
+    fn lay_out_compiled(&mut self) {
+        while let Some((node, next)) = self.pending.pop() {
+            match node {
+                ir::Node::Loop(Loop { body, offset }) => {
+                    let start = self.ops.len();
+                    self.resumes[self.loops[&(node as *const _)]] = start;
+                    self.ops.push(Op::JumpIfZero(*offset, 0));
+                    body.0.iter().for_each(|node| self.run(node));
+                    self.ops.push(Op::JumpIfNonZero(*offset, start + 1));
+                    self.ops[start] = Op::JumpIfZero(*offset, self.ops.len());
+                }
+                ir::Node::If(If { body, .. }) => body.0.iter().for_each(|node| self.run(node)),
+                other => unreachable!("{other:?} is not compiled with a body"),
+            }
+            self.ops.push(Op::Jump(next))
+        }
+    }
+}
+
+impl Engine {
+    /// Fail with [`RTError::OutOfMemory`] instead of growing a tape past `limit` cells
+    pub fn with_memory_limit(mut self, limit: usize) -> Self {
+        self.machine.memory_limit = limit;
+        self
+    }
+
+    /// Set what `,` does after the input was closed
+    pub fn with_eof(mut self, eof: Eof) -> Self {
+        self.eof = eof;
+        self
+    }
+}
+
+impl ProgrammableEngine for Engine {
+    type Program = ir::Program;
+
+    fn new(program: Self::Program) -> Self
+    where
+        Self: Sized,
+    {
+        let mut decoder = Decoder::default();
+        decoder.decode(&program.0);
+        decoder.ops.push(Op::Halt);
+        decoder.lay_out_compiled();
+        Self {
+            ops: decoder.ops.into(),
+            resumes: decoder.resumes.into(),
+            ip: 0,
+            machine: Machine {
+                tapes: vec![Memory::new()],
+                tape: 0,
+                mp: 0,
+                memory_limit: usize::MAX,
+                iterations: 0,
+            },
+            input: VecDeque::new(),
+            input_closed: false,
+            eof: Eof::default(),
+        }
+    }
+}
+
+impl super::Engine for Engine {
+    fn step(&mut self) -> Result<State, RTError> {
+        Ok(match &self.ops[self.ip] {
+            Op::Run(code) => {
+                self.machine.iterations = ITERATIONS_PER_STEP;
+                match code(&mut self.machine) {
+                    Ok(()) => self.ip += 1,
+                    Err(Interrupt::Yield(id)) => self.ip = self.resumes[id],
+                    Err(Interrupt::Error(err)) => return Err(err),
+                }
+                State::Running
+            }
+            &Op::Output(offset) => {
+                let out = self.machine.get(offset)?;
+                self.ip += 1;
+                State::Stopped(StopState::HasOutput(out))
+            }
+            Op::Diverge => State::Stopped(StopState::Diverged),
+            &Op::Print(out) => {
+                self.ip += 1;
+                State::Stopped(StopState::HasOutput(out))
+            }
+            &Op::Input(offset) => match self.input.pop_front() {
+                Some(input) => {
+                    self.machine.set(offset, input)?;
+                    self.ip += 1;
+                    State::Running
+                }
+                None if self.input_closed => match self.eof.value(&self.machine.get(offset)?) {
+                    Some(value) => {
+                        self.machine.set(offset, value)?;
+                        self.ip += 1;
+                        State::Running
+                    }
+                    None => State::Stopped(StopState::EndOfInput),
+                },
+                None => State::Stopped(StopState::NeedInput),
+            },
+            Op::InputDiscard => match self.input.pop_front() {
+                Some(_) => {
+                    self.ip += 1;
+                    State::Running
+                }
+                None if self.input_closed => match self.eof {
+                    Eof::Stop => State::Stopped(StopState::EndOfInput),
+                    _ => {
+                        self.ip += 1;
+                        State::Running
+                    }
+                },
+                None => State::Stopped(StopState::NeedInput),
+            },
+            &Op::JumpIfZero(offset, target) => {
+                if self.machine.get(offset)? == 0 {
+                    self.ip = target
+                } else {
+                    self.ip += 1
+                }
+                State::Running
+            }
+            &Op::JumpIfNonZero(offset, target) => {
+                if self.machine.get(offset)? != 0 {
+                    self.ip = target
+                } else {
+                    self.ip += 1
+                }
+                State::Running
+            }
+            &Op::Jump(target) => {
+                self.ip = target;
+                State::Running
+            }
+            Op::Halt => State::Stopped(StopState::Halted),
+        })
+    }
+
+    fn input(&self) -> Option<u8> {
+        self.input.front().copied()
+    }
+
+    fn give_input(&mut self, input: u8) -> Option<u8> {
+        super::replace_input(&mut self.input, input)
+    }
+
+    fn try_give_input(&mut self, input: u8) -> Result<(), u8> {
+        super::try_give_input(&mut self.input, input)
+    }
+
+    fn give_input_slice(&mut self, input: &[u8]) -> usize {
+        self.input.extend(input);
+        input.len()
+    }
+
+    fn close_input(&mut self) -> bool {
+        self.input_closed = true;
+        true
+    }
+
+    fn memory(&self) -> &Memory {
+        &self.machine.tapes[self.machine.tape]
+    }
+
+    fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.machine.tapes[self.machine.tape]
+    }
+
+    fn tape(&self) -> usize {
+        self.machine.tape
+    }
+
+    fn pointer(&self) -> isize {
+        self.machine.mp
+    }
+
+    fn set_pointer(&mut self, pointer: isize) {
+        self.machine.mp = pointer
+    }
+
+    fn position(&self) -> Vec<usize> {
+        vec![self.ip]
+    }
+
+    fn reset(&mut self) {
+        self.ip = 0;
+        self.machine = Machine {
+            tapes: vec![Memory::new()],
+            tape: 0,
+            mp: 0,
+            memory_limit: self.machine.memory_limit,
+            iterations: 0,
+        };
+        self.input.clear();
+        self.input_closed = false;
+    }
+}
@@ -0,0 +1,61 @@
+//! Coverage of the executed instructions
+//!
+//! Instructions that are never executed on any input are dead branches of the program. Running
+//! the examples with coverage also shows which parts of the optimizer output are actually tested.
+
+use serde::{Deserialize, Serialize};
+
+/// Instructions executed during a run
+///
+/// Instructions are numbered in program order. In nested programs each loop comes before its body.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Coverage {
+    executed: Vec<bool>,
+}
+
+impl Coverage {
+    /// Coverage of a program with `len` instructions, none of them executed
+    pub fn new(len: usize) -> Self {
+        Self {
+            executed: vec![false; len],
+        }
+    }
+
+    /// Number of instructions in the program
+    pub fn len(&self) -> usize {
+        self.executed.len()
+    }
+
+    /// Check if the program has no instructions
+    pub fn is_empty(&self) -> bool {
+        self.executed.is_empty()
+    }
+
+    /// Mark an instruction as executed
+    pub fn mark(&mut self, instr: usize) {
+        self.executed[instr] = true
+    }
+
+    /// Check if an instruction was executed
+    pub fn is_executed(&self, instr: usize) -> bool {
+        self.executed[instr]
+    }
+
+    /// The bitmap of the executed instructions
+    pub fn as_bitmap(&self) -> &[bool] {
+        &self.executed
+    }
+
+    /// Number of executed instructions
+    pub fn count(&self) -> usize {
+        self.executed.iter().filter(|e| **e).count()
+    }
+
+    /// Instructions that were never executed
+    pub fn never_executed(&self) -> impl Iterator<Item = usize> + '_ {
+        self.executed
+            .iter()
+            .enumerate()
+            .filter_map(|(instr, executed)| (!executed).then_some(instr))
+    }
+}
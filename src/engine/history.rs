@@ -0,0 +1,251 @@
+//! Stepping engines backwards
+//!
+//! Reversible engines return, for each step, the delta needed to undo it. [`History`] keeps the
+//! last deltas in a ring buffer, so the execution can be rewound for a bounded number of steps.
+
+use std::collections::VecDeque;
+
+use super::{
+    breakpoint::Breakpoint, coverage::Coverage, mem::Memory, stats::Stats, Engine, RTError, State,
+    StopState,
+};
+
+/// What is needed to undo a step
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Delta<C = u8> {
+    /// Position before the step
+    pub position: Vec<usize>,
+    /// Memory pointer before the step
+    pub pointer: isize,
+    /// Selected tape before the step
+    pub tape: usize,
+    /// Input read by the step, to be given back
+    pub input: Option<u8>,
+    /// Cells of the selected tape that the step could write, with their old value
+    pub writes: Vec<(isize, C)>,
+    /// Output bytes of a wide cell that were still to be emitted before the step
+    pub pending_output: Vec<u8>,
+    /// Input bytes of a wide cell that were already received before the step
+    pub partial_input: Vec<u8>,
+}
+
+/// An engine whose steps can be undone
+pub trait Reversible: Engine {
+    /// Step the engine, returning the delta that undoes the step
+    fn step_reversible(&mut self) -> Result<(State, Delta<Self::Cell>), RTError>;
+
+    /// Undo a step, given its delta
+    ///
+    /// Deltas must be undone in reverse order, starting from the last step.
+    /// Statistics and coverage are not rewound.
+    fn undo(&mut self, delta: Delta<Self::Cell>);
+}
+
+/// Engine that remembers its last steps, and can step back through them
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct History<E: Reversible> {
+    engine: E,
+    deltas: VecDeque<Delta<E::Cell>>,
+    /// Maximum number of steps that are remembered
+    capacity: usize,
+}
+
+impl<E: Reversible> History<E> {
+    /// Record the last `capacity` steps of the engine
+    pub fn new(engine: E, capacity: usize) -> Self {
+        Self {
+            engine,
+            deltas: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Number of steps that can be undone
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    /// Check if there are steps that can be undone
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    /// Undo the last step
+    ///
+    /// Returns `false` if there is no step left in the history
+    pub fn step_back(&mut self) -> bool {
+        match self.deltas.pop_back() {
+            Some(delta) => {
+                self.engine.undo(delta);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stop recording, returning the engine
+    pub fn into_inner(self) -> E {
+        self.engine
+    }
+}
+
+impl<E: Reversible> Engine for History<E> {
+    type Cell = E::Cell;
+
+    fn step(&mut self) -> Result<State, RTError> {
+        let (state, delta) = self.engine.step_reversible()?;
+        if !matches!(
+            state,
+            State::Stopped(
+                StopState::Halted
+                    | StopState::NeedInput
+                    | StopState::Breakpoint
+                    | StopState::EndOfInput
+            )
+        ) && self.capacity > 0
+        {
+            if self.deltas.len() == self.capacity {
+                self.deltas.pop_front();
+            }
+            self.deltas.push_back(delta)
+        }
+        Ok(state)
+    }
+
+    fn input(&self) -> Option<u8> {
+        self.engine.input()
+    }
+
+    fn give_input(&mut self, input: u8) -> Option<u8> {
+        self.engine.give_input(input)
+    }
+
+    fn try_give_input(&mut self, input: u8) -> Result<(), u8> {
+        self.engine.try_give_input(input)
+    }
+
+    fn give_input_slice(&mut self, input: &[u8]) -> usize {
+        self.engine.give_input_slice(input)
+    }
+
+    fn close_input(&mut self) -> bool {
+        self.engine.close_input()
+    }
+
+    fn memory(&self) -> &Memory<E::Cell> {
+        self.engine.memory()
+    }
+
+    /// Mutable access to the memory. As the changes are not recorded, the history is cleared
+    fn memory_mut(&mut self) -> &mut Memory<E::Cell> {
+        self.deltas.clear();
+        self.engine.memory_mut()
+    }
+
+    fn tape(&self) -> usize {
+        self.engine.tape()
+    }
+
+    fn pointer(&self) -> isize {
+        self.engine.pointer()
+    }
+
+    /// Move the memory pointer. As the change is not recorded, the history is cleared
+    fn set_pointer(&mut self, pointer: isize) {
+        self.deltas.clear();
+        self.engine.set_pointer(pointer)
+    }
+
+    fn position(&self) -> Vec<usize> {
+        self.engine.position()
+    }
+
+    fn instruction(&self) -> Option<String> {
+        self.engine.instruction()
+    }
+
+    /// Reset the engine, clearing the history
+    fn reset(&mut self) {
+        self.engine.reset();
+        self.deltas.clear();
+    }
+
+    fn collect_stats(&mut self) -> bool {
+        self.engine.collect_stats()
+    }
+
+    fn stats(&self) -> Option<&Stats> {
+        self.engine.stats()
+    }
+
+    fn collect_coverage(&mut self) -> bool {
+        self.engine.collect_coverage()
+    }
+
+    fn coverage(&self) -> Option<&Coverage> {
+        self.engine.coverage()
+    }
+
+    fn set_breakpoint(&mut self, breakpoint: Breakpoint) -> bool {
+        self.engine.set_breakpoint(breakpoint)
+    }
+
+    fn clear_breakpoint(&mut self, breakpoint: &Breakpoint) -> bool {
+        self.engine.clear_breakpoint(breakpoint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{History, Reversible};
+    use crate::engine::{Engine, ProgrammableEngine, StopState};
+
+    fn rewind<E>(program: &str)
+    where
+        E: Reversible + ProgrammableEngine + Clone + PartialEq + std::fmt::Debug,
+        E::Program: TryFrom<crate::raw::Program>,
+        <E::Program as TryFrom<crate::raw::Program>>::Error: std::fmt::Debug,
+    {
+        let mut start = E::new_from_str(program).unwrap();
+        // queuing the whole input, so it is given back when the reads are undone
+        assert_eq!(start.give_input_slice(b"ab\0"), 3);
+        let mut engine = History::new(start.clone(), 10_000);
+        let mut states = vec![];
+        loop {
+            states.push(engine.clone().into_inner());
+            match engine.run().unwrap() {
+                StopState::Halted => break,
+                StopState::HasOutput(_) => (),
+                stop => panic!("Unexpected {stop:?}"),
+            }
+        }
+        // rewinding passes through all the points where the engine stopped
+        while engine.step_back() {
+            if states.last() == Some(engine.clone().into_inner()).as_ref() {
+                states.pop();
+            }
+        }
+        assert!(states.is_empty());
+        assert_eq!(engine.position(), start.position());
+        assert_eq!(engine.memory(), start.memory());
+    }
+
+    #[test]
+    fn step_back() {
+        rewind::<crate::engine::raw::Engine>(",[>+++[<+>-]<.,]");
+        rewind::<crate::engine::ir::Engine>(",[>+++[<+>-]<.,]");
+    }
+
+    #[test]
+    fn bounded() {
+        let engine = <crate::engine::raw::Engine>::new_from_str("+++++").unwrap();
+        let mut engine = History::new(engine, 2);
+        assert_eq!(engine.run().unwrap(), StopState::Halted);
+        assert_eq!(engine.len(), 2);
+        assert!(engine.step_back());
+        assert!(engine.step_back());
+        assert!(!engine.step_back());
+        assert_eq!(*engine.memory().get(0), 3);
+        assert_eq!(engine.position(), [3]);
+    }
+}
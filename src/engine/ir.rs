@@ -2,16 +2,214 @@
 //!
 //! This is used to check all the steps of the optimization
 
-use crate::ir::{self, Add, Block, Input, Loop, Output, Shift};
+use std::{collections::VecDeque, sync::Arc};
 
-use super::{mem::Memory, ProgrammableEngine, RTError};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+use crate::ir::{
+    self, Add, Block, If, Input, Loop, Mul, Output, Print, Reach, Set, Shift, SwitchTape,
+};
+
+use super::{
+    cell::Overflow,
+    coverage::Coverage,
+    history::{Delta, Reversible},
+    mem::{Memory, TapeMode},
+    stats::Stats,
+    Eof, ProgrammableEngine, RTError,
+};
+
+/// A node of the program, with the loops reduced to their header
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+enum Op {
+    Noop,
+    Shift(Shift),
+    Add(Add),
+    Output(Output),
+    Input(Input),
+    /// Enter the body, starting at index `body`, if the cell at `offset` is not zero
+    Loop {
+        offset: isize,
+        body: usize,
+    },
+    /// Like [`Op::Loop`], but the body does not go back to the header
+    If {
+        offset: isize,
+        body: usize,
+    },
+    Mul(Mul),
+    SwitchTape(SwitchTape),
+    Set(Set),
+    Print(Print),
+    Diverge,
+    InputDiscard,
+}
+
+/// The program laid out flat, each loop followed by its body
+///
+/// The nodes are numbered in this order, so the index of a node is also its index in the
+/// coverage.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+struct Code {
+    ops: Vec<Op>,
+    /// Index of the node executed after each one. The last node of a body goes back to its loop
+    next: Vec<usize>,
+    /// Position of each node in the program, as given by [`Engine::position`](super::Engine::position).
+    /// Sorted, with an additional entry for the end of the program
+    paths: Vec<Vec<usize>>,
+    /// Number of loops and ifs around each node, with an additional entry for the end
+    depth: Vec<usize>,
+    /// Cells accessed by the body of each loop and if, relative to the pointer at the header
+    reach: Vec<Option<Reach>>,
+}
+
+impl Code {
+    fn new(program: &ir::Program) -> Self {
+        fn flatten(blk: &Block, after: usize, path: &mut Vec<usize>, code: &mut Code) {
+            for (pos, node) in blk.0.iter().enumerate() {
+                let idx = code.ops.len();
+                path.push(pos);
+                code.paths.push(path.clone());
+                code.depth.push(path.len() - 1);
+                code.reach.push(node.as_block().and_then(Block::reach));
+                // both filled after the body
+                code.next.push(usize::MAX);
+                code.ops.push(Op::Noop);
+                code.ops[idx] = match node {
+                    ir::Node::Noop => Op::Noop,
+                    ir::Node::Shift(shift) => Op::Shift(*shift),
+                    ir::Node::Add(add) => Op::Add(*add),
+                    ir::Node::Output(output) => Op::Output(*output),
+                    ir::Node::Input(input) => Op::Input(*input),
+                    ir::Node::InputDiscard => Op::InputDiscard,
+                    ir::Node::Mul(mul) => Op::Mul(mul.clone()),
+                    ir::Node::SwitchTape(switch) => Op::SwitchTape(*switch),
+                    ir::Node::Set(set) => Op::Set(*set),
+                    ir::Node::Print(print) => Op::Print(print.clone()),
+                    ir::Node::Diverge => Op::Diverge,
+                    ir::Node::Loop(Loop { body, offset }) => {
+                        // an empty body loops on the header itself
+                        let start = if body.0.is_empty() { idx } else { idx + 1 };
+                        flatten(body, idx, path, code);
+                        Op::Loop {
+                            offset: *offset,
+                            body: start,
+                        }
+                    }
+                    ir::Node::If(If { body, offset }) => {
+                        let exit = if pos + 1 == blk.0.len() {
+                            after
+                        } else {
+                            idx + 1 + count(body)
+                        };
+                        // an empty body goes straight to the exit
+                        let start = if body.0.is_empty() { exit } else { idx + 1 };
+                        flatten(body, exit, path, code);
+                        Op::If {
+                            offset: *offset,
+                            body: start,
+                        }
+                    }
+                };
+                path.pop();
+                code.next[idx] = if pos + 1 == blk.0.len() {
+                    after
+                } else {
+                    code.ops.len()
+                };
+            }
+        }
+        let mut code = Code {
+            ops: vec![],
+            next: vec![],
+            paths: vec![],
+            depth: vec![],
+            reach: vec![],
+        };
+        let end = count(&program.0);
+        flatten(&program.0, end, &mut vec![], &mut code);
+        code.paths.push(vec![program.0 .0.len()]);
+        code.depth.push(0);
+        code
+    }
+}
+
+/// Number of nodes in the block, including the ones in the loops
+fn count(blk: &Block) -> usize {
+    blk.0
+        .iter()
+        .map(|node| match node {
+            ir::Node::Loop(Loop { body, .. }) | ir::Node::If(If { body, .. }) => 1 + count(body),
+            _ => 1,
+        })
+        .sum()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct Engine {
-    stack: Vec<(Block, usize)>,
-    mem: Memory,
+    /// Program, shared between the clones of the engine
+    code: Arc<Code>,
+    /// Index of the next node to execute. Equal to the number of nodes if the program halted
+    pc: usize,
+    /// One memory for each tape. Tapes are added when first selected
+    tapes: Vec<Memory>,
+    /// Selected tape
+    tape: usize,
     mp: isize,
-    input: Option<u8>,
+    /// Input received and not yet read
+    input: VecDeque<u8>,
+    /// No more input will be received
+    input_closed: bool,
+    /// What `,` does after the input was closed
+    eof: Eof,
+    /// Bytes of a constant output still to be emitted
+    pending_output: VecDeque<u8>,
+    /// What additions do on overflow
+    overflow: Overflow,
+    /// What happens before the first cell
+    tape_mode: TapeMode,
+    /// Maximum number of cells of each tape
+    memory_limit: usize,
+    /// The nodes inside it access the tape without checking the pointer
+    #[serde(skip)]
+    verified: Verified,
+    /// Statistics, if they are being collected
+    stats: Option<Stats>,
+    /// Executed nodes, if they are being recorded
+    coverage: Option<Coverage>,
+}
+
+/// Depth of the outermost body whose accesses were checked on entry
+///
+/// This only caches a check, so it is ignored when comparing engines.
+#[derive(Debug, Clone, Copy)]
+struct Verified(usize);
+impl Verified {
+    const NONE: Self = Self(usize::MAX);
+}
+impl Default for Verified {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+impl PartialEq for Verified {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+impl Eq for Verified {}
+impl PartialOrd for Verified {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Verified {
+    fn cmp(&self, _: &Self) -> std::cmp::Ordering {
+        std::cmp::Ordering::Equal
+    }
+}
+impl std::hash::Hash for Verified {
+    fn hash<H: std::hash::Hasher>(&self, _: &mut H) {}
 }
 
 impl ProgrammableEngine for Engine {
@@ -22,132 +220,501 @@ impl ProgrammableEngine for Engine {
         Self: Sized,
     {
         Self {
-            stack: vec![(program.0, 0)],
-            mem: Memory::new(),
+            code: Arc::new(Code::new(&program)),
+            pc: 0,
+            tapes: vec![Memory::new()],
+            tape: 0,
             mp: 0,
-            input: None,
+            input: VecDeque::new(),
+            input_closed: false,
+            eof: Eof::default(),
+            pending_output: VecDeque::new(),
+            overflow: Overflow::default(),
+            tape_mode: TapeMode::default(),
+            memory_limit: usize::MAX,
+            verified: Verified::NONE,
+            stats: None,
+            coverage: None,
         }
     }
 }
 
-impl super::Engine for Engine {
-    fn step(&mut self) -> Result<super::State, RTError> {
-        if let [(blk, pos)] = &self.stack[..] {
-            if *pos == blk.0.len() {
-                return Ok(super::State::Stopped(super::StopState::Halted));
-            }
-        }
-        // storing it in case we need to read it keeping a mutable ref to self
+impl Engine {
+    /// Set what additions do on overflow
+    ///
+    /// The program must have been optimized with the same policy, see
+    /// [`ir::Program::from_raw_with_overflow`]. The amounts of the additions are taken as signed.
+    pub fn with_overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Set what happens when the pointer moves before the first cell
+    ///
+    /// On a wrapping tape the cells alias, so the program must have been optimized without
+    /// assuming a zeroed tape, see [`ir::Program::from_raw_with_overflow`].
+    pub fn with_tape_mode(mut self, tape_mode: TapeMode) -> Self {
+        self.tape_mode = tape_mode;
+        self.verified = Verified::NONE;
+        self
+    }
+
+    /// Fail with [`RTError::OutOfMemory`] instead of growing a tape past `limit` cells
+    pub fn with_memory_limit(mut self, limit: usize) -> Self {
+        self.memory_limit = limit;
+        self
+    }
+
+    /// Set what `,` does after the input was closed
+    pub fn with_eof(mut self, eof: Eof) -> Self {
+        self.eof = eof;
+        self
+    }
+
+    /// Execute the next node
+    fn execute(&mut self) -> Result<super::State, RTError> {
         let Self {
-            stack,
-            mem,
+            code,
+            pc,
+            tapes,
+            tape,
             mp,
             input,
+            input_closed,
+            eof,
+            pending_output,
+            overflow,
+            tape_mode,
+            memory_limit,
+            verified,
+            ..
         } = self;
-
-        let advance = |stack: &mut Vec<(Block, usize)>| {
-            stack.last_mut().unwrap().1 += 1;
-            while stack.len() > 1 && {
-                let (blk, pos) = stack.last().unwrap();
-                blk.0.len() == *pos
-            } {
-                let (blk, _) = stack.pop().unwrap();
-                let (sup, pos) = stack.last_mut().unwrap();
-                match &mut sup.0[*pos] {
-                    ir::Node::Loop(Loop { body, .. }) => {
-                        // putting back the body
-                        *body = blk;
-                        // leaving pos as it is, so the loop is reexamined
-                    }
-                    other => {
-                        unreachable!("{other:?} cannot be entered, so it should not be popped into")
-                    }
-                }
-            }
+        let Some(op) = code.ops.get(*pc) else {
+            return Ok(super::State::Stopped(super::StopState::Halted));
         };
+        let next = code.next[*pc];
+        let mem = &mut tapes[*tape];
 
-        let get_mem = |mem: &Memory, offset: isize| {
-            let mp = *mp + offset;
-            if mp < 0 {
-                Err(RTError::MemNegativeOut)
+        let depth = code.depth[*pc];
+        if depth < verified.0 {
+            // outside of the checked body
+            *verified = Verified::NONE
+        }
+        let checked = *verified == Verified::NONE;
+        let resolve = |offset: isize| {
+            if checked {
+                tape_mode.resolve(*mp + offset)
             } else {
-                Ok(*mem.get(mp as usize))
+                Ok(*mp + offset)
             }
         };
+        let get_mem = |mem: &Memory, offset: isize| Ok(*mem.get_signed(resolve(offset)?));
 
         let set_mem = |mem: &mut Memory, offset: isize, value: u8| {
-            let mp = *mp + offset;
-            if mp < 0 {
-                Err(RTError::MemNegativeOut)
-            } else {
-                Ok(mem.set(mp as usize, value))
-            }
+            mem.set_limited(resolve(offset)?, value, *memory_limit)
         };
 
-        match {
-            let (blk, pos) = stack.last_mut().unwrap();
-            &mut blk.0[*pos]
-        } {
-            ir::Node::Shift(Shift { amount }) => {
+        match op {
+            Op::Shift(Shift { amount }) => {
                 *mp += amount.get();
-                advance(stack);
+                *pc = next;
                 Ok(super::State::Running)
             }
-            ir::Node::Add(Add { amount, offset }) => {
-                set_mem(
-                    mem,
-                    *offset,
-                    get_mem(mem, *offset)?.wrapping_add(amount.get()),
-                )?;
-                advance(stack);
+            Op::Add(Add { amount, offset }) => {
+                let value = overflow
+                    .add_signed(get_mem(mem, *offset)?, amount.get() as i8)
+                    .ok_or(RTError::CellOverflow)?;
+                set_mem(mem, *offset, value)?;
+                *pc = next;
                 Ok(super::State::Running)
             }
-            ir::Node::Output(Output { offset }) => {
+            Op::Output(Output { offset }) => {
                 let out = get_mem(mem, *offset)?;
-                advance(stack);
+                *pc = next;
                 Ok(super::State::Stopped(super::StopState::HasOutput(out)))
             }
-            ir::Node::Input(Input { offset }) => {
-                if let Some(input) = input.take() {
+            Op::Input(Input { offset }) => {
+                if let Some(input) = input.pop_front() {
                     set_mem(mem, *offset, input)?;
-                    advance(stack);
+                    *pc = next;
                     Ok(super::State::Running)
+                } else if *input_closed {
+                    match eof.value(&get_mem(mem, *offset)?) {
+                        Some(value) => {
+                            set_mem(mem, *offset, value)?;
+                            *pc = next;
+                            Ok(super::State::Running)
+                        }
+                        None => Ok(super::State::Stopped(super::StopState::EndOfInput)),
+                    }
                 } else {
                     Ok(super::State::Stopped(super::StopState::NeedInput))
                 }
             }
-            ir::Node::Loop(Loop { body, offset }) => {
-                if get_mem(mem, *offset)? != 0 {
-                    let blk = std::mem::take(body);
-                    stack.push((blk, 0)); // opening the new frame
+            Op::InputDiscard => {
+                if input.pop_front().is_some() || *input_closed && *eof != Eof::Stop {
+                    *pc = next;
                     Ok(super::State::Running)
+                } else if *input_closed {
+                    Ok(super::State::Stopped(super::StopState::EndOfInput))
                 } else {
-                    advance(stack);
-                    Ok(super::State::Running)
+                    Ok(super::State::Stopped(super::StopState::NeedInput))
                 }
             }
-            ir::Node::Noop => {
-                advance(stack);
+            Op::Loop { offset, body } | Op::If { offset, body } => {
+                *pc = if get_mem(mem, *offset)? != 0 {
+                    // check the cells of the whole body at once
+                    if checked
+                        && code.reach[*pc].is_some_and(|Reach { min, max }| {
+                            [min, max]
+                                .into_iter()
+                                .all(|offset| tape_mode.resolve(*mp + offset) == Ok(*mp + offset))
+                        })
+                    {
+                        *verified = Verified(depth + 1)
+                    }
+                    *body
+                } else {
+                    next
+                };
+                Ok(super::State::Running)
+            }
+            Op::Mul(Mul { offset, targets }) => {
+                let n = get_mem(mem, *offset)?;
+                if n != 0 {
+                    let increments = targets
+                        .iter()
+                        .map(|(target, expr)| Ok((*target, expr.eval(|o| get_mem(mem, o))?)))
+                        .collect::<Result<Vec<_>, RTError>>()?;
+                    for (target, increment) in increments {
+                        let value = get_mem(mem, target)?.wrapping_add(n.wrapping_mul(increment));
+                        set_mem(mem, target, value)?;
+                    }
+                    set_mem(mem, *offset, 0)?;
+                }
+                *pc = next;
+                Ok(super::State::Running)
+            }
+            Op::Set(Set { value, offset }) => {
+                set_mem(mem, *offset, *value)?;
+                *pc = next;
+                Ok(super::State::Running)
+            }
+            // staying on the node, so the engine does not advance
+            Op::Diverge => Ok(super::State::Stopped(super::StopState::Diverged)),
+            Op::Print(Print { bytes }) => {
+                *pc = next;
+                *pending_output = bytes.iter().copied().collect();
+                Ok(match pending_output.pop_front() {
+                    Some(out) => super::State::Stopped(super::StopState::HasOutput(out)),
+                    None => super::State::Running,
+                })
+            }
+            Op::SwitchTape(SwitchTape { amount }) => {
+                *tape = tape
+                    .checked_add_signed(amount.get())
+                    .ok_or(RTError::TapeNegativeOut)?;
+                if *tape >= tapes.len() {
+                    tapes.resize_with(*tape + 1, Memory::new)
+                }
+                *pc = next;
+                Ok(super::State::Running)
+            }
+            Op::Noop => {
+                *pc = next;
                 Ok(super::State::Running)
             }
         }
     }
 
+    /// The next node to execute, if the program has not halted
+    fn current(&self) -> Option<&Op> {
+        self.code.ops.get(self.pc)
+    }
+}
+
+/// Kind of a node, as counted in the statistics
+fn kind(op: &Op) -> &'static str {
+    match op {
+        Op::Shift(_) => "shift",
+        Op::Add(_) => "add",
+        Op::Output(_) => "output",
+        Op::Input(_) => "input",
+        Op::InputDiscard => "input-discard",
+        Op::Loop { .. } => "loop",
+        Op::If { .. } => "if",
+        Op::Mul(_) => "mul",
+        Op::SwitchTape(_) => "switch-tape",
+        Op::Set(_) => "set",
+        Op::Print(_) => "print",
+        Op::Diverge => "diverge",
+        Op::Noop => "noop",
+    }
+}
+
+impl super::Engine for Engine {
+    fn step(&mut self) -> Result<super::State, RTError> {
+        if let Some(out) = self.pending_output.pop_front() {
+            if let Some(stats) = &mut self.stats {
+                stats.outputs += 1
+            }
+            return Ok(super::State::Stopped(super::StopState::HasOutput(out)));
+        }
+        if self.stats.is_none() && self.coverage.is_none() {
+            return self.execute();
+        }
+        let Some(kind) = self.current().map(kind) else {
+            return self.execute();
+        };
+        let index = self.pc;
+        let exit = self.code.next[index];
+        let state = self.execute()?;
+        if state == super::State::Stopped(super::StopState::NeedInput) {
+            return Ok(state);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.mark(index)
+        }
+        if let Some(stats) = &mut self.stats {
+            match state {
+                super::State::Stopped(super::StopState::HasOutput(_)) => stats.outputs += 1,
+                _ if kind == "input" => stats.inputs += 1,
+                _ if kind == "loop" && self.pc != exit => stats.loop_iterations += 1,
+                _ => (),
+            }
+            stats.record(kind);
+        }
+        Ok(state)
+    }
+
+    fn take_output(&mut self) -> Vec<u8> {
+        if let Some(stats) = &mut self.stats {
+            stats.outputs += self.pending_output.len() as u64
+        }
+        self.pending_output.drain(..).collect()
+    }
+
     fn input(&self) -> Option<u8> {
-        self.input
+        self.input.front().copied()
     }
 
     fn give_input(&mut self, input: u8) -> Option<u8> {
-        self.input.replace(input)
+        super::replace_input(&mut self.input, input)
     }
 
     fn try_give_input(&mut self, input: u8) -> Result<(), u8> {
-        match self.input {
-            Some(input) => Err(input),
-            None => {
-                self.input = Some(input);
-                Ok(())
+        super::try_give_input(&mut self.input, input)
+    }
+
+    fn give_input_slice(&mut self, input: &[u8]) -> usize {
+        self.input.extend(input);
+        input.len()
+    }
+
+    fn close_input(&mut self) -> bool {
+        self.input_closed = true;
+        true
+    }
+
+    fn memory(&self) -> &Memory {
+        &self.tapes[self.tape]
+    }
+
+    fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.tapes[self.tape]
+    }
+
+    fn tape(&self) -> usize {
+        self.tape
+    }
+
+    fn pointer(&self) -> isize {
+        self.mp
+    }
+
+    fn set_pointer(&mut self, pointer: isize) {
+        self.mp = pointer;
+        self.verified = Verified::NONE
+    }
+
+    fn position(&self) -> Vec<usize> {
+        self.code.paths[self.pc].clone()
+    }
+
+    fn instruction(&self) -> Option<String> {
+        self.current().map(|op| match op {
+            Op::Noop => "noop".to_owned(),
+            Op::Shift(shift) => shift.to_string(),
+            Op::Add(add) => add.to_string(),
+            Op::Output(output) => output.to_string(),
+            Op::Input(input) => input.to_string(),
+            Op::InputDiscard => ir::Node::InputDiscard.to_string(),
+            // only the header, as the body is executed in the following steps
+            Op::Loop { offset, .. } => format!("loop\t@{offset}"),
+            Op::If { offset, .. } => format!("if\t@{offset}"),
+            Op::Mul(mul) => mul.to_string(),
+            Op::SwitchTape(switch) => switch.to_string(),
+            Op::Set(set) => set.to_string(),
+            Op::Print(print) => print.to_string(),
+            Op::Diverge => "diverge".to_owned(),
+        })
+    }
+
+    fn reset(&mut self) {
+        self.pc = 0;
+        self.tapes = vec![Memory::new()];
+        self.tape = 0;
+        self.mp = 0;
+        self.verified = Verified::NONE;
+        self.input.clear();
+        self.input_closed = false;
+        self.pending_output.clear();
+        if let Some(stats) = &mut self.stats {
+            *stats = Stats::default()
+        }
+        if let Some(coverage) = &mut self.coverage {
+            *coverage = Coverage::new(coverage.len())
+        }
+    }
+
+    fn collect_stats(&mut self) -> bool {
+        self.stats.get_or_insert_with(Stats::default);
+        true
+    }
+
+    fn stats(&self) -> Option<&Stats> {
+        self.stats.as_ref()
+    }
+
+    fn collect_coverage(&mut self) -> bool {
+        if self.coverage.is_none() {
+            self.coverage = Some(Coverage::new(self.code.ops.len()))
+        }
+        true
+    }
+
+    fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+}
+
+impl Reversible for Engine {
+    fn step_reversible(&mut self) -> Result<(super::State, Delta), RTError> {
+        let offsets = match self.current() {
+            Some(
+                Op::Add(Add { offset, .. })
+                | Op::Input(Input { offset })
+                | Op::Set(Set { offset, .. }),
+            ) => {
+                vec![*offset]
+            }
+            Some(Op::Mul(Mul { offset, targets })) => {
+                let mut offsets = vec![*offset];
+                offsets.extend(targets.iter().map(|(target, _)| *target));
+                offsets
             }
+            _ => vec![],
+        };
+        let memory = &self.tapes[self.tape];
+        let writes = offsets
+            .into_iter()
+            .filter_map(|offset| self.tape_mode.resolve(self.mp + offset).ok())
+            .map(|cell| (cell, *memory.get_signed(cell)))
+            .collect();
+        let delta = Delta {
+            position: self.code.paths[self.pc].clone(),
+            pointer: self.mp,
+            tape: self.tape,
+            input: None,
+            writes,
+            pending_output: self.pending_output.iter().copied().collect(),
+            partial_input: vec![],
+        };
+        let (next, queued) = (self.input.front().copied(), self.input.len());
+        let state = super::Engine::step(self)?;
+        let input = (self.input.len() < queued).then_some(next).flatten();
+        Ok((state, Delta { input, ..delta }))
+    }
+
+    fn undo(&mut self, delta: Delta) {
+        self.pc = self
+            .code
+            .paths
+            .binary_search(&delta.position)
+            .expect("The delta was recorded on the same program");
+        self.mp = delta.pointer;
+        self.verified = Verified::NONE;
+        self.tape = delta.tape;
+        if let Some(input) = delta.input {
+            self.input.push_front(input)
         }
+        self.pending_output = delta.pending_output.into();
+        for (cell, value) in delta.writes {
+            self.tapes[self.tape].set_signed(cell, value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        engine::{mem::TapeMode, Engine, ProgrammableEngine, RTError, State, StopState},
+        ir::{passes::OptLevel, Block, Loop, Node, Print, Program, Set},
+    };
+
+    #[test]
+    fn empty_loop() {
+        // the optimizer would turn the loop into a divergence
+        let program = Program(Block(vec![
+            Node::Set(Set {
+                value: 1,
+                offset: 0,
+            }),
+            Node::Loop(Loop {
+                body: Block(vec![]),
+                offset: 0,
+            }),
+        ]));
+        let mut engine = super::Engine::new(program);
+        assert_eq!(engine.step_n(10).unwrap(), (State::Running, 10));
+        assert_eq!(engine.position(), [1]);
+
+        let mut engine = super::Engine::new_from_str("+[]").unwrap();
+        assert_eq!(engine.run().unwrap(), StopState::Diverged);
+        assert_eq!(engine.run().unwrap(), StopState::Diverged);
+    }
+
+    #[test]
+    fn checked_bodies() {
+        // the body of the outer loop reaches two cells to the right
+        let src = ",[>,[->+<]<-]>>.";
+        let run = |len| {
+            let program = Program::from_raw_with(src.parse().unwrap(), OptLevel::O0);
+            let mut engine =
+                super::Engine::new(program).with_tape_mode(TapeMode::Fixed { len, wrap: false });
+            engine.give_input_slice(&[1, 5]);
+            engine.run()
+        };
+        assert_eq!(run(3), Ok(StopState::HasOutput(5)));
+        assert_eq!(run(2), Err(RTError::MemPositiveOut));
+    }
+
+    #[test]
+    fn take_output() {
+        let program = Program(Block(vec![Node::Print(Print {
+            bytes: b"Hello".to_vec(),
+        })]));
+        let mut engine = super::Engine::new(program);
+        assert!(engine.take_output().is_empty());
+        assert_eq!(engine.run().unwrap(), StopState::HasOutput(b'H'));
+        assert_eq!(engine.take_output(), b"ello");
+        assert_eq!(engine.run().unwrap(), StopState::Halted);
+
+        // the rest of the print is still written when running with io
+        engine.reset();
+        let mut output = vec![];
+        let stop = engine.run_with_io(&mut || None, &mut |ch| output.push(ch));
+        assert_eq!(stop, Ok(StopState::Halted));
+        assert_eq!(output, b"Hello");
     }
 }
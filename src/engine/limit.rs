@@ -0,0 +1,297 @@
+//! Wrappers limiting the execution of an engine
+
+use std::time::{Duration, Instant};
+
+use super::{
+    breakpoint::Breakpoint, coverage::Coverage, mem::Memory, stats::Stats, Engine, RTError, State,
+    StopState,
+};
+
+/// Engine that errors after a maximum number of steps
+///
+/// Steps that emit output count toward the limit, while halting or asking for input do not.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StepLimit<E> {
+    engine: E,
+    limit: u64,
+    remaining: u64,
+}
+
+impl<E> StepLimit<E> {
+    /// Limit the engine to `limit` steps
+    pub fn new(engine: E, limit: u64) -> Self {
+        Self {
+            engine,
+            limit,
+            remaining: limit,
+        }
+    }
+
+    /// Steps left before the limit is exceeded
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// Remove the limit, returning the engine
+    pub fn into_inner(self) -> E {
+        self.engine
+    }
+}
+
+impl<E: Engine> Engine for StepLimit<E> {
+    type Cell = E::Cell;
+
+    fn step(&mut self) -> Result<State, RTError> {
+        let state = self.engine.step()?;
+        if matches!(
+            state,
+            State::Running | State::Stopped(StopState::HasOutput(_))
+        ) {
+            self.remaining = self
+                .remaining
+                .checked_sub(1)
+                .ok_or(RTError::StepLimitExceeded)?;
+        }
+        Ok(state)
+    }
+
+    fn input(&self) -> Option<u8> {
+        self.engine.input()
+    }
+
+    fn give_input(&mut self, input: u8) -> Option<u8> {
+        self.engine.give_input(input)
+    }
+
+    fn try_give_input(&mut self, input: u8) -> Result<(), u8> {
+        self.engine.try_give_input(input)
+    }
+
+    fn give_input_slice(&mut self, input: &[u8]) -> usize {
+        self.engine.give_input_slice(input)
+    }
+
+    fn close_input(&mut self) -> bool {
+        self.engine.close_input()
+    }
+
+    fn memory(&self) -> &Memory<E::Cell> {
+        self.engine.memory()
+    }
+
+    fn memory_mut(&mut self) -> &mut Memory<E::Cell> {
+        self.engine.memory_mut()
+    }
+
+    fn tape(&self) -> usize {
+        self.engine.tape()
+    }
+
+    fn pointer(&self) -> isize {
+        self.engine.pointer()
+    }
+
+    fn set_pointer(&mut self, pointer: isize) {
+        self.engine.set_pointer(pointer)
+    }
+
+    fn position(&self) -> Vec<usize> {
+        self.engine.position()
+    }
+
+    fn instruction(&self) -> Option<String> {
+        self.engine.instruction()
+    }
+
+    /// Reset the engine, restoring the full step limit
+    fn reset(&mut self) {
+        self.engine.reset();
+        self.remaining = self.limit;
+    }
+
+    fn collect_stats(&mut self) -> bool {
+        self.engine.collect_stats()
+    }
+
+    fn stats(&self) -> Option<&Stats> {
+        self.engine.stats()
+    }
+
+    fn collect_coverage(&mut self) -> bool {
+        self.engine.collect_coverage()
+    }
+
+    fn coverage(&self) -> Option<&Coverage> {
+        self.engine.coverage()
+    }
+
+    fn set_breakpoint(&mut self, breakpoint: Breakpoint) -> bool {
+        self.engine.set_breakpoint(breakpoint)
+    }
+
+    fn clear_breakpoint(&mut self, breakpoint: &Breakpoint) -> bool {
+        self.engine.clear_breakpoint(breakpoint)
+    }
+}
+
+/// Steps between two checks of the clock
+const DEADLINE_CHECK_INTERVAL: u32 = 1 << 12;
+
+/// Engine that errors if it is still running after a deadline
+///
+/// The clock is checked only every few thousands steps, so the deadline can be overrun by a bit.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Deadline<E> {
+    engine: E,
+    deadline: Option<Instant>,
+    /// Steps before the next check
+    countdown: u32,
+}
+
+impl<E> Deadline<E> {
+    /// Stop the engine at the given instant, if any
+    pub fn new(engine: E, deadline: Option<Instant>) -> Self {
+        Self {
+            engine,
+            deadline,
+            countdown: DEADLINE_CHECK_INTERVAL,
+        }
+    }
+
+    /// Stop the engine after the given time from now
+    pub fn with_timeout(engine: E, timeout: Duration) -> Self {
+        Self::new(engine, Some(Instant::now() + timeout))
+    }
+
+    /// Remove the deadline, returning the engine
+    pub fn into_inner(self) -> E {
+        self.engine
+    }
+}
+
+impl<E: Engine> Engine for Deadline<E> {
+    type Cell = E::Cell;
+
+    fn step(&mut self) -> Result<State, RTError> {
+        let state = self.engine.step()?;
+        self.countdown -= 1;
+        if self.countdown == 0 {
+            self.countdown = DEADLINE_CHECK_INTERVAL;
+            if self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                return Err(RTError::Timeout);
+            }
+        }
+        Ok(state)
+    }
+
+    fn take_output(&mut self) -> Vec<u8> {
+        self.engine.take_output()
+    }
+
+    fn input(&self) -> Option<u8> {
+        self.engine.input()
+    }
+
+    fn give_input(&mut self, input: u8) -> Option<u8> {
+        self.engine.give_input(input)
+    }
+
+    fn try_give_input(&mut self, input: u8) -> Result<(), u8> {
+        self.engine.try_give_input(input)
+    }
+
+    fn give_input_slice(&mut self, input: &[u8]) -> usize {
+        self.engine.give_input_slice(input)
+    }
+
+    fn close_input(&mut self) -> bool {
+        self.engine.close_input()
+    }
+
+    fn memory(&self) -> &Memory<E::Cell> {
+        self.engine.memory()
+    }
+
+    fn memory_mut(&mut self) -> &mut Memory<E::Cell> {
+        self.engine.memory_mut()
+    }
+
+    fn tape(&self) -> usize {
+        self.engine.tape()
+    }
+
+    fn pointer(&self) -> isize {
+        self.engine.pointer()
+    }
+
+    fn set_pointer(&mut self, pointer: isize) {
+        self.engine.set_pointer(pointer)
+    }
+
+    fn position(&self) -> Vec<usize> {
+        self.engine.position()
+    }
+
+    fn instruction(&self) -> Option<String> {
+        self.engine.instruction()
+    }
+
+    /// Reset the engine. The deadline is left unchanged
+    fn reset(&mut self) {
+        self.engine.reset()
+    }
+
+    fn collect_stats(&mut self) -> bool {
+        self.engine.collect_stats()
+    }
+
+    fn stats(&self) -> Option<&Stats> {
+        self.engine.stats()
+    }
+
+    fn collect_coverage(&mut self) -> bool {
+        self.engine.collect_coverage()
+    }
+
+    fn coverage(&self) -> Option<&Coverage> {
+        self.engine.coverage()
+    }
+
+    fn set_breakpoint(&mut self, breakpoint: Breakpoint) -> bool {
+        self.engine.set_breakpoint(breakpoint)
+    }
+
+    fn clear_breakpoint(&mut self, breakpoint: &Breakpoint) -> bool {
+        self.engine.clear_breakpoint(breakpoint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Deadline, StepLimit};
+    use crate::engine::{raw, Engine, ProgrammableEngine, RTError, StopState};
+
+    #[test]
+    fn step_limit() {
+        // 2 steps, then it halts
+        let mut engine = StepLimit::new(<raw::Engine>::new_from_str("++").unwrap(), 2);
+        assert_eq!(engine.run(), Ok(StopState::Halted));
+        // never halts
+        let mut engine = StepLimit::new(<raw::Engine>::new_from_str("+[]").unwrap(), 100);
+        assert_eq!(engine.run(), Err(RTError::StepLimitExceeded));
+    }
+
+    #[test]
+    fn deadline() {
+        let mut engine = Deadline::with_timeout(
+            <raw::Engine>::new_from_str("+[]").unwrap(),
+            Duration::from_millis(10),
+        );
+        assert_eq!(engine.run(), Err(RTError::Timeout));
+    }
+}
@@ -1,94 +1,677 @@
 //! Memory of a Brainfuck engine
 
 use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fmt::Debug,
     hash::Hash,
-    iter::{repeat, zip},
+    iter::{repeat_n, zip},
+    ops::Range,
+    sync::Arc,
 };
 
-#[derive(Debug, Clone)]
-pub struct Memory {
-    mem: Vec<u8>,
+use serde::{Deserialize, Serialize};
+
+use super::{cell::Cell, RTError};
+
+/// What happens when the memory pointer moves before the first cell
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum TapeMode {
+    /// The tape starts at the first cell, and moving before it is an error
+    #[default]
+    RightUnbounded,
+    /// The tape extends in both directions
+    Bidirectional,
+    /// A tape of `len` cells, with `len` not zero. Moving past the ends is an error, or wraps
+    /// around to the other end if `wrap` is set
+    Fixed { len: usize, wrap: bool },
 }
 
-impl Memory {
-    pub fn get(&self, pos: usize) -> &u8 {
-        self.mem.get(pos).unwrap_or(&0)
+impl TapeMode {
+    /// The cell the pointer is on, as given to [`Memory::get_signed`]
+    pub fn resolve(self, pointer: isize) -> Result<isize, RTError> {
+        match self {
+            TapeMode::RightUnbounded if pointer < 0 => Err(RTError::MemNegativeOut),
+            TapeMode::RightUnbounded | TapeMode::Bidirectional => Ok(pointer),
+            TapeMode::Fixed { len, wrap: true } => Ok(pointer.rem_euclid(len as isize)),
+            TapeMode::Fixed { wrap: false, .. } if pointer < 0 => Err(RTError::MemNegativeOut),
+            TapeMode::Fixed { len, wrap: false } if pointer as usize >= len => {
+                Err(RTError::MemPositiveOut)
+            }
+            TapeMode::Fixed { wrap: false, .. } => Ok(pointer),
+        }
     }
-    pub fn get_mut(&mut self, pos: usize) -> &mut u8 {
-        self.mem
-            .extend(repeat(0).take((pos + 1).saturating_sub(self.mem.len())));
-        &mut self.mem[pos]
+}
+
+/// Cells stored contiguously at the start of the tape
+///
+/// Most programs stay well below this, and never pay for the pages.
+const DENSE_CELLS: usize = 1 << 20;
+/// Cells in each page of the tape after the contiguous part
+const PAGE_CELLS: usize = 4096;
+
+/// The memory of an engine
+///
+/// The start of the tape is a single vector. Cells further to the right are stored in pages,
+/// allocated only when written, so programs that jump far away do not fill the memory with
+/// zeros.
+#[derive(Debug, Clone, Default)]
+pub struct Memory<C = u8> {
+    mem: Vec<C>,
+    /// Cells before the first one, starting from `-1`
+    negative: Vec<C>,
+    /// Cells past the contiguous part, indexed by the position of the page
+    pages: BTreeMap<usize, Box<[C]>>,
+}
+
+impl<C: Cell> Memory<C> {
+    /// Check if a cell is in the contiguous part of the tape
+    fn is_dense(&self, pos: usize) -> bool {
+        pos < DENSE_CELLS
     }
-    pub fn set(&mut self, pos: usize, value: u8) {
+    pub fn get(&self, pos: usize) -> &C {
+        if self.is_dense(pos) {
+            self.mem.get(pos).unwrap_or(C::ZERO)
+        } else {
+            self.pages
+                .get(&(pos / PAGE_CELLS))
+                .map_or(C::ZERO, |page| &page[pos % PAGE_CELLS])
+        }
+    }
+    pub fn get_mut(&mut self, pos: usize) -> &mut C {
+        if self.is_dense(pos) {
+            self.mem.extend(repeat_n(
+                C::ZERO.clone(),
+                (pos + 1).saturating_sub(self.mem.len()),
+            ));
+            &mut self.mem[pos]
+        } else {
+            &mut self
+                .pages
+                .entry(pos / PAGE_CELLS)
+                .or_insert_with(|| vec![C::ZERO.clone(); PAGE_CELLS].into_boxed_slice())
+                [pos % PAGE_CELLS]
+        }
+    }
+    pub fn set(&mut self, pos: usize, value: C) {
         if pos < self.mem.len() {
             self.mem[pos] = value
-        } else if value != 0 {
+        } else if value != *C::ZERO {
             *self.get_mut(pos) = value;
+        } else if let Some(page) = self.pages.get_mut(&(pos / PAGE_CELLS)) {
+            page[pos % PAGE_CELLS] = value
         } else {
             // Nothing to do. The memory over the limit is taken to be 0
         }
     }
+    /// Get a cell, that can also be before the first one
+    pub fn get_signed(&self, pos: isize) -> &C {
+        match usize::try_from(pos) {
+            Ok(pos) => self.get(pos),
+            Err(_) => self.negative.get(!pos as usize).unwrap_or(C::ZERO),
+        }
+    }
+    /// Set a cell, that can also be before the first one
+    pub fn set_signed(&mut self, pos: isize, value: C) {
+        match usize::try_from(pos) {
+            Ok(pos) => self.set(pos, value),
+            Err(_) => {
+                let pos = !pos as usize;
+                if pos < self.negative.len() {
+                    self.negative[pos] = value
+                } else if value != *C::ZERO {
+                    self.negative.resize(pos + 1, C::ZERO.clone());
+                    self.negative[pos] = value
+                }
+            }
+        }
+    }
+    /// Number of cells allocated
+    fn allocated(&self) -> usize {
+        self.mem.len() + self.negative.len() + self.pages.len() * PAGE_CELLS
+    }
+    /// Like [`Memory::set_signed`], but fails with [`RTError::OutOfMemory`] instead of growing
+    /// the memory past `limit` cells
+    pub fn set_limited(&mut self, pos: isize, value: C, limit: usize) -> Result<(), RTError> {
+        let grown = match usize::try_from(pos) {
+            Ok(pos) if self.is_dense(pos) => (pos + 1).saturating_sub(self.mem.len()),
+            Ok(pos) if self.pages.contains_key(&(pos / PAGE_CELLS)) => 0,
+            Ok(_) => PAGE_CELLS,
+            Err(_) => (!pos as usize + 1).saturating_sub(self.negative.len()),
+        };
+        if grown > 0 && value != *C::ZERO && self.allocated() + grown > limit {
+            return Err(RTError::OutOfMemory);
+        }
+        self.set_signed(pos, value);
+        Ok(())
+    }
+    /// Set all the cells in `range` to `value`
+    pub fn fill_range(&mut self, range: Range<isize>, value: C) {
+        if range.is_empty() {
+            return;
+        }
+        let Range { start, end } = range;
+        if start < 0 {
+            // cell `-1 - i` is at index `i`
+            fill(
+                &mut self.negative,
+                end.min(0).unsigned_abs()..start.unsigned_abs(),
+                value.clone(),
+            )
+        }
+        if end <= 0 {
+            return;
+        }
+        let (start, end) = (start.max(0) as usize, end as usize);
+        let dense_end = end.min(DENSE_CELLS);
+        if start < dense_end {
+            fill(&mut self.mem, start..dense_end, value.clone())
+        }
+        let start = start.max(dense_end);
+        if start == end {
+            return;
+        }
+        if value == *C::ZERO {
+            // only the pages already there need clearing
+            let pages: Vec<_> = self
+                .pages
+                .range(start / PAGE_CELLS..=(end - 1) / PAGE_CELLS)
+                .map(|(&page, _)| page)
+                .collect();
+            for page in pages {
+                let first = page * PAGE_CELLS;
+                let cells = &mut self.pages.get_mut(&page).unwrap()
+                    [start.max(first) - first..end.min(first + PAGE_CELLS) - first];
+                cells.fill(value.clone())
+            }
+        } else {
+            for pos in start..end {
+                *self.get_mut(pos) = value.clone()
+            }
+        }
+    }
+    /// Position of the first zero cell at or after `pos`, moving right
+    pub fn find_zero_from(&self, pos: isize) -> isize {
+        let zero = |cell: &C| cell == C::ZERO;
+        let start = match usize::try_from(pos) {
+            Ok(pos) => pos,
+            Err(_) => {
+                let idx = !pos as usize;
+                if idx >= self.negative.len() {
+                    return pos;
+                }
+                if let Some(found) = self.negative[..=idx].iter().rposition(zero) {
+                    return !(found as isize);
+                }
+                0
+            }
+        };
+        let mut pos = match self.mem.get(start..) {
+            Some(cells) => cells
+                .iter()
+                .position(zero)
+                .map_or(self.mem.len(), |i| start + i),
+            None => start,
+        };
+        // the zero is in the pages only if the contiguous part is full
+        while !self.is_dense(pos) {
+            let Some(page) = self.pages.get(&(pos / PAGE_CELLS)) else {
+                break;
+            };
+            match page[pos % PAGE_CELLS..].iter().position(zero) {
+                Some(i) => {
+                    pos += i;
+                    break;
+                }
+                None => pos = (pos / PAGE_CELLS + 1) * PAGE_CELLS,
+            }
+        }
+        pos.try_into()
+            .expect("The memory should be smaller than `isize::MAX`")
+    }
+    /// Add `amounts` to the cells starting at `pos`, wrapping around
+    pub fn add_slice(&mut self, pos: isize, amounts: &[C]) {
+        let (negative, positive) = amounts.split_at(pos.min(0).unsigned_abs().min(amounts.len()));
+        for (cell, amount) in (pos..).zip(negative) {
+            let value = self.get_signed(cell).wrapping_add(amount);
+            self.set_signed(cell, value)
+        }
+        let Some(last) = positive.iter().rposition(|amount| amount != C::ZERO) else {
+            return;
+        };
+        let start = pos.max(0) as usize;
+        if !self.is_dense(start + last) {
+            for (cell, amount) in (start..).zip(&positive[..=last]) {
+                let value = self.get(cell).wrapping_add(amount);
+                self.set(cell, value)
+            }
+            return;
+        }
+        self.get_mut(start + last);
+        for (cell, amount) in zip(&mut self.mem[start..], &positive[..=last]) {
+            *cell = cell.wrapping_add(amount)
+        }
+    }
     pub fn filled_len(&self) -> usize {
-        let mut len = self.mem.len();
-        while len > 0 && self.mem[len - 1] == 0 {
-            len -= 1
+        match self.pages().last() {
+            Some((first, cells)) => first + filled_len(cells),
+            None => filled_len(&self.mem),
         }
-        len
     }
     pub fn shrink_to_fit(&mut self) {
-        self.mem.truncate(self.filled_len());
+        self.mem.truncate(filled_len(&self.mem));
         self.mem.shrink_to_fit();
+        self.negative.truncate(filled_len(&self.negative));
+        self.negative.shrink_to_fit();
+        self.pages.retain(|_, page| filled_len(page) > 0);
+    }
+    /// The filled part of the contiguous memory at the start of the tape
+    ///
+    /// Cells far to the right are not included, see [`Memory::pages`]
+    pub fn as_slice(&self) -> &[C] {
+        &self.mem[..filled_len(&self.mem)]
     }
+    /// The filled part of the memory before the first cell, starting from `-1`
+    pub fn negative_slice(&self) -> &[C] {
+        &self.negative[..filled_len(&self.negative)]
+    }
+    /// The pages of cells past the contiguous part that are not all zeros, with the position
+    /// of their first cell
+    pub fn pages(&self) -> impl DoubleEndedIterator<Item = (usize, &[C])> {
+        self.pages
+            .iter()
+            .filter(|(_, page)| filled_len(page) > 0)
+            .map(|(&page, cells)| (page * PAGE_CELLS, &**cells))
+    }
+
+    pub fn new() -> Self {
+        Memory {
+            mem: vec![],
+            negative: vec![],
+            pages: BTreeMap::new(),
+        }
+    }
+}
+
+/// Fill a half of the memory, growing it only if `value` is not zero
+fn fill<C: Cell>(cells: &mut Vec<C>, range: Range<usize>, value: C) {
+    if value != *C::ZERO && cells.len() < range.end {
+        cells.resize(range.end, C::ZERO.clone())
+    }
+    let end = range.end.min(cells.len());
+    if let Some(cells) = cells.get_mut(range.start..end) {
+        cells.fill(value)
+    }
+}
+
+/// Length of the cells up to the last non zero one
+fn filled_len<C: Cell>(cells: &[C]) -> usize {
+    let mut len = cells.len();
+    while len > 0 && cells[len - 1] == *C::ZERO {
+        len -= 1
+    }
+    len
+}
+
+impl Memory<u8> {
     pub fn as_bytes(&self) -> &[u8] {
-        &self.mem[..self.filled_len()]
+        self.as_slice()
     }
 
-    pub fn new() -> Memory {
-        Memory { mem: vec![] }
+    /// Convert to wider cells, each byte becoming a cell
+    pub fn widen<C: Cell>(&self) -> Memory<C> {
+        fn widen<C: Cell, T: FromIterator<C>>(cells: &[u8]) -> T {
+            cells.iter().map(|&b| C::from_u64(b.into())).collect()
+        }
+        Memory {
+            mem: widen(&self.mem),
+            negative: widen(&self.negative),
+            pages: self
+                .pages
+                .iter()
+                .map(|(&page, cells)| (page, widen(cells)))
+                .collect(),
+        }
     }
 }
 
-impl PartialEq for Memory {
-    fn eq(&self, other: &Self) -> bool {
-        let [s1, s2] = if self.mem.len() >= other.mem.len() {
-            [&self.mem, &other.mem]
-        } else {
-            [&other.mem, &self.mem]
+#[derive(Serialize)]
+#[serde(untagged)]
+enum SerRepr<'m, C> {
+    Positive(&'m [C]),
+    Bidirectional {
+        positive: &'m [C],
+        negative: &'m [C],
+    },
+    Paged {
+        positive: &'m [C],
+        negative: &'m [C],
+        pages: Vec<(usize, &'m [C])>,
+    },
+}
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DeRepr<C> {
+    Positive(Vec<C>),
+    // before `Bidirectional`, that would ignore the pages
+    Paged {
+        positive: Vec<C>,
+        negative: Vec<C>,
+        pages: Vec<(usize, Vec<C>)>,
+    },
+    Bidirectional {
+        positive: Vec<C>,
+        negative: Vec<C>,
+    },
+}
+
+/// Serialized as the filled part of the tape. If some cells before the first one are filled,
+/// the two halves are serialized as `positive` and `negative`. If some pages are filled, they
+/// are added as `pages`, each with the position of its first cell
+impl<C: Cell> Serialize for Memory<C> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let pages: Vec<_> = self
+            .pages()
+            .map(|(first, cells)| (first, &cells[..filled_len(cells)]))
+            .collect();
+        match self.negative_slice() {
+            _ if !pages.is_empty() => SerRepr::Paged {
+                positive: self.as_slice(),
+                negative: self.negative_slice(),
+                pages,
+            },
+            [] => SerRepr::Positive(self.as_slice()),
+            negative => SerRepr::Bidirectional {
+                positive: self.as_slice(),
+                negative,
+            },
+        }
+        .serialize(serializer)
+    }
+}
+impl<'de, C: Cell> Deserialize<'de> for Memory<C> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Ok(match DeRepr::deserialize(deserializer)? {
+            DeRepr::Positive(mem) => Memory::from(mem),
+            DeRepr::Bidirectional { positive, negative } => Memory {
+                negative,
+                ..Memory::from(positive)
+            },
+            DeRepr::Paged {
+                positive,
+                negative,
+                pages,
+            } => {
+                let mut memory = Memory {
+                    negative,
+                    ..Memory::from(positive)
+                };
+                for (first, cells) in pages {
+                    for (pos, cell) in (first..).zip(cells) {
+                        memory.set(pos, cell)
+                    }
+                }
+                memory
+            }
+        })
+    }
+}
+
+impl<C: Cell> From<Vec<C>> for Memory<C> {
+    fn from(mut mem: Vec<C>) -> Self {
+        let far = mem.split_off(mem.len().min(DENSE_CELLS));
+        let mut memory = Memory {
+            mem,
+            negative: vec![],
+            pages: BTreeMap::new(),
+        };
+        for (pos, cell) in (DENSE_CELLS..).zip(far) {
+            memory.set(pos, cell)
         }
-        .map(Vec::as_slice);
-        let (s1, diff) = s1.split_at(s2.len());
-        zip(s1, s2).all(|(a, b)| a == b) && diff.into_iter().all(|x| *x == 0)
+        memory
     }
 }
-impl Eq for Memory {}
 
-impl PartialOrd for Memory {
+/// Check if two halves of the memory are equal, the missing cells being zero
+fn eq_filled<C: Cell>(a: &[C], b: &[C]) -> bool {
+    let [s1, s2] = if a.len() >= b.len() { [a, b] } else { [b, a] };
+    let (s1, diff) = s1.split_at(s2.len());
+    zip(s1, s2).all(|(a, b)| a == b) && diff.iter().all(|x| x == C::ZERO)
+}
+impl<C: Cell> PartialEq for Memory<C> {
+    fn eq(&self, other: &Self) -> bool {
+        eq_filled(&self.mem, &other.mem)
+            && eq_filled(&self.negative, &other.negative)
+            && self.pages().eq(other.pages())
+    }
+}
+impl<C: Cell> Eq for Memory<C> {}
+
+impl<C: Cell> PartialOrd for Memory<C> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         Some(self.cmp(other))
     }
 }
-impl Ord for Memory {
+impl<C: Cell> Ord for Memory<C> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        let common = usize::min(self.mem.len(), other.mem.len());
-        let (sc, sd) = self.mem.split_at(common);
-        let (oc, od) = other.mem.split_at(common);
-        match sc.cmp(oc) {
-            std::cmp::Ordering::Greater => std::cmp::Ordering::Greater,
-            std::cmp::Ordering::Less => std::cmp::Ordering::Less,
-            std::cmp::Ordering::Equal => {
-                if sd.iter().any(|x| *x != 0) {
-                    std::cmp::Ordering::Greater
-                } else if od.iter().any(|x| *x != 0) {
-                    std::cmp::Ordering::Less
-                } else {
-                    std::cmp::Ordering::Equal
-                }
+        cmp_filled(&self.mem, &other.mem)
+            .then_with(|| cmp_filled(&self.negative, &other.negative))
+            .then_with(|| self.pages().cmp(other.pages()))
+    }
+}
+/// Compare two halves of the memory, the missing cells being zero
+fn cmp_filled<C: Cell>(a: &[C], b: &[C]) -> std::cmp::Ordering {
+    let common = usize::min(a.len(), b.len());
+    let (sc, sd) = a.split_at(common);
+    let (oc, od) = b.split_at(common);
+    match sc.cmp(oc) {
+        std::cmp::Ordering::Greater => std::cmp::Ordering::Greater,
+        std::cmp::Ordering::Less => std::cmp::Ordering::Less,
+        std::cmp::Ordering::Equal => {
+            if sd.iter().any(|x| x != C::ZERO) {
+                std::cmp::Ordering::Greater
+            } else if od.iter().any(|x| x != C::ZERO) {
+                std::cmp::Ordering::Less
+            } else {
+                std::cmp::Ordering::Equal
             }
         }
     }
 }
-impl Hash for Memory {
+impl<C: Cell> Hash for Memory<C> {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.as_bytes().hash(state)
+        self.as_slice().hash(state);
+        self.negative_slice().hash(state);
+        for page in self.pages() {
+            page.hash(state)
+        }
+    }
+}
+
+/// Host code mapped to a cell of the memory
+///
+/// Reading or writing the cell calls the hook, so a program can talk with the host through the
+/// memory, e.g. reading the current time from a cell or drawing by writing to another.
+pub trait Hook<C>: Send + Sync {
+    /// Value read by the program. `None` to read the value stored in the cell
+    fn read(&self, stored: &C) -> Option<C> {
+        let _ = stored;
+        None
+    }
+    /// Called after the program wrote `value` in the cell
+    fn write(&self, value: &C) {
+        let _ = value;
+    }
+}
+
+/// Hooks mapped to the cells of a tape. See [`Hook`]
+///
+/// The hooks are host code, not state of the engine: all the sets of hooks compare equal, and
+/// they are not serialized.
+pub struct Hooks<C> {
+    cells: BTreeMap<isize, Arc<dyn Hook<C>>>,
+}
+
+impl<C: Cell> Hooks<C> {
+    pub fn new() -> Self {
+        Self {
+            cells: BTreeMap::new(),
+        }
+    }
+    /// Map `hook` to the cell at `pos`, replacing the previous one
+    pub fn with(mut self, pos: isize, hook: impl Hook<C> + 'static) -> Self {
+        self.cells.insert(pos, Arc::new(hook));
+        self
+    }
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+    /// Read a cell of `memory`, calling its hook if it has one
+    pub fn read<'m>(&self, memory: &'m Memory<C>, pos: isize) -> Cow<'m, C> {
+        let stored = memory.get_signed(pos);
+        match self.cells.get(&pos).and_then(|hook| hook.read(stored)) {
+            Some(value) => Cow::Owned(value),
+            None => Cow::Borrowed(stored),
+        }
+    }
+    /// Notify the hook of the cell at `pos`, if it has one, that `value` was written in it
+    pub fn write(&self, pos: isize, value: &C) {
+        if let Some(hook) = self.cells.get(&pos) {
+            hook.write(value)
+        }
+    }
+}
+
+impl<C> Clone for Hooks<C> {
+    fn clone(&self) -> Self {
+        Self {
+            cells: self.cells.clone(),
+        }
+    }
+}
+impl<C> Default for Hooks<C> {
+    fn default() -> Self {
+        Self {
+            cells: BTreeMap::new(),
+        }
+    }
+}
+impl<C> Debug for Hooks<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.cells.keys()).finish()
+    }
+}
+impl<C> PartialEq for Hooks<C> {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+impl<C> Eq for Hooks<C> {}
+impl<C> PartialOrd for Hooks<C> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl<C> Ord for Hooks<C> {
+    fn cmp(&self, _: &Self) -> std::cmp::Ordering {
+        std::cmp::Ordering::Equal
+    }
+}
+impl<C> Hash for Hooks<C> {
+    fn hash<H: std::hash::Hasher>(&self, _: &mut H) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Memory;
+
+    #[test]
+    fn bulk() {
+        let mut mem = Memory::<u8>::new();
+        mem.fill_range(-2..3, 7);
+        assert_eq!(mem.as_slice(), [7, 7, 7]);
+        assert_eq!(mem.negative_slice(), [7, 7]);
+        assert_eq!(mem.find_zero_from(-2), 3);
+        assert_eq!(mem.find_zero_from(-5), -5);
+        mem.fill_range(1..2, 0);
+        assert_eq!(mem.find_zero_from(-2), 1);
+        mem.add_slice(-1, &[1, 250, 0, 0, 1]);
+        assert_eq!(mem.as_slice(), [1, 0, 7, 1]);
+        assert_eq!(mem.negative_slice(), [8, 7]);
+        assert_eq!(mem.find_zero_from(0), 1);
+        // zeros past the end do not grow the memory
+        mem.fill_range(10..1000, 0);
+        mem.add_slice(10, &[0; 100]);
+        assert_eq!(mem.find_zero_from(20), 20);
+        assert_eq!(mem.filled_len(), 4);
+    }
+
+    #[test]
+    fn paged() {
+        let far = 1 << 40;
+        let mut mem = Memory::<u8>::new();
+        mem.set(far, 3);
+        mem.set(far + 1, 4);
+        assert_eq!(*mem.get(far), 3);
+        assert_eq!(*mem.get(far - 1), 0);
+        assert_eq!(mem.filled_len(), far + 2);
+        assert_eq!(mem.find_zero_from(far as isize), far as isize + 2);
+        // only a page is allocated
+        assert_eq!(
+            mem.set_limited(1 << 30, 1, 5000),
+            Err(crate::engine::RTError::OutOfMemory)
+        );
+        mem.add_slice(far as isize - 1, &[1, 1]);
+        assert_eq!(mem.pages().count(), 2);
+        let json = serde_json::to_string(&mem).unwrap();
+        assert_eq!(serde_json::from_str::<Memory>(&json).unwrap(), mem);
+        mem.fill_range(0..isize::MAX, 0);
+        assert_eq!(mem, Memory::new());
+    }
+
+    #[test]
+    fn hooks() {
+        use std::sync::{Arc, Mutex};
+
+        use super::{Hook, Hooks};
+        use crate::engine::{Engine, ProgrammableEngine, StopState};
+
+        struct Clock;
+        impl Hook<u8> for Clock {
+            fn read(&self, _: &u8) -> Option<u8> {
+                Some(7)
+            }
+        }
+        struct Recorder(Arc<Mutex<Vec<u8>>>);
+        impl Hook<u8> for Recorder {
+            fn write(&self, value: &u8) {
+                self.0.lock().unwrap().push(*value)
+            }
+        }
+
+        let writes = Arc::default();
+        let mut engine = <crate::engine::raw::Engine>::new_from_str(".>+++.[-]")
+            .unwrap()
+            .with_hooks(
+                Hooks::new()
+                    .with(0, Clock)
+                    .with(1, Recorder(Arc::clone(&writes))),
+            );
+        let mut output = vec![];
+        let stop = engine
+            .run_with_io(&mut || None, &mut |ch| output.push(ch))
+            .unwrap();
+        assert_eq!(stop, StopState::Halted);
+        assert_eq!(output, [7, 3]);
+        assert_eq!(*writes.lock().unwrap(), [1, 2, 3, 2, 1, 0]);
     }
 }
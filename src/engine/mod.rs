@@ -1,16 +1,60 @@
 //! Brainfuck executors
 
+use std::collections::VecDeque;
+
 use either::Either::{self, Left, Right};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::raw::UnmatchedParentheses;
 
+use self::{breakpoint::Breakpoint, coverage::Coverage, mem::Memory, stats::Stats};
+
 /// State of a stopped engine
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum StopState {
     Halted,
     NeedInput,
     HasOutput(u8),
+    /// The engine reached a breakpoint. See [`breakpoint`]
+    Breakpoint,
+    /// The engine tried to read after the input was closed, with the [`Eof::Stop`] policy.
+    /// See [`Engine::close_input`]
+    EndOfInput,
+    /// The program entered a loop that never ends
+    ///
+    /// Only the engines running optimized programs detect this. Stepping again gives the same
+    /// result.
+    Diverged,
+}
+
+/// What `,` does after the input was closed
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Eof {
+    /// Stop the engine with [`StopState::EndOfInput`]
+    #[default]
+    Stop,
+    /// Set the cell to zero
+    Zero,
+    /// Set the cell to `-1`, the largest value for unsigned cells
+    MinusOne,
+    /// Leave the cell unchanged
+    Unchanged,
+}
+
+impl Eof {
+    /// Value of a cell after reading past the end of the input. `None` if the engine must stop
+    pub fn value<C: cell::Cell>(self, cell: &C) -> Option<C> {
+        match self {
+            Eof::Stop => None,
+            Eof::Zero => Some(C::ZERO.clone()),
+            Eof::MinusOne => Some(C::ZERO.wrapping_sub(&C::from_u64(1))),
+            Eof::Unchanged => Some(cell.clone()),
+        }
+    }
 }
 
 /// State of an engine
@@ -25,10 +69,28 @@ pub enum State {
 pub enum RTError {
     #[error("The memory pointer exited the memory from below")]
     MemNegativeOut,
+    #[error("The memory pointer exited the memory from above")]
+    MemPositiveOut,
+    #[error("The tape selector went before the first tape")]
+    TapeNegativeOut,
+    #[error("The program exceeded the maximum number of steps")]
+    StepLimitExceeded,
+    #[error("The program exceeded its time limit")]
+    Timeout,
+    #[error("A cell overflowed")]
+    CellOverflow,
+    #[error("The program exceeded its memory limit")]
+    OutOfMemory,
 }
 
 /// A brainfuck engine
+///
+/// The trait is object safe, so the engine can be chosen at runtime and used as a
+/// `Box<dyn Engine<Cell = u8>>`. Creating engines is left to [`ProgrammableEngine`].
 pub trait Engine {
+    /// The memory cells. Only the raw engine supports cells wider than a byte
+    type Cell: cell::Cell = u8;
+
     /// Step the engine
     fn step(&mut self) -> Result<State, RTError>;
 
@@ -43,6 +105,62 @@ pub trait Engine {
         }
     }
 
+    /// Step the engine at most `fuel` times, stopping early if something stops it
+    ///
+    /// Returns the state of the engine and the fuel consumed. Emitting output consumes fuel,
+    /// while halting or asking for input does not, as the engine does not advance.
+    fn step_n(&mut self, fuel: u64) -> Result<(State, u64), RTError> {
+        for consumed in 0..fuel {
+            match self.step()? {
+                State::Running => (),
+                stop @ State::Stopped(StopState::HasOutput(_)) => return Ok((stop, consumed + 1)),
+                stop @ State::Stopped(_) => return Ok((stop, consumed)),
+            }
+        }
+        Ok((State::Running, fuel))
+    }
+
+    /// Run the engine, exchanging input and output through the given functions
+    ///
+    /// Stops when the engine halts, diverges, reaches a breakpoint or reads past the closed input,
+    /// or with [`StopState::NeedInput`] when `input` returns `None`. In that case the engine is
+    /// left waiting for input, and can be resumed later.
+    fn run_with_io(
+        &mut self,
+        input: &mut dyn FnMut() -> Option<u8>,
+        output: &mut dyn FnMut(u8),
+    ) -> Result<StopState, RTError> {
+        loop {
+            match self.run()? {
+                StopState::Halted => return Ok(StopState::Halted),
+                StopState::NeedInput => match input() {
+                    Some(ch) => {
+                        self.give_input(ch);
+                    }
+                    None => return Ok(StopState::NeedInput),
+                },
+                StopState::HasOutput(ch) => {
+                    output(ch);
+                    for ch in self.take_output() {
+                        output(ch)
+                    }
+                }
+                StopState::Breakpoint => return Ok(StopState::Breakpoint),
+                StopState::EndOfInput => return Ok(StopState::EndOfInput),
+                StopState::Diverged => return Ok(StopState::Diverged),
+            }
+        }
+    }
+
+    /// Take the output the engine will surely emit next, without stepping it
+    ///
+    /// Engines running constant prints return the rest of the print after the first byte is
+    /// emitted, so it can be written at once. The bytes taken are not emitted again. Engines that
+    /// emit one byte at a time return nothing.
+    fn take_output(&mut self) -> Vec<u8> {
+        vec![]
+    }
+
     /// Check if the engine has input
     fn has_input(&self) -> bool {
         self.input().is_some()
@@ -55,6 +173,211 @@ pub trait Engine {
     /// Give input to the engine
     /// If the engine has already some input, do not do anything and return the input present as error
     fn try_give_input(&mut self, input: u8) -> Result<(), u8>;
+    /// Queue input for the engine, to be read after the input it already has
+    ///
+    /// Returns the number of bytes taken. Engines without an input queue take a byte only if they
+    /// have no input
+    fn give_input_slice(&mut self, input: &[u8]) -> usize {
+        match input.first() {
+            Some(&first) if self.try_give_input(first).is_ok() => 1,
+            _ => 0,
+        }
+    }
+    /// Declare that no more input will be given
+    ///
+    /// Once the queued input is read, `,` follows the [`Eof`] policy of the engine instead of
+    /// asking for input. Returns `false` if the engine does not support closing the input
+    fn close_input(&mut self) -> bool {
+        false
+    }
+
+    /// Memory of the engine, on the selected tape
+    fn memory(&self) -> &Memory<Self::Cell>;
+    /// Mutable access to the memory of the engine, on the selected tape
+    fn memory_mut(&mut self) -> &mut Memory<Self::Cell>;
+    /// Index of the selected tape
+    fn tape(&self) -> usize;
+    /// Position of the memory pointer
+    fn pointer(&self) -> isize;
+    /// Move the memory pointer to the given position
+    fn set_pointer(&mut self, pointer: isize);
+    /// Position in the program of the next instruction to execute
+    ///
+    /// For nested programs, this is the index in each of the entered blocks
+    fn position(&self) -> Vec<usize>;
+    /// Human readable form of the next instruction to execute, if the engine can describe it
+    fn instruction(&self) -> Option<String> {
+        None
+    }
+
+    /// Rewind the engine to the start of the program, with empty memory and no input
+    fn reset(&mut self);
+
+    /// Start collecting execution statistics
+    ///
+    /// Returns `false` if the engine cannot collect them
+    fn collect_stats(&mut self) -> bool {
+        false
+    }
+    /// Statistics collected since [`Engine::collect_stats`] was called
+    fn stats(&self) -> Option<&Stats> {
+        None
+    }
+
+    /// Start recording which instructions are executed
+    ///
+    /// Returns `false` if the engine cannot record them
+    fn collect_coverage(&mut self) -> bool {
+        false
+    }
+    /// Instructions executed since [`Engine::collect_coverage`] was called
+    fn coverage(&self) -> Option<&Coverage> {
+        None
+    }
+
+    /// Set a breakpoint
+    ///
+    /// Returns `false` if the engine does not support breakpoints. Wrap it in a
+    /// [`breakpoint::Breakpoints`] to add them
+    fn set_breakpoint(&mut self, _breakpoint: Breakpoint) -> bool {
+        false
+    }
+    /// Remove a breakpoint
+    ///
+    /// Returns `false` if the breakpoint was not set
+    fn clear_breakpoint(&mut self, _breakpoint: &Breakpoint) -> bool {
+        false
+    }
+}
+
+impl<E: Engine + ?Sized> Engine for Box<E> {
+    type Cell = E::Cell;
+
+    fn step(&mut self) -> Result<State, RTError> {
+        (**self).step()
+    }
+
+    fn run(&mut self) -> Result<StopState, RTError> {
+        (**self).run()
+    }
+
+    fn step_n(&mut self, fuel: u64) -> Result<(State, u64), RTError> {
+        (**self).step_n(fuel)
+    }
+
+    fn run_with_io(
+        &mut self,
+        input: &mut dyn FnMut() -> Option<u8>,
+        output: &mut dyn FnMut(u8),
+    ) -> Result<StopState, RTError> {
+        (**self).run_with_io(input, output)
+    }
+
+    fn take_output(&mut self) -> Vec<u8> {
+        (**self).take_output()
+    }
+
+    fn has_input(&self) -> bool {
+        (**self).has_input()
+    }
+
+    fn input(&self) -> Option<u8> {
+        (**self).input()
+    }
+
+    fn give_input(&mut self, input: u8) -> Option<u8> {
+        (**self).give_input(input)
+    }
+
+    fn try_give_input(&mut self, input: u8) -> Result<(), u8> {
+        (**self).try_give_input(input)
+    }
+
+    fn give_input_slice(&mut self, input: &[u8]) -> usize {
+        (**self).give_input_slice(input)
+    }
+
+    fn close_input(&mut self) -> bool {
+        (**self).close_input()
+    }
+
+    fn memory(&self) -> &Memory<E::Cell> {
+        (**self).memory()
+    }
+
+    fn memory_mut(&mut self) -> &mut Memory<E::Cell> {
+        (**self).memory_mut()
+    }
+
+    fn tape(&self) -> usize {
+        (**self).tape()
+    }
+
+    fn pointer(&self) -> isize {
+        (**self).pointer()
+    }
+
+    fn set_pointer(&mut self, pointer: isize) {
+        (**self).set_pointer(pointer)
+    }
+
+    fn position(&self) -> Vec<usize> {
+        (**self).position()
+    }
+
+    fn instruction(&self) -> Option<String> {
+        (**self).instruction()
+    }
+
+    fn reset(&mut self) {
+        (**self).reset()
+    }
+
+    fn collect_stats(&mut self) -> bool {
+        (**self).collect_stats()
+    }
+
+    fn stats(&self) -> Option<&Stats> {
+        (**self).stats()
+    }
+
+    fn collect_coverage(&mut self) -> bool {
+        (**self).collect_coverage()
+    }
+
+    fn coverage(&self) -> Option<&Coverage> {
+        (**self).coverage()
+    }
+
+    fn set_breakpoint(&mut self, breakpoint: Breakpoint) -> bool {
+        (**self).set_breakpoint(breakpoint)
+    }
+
+    fn clear_breakpoint(&mut self, breakpoint: &Breakpoint) -> bool {
+        (**self).clear_breakpoint(breakpoint)
+    }
+}
+
+/// Replace the next input in the queue, or add it if the queue is empty
+fn replace_input(queue: &mut VecDeque<u8>, input: u8) -> Option<u8> {
+    match queue.front_mut() {
+        Some(old) => Some(std::mem::replace(old, input)),
+        None => {
+            queue.push_back(input);
+            None
+        }
+    }
+}
+
+/// Add the input to the queue only if it is empty, else return the next input
+fn try_give_input(queue: &mut VecDeque<u8>, input: u8) -> Result<(), u8> {
+    match queue.front() {
+        Some(&old) => Err(old),
+        None => {
+            queue.push_back(input);
+            Ok(())
+        }
+    }
 }
 
 /// A brainfuck engine that can be programmed
@@ -96,7 +419,455 @@ pub trait ProgrammableEngine {
     }
 }
 
-mod mem;
-
+#[cfg(feature = "async")]
+pub mod asynchronous;
+pub mod background;
+#[cfg(feature = "parallel")]
+pub mod batch;
+pub mod breakpoint;
+pub mod cell;
+pub mod closure;
+pub mod coverage;
+pub mod history;
 pub mod ir;
+pub mod limit;
+pub mod mem;
+pub mod pipeline;
 pub mod raw;
+pub mod sched;
+pub mod stats;
+pub mod threaded;
+pub mod trace;
+
+#[cfg(test)]
+mod tests {
+    use super::{Engine, Eof, ProgrammableEngine, State, StopState};
+
+    #[test]
+    fn step_n() {
+        // the raw engine takes a step for each instruction
+        let mut raw = <super::raw::Engine>::new_from_str("+++[-]+.").unwrap();
+        assert_eq!(raw.step_n(4).unwrap(), (State::Running, 4));
+        assert_eq!(
+            raw.step_n(100).unwrap(),
+            (State::Stopped(StopState::HasOutput(1)), 8)
+        );
+        assert_eq!(
+            raw.step_n(100).unwrap(),
+            (State::Stopped(StopState::Halted), 0)
+        );
+        assert_eq!(raw.step_n(0).unwrap(), (State::Running, 0));
+    }
+
+    #[test]
+    fn snapshot() {
+        fn resume<E>(program: &str)
+        where
+            E: Engine + ProgrammableEngine + serde::Serialize + serde::de::DeserializeOwned,
+            E::Program: TryFrom<crate::raw::Program>,
+            <E::Program as TryFrom<crate::raw::Program>>::Error: std::fmt::Debug,
+        {
+            let mut engine = E::new_from_str(program).unwrap();
+            let mut input = b"hello".iter().copied();
+            let mut output = vec![];
+            let mut stop = engine.run().unwrap();
+            while stop != StopState::Halted {
+                if stop == StopState::NeedInput {
+                    engine.give_input(input.next().unwrap_or(0));
+                }
+                if let StopState::HasOutput(ch) = stop {
+                    output.push(ch)
+                }
+                // checkpointing at every stop
+                let snapshot = serde_json::to_string(&engine).unwrap();
+                engine = serde_json::from_str(&snapshot).unwrap();
+                stop = engine.run().unwrap();
+            }
+            assert_eq!(output, b"hello");
+        }
+        resume::<super::raw::Engine>(",[>+++[<+>-]<---.,]");
+        resume::<super::ir::Engine>(",[>+++[<+>-]<---.,]");
+    }
+
+    #[test]
+    fn reset() {
+        fn rerun<E>(program: &str)
+        where
+            E: Engine<Cell = u8> + ProgrammableEngine,
+            E::Program: TryFrom<crate::raw::Program>,
+            <E::Program as TryFrom<crate::raw::Program>>::Error: std::fmt::Debug,
+        {
+            let mut engine = E::new_from_str(program).unwrap();
+            for _ in 0..2 {
+                let mut input = b"ab".iter().copied();
+                let mut output = vec![];
+                let stop = engine
+                    .run_with_io(&mut || input.next(), &mut |ch| output.push(ch))
+                    .unwrap();
+                assert_eq!(stop, StopState::NeedInput);
+                assert_eq!(output, b"bc");
+                engine.reset();
+                assert_eq!(engine.pointer(), 0);
+                assert_eq!(*engine.memory().get(0), 0);
+                assert!(!engine.has_input());
+            }
+        }
+        rerun::<super::raw::Engine>(",[+.>,]");
+        rerun::<super::ir::Engine>(",[+.>,]");
+        rerun::<super::threaded::Engine>(",[+.>,]");
+        rerun::<super::closure::Engine>(",[+.>,]");
+    }
+
+    #[test]
+    fn stats() {
+        fn collect<E>(program: &str) -> super::stats::Stats
+        where
+            E: Engine + ProgrammableEngine,
+            E::Program: TryFrom<crate::raw::Program>,
+            <E::Program as TryFrom<crate::raw::Program>>::Error: std::fmt::Debug,
+        {
+            let mut engine = E::new_from_str(program).unwrap();
+            assert!(engine.collect_stats());
+            let mut input = b"ab".iter().copied();
+            engine
+                .run_with_io(&mut || Some(input.next().unwrap_or(0)), &mut |_| ())
+                .unwrap();
+            engine.stats().unwrap().clone()
+        }
+        let program = ",[>+++[<+>-]<.,]";
+        let raw = collect::<super::raw::Engine>(program);
+        let ir = collect::<super::ir::Engine>(program);
+        for stats in [&raw, &ir] {
+            assert_eq!(stats.inputs, 3);
+            assert_eq!(stats.outputs, 2);
+        }
+        assert_eq!(raw.instructions["add"], 2 * (3 + 3 * 2));
+        assert_eq!(raw.loop_iterations, 2 + 2 * 3);
+        // the inner loop is optimized away
+        assert_eq!(ir.loop_iterations, 2);
+        assert!(ir.total() < raw.total());
+    }
+
+    #[test]
+    fn coverage() {
+        fn never_executed<E>(program: &str) -> Vec<usize>
+        where
+            E: Engine + ProgrammableEngine,
+            E::Program: TryFrom<crate::raw::Program>,
+            <E::Program as TryFrom<crate::raw::Program>>::Error: std::fmt::Debug,
+        {
+            let mut engine = E::new_from_str(program).unwrap();
+            assert!(engine.collect_coverage());
+            engine.run_with_io(&mut || Some(0), &mut |_| ()).unwrap();
+            engine.coverage().unwrap().never_executed().collect()
+        }
+        // the loop is never entered
+        assert_eq!(never_executed::<super::raw::Engine>(",[.,]"), [2, 3, 4]);
+        assert_eq!(never_executed::<super::ir::Engine>(",[.,]"), [2, 3]);
+    }
+
+    #[test]
+    fn inspect() {
+        let program =
+            crate::raw::Program::parse_dialect("}+>++{>", crate::raw::Dialect::MultiTape).unwrap();
+        let mut engine = <super::raw::Engine>::new(program);
+        assert_eq!(engine.run().unwrap(), StopState::Halted);
+        assert_eq!(engine.tape(), 0);
+        assert_eq!(engine.pointer(), 2);
+        assert_eq!(engine.position(), [7]);
+        assert_eq!(engine.memory().as_bytes(), b"");
+        engine.reset();
+        assert_eq!(engine.step().unwrap(), State::Running);
+        assert_eq!(engine.step().unwrap(), State::Running);
+        assert_eq!(engine.tape(), 1);
+        assert_eq!(engine.memory().as_bytes(), [1]);
+    }
+
+    #[test]
+    fn run_with_io() {
+        let mut engine = super::ir::Engine::new_from_str(",[.,]").unwrap();
+        let mut input = b"abc".iter().copied();
+        let mut output = vec![];
+        let stop = engine
+            .run_with_io(&mut || input.next(), &mut |ch| output.push(ch))
+            .unwrap();
+        assert_eq!(stop, StopState::NeedInput);
+        assert_eq!(output, b"abc");
+        // resuming with the end of the input
+        let stop = engine
+            .run_with_io(&mut || Some(0), &mut |ch| output.push(ch))
+            .unwrap();
+        assert_eq!(stop, StopState::Halted);
+        assert_eq!(output, b"abc");
+    }
+
+    #[test]
+    fn boxed() {
+        let program = ",[+.,]";
+        let engines: Vec<Box<dyn Engine<Cell = u8>>> = vec![
+            Box::new(<super::raw::Engine>::new_from_str(program).unwrap()),
+            Box::new(super::ir::Engine::new_from_str(program).unwrap()),
+            Box::new(super::threaded::Engine::new_from_str(program).unwrap()),
+            Box::new(super::closure::Engine::new_from_str(program).unwrap()),
+        ];
+        for engine in engines {
+            // wrappers accept boxed engines too
+            let mut engine = super::limit::StepLimit::new(engine, 100);
+            let mut input = b"ab\0".iter().copied();
+            let mut output = vec![];
+            let stop = engine
+                .run_with_io(&mut || input.next(), &mut |ch| output.push(ch))
+                .unwrap();
+            assert_eq!(stop, StopState::Halted);
+            assert_eq!(output, b"bc");
+        }
+    }
+
+    #[test]
+    fn input_slice() {
+        let program = ",[+.,]";
+        let engines: Vec<Box<dyn Engine<Cell = u8>>> = vec![
+            Box::new(<super::raw::Engine>::new_from_str(program).unwrap()),
+            Box::new(super::ir::Engine::new_from_str(program).unwrap()),
+            Box::new(super::threaded::Engine::new_from_str(program).unwrap()),
+            Box::new(super::closure::Engine::new_from_str(program).unwrap()),
+        ];
+        for mut engine in engines {
+            assert_eq!(engine.give_input_slice(b"ab"), 2);
+            assert_eq!(engine.give_input(b'x'), Some(b'a'));
+            assert_eq!(engine.give_input_slice(b"\0"), 1);
+            let mut output = vec![];
+            let stop = engine
+                .run_with_io(&mut || None, &mut |ch| output.push(ch))
+                .unwrap();
+            assert_eq!(stop, StopState::Halted);
+            assert_eq!(output, b"yc");
+        }
+    }
+
+    #[test]
+    fn end_of_input() {
+        let program = ",[.,]";
+        for eof in [Eof::Stop, Eof::Zero] {
+            let engines: Vec<Box<dyn Engine<Cell = u8>>> = vec![
+                Box::new(
+                    <super::raw::Engine>::new_from_str(program)
+                        .unwrap()
+                        .with_eof(eof),
+                ),
+                Box::new(
+                    super::ir::Engine::new_from_str(program)
+                        .unwrap()
+                        .with_eof(eof),
+                ),
+                Box::new(
+                    super::threaded::Engine::new_from_str(program)
+                        .unwrap()
+                        .with_eof(eof),
+                ),
+                Box::new(
+                    super::closure::Engine::new_from_str(program)
+                        .unwrap()
+                        .with_eof(eof),
+                ),
+            ];
+            for mut engine in engines {
+                assert_eq!(engine.give_input_slice(b"ab"), 2);
+                assert!(engine.close_input());
+                let mut output = vec![];
+                let stop = engine
+                    .run_with_io(&mut || None, &mut |ch| output.push(ch))
+                    .unwrap();
+                let expected = match eof {
+                    Eof::Stop => StopState::EndOfInput,
+                    _ => StopState::Halted,
+                };
+                assert_eq!(stop, expected);
+                assert_eq!(output, b"ab");
+            }
+        }
+        // -1 ends the loop only after one more increment
+        let mut engine = <super::raw::Engine>::new_from_str(",+[-.,+]")
+            .unwrap()
+            .with_eof(Eof::MinusOne);
+        assert_eq!(engine.give_input_slice(b"a"), 1);
+        assert!(engine.close_input());
+        let mut output = vec![];
+        let stop = engine
+            .run_with_io(&mut || None, &mut |ch| output.push(ch))
+            .unwrap();
+        assert_eq!(stop, StopState::Halted);
+        assert_eq!(output, b"a");
+    }
+
+    #[test]
+    fn wide_cells() {
+        use super::cell::CellIo;
+
+        // 256 does not fit in a byte
+        let program = format!(",>{}.<.", "+".repeat(256));
+        let mut engine = <super::raw::Engine<u16>>::new_from_str(&program)
+            .unwrap()
+            .with_cell_io(CellIo::LittleEndian);
+        let mut input = [0x34, 0x12].into_iter();
+        let mut output = vec![];
+        let stop = engine
+            .run_with_io(&mut || input.next(), &mut |ch| output.push(ch))
+            .unwrap();
+        assert_eq!(stop, StopState::Halted);
+        assert_eq!(output, [0x00, 0x01, 0x34, 0x12]);
+
+        let mut engine = <super::raw::Engine<u32>>::new_from_str(",-.")
+            .unwrap()
+            .with_cell_io(CellIo::Numeric);
+        let mut input = b"100000\n".iter().copied();
+        let mut output = vec![];
+        let stop = engine
+            .run_with_io(&mut || input.next(), &mut |ch| output.push(ch))
+            .unwrap();
+        assert_eq!(stop, StopState::Halted);
+        assert_eq!(output, b"99999\n");
+        // the end of the input terminates the number
+        let mut engine = <super::raw::Engine<u16>>::new_from_str(",.")
+            .unwrap()
+            .with_cell_io(CellIo::Numeric);
+        assert_eq!(engine.give_input_slice(b"12"), 2);
+        assert!(engine.close_input());
+        let mut output = vec![];
+        let stop = engine
+            .run_with_io(&mut || None, &mut |ch| output.push(ch))
+            .unwrap();
+        assert_eq!(stop, StopState::Halted);
+        assert_eq!(output, b"12\n");
+    }
+
+    #[test]
+    fn overflow() {
+        use super::{cell::Overflow, RTError};
+
+        fn output(mut engine: impl Engine) -> Result<Vec<u8>, RTError> {
+            let mut output = vec![];
+            engine
+                .run_with_io(&mut || None, &mut |ch| output.push(ch))
+                .map(|_| output)
+        }
+        fn check(program: &str, overflow: Overflow) -> Result<Vec<u8>, RTError> {
+            let src: crate::raw::Program = program.parse().unwrap();
+            let raw = output(<super::raw::Engine>::new(src.clone()).with_overflow(overflow));
+            let ir = crate::ir::Program::from_raw_with_overflow(src, overflow, true);
+            let ir = output(super::ir::Engine::new(ir).with_overflow(overflow));
+            assert_eq!(raw, ir);
+            raw
+        }
+        assert_eq!(check("-.+.", Overflow::Wrap), Ok(vec![255, 0]));
+        assert_eq!(check("-.+.", Overflow::Saturate), Ok(vec![0, 1]));
+        assert_eq!(check("+.--.", Overflow::Trap), Err(RTError::CellOverflow));
+        // the last instruction traps, so it cannot be trimmed
+        assert_eq!(check("+-.-", Overflow::Trap), Err(RTError::CellOverflow));
+        // the loop cannot be turned into a multiplication
+        assert_eq!(check("+[>+<+]>.", Overflow::Wrap), Ok(vec![255]));
+        assert_eq!(
+            check("+[>+<+]>.", Overflow::Trap),
+            Err(RTError::CellOverflow)
+        );
+    }
+
+    #[test]
+    fn bidirectional() {
+        use super::{mem::TapeMode, RTError};
+
+        let program = "<+<++>>+<<.>.>.";
+        let mut raw = <super::raw::Engine>::new_from_str(program)
+            .unwrap()
+            .with_tape_mode(TapeMode::Bidirectional);
+        let mut ir = super::ir::Engine::new_from_str(program)
+            .unwrap()
+            .with_tape_mode(TapeMode::Bidirectional);
+        for _ in 0..3 {
+            assert_eq!(raw.run(), ir.run());
+        }
+        assert_eq!(raw.run().unwrap(), StopState::Halted);
+        assert_eq!(raw.memory().negative_slice(), [1, 2]);
+        // the cells before the first one survive a snapshot
+        let snapshot: super::raw::Engine =
+            serde_json::from_str(&serde_json::to_string(&raw).unwrap()).unwrap();
+        assert_eq!(snapshot, raw);
+
+        let mut raw = <super::raw::Engine>::new_from_str(program).unwrap();
+        assert_eq!(raw.run(), Err(RTError::MemNegativeOut));
+    }
+
+    #[test]
+    fn fixed_tape() {
+        use super::{cell::Overflow, mem::TapeMode, RTError};
+
+        // the cell after the last is the first one
+        let program = ">>>+<<<.";
+        // the optimizer assumes an unbounded tape, that starts zeroed
+        let optimized = || {
+            super::ir::Engine::new(crate::ir::Program::from_raw_with_overflow(
+                program.parse().unwrap(),
+                Overflow::Wrap,
+                false,
+            ))
+        };
+        let wrapping = TapeMode::Fixed { len: 3, wrap: true };
+        let mut raw = <super::raw::Engine>::new_from_str(program)
+            .unwrap()
+            .with_tape_mode(wrapping);
+        let mut ir = optimized().with_tape_mode(wrapping);
+        assert_eq!(raw.run().unwrap(), StopState::HasOutput(1));
+        assert_eq!(ir.run().unwrap(), StopState::HasOutput(1));
+
+        let bounded = TapeMode::Fixed {
+            len: 3,
+            wrap: false,
+        };
+        let mut raw = <super::raw::Engine>::new_from_str(program)
+            .unwrap()
+            .with_tape_mode(bounded);
+        let mut ir = optimized().with_tape_mode(bounded);
+        assert_eq!(raw.run(), Err(RTError::MemPositiveOut));
+        assert_eq!(ir.run(), Err(RTError::MemPositiveOut));
+    }
+
+    #[test]
+    fn memory_limit() {
+        use super::RTError;
+
+        let program = "+[>+]";
+        let mut raw = <super::raw::Engine>::new_from_str(program)
+            .unwrap()
+            .with_memory_limit(100);
+        assert_eq!(raw.run(), Err(RTError::OutOfMemory));
+        assert_eq!(raw.memory().filled_len(), 100);
+        let mut ir = super::ir::Engine::new_from_str(program)
+            .unwrap()
+            .with_memory_limit(100);
+        assert_eq!(ir.run(), Err(RTError::OutOfMemory));
+        let mut threaded = super::threaded::Engine::new_from_str(program)
+            .unwrap()
+            .with_memory_limit(100);
+        assert_eq!(threaded.run(), Err(RTError::OutOfMemory));
+        let mut closure = super::closure::Engine::new_from_str(program)
+            .unwrap()
+            .with_memory_limit(100);
+        assert_eq!(closure.run(), Err(RTError::OutOfMemory));
+        // the compiled loop runs out of iterations and continues in the next steps
+        let mut closure = super::closure::Engine::new_from_str(program)
+            .unwrap()
+            .with_memory_limit(200_000);
+        assert_eq!(closure.run(), Err(RTError::OutOfMemory));
+        assert_eq!(closure.memory().filled_len(), 200_000);
+    }
+
+    #[test]
+    fn endless_compiled_loop() {
+        let mut closure = super::closure::Engine::new_from_str("+[[.[+>+]]]").unwrap();
+        assert_eq!(
+            closure.step_n(100).unwrap().0,
+            State::Stopped(StopState::HasOutput(1))
+        );
+        assert_eq!(closure.step_n(10).unwrap(), (State::Running, 10));
+    }
+}
@@ -0,0 +1,154 @@
+//! Chaining engines, each reading the output of the previous one
+//!
+//! The stages are driven on demand: a stage runs only when the next one needs input, so no
+//! output is ever buffered between them.
+
+use thiserror::Error;
+
+use super::{Engine, RTError, StopState};
+
+/// Runtime error in one of the stages of a pipeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("Runtime error in stage {stage}")]
+pub struct PipelineError {
+    /// Index of the failed stage
+    pub stage: usize,
+    #[source]
+    pub error: RTError,
+}
+
+/// Engines connected so that the output of each one is the input of the next
+#[derive(Debug, Clone)]
+pub struct Pipeline<E> {
+    stages: Vec<E>,
+    /// Input given to a stage after the previous one halted
+    eof: u8,
+}
+
+/// What a stage can read
+enum Pulled {
+    Byte(u8),
+    /// The pipeline must stop before the stage can read
+    Stop(StopState),
+}
+
+impl<E: Engine> Pipeline<E> {
+    /// Chain the stages, in order
+    ///
+    /// # Panics
+    /// If there are no stages
+    pub fn new(stages: Vec<E>) -> Self {
+        assert!(!stages.is_empty(), "A pipeline needs at least a stage");
+        Self { stages, eof: 0 }
+    }
+
+    /// Set the byte read by a stage after the previous one halted. Defaults to 0
+    pub fn with_eof(mut self, eof: u8) -> Self {
+        self.eof = eof;
+        self
+    }
+
+    pub fn stages(&self) -> &[E] {
+        &self.stages
+    }
+
+    pub fn stages_mut(&mut self) -> &mut [E] {
+        &mut self.stages
+    }
+
+    /// Remove the connections, returning the stages
+    pub fn into_inner(self) -> Vec<E> {
+        self.stages
+    }
+
+    /// Run the pipeline, feeding `input` to the first stage and passing the output of the last
+    /// one to `output`
+    ///
+    /// Stops when the last stage halts, or when any stage diverges, reaches a breakpoint or reads
+    /// past its closed input. If `input` returns `None`, stops with [`StopState::NeedInput`], and can be
+    /// resumed later.
+    pub fn run_with_io(
+        &mut self,
+        input: &mut impl FnMut() -> Option<u8>,
+        output: &mut impl FnMut(u8),
+    ) -> Result<StopState, PipelineError> {
+        let last = self.stages.len() - 1;
+        loop {
+            match self.run_stage(last)? {
+                StopState::Halted => return Ok(StopState::Halted),
+                StopState::Breakpoint => return Ok(StopState::Breakpoint),
+                StopState::EndOfInput => return Ok(StopState::EndOfInput),
+                StopState::Diverged => return Ok(StopState::Diverged),
+                StopState::HasOutput(ch) => output(ch),
+                StopState::NeedInput => match self.pull(last, input)? {
+                    Pulled::Byte(ch) => {
+                        self.stages[last].give_input(ch);
+                    }
+                    Pulled::Stop(stop) => return Ok(stop),
+                },
+            }
+        }
+    }
+
+    fn run_stage(&mut self, stage: usize) -> Result<StopState, PipelineError> {
+        self.stages[stage]
+            .run()
+            .map_err(|error| PipelineError { stage, error })
+    }
+
+    /// Get the next input of `stage`, running the previous ones as needed
+    fn pull(
+        &mut self,
+        stage: usize,
+        input: &mut impl FnMut() -> Option<u8>,
+    ) -> Result<Pulled, PipelineError> {
+        let Some(prev) = stage.checked_sub(1) else {
+            return Ok(match input() {
+                Some(ch) => Pulled::Byte(ch),
+                None => Pulled::Stop(StopState::NeedInput),
+            });
+        };
+        loop {
+            match self.run_stage(prev)? {
+                StopState::HasOutput(ch) => return Ok(Pulled::Byte(ch)),
+                StopState::Halted => return Ok(Pulled::Byte(self.eof)),
+                StopState::Breakpoint => return Ok(Pulled::Stop(StopState::Breakpoint)),
+                StopState::EndOfInput => return Ok(Pulled::Stop(StopState::EndOfInput)),
+                // the stage will never produce its input
+                StopState::Diverged => return Ok(Pulled::Stop(StopState::Diverged)),
+                StopState::NeedInput => match self.pull(prev, input)? {
+                    Pulled::Byte(ch) => {
+                        self.stages[prev].give_input(ch);
+                    }
+                    stop @ Pulled::Stop(_) => return Ok(stop),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pipeline;
+    use crate::engine::{ProgrammableEngine, StopState};
+
+    #[test]
+    fn chain() {
+        // each stage increments the bytes, until a zero
+        let stage = || crate::engine::ir::Engine::new_from_str(",[+.,]").unwrap();
+        let mut pipeline = Pipeline::new(vec![stage(), stage()]);
+        let mut input = b"abc".iter().copied();
+        let mut output = vec![];
+        let stop = pipeline
+            .run_with_io(&mut || input.next(), &mut |ch| output.push(ch))
+            .unwrap();
+        assert_eq!(stop, StopState::NeedInput);
+        assert_eq!(output, b"cde");
+        // the second stage reads the end of input after the first one halts
+        let stop = pipeline
+            .run_with_io(&mut || Some(0), &mut |ch| output.push(ch))
+            .unwrap();
+        assert_eq!(stop, StopState::Halted);
+        assert_eq!(output, b"cde");
+    }
+}
@@ -2,59 +2,180 @@
 //!
 //! This is used as baseline, and to check outputs
 
+use std::{borrow::Cow, collections::VecDeque, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
 use crate::raw;
 
-use super::{mem::Memory, ProgrammableEngine, RTError, State, StopState};
+use super::{
+    cell::{Cell, CellIo, Overflow},
+    coverage::Coverage,
+    history::{Delta, Reversible},
+    mem::{Hooks, Memory, TapeMode},
+    stats::Stats,
+    Eof, ProgrammableEngine, RTError, State, StopState,
+};
 
 /// Unoptimized engine running raw brainfuck
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct Engine {
-    program: raw::Program,
+///
+/// This is the only engine that can run with cells wider than a byte.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Engine<C: Cell = u8> {
+    /// Program, shared between the clones of the engine
+    program: Arc<raw::Program>,
+    /// Position of the matching bracket, for each bracket in the program
+    jumps: Arc<[usize]>,
     ip: usize,
-    mem: super::mem::Memory,
+    /// One memory for each tape. Tapes are added when first selected
+    tapes: Vec<Memory<C>>,
+    /// Selected tape
+    tape: usize,
     mp: isize,
-    input: Option<u8>,
+    /// Input received and not yet read
+    input: VecDeque<u8>,
+    /// No more input will be received
+    input_closed: bool,
+    /// What `,` does after the input was closed
+    eof: Eof,
+    /// Conversion between the cells and the input and output bytes
+    io: CellIo,
+    /// What `+` and `-` do on overflow
+    overflow: Overflow,
+    /// What happens before the first cell
+    tape_mode: TapeMode,
+    /// Maximum number of cells of each tape
+    memory_limit: usize,
+    /// Output bytes still to be emitted
+    pending_output: VecDeque<u8>,
+    /// Input bytes received for a cell that is not yet complete
+    partial_input: Vec<u8>,
+    /// Statistics, if they are being collected
+    stats: Option<Stats>,
+    /// Executed instructions, if they are being recorded
+    coverage: Option<Coverage>,
+    /// Host code mapped to the cells of the first tape
+    #[serde(skip)]
+    hooks: Hooks<C>,
 }
-impl Engine {
+impl<C: Cell> Engine<C> {
+    /// Set how the cells are converted to and from the input and output bytes
+    pub fn with_cell_io(mut self, io: CellIo) -> Self {
+        self.io = io;
+        self
+    }
+
+    /// Set what `+` and `-` do on overflow
+    pub fn with_overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Set what happens when the pointer moves before the first cell
+    pub fn with_tape_mode(mut self, tape_mode: TapeMode) -> Self {
+        self.tape_mode = tape_mode;
+        self
+    }
+
+    /// Fail with [`RTError::OutOfMemory`] instead of growing a tape past `limit` cells
+    pub fn with_memory_limit(mut self, limit: usize) -> Self {
+        self.memory_limit = limit;
+        self
+    }
+
+    /// Set what `,` does after the input was closed
+    pub fn with_eof(mut self, eof: Eof) -> Self {
+        self.eof = eof;
+        self
+    }
+
+    /// Map host code to the cells of the first tape
+    ///
+    /// The hooks are called each time the program reads or writes the cells
+    pub fn with_hooks(mut self, hooks: Hooks<C>) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Value stored in the current cell, without calling its hook
+    #[inline]
+    fn stored_mem_curr(&self) -> Result<&C, RTError> {
+        Ok(self.tapes[self.tape].get_signed(self.tape_mode.resolve(self.mp)?))
+    }
     #[inline]
-    #[must_use]
-    fn get_mem_curr(&self) -> Result<&u8, RTError> {
-        if self.mp < 0 {
-            Err(RTError::MemNegativeOut)
+    fn get_mem_curr(&self) -> Result<Cow<'_, C>, RTError> {
+        let pos = self.tape_mode.resolve(self.mp)?;
+        Ok(if self.tape == 0 {
+            self.hooks.read(&self.tapes[0], pos)
         } else {
-            Ok(self.mem.get(self.mp as usize))
-        }
+            Cow::Borrowed(self.tapes[self.tape].get_signed(pos))
+        })
     }
     #[inline]
-    #[must_use]
-    fn set_mem_curr(&mut self, value: u8) -> Result<(), RTError> {
-        if self.mp < 0 {
-            Err(RTError::MemNegativeOut)
+    fn set_mem_curr(&mut self, value: C) -> Result<(), RTError> {
+        let pos = self.tape_mode.resolve(self.mp)?;
+        if self.tape == 0 && !self.hooks.is_empty() {
+            self.tapes[0].set_limited(pos, value.clone(), self.memory_limit)?;
+            self.hooks.write(pos, &value);
+            Ok(())
         } else {
-            Ok(self.mem.set(self.mp as usize, value))
+            self.tapes[self.tape].set_limited(pos, value, self.memory_limit)
         }
     }
 }
 
-impl ProgrammableEngine for Engine {
+impl<C: Cell> ProgrammableEngine for Engine<C> {
     type Program = crate::raw::Program;
 
     fn new(program: Self::Program) -> Self
     where
         Self: Sized,
     {
+        let mut jumps = vec![0; program.len()];
+        let mut open = vec![];
+        for (pos, instr) in program.iter().enumerate() {
+            match instr {
+                raw::Instruction::OpenLoop => open.push(pos),
+                raw::Instruction::CloseLoop => {
+                    let start = open
+                        .pop()
+                        .expect("The parsed programs should have matched parentheses");
+                    jumps[start] = pos;
+                    jumps[pos] = start;
+                }
+                _ => (),
+            }
+        }
         Self {
-            program,
+            program: Arc::new(program),
+            jumps: jumps.into(),
             ip: 0,
-            mem: Memory::new(),
+            tapes: vec![Memory::new()],
+            tape: 0,
             mp: 0,
-            input: None,
+            input: VecDeque::new(),
+            input_closed: false,
+            eof: Eof::default(),
+            io: CellIo::default(),
+            overflow: Overflow::default(),
+            tape_mode: TapeMode::default(),
+            memory_limit: usize::MAX,
+            hooks: Hooks::new(),
+            pending_output: VecDeque::new(),
+            partial_input: vec![],
+            stats: None,
+            coverage: None,
         }
     }
 }
 
-impl super::Engine for Engine {
-    fn step(&mut self) -> Result<State, RTError> {
+impl<C: Cell> Engine<C> {
+    /// Execute the next instruction
+    fn execute(&mut self) -> Result<State, RTError> {
+        if let Some(out) = self.pending_output.pop_front() {
+            return Ok(State::Stopped(StopState::HasOutput(out)));
+        }
         if self.ip == self.program.len() {
             return Ok(State::Stopped(StopState::Halted));
         }
@@ -70,57 +191,85 @@ impl super::Engine for Engine {
                 State::Running
             }
             raw::Instruction::Add => {
-                self.set_mem_curr(self.get_mem_curr()?.wrapping_add(1))?;
+                let value = self
+                    .overflow
+                    .add(&*self.get_mem_curr()?, &C::from_u64(1))
+                    .ok_or(RTError::CellOverflow)?;
+                self.set_mem_curr(value)?;
                 self.ip += 1;
                 State::Running
             }
             raw::Instruction::Sub => {
-                self.set_mem_curr(self.get_mem_curr()?.wrapping_sub(1))?;
+                let value = self
+                    .overflow
+                    .sub(&*self.get_mem_curr()?, &C::from_u64(1))
+                    .ok_or(RTError::CellOverflow)?;
+                self.set_mem_curr(value)?;
                 self.ip += 1;
                 State::Running
             }
             raw::Instruction::Output => {
-                let out = *self.get_mem_curr()?;
+                self.pending_output = self.io.encode(&*self.get_mem_curr()?).into();
                 self.ip += 1;
+                let out = self
+                    .pending_output
+                    .pop_front()
+                    .expect("Each cell is encoded in at least a byte");
                 State::Stopped(StopState::HasOutput(out))
             }
-            raw::Instruction::Input => match self.input.take() {
+            raw::Instruction::Input => match self.input.pop_front() {
                 Some(input) => {
-                    self.set_mem_curr(input)?;
-                    self.ip += 1;
+                    // the instruction is completed only when the whole cell is received
+                    if let Some(cell) = self.io.decode(&mut self.partial_input, input) {
+                        self.set_mem_curr(cell)?;
+                        self.ip += 1;
+                    }
                     State::Running
                 }
+                None if self.input_closed => {
+                    // a number still pending is ended by the end of the input
+                    let value = match self.io.finish(&mut self.partial_input) {
+                        Some(cell) => Some(cell),
+                        None => self.eof.value(&*self.get_mem_curr()?),
+                    };
+                    match value {
+                        Some(cell) => {
+                            self.partial_input.clear();
+                            self.set_mem_curr(cell)?;
+                            self.ip += 1;
+                            State::Running
+                        }
+                        None => State::Stopped(StopState::EndOfInput),
+                    }
+                }
                 None => State::Stopped(StopState::NeedInput),
             },
+            raw::Instruction::PrevTape => {
+                self.tape = self.tape.checked_sub(1).ok_or(RTError::TapeNegativeOut)?;
+                self.ip += 1;
+                State::Running
+            }
+            raw::Instruction::NextTape => {
+                self.tape += 1;
+                if self.tape == self.tapes.len() {
+                    self.tapes.push(Memory::new())
+                }
+                self.ip += 1;
+                State::Running
+            }
             raw::Instruction::OpenLoop => {
-                if *self.get_mem_curr()? == 0 {
-                    let mut count = 1usize;
+                if *self.get_mem_curr()? == *C::ZERO {
                     // go to the matching ]
-                    while count > 0 {
-                        self.ip += 1;
-                        match self.program[self.ip] {
-                            raw::Instruction::OpenLoop => count += 1,
-                            raw::Instruction::CloseLoop => count -= 1,
-                            _ => (),
-                        }
-                    }
+                    self.ip = self.jumps[self.ip];
                 }
                 // jump the [/]
                 self.ip += 1;
                 State::Running
             }
             raw::Instruction::CloseLoop => {
-                if *self.get_mem_curr()? != 0 {
-                    let mut count = 1usize;
+                if *self.get_mem_curr()? != *C::ZERO {
                     // go to the matching [
-                    while count > 0 {
-                        self.ip -= 1;
-                        match self.program[self.ip] {
-                            raw::Instruction::OpenLoop => count -= 1,
-                            raw::Instruction::CloseLoop => count += 1,
-                            _ => (),
-                        }
-                    }
+                    self.ip = self.jumps[self.ip];
                 }
                 // jump the [/]
                 self.ip += 1;
@@ -128,22 +277,183 @@ impl super::Engine for Engine {
             }
         })
     }
+}
+
+/// Kind of an instruction, as counted in the statistics
+fn kind(instr: raw::Instruction) -> &'static str {
+    match instr {
+        raw::Instruction::ShiftRight | raw::Instruction::ShiftLeft => "shift",
+        raw::Instruction::Add | raw::Instruction::Sub => "add",
+        raw::Instruction::Output => "output",
+        raw::Instruction::Input => "input",
+        raw::Instruction::OpenLoop | raw::Instruction::CloseLoop => "loop",
+        raw::Instruction::PrevTape | raw::Instruction::NextTape => "switch-tape",
+    }
+}
+
+impl<C: Cell> super::Engine for Engine<C> {
+    type Cell = C;
+
+    fn step(&mut self) -> Result<State, RTError> {
+        if self.stats.is_none() && self.coverage.is_none() {
+            return self.execute();
+        }
+        let ip = self.ip;
+        let Some(&instr) = self.program.iter().nth(ip) else {
+            return self.execute();
+        };
+        let state = self.execute()?;
+        if state == State::Stopped(StopState::NeedInput) {
+            return Ok(state);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.mark(ip)
+        }
+        let entered = matches!(
+            instr,
+            raw::Instruction::OpenLoop | raw::Instruction::CloseLoop
+        ) && self.stored_mem_curr()? != C::ZERO;
+        if let Some(stats) = &mut self.stats {
+            match state {
+                State::Stopped(StopState::HasOutput(_)) => stats.outputs += 1,
+                _ if instr == raw::Instruction::Input => stats.inputs += 1,
+                _ => (),
+            }
+            if entered {
+                stats.loop_iterations += 1
+            }
+            stats.record(kind(instr));
+        }
+        Ok(state)
+    }
 
     fn input(&self) -> Option<u8> {
-        self.input
+        self.input.front().copied()
     }
 
     fn give_input(&mut self, input: u8) -> Option<u8> {
-        self.input.replace(input)
+        super::replace_input(&mut self.input, input)
     }
 
     fn try_give_input(&mut self, input: u8) -> Result<(), u8> {
-        match self.input {
-            Some(input) => Err(input),
-            None => {
-                self.input = Some(input);
-                Ok(())
+        super::try_give_input(&mut self.input, input)
+    }
+
+    fn give_input_slice(&mut self, input: &[u8]) -> usize {
+        self.input.extend(input);
+        input.len()
+    }
+
+    fn close_input(&mut self) -> bool {
+        self.input_closed = true;
+        true
+    }
+
+    fn memory(&self) -> &Memory<C> {
+        &self.tapes[self.tape]
+    }
+
+    fn memory_mut(&mut self) -> &mut Memory<C> {
+        &mut self.tapes[self.tape]
+    }
+
+    fn tape(&self) -> usize {
+        self.tape
+    }
+
+    fn pointer(&self) -> isize {
+        self.mp
+    }
+
+    fn set_pointer(&mut self, pointer: isize) {
+        self.mp = pointer
+    }
+
+    fn position(&self) -> Vec<usize> {
+        vec![self.ip]
+    }
+
+    fn instruction(&self) -> Option<String> {
+        self.program
+            .iter()
+            .nth(self.ip)
+            .map(|&instr| (instr as u8 as char).to_string())
+    }
+
+    fn reset(&mut self) {
+        self.ip = 0;
+        self.tapes = vec![Memory::new()];
+        self.tape = 0;
+        self.mp = 0;
+        self.input.clear();
+        self.input_closed = false;
+        self.pending_output.clear();
+        self.partial_input.clear();
+        if let Some(stats) = &mut self.stats {
+            *stats = Stats::default()
+        }
+        if let Some(coverage) = &mut self.coverage {
+            *coverage = Coverage::new(coverage.len())
+        }
+    }
+
+    fn collect_stats(&mut self) -> bool {
+        self.stats.get_or_insert_with(Stats::default);
+        true
+    }
+
+    fn stats(&self) -> Option<&Stats> {
+        self.stats.as_ref()
+    }
+
+    fn collect_coverage(&mut self) -> bool {
+        let len = self.program.len();
+        self.coverage.get_or_insert_with(|| Coverage::new(len));
+        true
+    }
+
+    fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+}
+
+impl<C: Cell> Reversible for Engine<C> {
+    fn step_reversible(&mut self) -> Result<(State, Delta<C>), RTError> {
+        let writes = match self.program.iter().nth(self.ip) {
+            Some(raw::Instruction::Add | raw::Instruction::Sub | raw::Instruction::Input) => {
+                vec![(
+                    self.tape_mode.resolve(self.mp)?,
+                    self.stored_mem_curr()?.clone(),
+                )]
             }
+            _ => vec![],
+        };
+        let delta = Delta {
+            position: vec![self.ip],
+            pointer: self.mp,
+            tape: self.tape,
+            input: None,
+            writes,
+            pending_output: self.pending_output.iter().copied().collect(),
+            partial_input: self.partial_input.clone(),
+        };
+        let (next, queued) = (self.input.front().copied(), self.input.len());
+        let state = super::Engine::step(self)?;
+        let input = (self.input.len() < queued).then_some(next).flatten();
+        Ok((state, Delta { input, ..delta }))
+    }
+
+    fn undo(&mut self, delta: Delta<C>) {
+        self.ip = delta.position[0];
+        self.mp = delta.pointer;
+        self.tape = delta.tape;
+        if let Some(input) = delta.input {
+            self.input.push_front(input)
+        }
+        self.pending_output = delta.pending_output.into();
+        self.partial_input = delta.partial_input;
+        for (cell, value) in delta.writes {
+            self.tapes[self.tape].set_signed(cell, value)
         }
     }
 }
@@ -0,0 +1,216 @@
+//! Running several engines at once, exchanging data
+//!
+//! The engines are run in turn, each for a slice of steps. The output of a machine can be linked
+//! to the input of another, so programs can talk to each other, while the unlinked inputs and
+//! outputs are exchanged with the caller.
+
+use std::collections::VecDeque;
+
+use thiserror::Error;
+
+use super::{Engine, RTError, State, StopState};
+
+/// Identifier of a machine in a [`Scheduler`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MachineId(usize);
+
+/// Runtime error in one of the machines of a scheduler
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("Runtime error in machine {}", machine.0)]
+pub struct SchedError {
+    pub machine: MachineId,
+    #[source]
+    pub error: RTError,
+}
+
+/// Why the scheduler stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Status {
+    /// All the machines halted
+    Halted,
+    /// Some machines are waiting for input, and no other machine can send it
+    Blocked,
+    /// A machine reached a breakpoint
+    Breakpoint(MachineId),
+}
+
+#[derive(Debug, Clone)]
+struct Machine<E> {
+    engine: E,
+    /// Bytes sent to the machine and not yet read
+    input: VecDeque<u8>,
+    /// Where the output goes. `None` if it is kept for the caller
+    link: Option<MachineId>,
+    /// Output kept for the caller
+    output: Vec<u8>,
+    halted: bool,
+}
+
+/// Round robin scheduler of engines
+#[derive(Debug, Clone)]
+pub struct Scheduler<E> {
+    machines: Vec<Machine<E>>,
+    /// Steps given to each machine on its turn
+    fuel: u64,
+}
+
+impl<E: Engine> Scheduler<E> {
+    /// Create a scheduler that runs each machine for `fuel` steps on its turn
+    ///
+    /// # Panics
+    /// If `fuel` is zero
+    pub fn new(fuel: u64) -> Self {
+        assert!(fuel > 0, "The machines need some fuel to advance");
+        Self {
+            machines: vec![],
+            fuel,
+        }
+    }
+
+    /// Add a machine. Its input and output are exchanged with the caller until it is linked
+    pub fn add_machine(&mut self, engine: E) -> MachineId {
+        self.machines.push(Machine {
+            engine,
+            input: VecDeque::new(),
+            link: None,
+            output: vec![],
+            halted: false,
+        });
+        MachineId(self.machines.len() - 1)
+    }
+
+    /// Send the output of `from` to the input of `to`
+    ///
+    /// Any output of `from` still kept for the caller is not sent.
+    pub fn link(&mut self, from: MachineId, to: MachineId) {
+        assert!(to.0 < self.machines.len(), "Unknown machine {}", to.0);
+        self.machines[from.0].link = Some(to)
+    }
+
+    /// Keep the output of `machine` for the caller again
+    pub fn unlink(&mut self, machine: MachineId) {
+        self.machines[machine.0].link = None
+    }
+
+    /// Send input to a machine
+    pub fn send(&mut self, machine: MachineId, input: &[u8]) {
+        self.machines[machine.0].input.extend(input)
+    }
+
+    /// Take the output that a machine emitted while not linked
+    pub fn take_output(&mut self, machine: MachineId) -> Vec<u8> {
+        std::mem::take(&mut self.machines[machine.0].output)
+    }
+
+    pub fn engine(&self, machine: MachineId) -> &E {
+        &self.machines[machine.0].engine
+    }
+
+    pub fn engine_mut(&mut self, machine: MachineId) -> &mut E {
+        &mut self.machines[machine.0].engine
+    }
+
+    /// Check if a machine halted, or read past its closed input
+    pub fn halted(&self, machine: MachineId) -> bool {
+        self.machines[machine.0].halted
+    }
+
+    /// Run the machines in turn until they all halt or wait for input
+    ///
+    /// Can be resumed after sending more input, or after a breakpoint.
+    pub fn run(&mut self) -> Result<Status, SchedError> {
+        loop {
+            let mut advanced = false;
+            for id in 0..self.machines.len() {
+                match self.run_slice(id)? {
+                    Slice::Advanced => advanced = true,
+                    Slice::Idle => (),
+                    Slice::Breakpoint => return Ok(Status::Breakpoint(MachineId(id))),
+                }
+            }
+            if !advanced {
+                return Ok(if self.machines.iter().all(|m| m.halted) {
+                    Status::Halted
+                } else {
+                    Status::Blocked
+                });
+            }
+        }
+    }
+
+    /// Run a machine for its slice of steps
+    fn run_slice(&mut self, id: usize) -> Result<Slice, SchedError> {
+        let mut fuel = self.fuel;
+        let mut advanced = false;
+        while fuel > 0 && !self.machines[id].halted {
+            let machine = &mut self.machines[id];
+            let (state, consumed) = machine.engine.step_n(fuel).map_err(|error| SchedError {
+                machine: MachineId(id),
+                error,
+            })?;
+            fuel -= consumed;
+            advanced |= consumed > 0;
+            match state {
+                State::Running => (),
+                State::Stopped(StopState::HasOutput(ch)) => match machine.link {
+                    Some(to) => self.machines[to.0].input.push_back(ch),
+                    None => self.machines[id].output.push(ch),
+                },
+                State::Stopped(StopState::NeedInput) => {
+                    let taken = machine
+                        .engine
+                        .give_input_slice(machine.input.make_contiguous());
+                    if taken == 0 {
+                        break;
+                    }
+                    machine.input.drain(..taken);
+                }
+                State::Stopped(StopState::Halted | StopState::EndOfInput | StopState::Diverged) => {
+                    machine.halted = true
+                }
+                State::Stopped(StopState::Breakpoint) => return Ok(Slice::Breakpoint),
+            }
+        }
+        Ok(if advanced {
+            Slice::Advanced
+        } else {
+            Slice::Idle
+        })
+    }
+}
+
+/// What a machine did in its slice
+enum Slice {
+    Advanced,
+    Idle,
+    Breakpoint,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Scheduler, Status};
+    use crate::engine::{Engine, ProgrammableEngine};
+
+    #[test]
+    fn ping_pong() {
+        // doubles each byte, until a zero
+        let doubler = || -> Box<dyn Engine<Cell = u8>> {
+            Box::new(crate::engine::ir::Engine::new_from_str(",[[->++<]>.[-]<,]").unwrap())
+        };
+        // counts down from the first byte
+        let countdown = || -> Box<dyn Engine<Cell = u8>> {
+            Box::new(<crate::engine::raw::Engine>::new_from_str(",[.-]").unwrap())
+        };
+        let mut sched = Scheduler::new(3);
+        let first = sched.add_machine(doubler());
+        let second = sched.add_machine(countdown());
+        sched.link(first, second);
+        assert_eq!(sched.run().unwrap(), Status::Blocked);
+        sched.send(first, &[2]);
+        assert_eq!(sched.run().unwrap(), Status::Blocked);
+        assert_eq!(sched.take_output(second), [4, 3, 2, 1]);
+        assert!(sched.halted(second));
+        sched.send(first, &[0]);
+        assert_eq!(sched.run().unwrap(), Status::Halted);
+    }
+}
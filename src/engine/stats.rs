@@ -0,0 +1,38 @@
+//! Execution statistics
+//!
+//! Counting what an engine actually executes allows to compare how much work the optimizer
+//! removes, running the same program on the raw and on the ir engine.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Statistics collected during a run
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Stats {
+    /// Executed instructions, by kind
+    pub instructions: BTreeMap<String, u64>,
+    /// Number of times the body of a loop was entered
+    pub loop_iterations: u64,
+    /// Bytes read
+    pub inputs: u64,
+    /// Bytes written
+    pub outputs: u64,
+}
+
+impl Stats {
+    /// Total number of executed instructions
+    pub fn total(&self) -> u64 {
+        self.instructions.values().sum()
+    }
+
+    /// Count an executed instruction
+    pub fn record(&mut self, kind: &str) {
+        match self.instructions.get_mut(kind) {
+            Some(count) => *count += 1,
+            None => {
+                self.instructions.insert(kind.to_owned(), 1);
+            }
+        }
+    }
+}
@@ -0,0 +1,374 @@
+//! Engine running ir brainfuck with direct threaded dispatch
+//!
+//! The program is decoded once into a flat list of instructions, each one carrying a pointer to
+//! the function executing it, so that a step is a single indirect call.
+
+use std::{collections::VecDeque, sync::Arc};
+
+use crate::ir::{
+    self, Add, Affine, Block, If, Input, Loop, Mul, Output, Print, Set, Shift, SwitchTape,
+};
+
+use super::{mem::Memory, Eof, ProgrammableEngine, RTError, State, StopState};
+
+/// Function executing an instruction
+type Handler = fn(&mut Engine, Op) -> Result<State, RTError>;
+
+/// A decoded instruction
+#[derive(Debug, Clone, Copy)]
+struct Op {
+    exec: Handler,
+    offset: isize,
+    /// Meaning depends on the handler: an amount, a byte, a jump target or an index in the
+    /// multiplications
+    arg: isize,
+}
+
+/// The decoded program
+#[derive(Debug, Default)]
+struct Code {
+    ops: Vec<Op>,
+    /// Targets of the multiplications
+    muls: Vec<Vec<(isize, Affine)>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Engine {
+    /// Decoded program, shared between the clones of the engine
+    code: Arc<Code>,
+    ip: usize,
+    /// One memory for each tape. Tapes are added when first selected
+    tapes: Vec<Memory>,
+    /// Selected tape
+    tape: usize,
+    mp: isize,
+    /// Input received and not yet read
+    input: VecDeque<u8>,
+    /// No more input will be received
+    input_closed: bool,
+    /// What `,` does after the input was closed
+    eof: Eof,
+    /// Maximum number of cells of each tape
+    memory_limit: usize,
+}
+
+impl Code {
+    fn decode(&mut self, block: &Block) {
+        for node in &block.0 {
+            match node {
+                ir::Node::Noop => (),
+                ir::Node::Shift(Shift { amount }) => self.ops.push(Op {
+                    exec: Engine::shift,
+                    offset: 0,
+                    arg: amount.get(),
+                }),
+                ir::Node::Add(Add { amount, offset }) => self.ops.push(Op {
+                    exec: Engine::add,
+                    offset: *offset,
+                    arg: amount.get() as isize,
+                }),
+                ir::Node::Output(Output { offset }) => self.ops.push(Op {
+                    exec: Engine::write,
+                    offset: *offset,
+                    arg: 0,
+                }),
+                ir::Node::Input(Input { offset }) => self.ops.push(Op {
+                    exec: Engine::read,
+                    offset: *offset,
+                    arg: 0,
+                }),
+                ir::Node::InputDiscard => self.ops.push(Op {
+                    exec: Engine::discard,
+                    offset: 0,
+                    arg: 0,
+                }),
+                ir::Node::Loop(Loop { body, offset }) => {
+                    let start = self.ops.len();
+                    self.ops.push(Op {
+                        exec: Engine::jump_if_zero,
+                        offset: *offset,
+                        arg: 0,
+                    });
+                    self.decode(body);
+                    self.ops.push(Op {
+                        exec: Engine::jump_if_nonzero,
+                        offset: *offset,
+                        arg: start as isize + 1,
+                    });
+                    self.ops[start].arg = self.ops.len() as isize;
+                }
+                ir::Node::If(If { body, offset }) => {
+                    let start = self.ops.len();
+                    self.ops.push(Op {
+                        exec: Engine::jump_if_zero,
+                        offset: *offset,
+                        arg: 0,
+                    });
+                    self.decode(body);
+                    self.ops[start].arg = self.ops.len() as isize;
+                }
+                ir::Node::Mul(Mul { offset, targets }) => {
+                    self.ops.push(Op {
+                        exec: Engine::mul,
+                        offset: *offset,
+                        arg: self.muls.len() as isize,
+                    });
+                    self.muls.push(targets.clone());
+                }
+                ir::Node::SwitchTape(SwitchTape { amount }) => self.ops.push(Op {
+                    exec: Engine::switch_tape,
+                    offset: 0,
+                    arg: amount.get(),
+                }),
+                ir::Node::Set(Set { value, offset }) => self.ops.push(Op {
+                    exec: Engine::set_value,
+                    offset: *offset,
+                    arg: *value as isize,
+                }),
+                ir::Node::Diverge => self.ops.push(Op {
+                    exec: Engine::diverge,
+                    offset: 0,
+                    arg: 0,
+                }),
+                ir::Node::Print(Print { bytes }) => self.ops.extend(bytes.iter().map(|&byte| Op {
+                    exec: Engine::print,
+                    offset: 0,
+                    arg: byte as isize,
+                })),
+            }
+        }
+    }
+}
+
+impl Engine {
+    /// Fail with [`RTError::OutOfMemory`] instead of growing a tape past `limit` cells
+    pub fn with_memory_limit(mut self, limit: usize) -> Self {
+        self.memory_limit = limit;
+        self
+    }
+
+    /// Set what `,` does after the input was closed
+    pub fn with_eof(mut self, eof: Eof) -> Self {
+        self.eof = eof;
+        self
+    }
+
+    #[inline]
+    fn get(&self, offset: isize) -> Result<u8, RTError> {
+        let mp = self.mp + offset;
+        if mp < 0 {
+            Err(RTError::MemNegativeOut)
+        } else {
+            Ok(*self.tapes[self.tape].get(mp as usize))
+        }
+    }
+
+    #[inline]
+    fn set(&mut self, offset: isize, value: u8) -> Result<(), RTError> {
+        let mp = self.mp + offset;
+        if mp < 0 {
+            Err(RTError::MemNegativeOut)
+        } else {
+            self.tapes[self.tape].set_limited(mp, value, self.memory_limit)
+        }
+    }
+
+    fn shift(&mut self, op: Op) -> Result<State, RTError> {
+        self.mp += op.arg;
+        self.ip += 1;
+        Ok(State::Running)
+    }
+
+    fn add(&mut self, op: Op) -> Result<State, RTError> {
+        self.set(op.offset, self.get(op.offset)?.wrapping_add(op.arg as u8))?;
+        self.ip += 1;
+        Ok(State::Running)
+    }
+
+    fn set_value(&mut self, op: Op) -> Result<State, RTError> {
+        self.set(op.offset, op.arg as u8)?;
+        self.ip += 1;
+        Ok(State::Running)
+    }
+
+    fn write(&mut self, op: Op) -> Result<State, RTError> {
+        let out = self.get(op.offset)?;
+        self.ip += 1;
+        Ok(State::Stopped(StopState::HasOutput(out)))
+    }
+
+    fn diverge(&mut self, _: Op) -> Result<State, RTError> {
+        Ok(State::Stopped(StopState::Diverged))
+    }
+
+    fn print(&mut self, op: Op) -> Result<State, RTError> {
+        self.ip += 1;
+        Ok(State::Stopped(StopState::HasOutput(op.arg as u8)))
+    }
+
+    fn read(&mut self, op: Op) -> Result<State, RTError> {
+        match self.input.pop_front() {
+            Some(input) => {
+                self.set(op.offset, input)?;
+                self.ip += 1;
+                Ok(State::Running)
+            }
+            None if self.input_closed => match self.eof.value(&self.get(op.offset)?) {
+                Some(value) => {
+                    self.set(op.offset, value)?;
+                    self.ip += 1;
+                    Ok(State::Running)
+                }
+                None => Ok(State::Stopped(StopState::EndOfInput)),
+            },
+            None => Ok(State::Stopped(StopState::NeedInput)),
+        }
+    }
+
+    fn discard(&mut self, _: Op) -> Result<State, RTError> {
+        match self.input.pop_front() {
+            Some(_) => (),
+            None if self.input_closed && self.eof != Eof::Stop => (),
+            None if self.input_closed => return Ok(State::Stopped(StopState::EndOfInput)),
+            None => return Ok(State::Stopped(StopState::NeedInput)),
+        }
+        self.ip += 1;
+        Ok(State::Running)
+    }
+
+    fn jump_if_zero(&mut self, op: Op) -> Result<State, RTError> {
+        if self.get(op.offset)? == 0 {
+            self.ip = op.arg as usize
+        } else {
+            self.ip += 1
+        }
+        Ok(State::Running)
+    }
+
+    fn jump_if_nonzero(&mut self, op: Op) -> Result<State, RTError> {
+        if self.get(op.offset)? != 0 {
+            self.ip = op.arg as usize
+        } else {
+            self.ip += 1
+        }
+        Ok(State::Running)
+    }
+
+    fn mul(&mut self, op: Op) -> Result<State, RTError> {
+        let n = self.get(op.offset)?;
+        if n != 0 {
+            let increments = self.code.muls[op.arg as usize]
+                .iter()
+                .map(|(target, expr)| Ok((*target, expr.eval(|o| self.get(o))?)))
+                .collect::<Result<Vec<_>, RTError>>()?;
+            for (target, increment) in increments {
+                self.set(
+                    target,
+                    self.get(target)?.wrapping_add(n.wrapping_mul(increment)),
+                )?;
+            }
+            self.set(op.offset, 0)?;
+        }
+        self.ip += 1;
+        Ok(State::Running)
+    }
+
+    fn switch_tape(&mut self, op: Op) -> Result<State, RTError> {
+        self.tape = self
+            .tape
+            .checked_add_signed(op.arg)
+            .ok_or(RTError::TapeNegativeOut)?;
+        if self.tape >= self.tapes.len() {
+            self.tapes.resize_with(self.tape + 1, Memory::new)
+        }
+        self.ip += 1;
+        Ok(State::Running)
+    }
+}
+
+impl ProgrammableEngine for Engine {
+    type Program = ir::Program;
+
+    fn new(program: Self::Program) -> Self
+    where
+        Self: Sized,
+    {
+        let mut code = Code::default();
+        code.decode(&program.0);
+        Self {
+            code: Arc::new(code),
+            ip: 0,
+            tapes: vec![Memory::new()],
+            tape: 0,
+            mp: 0,
+            input: VecDeque::new(),
+            input_closed: false,
+            eof: Eof::default(),
+            memory_limit: usize::MAX,
+        }
+    }
+}
+
+impl super::Engine for Engine {
+    fn step(&mut self) -> Result<State, RTError> {
+        match self.code.ops.get(self.ip) {
+            Some(&op) => (op.exec)(self, op),
+            None => Ok(State::Stopped(StopState::Halted)),
+        }
+    }
+
+    fn input(&self) -> Option<u8> {
+        self.input.front().copied()
+    }
+
+    fn give_input(&mut self, input: u8) -> Option<u8> {
+        super::replace_input(&mut self.input, input)
+    }
+
+    fn try_give_input(&mut self, input: u8) -> Result<(), u8> {
+        super::try_give_input(&mut self.input, input)
+    }
+
+    fn give_input_slice(&mut self, input: &[u8]) -> usize {
+        self.input.extend(input);
+        input.len()
+    }
+
+    fn close_input(&mut self) -> bool {
+        self.input_closed = true;
+        true
+    }
+
+    fn memory(&self) -> &Memory {
+        &self.tapes[self.tape]
+    }
+
+    fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.tapes[self.tape]
+    }
+
+    fn tape(&self) -> usize {
+        self.tape
+    }
+
+    fn pointer(&self) -> isize {
+        self.mp
+    }
+
+    fn set_pointer(&mut self, pointer: isize) {
+        self.mp = pointer
+    }
+
+    fn position(&self) -> Vec<usize> {
+        vec![self.ip]
+    }
+
+    fn reset(&mut self) {
+        self.ip = 0;
+        self.tapes = vec![Memory::new()];
+        self.tape = 0;
+        self.mp = 0;
+        self.input.clear();
+        self.input_closed = false;
+    }
+}
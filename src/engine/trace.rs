@@ -0,0 +1,215 @@
+//! Tracing the instructions executed by an engine
+//!
+//! The tracing is done by a wrapper, so engines that are not traced pay nothing for it.
+
+use super::{
+    breakpoint::Breakpoint, cell::Cell, coverage::Coverage, mem::Memory, stats::Stats, Engine,
+    RTError, State, StopState,
+};
+
+/// An executed instruction
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TraceEvent<C = u8> {
+    /// Number of the step, counting from the start of the tracing
+    pub step: u64,
+    /// Position of the instruction in the program
+    pub position: Vec<usize>,
+    /// Human readable form of the instruction, if the engine can describe it
+    pub instruction: Option<String>,
+    /// Position of the memory pointer before the instruction
+    pub pointer: isize,
+    /// Value of the pointed cell before the instruction. `None` if the pointer was out of the memory
+    pub before: Option<C>,
+    /// Value of the same cell after the instruction
+    pub after: Option<C>,
+}
+
+/// Engine that calls a function for each executed instruction
+///
+/// Steps that halt, ask for input or stop at a breakpoint do not execute anything, so they are
+/// not traced.
+#[derive(Debug, Clone)]
+pub struct Trace<E, F> {
+    engine: E,
+    callback: F,
+    step: u64,
+}
+
+impl<E, F> Trace<E, F>
+where
+    E: Engine,
+    F: FnMut(&TraceEvent<E::Cell>),
+{
+    /// Trace the engine, passing each event to `callback`
+    pub fn new(engine: E, callback: F) -> Self {
+        Self {
+            engine,
+            callback,
+            step: 0,
+        }
+    }
+
+    /// Stop tracing, returning the engine
+    pub fn into_inner(self) -> E {
+        self.engine
+    }
+}
+
+/// Value of the cell at `pointer`, if it is in the memory
+fn cell<C: Cell>(memory: &Memory<C>, pointer: isize) -> Option<C> {
+    (pointer >= 0).then(|| memory.get(pointer as usize).clone())
+}
+
+impl<E, F> Engine for Trace<E, F>
+where
+    E: Engine,
+    F: FnMut(&TraceEvent<E::Cell>),
+{
+    type Cell = E::Cell;
+
+    fn step(&mut self) -> Result<State, RTError> {
+        let position = self.engine.position();
+        let instruction = self.engine.instruction();
+        let pointer = self.engine.pointer();
+        let before = cell(self.engine.memory(), pointer);
+        let state = self.engine.step()?;
+        if matches!(
+            state,
+            State::Stopped(
+                StopState::Halted
+                    | StopState::NeedInput
+                    | StopState::Breakpoint
+                    | StopState::EndOfInput
+            )
+        ) {
+            return Ok(state);
+        }
+        (self.callback)(&TraceEvent {
+            step: self.step,
+            position,
+            instruction,
+            pointer,
+            before,
+            after: cell(self.engine.memory(), pointer),
+        });
+        self.step += 1;
+        Ok(state)
+    }
+
+    fn input(&self) -> Option<u8> {
+        self.engine.input()
+    }
+
+    fn give_input(&mut self, input: u8) -> Option<u8> {
+        self.engine.give_input(input)
+    }
+
+    fn try_give_input(&mut self, input: u8) -> Result<(), u8> {
+        self.engine.try_give_input(input)
+    }
+
+    fn give_input_slice(&mut self, input: &[u8]) -> usize {
+        self.engine.give_input_slice(input)
+    }
+
+    fn close_input(&mut self) -> bool {
+        self.engine.close_input()
+    }
+
+    fn memory(&self) -> &Memory<E::Cell> {
+        self.engine.memory()
+    }
+
+    fn memory_mut(&mut self) -> &mut Memory<E::Cell> {
+        self.engine.memory_mut()
+    }
+
+    fn tape(&self) -> usize {
+        self.engine.tape()
+    }
+
+    fn pointer(&self) -> isize {
+        self.engine.pointer()
+    }
+
+    fn set_pointer(&mut self, pointer: isize) {
+        self.engine.set_pointer(pointer)
+    }
+
+    fn position(&self) -> Vec<usize> {
+        self.engine.position()
+    }
+
+    fn instruction(&self) -> Option<String> {
+        self.engine.instruction()
+    }
+
+    /// Reset the engine, restarting the count of the steps
+    fn reset(&mut self) {
+        self.engine.reset();
+        self.step = 0;
+    }
+
+    fn collect_stats(&mut self) -> bool {
+        self.engine.collect_stats()
+    }
+
+    fn stats(&self) -> Option<&Stats> {
+        self.engine.stats()
+    }
+
+    fn collect_coverage(&mut self) -> bool {
+        self.engine.collect_coverage()
+    }
+
+    fn coverage(&self) -> Option<&Coverage> {
+        self.engine.coverage()
+    }
+
+    fn set_breakpoint(&mut self, breakpoint: Breakpoint) -> bool {
+        self.engine.set_breakpoint(breakpoint)
+    }
+
+    fn clear_breakpoint(&mut self, breakpoint: &Breakpoint) -> bool {
+        self.engine.clear_breakpoint(breakpoint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Trace, TraceEvent};
+    use crate::engine::{Engine, ProgrammableEngine, StopState};
+
+    #[test]
+    fn trace() {
+        let mut events = vec![];
+        let engine = <crate::engine::raw::Engine>::new_from_str("+>+<-.").unwrap();
+        let mut engine = Trace::new(engine, |event: &TraceEvent| events.push(event.clone()));
+        assert_eq!(engine.run().unwrap(), StopState::HasOutput(0));
+        assert_eq!(engine.run().unwrap(), StopState::Halted);
+        drop(engine);
+        let summary: Vec<_> = events
+            .iter()
+            .map(|e| {
+                (
+                    e.step,
+                    e.instruction.as_deref().unwrap(),
+                    e.pointer,
+                    e.before.unwrap(),
+                    e.after.unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (0, "+", 0, 0, 1),
+                (1, ">", 0, 1, 1),
+                (2, "+", 1, 0, 1),
+                (3, "<", 1, 1, 1),
+                (4, "-", 0, 1, 0),
+                (5, ".", 0, 0, 0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,248 @@
+//! Notes attached to the nodes of the ir
+//!
+//! The [`Annotations`] of a program give to some of its nodes, identified by their path, a set of
+//! notes: labels, where the code comes from, or the comments of the source. The optimizer keeps
+//! them aligned as it keeps the [`SourceMap`](super::SourceMap): the nodes left untouched keep
+//! their notes, and the new nodes gather the notes of all the nodes they replaced. A node that is
+//! moved past an unchanged one is taken as removed and inserted again, so it loses its notes.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::raw::{Dialect, Instruction};
+
+use super::{
+    source_map::{Follow, Merge, NodeMap},
+    Block,
+};
+
+/// A note on a node
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Annotation {
+    /// A name given to the node
+    Label(String),
+    /// Where the node comes from, as a file or a library
+    Provenance(String),
+    /// A comment in the source, before the instruction the node was made from
+    Comment(String),
+}
+
+/// Notes of the nodes of a program, by the path of the node
+///
+/// The paths are the ones returned by [`crate::engine::Engine::position`]. In the files they are
+/// written with the indices separated by dots, as `3.0.1`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+#[serde(
+    into = "BTreeMap<String, BTreeSet<Annotation>>",
+    try_from = "BTreeMap<String, BTreeSet<Annotation>>"
+)]
+pub struct Annotations(BTreeMap<Vec<usize>, BTreeSet<Annotation>>);
+
+/// A path in the annotations that is not made of indices
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Error)]
+#[error("Invalid path of a node `{0}`")]
+pub struct InvalidPath(String);
+
+impl Annotations {
+    /// Annotations with the comments of a source, for the program translated without
+    /// optimizations
+    ///
+    /// The text between two instructions is the comment of the node made from the second one. A
+    /// comment before the `]` closing a loop is given to the loop, and the text after the last
+    /// instruction is dropped.
+    pub fn of_source(source: &str, dialect: Dialect) -> Annotations {
+        let mut annotations = Annotations::default();
+        // path of the next node, and the start of the loops it is in
+        let mut path = vec![0];
+        let mut comment = String::new();
+        for ch in source.chars() {
+            let Some(instr) = Instruction::parse(ch, dialect) else {
+                comment.push(ch);
+                continue;
+            };
+            if instr == Instruction::CloseLoop && path.len() > 1 {
+                path.pop();
+            }
+            let text = comment.split_whitespace().collect::<Vec<_>>().join(" ");
+            if !text.is_empty() {
+                annotations.annotate(&path, Annotation::Comment(text))
+            }
+            comment.clear();
+            match instr {
+                Instruction::OpenLoop => path.push(0),
+                _ => *path.last_mut().unwrap() += 1,
+            }
+        }
+        annotations
+    }
+
+    /// Notes of the node at the given path
+    pub fn get(&self, path: &[usize]) -> Option<&BTreeSet<Annotation>> {
+        self.0.get(path)
+    }
+
+    /// Add a note to the node at the given path
+    pub fn annotate(&mut self, path: &[usize], annotation: Annotation) {
+        self.0.entry(path.to_vec()).or_default().insert(annotation);
+    }
+
+    /// The annotated nodes, by path
+    pub fn iter(&self) -> impl Iterator<Item = (&[usize], &BTreeSet<Annotation>)> {
+        self.0.iter().map(|(path, notes)| (&path[..], notes))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// The new nodes gather the notes of the ones they replaced, and the inserted ones have none
+impl Merge for BTreeSet<Annotation> {
+    fn merge<'a>(mut notes: impl Iterator<Item = &'a Self>) -> Option<Self> {
+        let mut merged = notes.next()?.clone();
+        merged.extend(notes.flatten().cloned());
+        Some(merged)
+    }
+
+    fn inserted(_: &Self) -> Self {
+        BTreeSet::new()
+    }
+}
+
+impl Follow for Annotations {
+    fn follow(&mut self, old: &Block, new: &Block) {
+        let map = NodeMap::build(old, &mut |path| {
+            self.0.get(path).cloned().unwrap_or_default()
+        });
+        self.0.clear();
+        map.realign(old, new).visit(&mut |path, notes| {
+            if !notes.is_empty() {
+                self.0.insert(path.to_vec(), notes.clone());
+            }
+        })
+    }
+}
+
+impl From<Annotations> for BTreeMap<String, BTreeSet<Annotation>> {
+    fn from(Annotations(notes): Annotations) -> Self {
+        notes
+            .into_iter()
+            .map(|(path, notes)| {
+                let path: Vec<_> = path.iter().map(usize::to_string).collect();
+                (path.join("."), notes)
+            })
+            .collect()
+    }
+}
+
+impl TryFrom<BTreeMap<String, BTreeSet<Annotation>>> for Annotations {
+    type Error = InvalidPath;
+
+    fn try_from(notes: BTreeMap<String, BTreeSet<Annotation>>) -> Result<Self, Self::Error> {
+        notes
+            .into_iter()
+            .map(|(path, notes)| {
+                let indices = path
+                    .split('.')
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| InvalidPath(path))?;
+                Ok((indices, notes))
+            })
+            .collect::<Result<_, _>>()
+            .map(Annotations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::{Annotation, Annotations, InvalidPath};
+    use crate::{
+        ir::{passes::PassManager, Node, Program},
+        raw::Dialect,
+    };
+
+    fn comments(annotations: &Annotations, path: &[usize]) -> Vec<String> {
+        annotations
+            .get(path)
+            .into_iter()
+            .flatten()
+            .filter_map(|note| match note {
+                Annotation::Comment(text) => Some(text.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn of_source() {
+        let annotations = Annotations::of_source(
+            "read ,\n> [ copy it -> +< loop back ] > end",
+            Dialect::Standard,
+        );
+        assert_eq!(comments(&annotations, &[0]), ["read"]);
+        assert_eq!(annotations.get(&[1]), None);
+        assert_eq!(comments(&annotations, &[2, 0]), ["copy it"]);
+        assert_eq!(annotations.get(&[2, 1]), None);
+        assert_eq!(comments(&annotations, &[2]), ["loop back"]);
+        assert_eq!(annotations.iter().count(), 3);
+    }
+
+    #[test]
+    fn follow_the_optimizer() {
+        let source = "read , double it [- once >++<] > print .";
+        let mut annotations = Annotations::of_source(source, Dialect::Standard);
+        annotations.annotate(&[1], Annotation::Label("double".to_owned()));
+        let (program, outcome) = Program::from_raw_annotated(
+            source.parse().unwrap(),
+            &PassManager::new(),
+            &mut annotations,
+            |_| (),
+        );
+        outcome.unwrap();
+        let path_of = |pred: fn(&Node) -> bool| vec![program.0 .0.iter().position(pred).unwrap()];
+        assert_eq!(
+            comments(
+                &annotations,
+                &path_of(|node| matches!(node, Node::Input(_)))
+            ),
+            ["read"]
+        );
+        // the multiplication gathers the notes of the loop and of its body
+        let mul = annotations
+            .get(&path_of(|node| matches!(node, Node::Mul(_))))
+            .unwrap();
+        assert!(mul.contains(&Annotation::Label("double".to_owned())));
+        assert!(mul.contains(&Annotation::Comment("double it".to_owned())));
+        assert!(mul.contains(&Annotation::Comment("once".to_owned())));
+        assert!(annotations
+            .iter()
+            .all(|(path, _)| path.len() == 1 && path[0] < program.0 .0.len()));
+    }
+
+    #[test]
+    fn serialization() {
+        let mut annotations = Annotations::default();
+        annotations.annotate(&[3, 0, 1], Annotation::Label("inner".to_owned()));
+        annotations.annotate(&[0], Annotation::Provenance("lib.b".to_owned()));
+        let yaml = serde_yaml::to_string(&annotations).unwrap();
+        assert_eq!(yaml, "'0':\n- !provenance lib.b\n3.0.1:\n- !label inner\n");
+        assert_eq!(
+            serde_yaml::from_str::<Annotations>(&yaml).unwrap(),
+            annotations
+        );
+        let invalid = serde_yaml::from_str::<Annotations>("a.1: []\n").unwrap_err();
+        assert!(invalid
+            .to_string()
+            .contains(&InvalidPath("a.1".to_owned()).to_string()));
+        assert_eq!(
+            serde_yaml::from_str::<Annotations>("{}").unwrap().get(&[0]),
+            None::<&BTreeSet<_>>
+        );
+    }
+}
@@ -0,0 +1,400 @@
+//! Checks on the behaviour of the optimizer
+//!
+//! Those are meant to be used by tests, to catch optimizations that fight each other
+
+use thiserror::Error;
+
+use super::{verify, NotConverged, Program, VerifyError};
+use crate::{
+    engine::{self, Engine, ProgrammableEngine, RTError, State, StopState},
+    raw,
+};
+
+/// A misbehaviour of the optimizer
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Error)]
+pub enum CheckError {
+    #[error(transparent)]
+    NotConverged(#[from] NotConverged),
+    #[error("Optimizing an already optimized program changed it")]
+    NotIdempotent { first: Program, second: Program },
+    #[error(transparent)]
+    Invalid(#[from] VerifyError),
+}
+
+/// Check that the optimization of `program` converges in at most `max_passes` passes to a
+/// valid program, and that optimizing the result again leaves it unchanged
+///
+/// Return the optimized program
+pub fn check_optimizer(program: raw::Program, max_passes: usize) -> Result<Program, CheckError> {
+    let mut first = Program::from_raw_unoptimized(program);
+    first.optimize_bounded(max_passes)?;
+    verify(&first)?;
+    check_idempotent(&first, max_passes)?;
+    Ok(first)
+}
+
+/// Check that optimizing an already optimized program has no effect
+pub fn check_idempotent(program: &Program, max_passes: usize) -> Result<(), CheckError> {
+    let mut second = program.clone();
+    if second.optimize_bounded(max_passes)? > 0 || &second != program {
+        return Err(CheckError::NotIdempotent {
+            first: program.clone(),
+            second,
+        });
+    }
+    Ok(())
+}
+
+/// How a bounded run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RunEnd {
+    Halted,
+    /// The program asked for more input than was given
+    NeedInput,
+    /// The program did not stop in the allotted steps
+    OutOfSteps,
+    Error(RTError),
+}
+
+/// Result of running a program on a given input
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Outcome {
+    pub output: Vec<u8>,
+    pub end: RunEnd,
+}
+
+/// Run an engine on a fixed input, for at most `max_steps` steps
+fn run_bounded(mut engine: impl Engine, mut input: &[u8], max_steps: u64) -> Outcome {
+    let mut output = vec![];
+    let mut steps = 0u64;
+    let end = loop {
+        if steps == max_steps {
+            break RunEnd::OutOfSteps;
+        }
+        steps += 1;
+        match engine.step() {
+            // breakpoints do not change the behaviour of the program
+            Ok(State::Running | State::Stopped(StopState::Breakpoint)) => (),
+            Ok(State::Stopped(StopState::Halted)) => break RunEnd::Halted,
+            // the raw engine would run until the end of the steps
+            Ok(State::Stopped(StopState::Diverged)) => break RunEnd::OutOfSteps,
+            Ok(State::Stopped(StopState::EndOfInput)) => unreachable!("The input is never closed"),
+            Ok(State::Stopped(StopState::HasOutput(ch))) => output.push(ch),
+            Ok(State::Stopped(StopState::NeedInput)) => match input.split_first() {
+                Some((ch, rest)) => {
+                    engine.give_input(*ch);
+                    input = rest
+                }
+                None => break RunEnd::NeedInput,
+            },
+            Err(err) => break RunEnd::Error(err),
+        }
+    };
+    Outcome { output, end }
+}
+
+/// The optimized program behaved differently from the raw one
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Error)]
+#[error("The optimized program diverged from the raw one on input {input:?}")]
+pub struct Divergence {
+    pub input: Vec<u8>,
+    pub raw: Outcome,
+    pub optimized: Outcome,
+}
+
+/// Summary of an exhaustive check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ExhaustiveReport {
+    /// Inputs on which the two programs were compared
+    pub checked: usize,
+    /// Inputs on which the raw program did not stop in time
+    pub inconclusive: usize,
+}
+
+/// Check that the raw and the optimized program have the same output
+/// on every input of at most `max_len` bytes taken from `alphabet`
+///
+/// Inputs are explored as a tree: a new byte is added only if the raw program asks for it,
+/// so programs reading few bytes are checked quickly.
+/// Every run is limited to `max_steps` steps.
+pub fn check_exhaustive(
+    program: &raw::Program,
+    alphabet: &[u8],
+    max_len: usize,
+    max_steps: u64,
+) -> Result<ExhaustiveReport, Divergence> {
+    let optimized = Program::from_raw(program.clone());
+    let mut report = ExhaustiveReport::default();
+    let mut input = vec![];
+    explore(
+        program,
+        &optimized,
+        alphabet,
+        max_len,
+        max_steps,
+        &mut input,
+        &mut report,
+    )?;
+    Ok(report)
+}
+
+fn explore(
+    program: &raw::Program,
+    optimized: &Program,
+    alphabet: &[u8],
+    max_len: usize,
+    max_steps: u64,
+    input: &mut Vec<u8>,
+    report: &mut ExhaustiveReport,
+) -> Result<(), Divergence> {
+    let raw = run_bounded(
+        <engine::raw::Engine>::new(program.clone()),
+        input,
+        max_steps,
+    );
+    match raw.end {
+        RunEnd::NeedInput if input.len() < max_len => {
+            for ch in alphabet {
+                input.push(*ch);
+                explore(
+                    program, optimized, alphabet, max_len, max_steps, input, report,
+                )?;
+                input.pop();
+            }
+            Ok(())
+        }
+        RunEnd::OutOfSteps => {
+            report.inconclusive += 1;
+            Ok(())
+        }
+        _ => {
+            let optimized =
+                run_bounded(engine::ir::Engine::new(optimized.clone()), input, max_steps);
+            report.checked += 1;
+            if raw == optimized {
+                Ok(())
+            } else {
+                Err(Divergence {
+                    input: input.clone(),
+                    raw,
+                    optimized,
+                })
+            }
+        }
+    }
+}
+
+/// Random programs for the tests
+#[cfg(test)]
+pub(crate) mod random {
+    use std::iter;
+
+    use crate::{
+        analysis::symexec::Bounds,
+        raw::{Instruction, Program},
+    };
+
+    /// Bounds small enough to compare hundreds of random programs symbolically
+    pub(crate) const BOUNDS: Bounds = Bounds {
+        max_steps: 2000,
+        max_inputs: 2,
+        max_paths: 2000,
+        max_unknowns: 2,
+    };
+
+    /// Linear congruential generator, so that the tests are repeatable
+    pub(crate) struct Lcg(u64);
+
+    impl Lcg {
+        pub(crate) fn new(seed: u64) -> Self {
+            Lcg(seed)
+        }
+
+        /// A number smaller than `n`
+        pub(crate) fn below(&mut self, n: usize) -> usize {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (self.0 >> 33) as usize % n
+        }
+    }
+
+    /// Source of a balanced program of at least `len` instructions, reading more than it writes
+    pub(crate) fn source(rng: &mut Lcg, len: usize) -> String {
+        let mut source = String::new();
+        let mut depth = 0;
+        for _ in 0..len {
+            match rng.below(9) {
+                0 => {
+                    source.push('[');
+                    depth += 1
+                }
+                1 if depth > 0 => {
+                    source.push(']');
+                    depth -= 1
+                }
+                n => source.push(b"><+-.,,"[n % 7] as char),
+            }
+        }
+        source.extend((0..depth).map(|_| ']'));
+        source
+    }
+
+    /// A program of at most `max_len` instructions, with any instruction and nesting
+    pub(crate) fn arbitrary(seed: u64, max_len: usize) -> Program {
+        const INSTRS: [Instruction; 10] = [
+            Instruction::ShiftRight,
+            Instruction::ShiftLeft,
+            Instruction::Add,
+            Instruction::Sub,
+            Instruction::Output,
+            Instruction::Input,
+            Instruction::OpenLoop,
+            Instruction::CloseLoop,
+            Instruction::PrevTape,
+            Instruction::NextTape,
+        ];
+        let mut rng = Lcg::new(seed);
+        let mut code = vec![];
+        let mut depth = 0usize;
+        for _ in 0..rng.below(max_len + 1) {
+            let instr = INSTRS[rng.below(INSTRS.len())];
+            match instr {
+                Instruction::OpenLoop => depth += 1,
+                Instruction::CloseLoop if depth == 0 => continue,
+                Instruction::CloseLoop => depth -= 1,
+                _ => (),
+            }
+            code.push(instr)
+        }
+        code.extend(iter::repeat_n(Instruction::CloseLoop, depth));
+        Program::from_instrs(code).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::raw::{Dialect, Program};
+
+    use super::{
+        check_exhaustive, check_optimizer,
+        random::{self, Lcg},
+        run_bounded, RunEnd,
+    };
+    use crate::engine::{self, cell::Overflow, ProgrammableEngine, RTError};
+
+    #[test]
+    fn random_corpus() {
+        let mut rng = Lcg::new(0);
+        for _ in 0..200 {
+            // ending with an output, so the trimming always has something to keep
+            let program: Program = (random::source(&mut rng, 64) + ".").parse().unwrap();
+            if let Err(err) = check_optimizer(program.clone(), 1000) {
+                panic!("Optimizer check failed on `{program}`: {err}")
+            }
+        }
+    }
+
+    #[test]
+    fn exhaustive_small_programs() {
+        for program in [",[.,]", ",[->+>+<<]>.>.", ",>,<[->-<]>[[-]+++.]", "+[,.]"] {
+            let program: Program = program.parse().unwrap();
+            if let Err(err) = check_exhaustive(&program, &[0, 1, 2, b'a'], 3, 10_000) {
+                panic!("Exhaustive check failed on `{program}`: {err}: {err:?}")
+            }
+        }
+    }
+
+    #[test]
+    fn exhaustive_multiplications() {
+        for program in [
+            ",[->+++<]>.",
+            ",[+>--<]>.",
+            ",>,<[->[->+>+<<]>>[-<<+>>]<<<]>>.",
+            ",>,<[>[->+<]>[-<+>+>+<<]<<-]>>>.<.",
+        ] {
+            let program: Program = program.parse().unwrap();
+            if let Err(err) = check_exhaustive(&program, &[0, 1, 2, 7, 255], 2, 100_000) {
+                panic!("Exhaustive check failed on `{program}`: {err}: {err:?}")
+            }
+        }
+    }
+
+    #[test]
+    fn exhaustive_multi_tape() {
+        for program in [",},{.}.", ",}+{[-}+{]}.", "}>,[-{+}]{."] {
+            let program = Program::parse_dialect(program, Dialect::MultiTape).unwrap();
+            if let Err(err) = check_exhaustive(&program, &[0, 1, 2, 255], 2, 100_000) {
+                panic!("Exhaustive check failed on `{program}`: {err}: {err:?}")
+            }
+        }
+    }
+
+    #[test]
+    fn output_before_negative_access() {
+        // the output and the input come before the error, and must not be moved after it
+        for source in [
+            "++[>++<-]>.<<+++",
+            "++[>++<-]>.,<<+++.",
+            ",[>+<-]>.<<-.",
+            "+.>.<<<-",
+        ] {
+            let program: Program = source.parse().unwrap();
+            let optimized = match check_optimizer(program.clone(), 1000) {
+                Ok(optimized) => optimized,
+                Err(err) => panic!("Optimizer check failed on `{source}`: {err}"),
+            };
+            for input in [[0], [3]] {
+                let raw = run_bounded(<engine::raw::Engine>::new(program.clone()), &input, 10_000);
+                let ir = run_bounded(engine::ir::Engine::new(optimized.clone()), &input, 10_000);
+                assert_eq!(raw.end, RunEnd::Error(RTError::MemNegativeOut));
+                // the unread cells might not be written at all
+                assert_eq!(
+                    raw.output, ir.output,
+                    "`{source}` on {input:?}\n{optimized}"
+                )
+            }
+        }
+    }
+
+    #[test]
+    fn nested_loops_are_lowered() {
+        let program: Program = ",>,<[->[->+>+<<]>>[-<<+>>]<<<]>>.".parse().unwrap();
+        let stats = crate::ir::Program::from_raw(program).stats();
+        assert_eq!(stats.loops, 0);
+        assert_eq!(stats.ifs, 1, "Only the peeled iteration should remain");
+        assert_eq!(stats.muls, 3);
+        assert_eq!(stats.max_depth, 2);
+    }
+
+    #[test]
+    fn trapping_cells() {
+        let mut rng = Lcg::new(0);
+        let mut sources = vec![".>-".to_owned(), ",.>-[.]".to_owned()];
+        sources.extend((0..400).map(|_| random::source(&mut rng, 24)));
+        for source in sources {
+            let program: Program = source.parse().unwrap();
+            let optimized =
+                crate::ir::Program::from_raw_with_overflow(program.clone(), Overflow::Trap, true);
+            for input in [&[][..], &[0, 0], &[255, 1]] {
+                let raw = run_bounded(
+                    <engine::raw::Engine>::new(program.clone()).with_overflow(Overflow::Trap),
+                    input,
+                    10_000,
+                );
+                let ir = run_bounded(
+                    engine::ir::Engine::new(optimized.clone()).with_overflow(Overflow::Trap),
+                    input,
+                    10_000,
+                );
+                // the optimizer might move or drop the accesses before the start of the tape
+                if raw.end != RunEnd::OutOfSteps
+                    && raw.end != RunEnd::Error(RTError::MemNegativeOut)
+                {
+                    assert_eq!(raw, ir, "`{source}` on {input:?}\n{optimized}")
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,195 @@
+//! Graphviz export of the structure of a program
+//!
+//! Each node is a vertex, and the bodies of the loops and ifs are clusters around them. The solid
+//! edges follow the order in which the nodes run, and the dashed ones go from the nodes writing a
+//! cell to the ones reading it. The cells are followed only while the position of the pointer is
+//! known, so nothing flows across the loops that move it. The flow from the end of a loop back to
+//! its start is not drawn.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+};
+
+use super::{Add, Block, If, Input, Loop, Mul, Node, Output, Program, Set, Shift};
+
+impl Program {
+    /// Draw the program as a graph, in the DOT language of Graphviz
+    pub fn to_dot(&self) -> String {
+        let mut graph = Graph::default();
+        let mut writers = Some(BTreeMap::new());
+        let mut dot =
+            String::from("digraph program {\n    node [shape=box, fontname=monospace];\n");
+        graph.block(&self.0, 0, &mut writers, 1, &mut dot);
+        for (from, to) in &graph.order {
+            writeln!(dot, "    n{from} -> n{to};").unwrap()
+        }
+        for (from, to, cell) in &graph.flow {
+            writeln!(
+                dot,
+                "    n{from} -> n{to} [style=dashed, label=\"@{cell}\"];"
+            )
+            .unwrap()
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Last nodes that might have written each cell, by position relative to the start of the
+/// outermost block. `None` if the position of the pointer is not known
+type Writers = Option<BTreeMap<isize, BTreeSet<usize>>>;
+
+#[derive(Debug, Default)]
+struct Graph {
+    /// Vertices already drawn
+    vertices: usize,
+    /// Edges in the order the nodes run
+    order: Vec<(usize, usize)>,
+    /// Edges from the writers of a cell to its readers, with the position of the cell
+    flow: BTreeSet<(usize, usize, isize)>,
+}
+
+impl Graph {
+    /// Draw a block starting at position `pos`, returning the first and the last vertex
+    fn block(
+        &mut self,
+        block: &Block,
+        mut pos: isize,
+        writers: &mut Writers,
+        depth: usize,
+        dot: &mut String,
+    ) -> Option<(usize, usize)> {
+        let indent = "    ".repeat(depth);
+        let mut ends: Option<(usize, usize)> = None;
+        for node in &block.0 {
+            let id = self.vertices;
+            self.vertices += 1;
+            if let Some((_, last)) = ends {
+                self.order.push((last, id))
+            }
+            ends = Some((ends.map_or(id, |(first, _)| first), id));
+            match node {
+                Node::Loop(Loop { body, offset }) | Node::If(If { body, offset }) => {
+                    let (kind, shape) = match node {
+                        Node::Loop(_) => ("loop", "diamond"),
+                        _ => ("if", "invhouse"),
+                    };
+                    writeln!(dot, "{indent}subgraph cluster_{id} {{").unwrap();
+                    writeln!(
+                        dot,
+                        "{indent}    n{id} [label=\"{kind} @{offset}\", shape={shape}];"
+                    )
+                    .unwrap();
+                    self.read(id, pos + offset, writers);
+                    let before = writers.clone();
+                    if let Some((first, last)) = self.block(body, pos, writers, depth + 1, dot) {
+                        self.order.push((id, first));
+                        if let Node::Loop(_) = node {
+                            self.order.push((last, id))
+                        }
+                    }
+                    writeln!(dot, "{indent}}}").unwrap();
+                    if !body.is_balanced() {
+                        *writers = None;
+                        continue;
+                    }
+                    // the body might not run
+                    *writers = merge(before, writers.take());
+                    if let Some(writers) = writers {
+                        writers.insert(pos + offset, BTreeSet::from([id]));
+                    }
+                }
+                node => {
+                    writeln!(dot, "{indent}n{id} [label=\"{}\"];", label(node)).unwrap();
+                    match node {
+                        Node::Shift(Shift { amount }) => pos += amount.get(),
+                        Node::SwitchTape(_) => *writers = None,
+                        Node::Add(Add { offset, .. }) => {
+                            self.read(id, pos + offset, writers);
+                            self.write(id, pos + offset, writers)
+                        }
+                        Node::Output(Output { offset }) => self.read(id, pos + offset, writers),
+                        Node::Input(Input { offset }) | Node::Set(Set { offset, .. }) => {
+                            self.write(id, pos + offset, writers)
+                        }
+                        Node::Mul(Mul { offset, targets }) => {
+                            self.read(id, pos + offset, writers);
+                            for (target, expr) in targets {
+                                self.read(id, pos + target, writers);
+                                for cell in expr.terms.keys() {
+                                    self.read(id, pos + cell, writers)
+                                }
+                            }
+                            self.write(id, pos + offset, writers);
+                            for (target, _) in targets {
+                                self.write(id, pos + target, writers)
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        }
+        ends
+    }
+
+    fn read(&mut self, id: usize, cell: isize, writers: &Writers) {
+        if let Some(from) = writers.as_ref().and_then(|writers| writers.get(&cell)) {
+            self.flow.extend(from.iter().map(|&from| (from, id, cell)))
+        }
+    }
+
+    fn write(&mut self, id: usize, cell: isize, writers: &mut Writers) {
+        if let Some(writers) = writers {
+            writers.insert(cell, BTreeSet::from([id]));
+        }
+    }
+}
+
+/// The writers on either of two paths
+fn merge(first: Writers, second: Writers) -> Writers {
+    let (mut first, second) = (first?, second?);
+    for (cell, ids) in second {
+        first.entry(cell).or_default().extend(ids)
+    }
+    Some(first)
+}
+
+/// The text of a node, escaped for a label
+fn label(node: &Node) -> String {
+    node.to_string()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ir::Program;
+
+    #[test]
+    fn to_dot() {
+        let program: Program = ",>,<[>[>+<-]<-]>>.".parse().unwrap();
+        let dot = program.to_dot();
+        assert!(dot.starts_with("digraph program {"), "{dot}");
+        assert_eq!(dot.matches('{').count(), dot.matches('}').count(), "{dot}");
+        assert_eq!(dot.matches("subgraph cluster_").count(), 1, "{dot}");
+        // the loop reads the first input, and the output the result of the multiplication
+        let flow: Vec<_> = dot.lines().filter(|line| line.contains("dashed")).collect();
+        assert!(
+            flow.contains(&"    n0 -> n2 [style=dashed, label=\"@0\"];"),
+            "{dot}"
+        );
+        assert!(
+            flow.iter()
+                .any(|line| line.ends_with("label=\"@2\"];") && line.contains("-> n5")),
+            "{dot}"
+        );
+        // the quotes in the prints are escaped
+        let program: Program = "++++++++++++++++++++++++++++++++++.".parse().unwrap();
+        assert!(program.to_dot().contains(r#"label="print \"\\\"\"""#));
+    }
+}
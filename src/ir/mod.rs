@@ -1,6 +1,7 @@
 //! Intermediate representation for optimized execution
 
 use std::{
+    collections::BTreeMap,
     fmt::{Display, Write},
     mem,
     num::{NonZeroIsize, NonZeroU8},
@@ -10,18 +11,156 @@ use std::{
 
 use bincode::{Decode, Encode};
 use indenter::indented;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use thiserror::Error;
 
-use crate::raw;
+use crate::{engine::cell::Overflow, raw};
 
+use passes::{OptLevel, PassManager};
+
+mod annotations;
+pub mod check;
+mod dot;
+mod nullity;
 mod optimizations;
+pub mod passes;
+mod reach;
+mod slice;
+mod source_map;
+mod text;
+mod values;
+mod verify;
+
+pub use annotations::{Annotation, Annotations, InvalidPath};
+pub use nullity::Nullity;
+pub use reach::Reach;
+pub use slice::Criterion;
+pub use source_map::{NodeMap, SourceMap, Span};
+pub use text::{Malformed, ParseTextError};
+pub use values::{CellValues, Domain, Values};
+pub use verify::{verify, Invalid, VerifyError};
+
+/// Version of the optimizer, to be bumped every time the passes change
+pub const OPTIMIZER_VERSION: u32 = 20;
+
+/// Passes after which the optimizer gives up, if no bound is given
+///
+/// Every pass keeps the program correct, so the last one is used. Reaching this is a bug in the
+/// passes, that undo each other.
+pub const PASS_LIMIT: usize = 10_000;
 
 #[derive(
     Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Encode, Decode,
 )]
 pub struct Program(pub Block);
 impl Program {
-    fn from_raw(value: crate::raw::Program) -> Program {
+    pub fn from_raw(value: crate::raw::Program) -> Program {
+        let mut program = Self::from_raw_unoptimized(value);
+        program.optimize();
+        program
+    }
+
+    /// Translate and optimize raw brainfuck, with the passes of the given level
+    pub fn from_raw_with(value: crate::raw::Program, level: OptLevel) -> Program {
+        Self::from_raw_with_passes(value, &PassManager::for_level(level), |_| ())
+    }
+
+    /// Translate and optimize raw brainfuck, reporting the progress of the optimizer
+    pub fn from_raw_with_progress(
+        value: crate::raw::Program,
+        progress: impl FnMut(Progress),
+    ) -> Program {
+        Self::from_raw_with_passes(value, &PassManager::new(), progress)
+    }
+
+    /// Translate and optimize raw brainfuck, without assuming the tape starts zeroed
+    ///
+    /// This is needed if the memory is seeded before the program starts
+    pub fn from_raw_unknown_tape(value: crate::raw::Program) -> Program {
+        Self::from_raw_with_passes(value, &PassManager::new().with_zeroed_tape(false), |_| ())
+    }
+
+    /// Translate and optimize raw brainfuck, to be joined to other code with [`Program::concat`]
+    ///
+    /// Nothing is assumed on the tape the fragment starts on, or on the code that follows it.
+    pub fn fragment(value: crate::raw::Program) -> Program {
+        Self::from_raw_with_passes(value, &PassManager::fragment(), |_| ())
+    }
+
+    /// Translate and optimize raw brainfuck, for cells with the given overflow policy
+    ///
+    /// With policies other than [`Overflow::Wrap`] the additions must be run as signed, and
+    /// fewer optimizations apply.
+    pub fn from_raw_with_overflow(
+        value: crate::raw::Program,
+        overflow: Overflow,
+        zeroed_tape: bool,
+    ) -> Program {
+        Self::from_raw_with_passes(
+            value,
+            &PassManager::new()
+                .with_overflow(overflow)
+                .with_zeroed_tape(zeroed_tape),
+            |_| (),
+        )
+    }
+
+    /// Translate raw brainfuck, and optimize it with the given pipeline
+    pub fn from_raw_with_passes(
+        value: crate::raw::Program,
+        passes: &PassManager,
+        progress: impl FnMut(Progress),
+    ) -> Program {
+        let (program, outcome) = Self::from_raw_with_outcome(value, passes, progress);
+        if let Err(err) = outcome {
+            log::warn!("{err}, the program is not fully optimized")
+        }
+        program
+    }
+
+    /// As [`Program::from_raw_with_passes`], also returning if the optimizer reached a fixpoint
+    ///
+    /// This is the number of passes that changed the program, or why the optimizer stopped early.
+    pub fn from_raw_with_outcome(
+        value: crate::raw::Program,
+        passes: &PassManager,
+        progress: impl FnMut(Progress),
+    ) -> (Program, Result<usize, NotConverged>) {
+        let mut program = Self::from_raw_unoptimized(value);
+        let outcome = passes.optimize(&mut program, PASS_LIMIT, progress);
+        (program, outcome)
+    }
+
+    /// As [`Program::from_raw_with_outcome`], keeping the annotations of the nodes aligned
+    ///
+    /// `annotations` must be given for the program translated without optimizations, as the ones
+    /// made by [`Annotations::of_source`].
+    pub fn from_raw_annotated(
+        value: crate::raw::Program,
+        passes: &PassManager,
+        annotations: &mut Annotations,
+        progress: impl FnMut(Progress),
+    ) -> (Program, Result<usize, NotConverged>) {
+        let mut program = Self::from_raw_unoptimized(value);
+        let outcome = passes.optimize_annotated(&mut program, annotations, PASS_LIMIT, progress);
+        (program, outcome)
+    }
+
+    /// As [`Program::from_raw_with_passes`], also returning the span in the source of each node
+    pub fn from_raw_with_source_map(
+        value: crate::raw::Program,
+        passes: &PassManager,
+    ) -> (Program, SourceMap) {
+        let mut map = SourceMap::of_raw(&value);
+        let mut program = Self::from_raw_unoptimized(value);
+        if let Err(err) = passes.optimize_mapped(&mut program, &mut map, PASS_LIMIT, |_| ()) {
+            log::warn!("{err}, the program is not fully optimized")
+        }
+        (program, map)
+    }
+
+    /// Translate raw brainfuck into ir, without optimizing it
+    fn from_raw_unoptimized(value: crate::raw::Program) -> Program {
         let mut stack: Vec<Vec<Node>> = vec![vec![]];
         for instr in value {
             match instr {
@@ -60,28 +199,190 @@ impl Program {
                     .last_mut()
                     .unwrap()
                     .push(Node::Input(Input { offset: 0 })),
+                crate::raw::Instruction::PrevTape => {
+                    stack.last_mut().unwrap().push(Node::SwitchTape(SwitchTape {
+                        amount: NonZeroIsize::new(-1).unwrap(),
+                    }))
+                }
+                crate::raw::Instruction::NextTape => {
+                    stack.last_mut().unwrap().push(Node::SwitchTape(SwitchTape {
+                        amount: NonZeroIsize::new(1).unwrap(),
+                    }))
+                }
             }
         }
         let [body] = &mut stack[..] else {unreachable!()};
-        let mut body = Block(mem::take(body));
-        while body.optimize() {
-            // removing leading loops
-            let mut s = 0;
-            while matches!(body.0[s], Node::Loop(_)) {
-                s += 1;
+        Program(Block(mem::take(body)))
+    }
+
+    /// Optimize the program, giving up after [`PASS_LIMIT`] passes
+    ///
+    /// Return if something changed
+    pub fn optimize(&mut self) -> bool {
+        match self.optimize_bounded(PASS_LIMIT) {
+            Ok(passes) => passes > 0,
+            Err(err) => {
+                log::warn!("{err}, the program is not fully optimized");
+                true
             }
-            // removing tail with no side-effects or inputs
-            let mut e = body.0.len().saturating_sub(1);
-            while body.0[e].diverge() == Some(false) && !body.0[e].does_output() {
-                e -= 1;
+        }
+    }
+
+    /// Optimize the program, giving up if the fixpoint is not reached in `max_passes` passes
+    ///
+    /// Return the number of passes that changed the program
+    pub fn optimize_bounded(&mut self, max_passes: usize) -> Result<usize, NotConverged> {
+        PassManager::new().optimize(self, max_passes, |_| ())
+    }
+
+    /// Like [`Program::optimize_bounded`], calling `progress` at each phase boundary
+    pub fn optimize_with_progress(
+        &mut self,
+        max_passes: usize,
+        progress: impl FnMut(Progress),
+    ) -> Result<usize, NotConverged> {
+        PassManager::new().optimize(self, max_passes, progress)
+    }
+
+    /// Run `other` after this program, optimizing across the seam
+    ///
+    /// Both programs must be fragments, as made by [`Program::fragment`] or by this, since a whole
+    /// program might have dropped the writes at its end, or relied on starting on a zeroed tape.
+    /// The result is still a fragment: once it is complete [`Program::optimize`] can assume it is
+    /// a whole program.
+    pub fn concat(mut self, other: Program) -> Program {
+        self.0 .0.extend(other.0 .0);
+        if let Err(err) = PassManager::fragment().optimize(&mut self, PASS_LIMIT, |_| ()) {
+            log::warn!("{err}, the program is not fully optimized")
+        }
+        self
+    }
+
+    /// Run the program at compile time, if it reads no input and halts within `budget` steps
+    ///
+    /// The program is then replaced by the constant output. As the other optimizations, this
+    /// assumes wrapping cells and a zeroed tape. Return if the program was replaced.
+    pub fn precompute(&mut self, budget: u64) -> bool {
+        match optimizations::precompute(&self.0, budget) {
+            Some(block) => {
+                self.0 = block;
+                true
             }
-            body = Block(body.0.drain(s..=e).collect())
+            None => false,
         }
+    }
+
+    /// Put the program in canonical form. See [`Block::canonicalize`]
+    pub fn canonicalize(&mut self) {
+        self.0.canonicalize()
+    }
 
-        Program(body)
+    /// Check if the programs are equal, up to the order of the nodes that commute and the
+    /// position of the shifts
+    pub fn equivalent(&self, other: &Program) -> bool {
+        let mut this = self.clone();
+        let mut other = other.clone();
+        this.canonicalize();
+        other.canonicalize();
+        this == other
+    }
+
+    /// Collect statistics about the structure of the program
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats::default();
+        self.0.collect_stats(1, &mut stats);
+        stats
     }
 }
 
+/// Progress of the optimizer, reported at each phase boundary
+#[derive(Debug, Clone, Copy)]
+pub struct Progress<'p> {
+    /// Phase just completed
+    pub phase: Phase,
+    /// Passes that changed the program so far
+    pub passes: usize,
+    /// Iteration of the outer fixpoint, incremented after each trim
+    pub iteration: usize,
+    /// Current state of the program
+    pub program: &'p Program,
+}
+impl Progress<'_> {
+    /// Number of nodes in the program
+    pub fn nodes(&self) -> usize {
+        self.program.stats().nodes
+    }
+}
+
+/// Phases of the optimizer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Phase {
+    /// A pass over the whole program changed something
+    Pass,
+    /// The ends of the program were trimmed
+    Trim,
+    /// The values of the cells were propagated from the start of the program
+    Propagate,
+    /// The possible values of the cells were used to simplify the loops and the sets
+    Narrow,
+    /// The loops on known cells were run, and replaced with the values they leave
+    Fold,
+    /// The start of the program was run, and replaced with its outputs and final state
+    Evaluate,
+    /// The fixpoint was reached
+    Done,
+}
+
+/// The optimizer did not reach a fixpoint
+///
+/// The program is left as the last completed pass made it, so it is still correct.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Error)]
+#[error("The optimizer {cause} after {passes} passes")]
+pub struct NotConverged {
+    pub passes: usize,
+    pub cause: Exhausted,
+}
+
+/// Budget of the optimizer that ran out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Exhausted {
+    /// The maximum number of passes
+    Passes,
+    /// The fuel given with [`PassManager::with_fuel`]
+    Fuel,
+    /// The time given with [`PassManager::with_timeout`]
+    Time,
+}
+impl Display for Exhausted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Exhausted::Passes => write!(f, "did not converge"),
+            Exhausted::Fuel => write!(f, "ran out of fuel"),
+            Exhausted::Time => write!(f, "ran out of time"),
+        }
+    }
+}
+
+/// Structural statistics of a program
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct Stats {
+    /// Total number of nodes
+    pub nodes: usize,
+    pub shifts: usize,
+    pub adds: usize,
+    pub outputs: usize,
+    pub inputs: usize,
+    pub loops: usize,
+    pub ifs: usize,
+    pub muls: usize,
+    pub tape_switches: usize,
+    pub sets: usize,
+    pub prints: usize,
+    pub diverges: usize,
+    /// Maximum nesting depth of the blocks
+    pub max_depth: usize,
+}
+
 impl Display for Program {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for n in &self.0 .0 {
@@ -128,14 +429,77 @@ impl Block {
     ///
     /// Return if something changed
     pub fn optimize(&mut self) -> bool {
-        let mut changed = true;
-        let mut repeats = 0usize;
-        while changed {
-            changed = false;
-            repeats += 1;
-            self.0 = optimizations::optimize(mem::take(&mut self.0), &mut changed);
+        let mut changed = false;
+        while self.optimize_once() {
+            changed = true;
+        }
+        changed
+    }
+
+    /// Run a single optimization pass on the block, and on the nested ones
+    ///
+    /// Return if something changed
+    pub fn optimize_once(&mut self) -> bool {
+        PassManager::new().sweep(self)
+    }
+
+    /// Put the block, and the nested ones, in canonical form
+    ///
+    /// The noops are removed, the shifts are moved to the end of each block, and the nodes that
+    /// commute are sorted, so blocks that differ only in the order of independent nodes become
+    /// equal.
+    pub fn canonicalize(&mut self) {
+        for node in &mut self.0 {
+            if let Node::Loop(Loop { body, .. }) | Node::If(If { body, .. }) = node {
+                body.canonicalize()
+            }
+        }
+        let mut changed = false;
+        self.0 = optimizations::canonicalize(mem::take(&mut self.0), &mut changed, Overflow::Wrap);
+    }
+
+    /// Net movement of the pointer after running the block
+    ///
+    /// `None` if it depends on the memory, because the block contains an unbalanced loop
+    pub fn net_shift(&self) -> Option<isize> {
+        self.0.iter().try_fold(0, |shift, node| match node {
+            Node::Shift(Shift { amount }) => Some(shift + amount.get()),
+            Node::Loop(Loop { body, .. }) | Node::If(If { body, .. }) => {
+                body.is_balanced().then_some(shift)
+            }
+            _ => Some(shift),
+        })
+    }
+    /// Check if the block always leaves the pointer where it started
+    pub fn is_balanced(&self) -> bool {
+        self.net_shift() == Some(0)
+    }
+
+    fn collect_stats(&self, depth: usize, stats: &mut Stats) {
+        stats.max_depth = stats.max_depth.max(depth);
+        for node in &self.0 {
+            stats.nodes += 1;
+            match node {
+                Node::Noop => (),
+                Node::Shift(_) => stats.shifts += 1,
+                Node::Add(_) => stats.adds += 1,
+                Node::Output(_) => stats.outputs += 1,
+                Node::Input(_) | Node::InputDiscard => stats.inputs += 1,
+                Node::Mul(_) => stats.muls += 1,
+                Node::SwitchTape(_) => stats.tape_switches += 1,
+                Node::Set(_) => stats.sets += 1,
+                Node::Print(_) => stats.prints += 1,
+                Node::Diverge => stats.diverges += 1,
+                Node::Loop(Loop { body, .. }) => {
+                    stats.loops += 1;
+                    body.collect_stats(depth + 1, stats)
+                }
+                Node::If(If { body, .. }) => {
+                    stats.ifs += 1;
+                    body.collect_stats(depth + 1, stats)
+                }
+            }
         }
-        repeats > 1
     }
 }
 
@@ -176,6 +540,15 @@ pub enum Node {
     Output(Output),
     Input(Input),
     Loop(Loop),
+    Mul(Mul),
+    SwitchTape(SwitchTape),
+    Set(Set),
+    If(If),
+    Print(Print),
+    /// Never end
+    Diverge,
+    /// Read a byte of input, and drop it
+    InputDiscard,
 }
 impl Display for Node {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -185,7 +558,14 @@ impl Display for Node {
             Node::Add(c) => write!(f, "{c}"),
             Node::Output(c) => write!(f, "{c}"),
             Node::Input(c) => write!(f, "{c}"),
+            Node::InputDiscard => write!(f, "input\t\tdiscard"),
             Node::Loop(c) => write!(f, "{c}"),
+            Node::Mul(c) => write!(f, "{c}"),
+            Node::SwitchTape(c) => write!(f, "{c}"),
+            Node::Set(c) => write!(f, "{c}"),
+            Node::If(c) => write!(f, "{c}"),
+            Node::Print(c) => write!(f, "{c}"),
+            Node::Diverge => write!(f, "diverge"),
         }
     }
 }
@@ -193,10 +573,9 @@ impl Display for Node {
 impl Node {
     #[must_use]
     pub fn as_block(&self) -> Option<&Block> {
-        if let Self::Loop(Loop { body, .. }) = self {
-            Some(body)
-        } else {
-            None
+        match self {
+            Self::Loop(Loop { body, .. }) | Self::If(If { body, .. }) => Some(body),
+            _ => None,
         }
     }
 
@@ -228,38 +607,161 @@ impl Node {
                 ),
                 offset: offset + additional_offset,
             }),
+            // all tapes share the same pointer
+            Node::SwitchTape(switch) => Node::SwitchTape(switch),
+            Node::Print(print) => Node::Print(print),
+            Node::InputDiscard => Node::InputDiscard,
+            Node::Diverge => Node::Diverge,
+            Node::Set(Set { value, offset }) => Node::Set(Set {
+                value,
+                offset: offset + additional_offset,
+            }),
+            Node::If(If {
+                body: Block(nodes),
+                offset,
+            }) => Node::If(If {
+                body: Block(
+                    nodes
+                        .into_iter()
+                        .map(|n| n.shifted(additional_offset))
+                        .collect(),
+                ),
+                offset: offset + additional_offset,
+            }),
+            Node::Mul(Mul { offset, targets }) => Node::Mul(Mul {
+                offset: offset + additional_offset,
+                targets: targets
+                    .into_iter()
+                    .map(|(target, expr)| {
+                        (target + additional_offset, expr.shifted(additional_offset))
+                    })
+                    .collect(),
+            }),
         }
     }
 
     fn does_input(&self) -> bool {
         match self {
-            Node::Output(_) => true,
+            Node::Input(_) | Node::InputDiscard => true,
             Node::Loop(Loop {
                 body: Block(nodes), ..
-            }) => nodes.iter().any(Node::does_output),
-            Node::Noop | Node::Shift(_) | Node::Add(_) | Node::Input(_) => false,
+            })
+            | Node::If(If {
+                body: Block(nodes), ..
+            }) => nodes.iter().any(Node::does_input),
+            Node::Noop
+            | Node::Shift(_)
+            | Node::Add(_)
+            | Node::Output(_)
+            | Node::Mul(_)
+            | Node::SwitchTape(_)
+            | Node::Set(_)
+            | Node::Print(_)
+            | Node::Diverge => false,
         }
     }
     fn does_output(&self) -> bool {
         match self {
-            Node::Output(_) => true,
+            Node::Output(_) | Node::Print(_) => true,
             Node::Loop(Loop {
                 body: Block(nodes), ..
+            })
+            | Node::If(If {
+                body: Block(nodes), ..
             }) => nodes.iter().any(Node::does_output),
-            Node::Noop | Node::Shift(_) | Node::Add(_) | Node::Input(_) => false,
+            Node::Noop
+            | Node::Shift(_)
+            | Node::Add(_)
+            | Node::Input(_)
+            | Node::InputDiscard
+            | Node::Mul(_)
+            | Node::SwitchTape(_)
+            | Node::Set(_)
+            | Node::Diverge => false,
         }
     }
     fn diverge(&self) -> Option<bool> {
         match self {
-            Node::Noop | Node::Shift(_) | Node::Add(_) | Node::Output(_) | Node::Input(_) => {
-                Some(false)
-            }
-            Node::Loop(_) => None, // TODO: More checks to identify diverging loops
+            Node::Noop
+            | Node::Shift(_)
+            | Node::Add(_)
+            | Node::Output(_)
+            | Node::Input(_)
+            | Node::InputDiscard
+            | Node::Mul(_)
+            | Node::SwitchTape(_)
+            | Node::Set(_)
+            | Node::Print(_) => Some(false),
+            Node::Diverge => Some(true),
+            // the loops that surely diverge once entered are lowered to an if around `Diverge`
+            Node::Loop(_) => None,
+            // the body might not run at all
+            Node::If(If { body, .. }) => body
+                .0
+                .iter()
+                .all(|node| node.diverge() == Some(false))
+                .then_some(false),
+        }
+    }
+
+    /// Check if the node might stop the program on an overflow
+    ///
+    /// With cells that do not wrap, the arithmetic is a side effect.
+    fn may_trap(&self, overflow: Overflow) -> bool {
+        match self {
+            Node::Add(_) | Node::Mul(_) => overflow != Overflow::Wrap,
+            Node::Loop(Loop {
+                body: Block(nodes), ..
+            })
+            | Node::If(If {
+                body: Block(nodes), ..
+            }) => nodes.iter().any(|node| node.may_trap(overflow)),
+            Node::Noop
+            | Node::Shift(_)
+            | Node::Output(_)
+            | Node::Input(_)
+            | Node::InputDiscard
+            | Node::SwitchTape(_)
+            | Node::Set(_)
+            | Node::Print(_)
+            | Node::Diverge => false,
+        }
+    }
+
+    /// Check if the node might access a cell before the current one
+    ///
+    /// That access fails if the pointer is near the start of the tape. The nodes with a body, or
+    /// reading many cells, are assumed to.
+    fn may_access_before(&self) -> bool {
+        match self {
+            Node::Add(Add { offset, .. })
+            | Node::Set(Set { offset, .. })
+            | Node::Output(Output { offset })
+            | Node::Input(Input { offset }) => *offset < 0,
+            Node::Mul(_) | Node::Loop(_) | Node::If(_) => true,
+            Node::Noop
+            | Node::Shift(_)
+            | Node::InputDiscard
+            | Node::SwitchTape(_)
+            | Node::Print(_)
+            | Node::Diverge => false,
         }
     }
 
     /// check if two nodes can be exchanged
-    fn commute(&self, other: &Self) -> bool {
+    fn commute(&self, other: &Self, overflow: Overflow) -> bool {
+        let io = |node: &Node| node.does_input() || node.does_output();
+        let fails = |node: &Node| node.may_trap(overflow) || node.may_access_before();
+        if fails(self) && io(other) || io(self) && fails(other) {
+            // the io before a failure must happen, and the one after must not
+            return false;
+        }
+        if self.may_trap(overflow) && other.may_access_before()
+            || self.may_access_before() && other.may_trap(overflow)
+        {
+            // the first to fail decides the error
+            return false;
+        }
         match (self, other) {
             // Noop always commute
             (Node::Noop, _) | (_, Node::Noop) => true,
@@ -280,6 +782,23 @@ impl Node {
                 Node::Output(Output { offset: o2 }) | Node::Input(Input { offset: o2 }),
                 Node::Add(Add { offset: o1, .. }),
             ) => o1 != o2,
+            // Set commute with the other accesses to single cells, but only on different cells
+            (
+                Node::Set(Set { offset: o1, .. }),
+                Node::Set(Set { offset: o2, .. })
+                | Node::Add(Add { offset: o2, .. })
+                | Node::Output(Output { offset: o2 })
+                | Node::Input(Input { offset: o2 }),
+            )
+            | (
+                Node::Add(Add { offset: o2, .. })
+                | Node::Output(Output { offset: o2 })
+                | Node::Input(Input { offset: o2 }),
+                Node::Set(Set { offset: o1, .. }),
+            ) => o1 != o2,
+            // constant outputs do not touch the memory
+            (Node::Print(_) | Node::InputDiscard, Node::Add(_) | Node::Set(_))
+            | (Node::Add(_) | Node::Set(_), Node::Print(_) | Node::InputDiscard) => true,
             // input and output will never exchange positions
             (Node::Output(_) | Node::Input(_), Node::Output(_) | Node::Input(_)) => false,
 
@@ -345,6 +864,15 @@ pub struct Loop {
     pub body: Block,
     pub offset: isize,
 }
+impl Loop {
+    /// Check if each iteration leaves the pointer where it started
+    ///
+    /// The cells touched by a balanced loop are known in advance, while an unbalanced one moves
+    /// along the tape, and cannot be collapsed in a single node
+    pub fn is_balanced(&self) -> bool {
+        self.body.is_balanced()
+    }
+}
 impl Display for Loop {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "loop\t@{} [", self.offset)?;
@@ -355,3 +883,228 @@ impl Display for Loop {
         Ok(())
     }
 }
+
+/// Run the body once if the cell at `offset` is not zero
+///
+/// This is a loop whose body always leaves the cell at zero, so it never repeats
+#[derive(
+    Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Encode, Decode,
+)]
+pub struct If {
+    pub body: Block,
+    pub offset: isize,
+}
+impl Display for If {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "if\t@{} [", self.offset)?;
+        for node in &self.body.0 {
+            writeln!(indented(f), "{}", node)?
+        }
+        write!(f, "]")?;
+        Ok(())
+    }
+}
+
+/// Select another tape, `amount` tapes after the current one
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Encode, Decode,
+)]
+pub struct SwitchTape {
+    pub amount: NonZeroIsize,
+}
+impl Display for SwitchTape {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "tape\t{}", self.amount)
+    }
+}
+
+/// Set a cell to a constant
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Encode, Decode,
+)]
+pub struct Set {
+    pub value: u8,
+    pub offset: isize,
+}
+impl Display for Set {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "set\t{}\t@{}", self.value, self.offset)
+    }
+}
+
+/// Output constant bytes
+#[derive(
+    Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Encode, Decode,
+)]
+pub struct Print {
+    pub bytes: Vec<u8>,
+}
+impl Display for Print {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "print\t\"{}\"", self.bytes.escape_ascii())
+    }
+}
+
+/// Multiply and add
+///
+/// Add `cell[offset] * expr` to each target cell, then clear `cell[offset]`.
+/// All the expressions are evaluated before any cell is modified
+#[derive(
+    Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Encode, Decode,
+)]
+pub struct Mul {
+    pub offset: isize,
+    pub targets: Vec<(isize, Affine)>,
+}
+impl Display for Mul {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "mul\t\t@{}\t[", self.offset)?;
+        for (i, (target, expr)) in self.targets.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?
+            }
+            write!(f, "@{target} += {expr}")?
+        }
+        write!(f, "]")
+    }
+}
+
+/// Affine combination of cells: `constant + sum(coefficient * cell[offset])`
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Default,
+    Serialize,
+    Deserialize,
+    Encode,
+    Decode,
+)]
+pub struct Affine {
+    pub constant: u8,
+    #[serde(deserialize_with = "deserialize_terms")]
+    pub terms: BTreeMap<isize, NonZeroU8>,
+}
+
+/// Read the terms of an affine expression
+///
+/// In json the offsets are the keys of an object, so they are written as strings. Inside a node
+/// serde buffers them without knowing they were keys, and would not parse them back.
+fn deserialize_terms<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<isize, NonZeroU8>, D::Error> {
+    #[derive(Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+    #[serde(untagged)]
+    enum Key {
+        Offset(isize),
+        Text(String),
+    }
+    BTreeMap::<Key, NonZeroU8>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(key, coefficient)| match key {
+            Key::Offset(offset) => Ok((offset, coefficient)),
+            Key::Text(text) => text
+                .parse()
+                .map(|offset| (offset, coefficient))
+                .map_err(de::Error::custom),
+        })
+        .collect()
+}
+impl Affine {
+    pub fn constant(constant: u8) -> Self {
+        Self {
+            constant,
+            terms: BTreeMap::new(),
+        }
+    }
+    /// The value of a single cell
+    pub fn cell(offset: isize) -> Self {
+        Self {
+            constant: 0,
+            terms: BTreeMap::from([(offset, NonZeroU8::new(1).unwrap())]),
+        }
+    }
+
+    pub fn is_constant(&self) -> bool {
+        self.terms.is_empty()
+    }
+    /// Coefficient of the given cell
+    pub fn coefficient(&self, offset: isize) -> u8 {
+        self.terms.get(&offset).map_or(0, |c| c.get())
+    }
+
+    /// Add `factor * other` to this expression
+    pub fn add_scaled(&mut self, other: &Affine, factor: u8) {
+        self.constant = self
+            .constant
+            .wrapping_add(other.constant.wrapping_mul(factor));
+        for (offset, coeff) in &other.terms {
+            let coeff = self
+                .coefficient(*offset)
+                .wrapping_add(coeff.get().wrapping_mul(factor));
+            match NonZeroU8::new(coeff) {
+                Some(coeff) => self.terms.insert(*offset, coeff),
+                None => self.terms.remove(offset),
+            };
+        }
+    }
+    /// This expression multiplied by `factor`
+    pub fn scaled(&self, factor: u8) -> Affine {
+        let mut scaled = Affine::default();
+        scaled.add_scaled(self, factor);
+        scaled
+    }
+
+    /// Replace every cell with its value in `values`, leaving the missing ones untouched
+    pub fn substitute(&self, values: &BTreeMap<isize, Affine>) -> Affine {
+        let mut result = Affine::constant(self.constant);
+        for (offset, coeff) in &self.terms {
+            match values.get(offset) {
+                Some(value) => result.add_scaled(value, coeff.get()),
+                None => result.add_scaled(&Affine::cell(*offset), coeff.get()),
+            }
+        }
+        result
+    }
+
+    /// Evaluate the expression, reading the cells with `cell`
+    pub fn eval<E>(&self, mut cell: impl FnMut(isize) -> Result<u8, E>) -> Result<u8, E> {
+        let mut value = self.constant;
+        for (offset, coeff) in &self.terms {
+            value = value.wrapping_add(cell(*offset)?.wrapping_mul(coeff.get()))
+        }
+        Ok(value)
+    }
+
+    fn shifted(self, additional_offset: isize) -> Self {
+        Self {
+            constant: self.constant,
+            terms: self
+                .terms
+                .into_iter()
+                .map(|(offset, coeff)| (offset + additional_offset, coeff))
+                .collect(),
+        }
+    }
+}
+impl Display for Affine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.terms.is_empty() || self.constant != 0 {
+            write!(f, "{}", self.constant)?;
+        }
+        for (i, (offset, coeff)) in self.terms.iter().enumerate() {
+            if i > 0 || self.constant != 0 {
+                write!(f, " + ")?
+            }
+            if coeff.get() != 1 {
+                write!(f, "{coeff}*")?
+            }
+            write!(f, "@{offset}")?
+        }
+        Ok(())
+    }
+}
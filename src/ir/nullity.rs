@@ -0,0 +1,168 @@
+//! Cells known to be zero, or not zero
+//!
+//! A coarser abstraction of the values than [`Values`](super::Values), that stays sound with every
+//! overflow policy and on a tape whose content is not known. The cell tested by a loop is zero
+//! after it, and a cell that was set, or incremented from zero, is not: this is enough to find
+//! the loops that run at most once, and the ones that never run.
+
+use crate::engine::cell::Overflow;
+
+use super::{
+    values::{self, CellValues, Domain},
+    Block, Program,
+};
+
+/// What is known of a cell being zero
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Nullity {
+    /// The node is never reached
+    Never,
+    Zero,
+    NonZero,
+    Unknown,
+}
+
+impl Domain for Nullity {
+    const ANY: Self = Nullity::Unknown;
+    const NONE: Self = Nullity::Never;
+
+    fn only(value: u8) -> Self {
+        if value == 0 {
+            Nullity::Zero
+        } else {
+            Nullity::NonZero
+        }
+    }
+
+    fn known(&self) -> Option<u8> {
+        (*self == Nullity::Zero).then_some(0)
+    }
+
+    fn union(self, other: Self) -> Self {
+        match (self, other) {
+            (Nullity::Never, other) | (other, Nullity::Never) => other,
+            (a, b) if a == b => a,
+            _ => Nullity::Unknown,
+        }
+    }
+
+    fn without_zero(self) -> Self {
+        match self {
+            Nullity::Zero => Nullity::Never,
+            Nullity::Unknown => Nullity::NonZero,
+            other => other,
+        }
+    }
+
+    fn zero_only(self) -> Self {
+        match self {
+            Nullity::NonZero => Nullity::Never,
+            Nullity::Unknown => Nullity::Zero,
+            other => other,
+        }
+    }
+
+    fn added(self, amount: u8, overflow: Overflow) -> Self {
+        let up = (amount as i8) > 0;
+        match (self, overflow) {
+            (Nullity::Never, _) => Nullity::Never,
+            (Nullity::Zero, Overflow::Wrap) => Nullity::NonZero,
+            (_, Overflow::Wrap) => Nullity::Unknown,
+            // going up the cell cannot reach zero again
+            (_, Overflow::Saturate | Overflow::Trap) if up => Nullity::NonZero,
+            (Nullity::Zero, Overflow::Saturate) => Nullity::Zero,
+            // the program stops there
+            (Nullity::Zero, Overflow::Trap) => Nullity::Never,
+            (_, Overflow::Saturate | Overflow::Trap) => Nullity::Unknown,
+        }
+    }
+
+    fn mul_added(self, n: Self, factor: Option<u8>, _: Overflow) -> Self {
+        match (n, factor) {
+            (Nullity::Never, _) => Nullity::Never,
+            (Nullity::Zero, _) | (_, Some(0)) => self,
+            _ => self.union(Nullity::Unknown),
+        }
+    }
+}
+
+impl Program {
+    /// Find which cells are zero before each node
+    ///
+    /// Unlike [`Program::cell_values`], this is sound with every overflow policy.
+    pub fn nullities(&self, zeroed_tape: bool, overflow: Overflow) -> CellValues<Nullity> {
+        analyze(&self.0, zeroed_tape, overflow)
+    }
+}
+
+/// Find which cells are zero before each node of a block
+pub(super) fn analyze(block: &Block, zeroed_tape: bool, overflow: Overflow) -> CellValues<Nullity> {
+    let start = if zeroed_tape {
+        Nullity::Zero
+    } else {
+        Nullity::Unknown
+    };
+    CellValues::of_block_with(block, start, overflow)
+}
+
+/// Simplify the loops, ifs and sets on cells known to be zero or not
+///
+/// As the narrowing on the values of the cells, but for any tape and overflow policy.
+///
+/// Return if something changed
+pub(super) fn narrow_nullities(block: &mut Block, nullities: &CellValues<Nullity>) -> bool {
+    values::rewrite_with(block, nullities, values::narrow_node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Nullity;
+    use crate::{
+        engine::cell::Overflow,
+        ir::{passes::PassManager, If, Node, Program},
+    };
+
+    #[test]
+    fn nullities() {
+        let program = Program::from_raw_unoptimized(",[>+<-]>[<+>[-]]".parse().unwrap());
+        let nullities = program.nullities(false, Overflow::Saturate);
+        // after the loop on it
+        assert_eq!(nullities.get(&[3], -1), Nullity::Zero);
+        assert_eq!(nullities.get(&[3], 0), Nullity::Unknown);
+        // inside the second loop the cell is not zero, and is still not after the increment
+        assert_eq!(nullities.get(&[3, 0], 0), Nullity::NonZero);
+        assert_eq!(nullities.get(&[3, 3], -1), Nullity::NonZero);
+        // the tape might not be zeroed, so the cells never touched are not known
+        assert_eq!(nullities.get(&[3], 5), Nullity::Unknown);
+        assert_eq!(
+            program.nullities(true, Overflow::Wrap).get(&[3], 5),
+            Nullity::Zero
+        );
+    }
+
+    #[test]
+    fn narrow() {
+        // the body always leaves the cell zero, as the decrement saturates, and with an unknown
+        // tape
+        let program = Program::from_raw_with_passes(
+            ",[.[-]-]".parse().unwrap(),
+            &PassManager::new()
+                .with_overflow(Overflow::Saturate)
+                .with_zeroed_tape(false),
+            |_| (),
+        );
+        assert!(
+            matches!(program.0 .0[..], [_, Node::If(If { .. })]),
+            "{program}"
+        );
+        // the second loop tests the same cell as the first one
+        let program = Program::from_raw_with_passes(
+            "[>+<-][.]".parse().unwrap(),
+            &PassManager::new()
+                .with_overflow(Overflow::Trap)
+                .with_zeroed_tape(false),
+            |_| (),
+        );
+        assert_eq!(program.stats().outputs, 0, "{program}");
+    }
+}
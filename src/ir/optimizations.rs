@@ -1,42 +1,432 @@
 //! Various ir optimizations
 
 use std::{
-    mem,
+    array,
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, BinaryHeap, VecDeque},
+    iter, mem,
     num::{NonZeroIsize, NonZeroU8},
+    slice,
 };
 
 use either::Either::{self, Left, Right};
 
-use super::{Add, Loop, Node, Shift};
+use crate::engine::cell::Overflow;
 
-const OPTIMIZATIONS_1: &[fn([Node; 1]) -> Either<[Node; 1], Vec<Node>>] = &[recurse, remove_noops];
-const OPTIMIZATIONS_2: &[fn([Node; 2]) -> Either<[Node; 2], Vec<Node>>] = &[
-    merge_instruction,
-    defer_shifts,
-    sort_ops,
-    remove_around_diverge,
+use super::{
+    nullity,
+    passes::{
+        Kind,
+        OptLevel::{O1, O2, O3},
+        Pass,
+    },
+    values, Add, Affine, Block, If, Input, Loop, Mul, Node, Output, Phase, Print, Reach, Set,
+    Shift, SwitchTape,
+};
+
+/// An optimization on `N` consecutive nodes, for cells with the given overflow policy
+pub(super) type Optimization<const N: usize> =
+    fn([Node; N], Overflow) -> Either<[Node; N], Vec<Node>>;
+
+/// The default pipeline, in the order the passes run
+pub(super) const PIPELINE: &[Pass] = &[
+    Pass::new("recurse", O1, Kind::Recurse),
+    Pass::new("remove-noops", O1, Kind::Single(remove_noops)),
+    Pass::new(
+        "normalize-balanced-loops",
+        O1,
+        Kind::Single(normalize_balanced_loops),
+    ),
+    Pass::new("lower-clear-loops", O1, Kind::Single(lower_clear_loops)),
+    Pass::new("lower-mul-loops", O2, Kind::Single(lower_mul_loops)),
+    Pass::new("lower-counted-loops", O3, Kind::Single(lower_counted_loops)).opt_in(),
+    Pass::new("lower-if-loops", O2, Kind::Single(lower_if_loops)),
+    Pass::new(
+        "lower-infinite-loops",
+        O2,
+        Kind::Single(lower_infinite_loops),
+    ),
+    Pass::new("merge-instructions", O1, Kind::Pair(merge_instruction)),
+    Pass::new("defer-shifts", O1, Kind::Pair(defer_shifts)),
+    Pass::new("sort-ops", O1, Kind::Pair(sort_ops)),
+    Pass::new(
+        "remove-around-diverge",
+        O2,
+        Kind::Pair(remove_around_diverge),
+    ),
+    Pass::new("unroll-known-loops", O3, Kind::Pair(unroll_known_loops)),
+    Pass::new("enter-known-branches", O2, Kind::Pair(enter_known_branches)),
+    Pass::new("fuse-loops", O1, Kind::Block(fuse_loops)),
+    Pass::new("dead-stores", O2, Kind::Block(eliminate_dead_stores)),
+    Pass::new("discard-dead-inputs", O2, Kind::Block(discard_dead_inputs)),
+    Pass::new("canonicalize", O3, Kind::Block(canonicalize)),
+    Pass::new("dead-code", O2, Kind::Trim(eliminate_dead_code)),
+    Pass::new(
+        "narrow-nullities",
+        O2,
+        Kind::WithNullities(nullity::narrow_nullities, Phase::Narrow),
+    ),
+    Pass::new(
+        "propagate-constants",
+        O3,
+        Kind::Global(propagate_constants, Phase::Propagate),
+    ),
+    Pass::new(
+        "narrow-values",
+        O3,
+        Kind::Global(values::narrow_values, Phase::Narrow),
+    ),
+    Pass::new(
+        "fold-outputs",
+        O3,
+        Kind::Global(values::fold_outputs, Phase::Narrow),
+    ),
+    Pass::new(
+        "fold-pure-loops",
+        O3,
+        Kind::Global(fold_pure_loops, Phase::Fold),
+    ),
+    Pass::new(
+        "evaluate-prefix",
+        O3,
+        Kind::Global(evaluate_prefix, Phase::Evaluate),
+    ),
 ];
 
-fn recurse(node: [Node; 1]) -> Either<[Node; 1], Vec<Node>> {
+fn remove_noops(node: [Node; 1], _: Overflow) -> Either<[Node; 1], Vec<Node>> {
     match node {
-        [Node::Loop(Loop { mut body, offset })] => {
-            if body.optimize() {
-                Right(vec![Node::Loop(Loop { body, offset })])
-            } else {
-                Left([Node::Loop(Loop { body, offset })])
+        [Node::Noop] => Right(vec![]),
+        [Node::Print(Print { bytes })] if bytes.is_empty() => Right(vec![]),
+        node => Left(node),
+    }
+}
+
+/// Fold the shifts inside balanced loops into the offsets of the other nodes
+///
+/// This is the result of deferring all the shifts to the end of the body, where they cancel out,
+/// in a single step. Unbalanced loops keep their shifts, as where they end depends on the memory.
+fn normalize_balanced_loops(node: [Node; 1], _: Overflow) -> Either<[Node; 1], Vec<Node>> {
+    let normalized = |body: Block| {
+        let mut pos = 0;
+        let mut nodes = vec![];
+        for node in body.0 {
+            match node {
+                Node::Shift(Shift { amount }) => pos += amount.get(),
+                node => nodes.push(node.shifted(pos)),
             }
         }
+        Block(nodes)
+    };
+    let has_shifts = |body: &Block| body.0.iter().any(|node| matches!(node, Node::Shift(_)));
+    match node {
+        [Node::Loop(Loop { body, offset })] if body.is_balanced() && has_shifts(&body) => {
+            Right(vec![Node::Loop(Loop {
+                body: normalized(body),
+                offset,
+            })])
+        }
+        [Node::If(If { body, offset })] if body.is_balanced() && has_shifts(&body) => {
+            Right(vec![Node::If(If {
+                body: normalized(body),
+                offset,
+            })])
+        }
         node => Left(node),
     }
 }
-fn remove_noops(node: [Node; 1]) -> Either<[Node; 1], Vec<Node>> {
+
+/// Lower loops like `[-]` into a [`Set`] of the cell to zero
+///
+/// Any odd amount reaches zero, possibly wrapping around, so this is done only for wrapping cells.
+fn lower_clear_loops(node: [Node; 1], overflow: Overflow) -> Either<[Node; 1], Vec<Node>> {
     match node {
-        [Node::Noop] => Right(vec![]),
+        [Node::Loop(Loop { body, offset })]
+            if overflow == Overflow::Wrap
+                && matches!(
+                    body.0[..],
+                    [Node::Add(Add { amount, offset: o })] if o == offset && amount.get() % 2 == 1
+                ) =>
+        {
+            Right(vec![Node::Set(Set { value: 0, offset })])
+        }
         node => Left(node),
     }
 }
 
-fn merge_instruction(nodes: [Node; 2]) -> Either<[Node; 2], Vec<Node>> {
+/// Lower loops whose body is an affine function of the memory into multiplications
+///
+/// Every cell touched by the body must either be an accumulator, incremented by the same
+/// amount at each iteration, or stable, reaching its final value after the first iteration.
+/// If the stable cells are changed by the first iteration, that one is peeled off.
+///
+/// The multiplication wraps around, so this is done only for wrapping cells.
+fn lower_mul_loops(node: [Node; 1], overflow: Overflow) -> Either<[Node; 1], Vec<Node>> {
+    lower_affine_loops(node, overflow, false)
+}
+/// Like [`lower_mul_loops`], for counters that step by any odd amount
+///
+/// The number of iterations is the counter times the inverse of the step, that exists modulo
+/// 256 only for odd steps. With even steps the loop might never end, so it is kept.
+fn lower_counted_loops(node: [Node; 1], overflow: Overflow) -> Either<[Node; 1], Vec<Node>> {
+    lower_affine_loops(node, overflow, true)
+}
+fn lower_affine_loops(
+    node: [Node; 1],
+    overflow: Overflow,
+    any_step: bool,
+) -> Either<[Node; 1], Vec<Node>> {
+    let [Node::Loop(Loop { body, offset })] = node else {
+        return Left(node);
+    };
+    if overflow != Overflow::Wrap {
+        return Left([Node::Loop(Loop { body, offset })]);
+    }
+    // without targets the multiplication just clears the counter
+    let lowered = |targets: Vec<_>| {
+        if targets.is_empty() {
+            Node::Set(Set { value: 0, offset })
+        } else {
+            Node::Mul(Mul { offset, targets })
+        }
+    };
+    match lower_mul_loop(&body, offset, any_step) {
+        Some((targets, false)) => Right(vec![lowered(targets)]),
+        Some((targets, true)) => {
+            let mut body = body;
+            body.0.push(lowered(targets));
+            Right(vec![Node::Loop(Loop { body, offset })])
+        }
+        None => Left([Node::Loop(Loop { body, offset })]),
+    }
+}
+/// Lower loops whose body always clears the cell they check into [`If`]s
+///
+/// The body must leave the pointer and the tape where they were, and end with the cell cleared.
+fn lower_if_loops(node: [Node; 1], _: Overflow) -> Either<[Node; 1], Vec<Node>> {
+    match node {
+        [Node::Loop(Loop { body, offset })] if clears(&body, offset) => {
+            Right(vec![Node::If(If { body, offset })])
+        }
+        node => Left(node),
+    }
+}
+/// Lower the loops that can never end once entered into an [`If`] around [`Node::Diverge`]
+///
+/// The body must do no input or output, leave the pointer and the tape where they were, and never
+/// write the cell the loop checks.
+fn lower_infinite_loops(node: [Node; 1], _: Overflow) -> Either<[Node; 1], Vec<Node>> {
+    match node {
+        [Node::Loop(Loop { body, offset })]
+            if !body
+                .0
+                .iter()
+                .any(|node| node.does_input() || node.does_output())
+                && stays_in_place(&body)
+                && !writes(&body.0, offset) =>
+        {
+            Right(vec![Node::If(If {
+                body: Block(vec![Node::Diverge]),
+                offset,
+            })])
+        }
+        node => Left(node),
+    }
+}
+/// Check if a block always ends with `cell` at zero, and the pointer where it started
+fn clears(body: &Block, cell: isize) -> bool {
+    let mut cleared = false;
+    let mut pos = 0;
+    for node in &body.0 {
+        match node {
+            Node::Noop | Node::Output(_) | Node::Print(_) | Node::InputDiscard => (),
+            Node::Shift(Shift { amount }) => pos += amount.get(),
+            Node::Add(Add { offset, .. }) | Node::Input(Input { offset }) => {
+                cleared &= pos + offset != cell
+            }
+            Node::Set(Set { value, offset }) => {
+                if pos + offset == cell {
+                    cleared = *value == 0
+                }
+            }
+            Node::Mul(Mul { offset, targets }) => {
+                if pos + offset == cell {
+                    cleared = true
+                } else if targets.iter().any(|(target, _)| pos + target == cell) {
+                    cleared = false
+                }
+            }
+            // both leave the cell they check at zero
+            Node::Loop(Loop { body, offset }) | Node::If(If { body, offset }) => {
+                if !stays_in_place(body) {
+                    return false;
+                }
+                if pos + offset == cell {
+                    cleared = true
+                } else if writes(&body.0, cell - pos) {
+                    cleared = false
+                }
+            }
+            Node::SwitchTape(_) | Node::Diverge => return false,
+        }
+    }
+    pos == 0 && cleared
+}
+/// Check if a block leaves the pointer and the tape where they were
+fn stays_in_place(body: &Block) -> bool {
+    body.is_balanced() && !switches_tape(body)
+}
+fn switches_tape(body: &Block) -> bool {
+    body.0.iter().any(|node| match node {
+        Node::SwitchTape(_) => true,
+        Node::Loop(Loop { body, .. }) | Node::If(If { body, .. }) => switches_tape(body),
+        _ => false,
+    })
+}
+/// Check if balanced nodes might write `cell`
+fn writes(nodes: &[Node], cell: isize) -> bool {
+    let mut pos = 0;
+    nodes.iter().any(|node| match node {
+        Node::Noop | Node::Output(_) | Node::Print(_) | Node::InputDiscard | Node::Diverge => false,
+        Node::Shift(Shift { amount }) => {
+            pos += amount.get();
+            false
+        }
+        Node::Add(Add { offset, .. })
+        | Node::Input(Input { offset })
+        | Node::Set(Set { offset, .. }) => pos + offset == cell,
+        Node::Mul(Mul { offset, targets }) => {
+            pos + offset == cell || targets.iter().any(|(target, _)| pos + target == cell)
+        }
+        Node::Loop(Loop { body, .. }) | Node::If(If { body, .. }) => writes(&body.0, cell - pos),
+        Node::SwitchTape(_) => true,
+    })
+}
+
+/// Find the targets of the multiplication equivalent to the loop, and if the first iteration must be peeled
+///
+/// Unless `any_step` is set, the counter must step by one.
+fn lower_mul_loop(
+    body: &Block,
+    counter: isize,
+    any_step: bool,
+) -> Option<(Vec<(isize, Affine)>, bool)> {
+    let effect = loop_effect(body)?;
+    // the number of iterations is `cell[counter]` times the inverse of the decrement
+    let factor = match effect.get(&counter)? {
+        step if *step == decremented(counter, 1) => 1,
+        step if *step == decremented(counter, u8::MAX) => u8::MAX,
+        step if any_step
+            && step.coefficient(counter) == 1
+            && step.terms.len() == 1
+            && step.constant % 2 == 1 =>
+        {
+            inverse(step.constant.wrapping_neg())
+        }
+        _ => return None,
+    };
+    let stable: Vec<_> = effect
+        .iter()
+        .filter(|(&cell, value)| value.substitute(&effect) == **value && cell != counter)
+        .map(|(&cell, _)| cell)
+        .collect();
+    let is_invariant = |cell: &isize| stable.contains(cell) || !effect.contains_key(cell);
+
+    let mut targets = vec![];
+    for (&cell, value) in &effect {
+        if cell == counter || stable.contains(&cell) {
+            continue;
+        }
+        // accumulator: `cell + delta`, with `delta` invariant after the first iteration
+        if value.coefficient(cell) != 1 {
+            return None;
+        }
+        let mut delta = value.clone();
+        delta.terms.remove(&cell);
+        if !delta.terms.keys().all(is_invariant) {
+            return None;
+        }
+        targets.push((cell, delta.scaled(factor)));
+    }
+    Some((targets, !stable.is_empty()))
+}
+/// Inverse of an odd number, modulo 256
+fn inverse(n: u8) -> u8 {
+    // each step of Newton's method doubles the correct bits, and `n` is its own inverse modulo 8
+    let mut inv = n;
+    for _ in 0..2 {
+        inv = inv.wrapping_mul(2u8.wrapping_sub(n.wrapping_mul(inv)))
+    }
+    inv
+}
+/// `cell[offset] - amount`
+fn decremented(offset: isize, amount: u8) -> Affine {
+    let mut value = Affine::cell(offset);
+    value.constant = amount.wrapping_neg();
+    value
+}
+/// Symbolically execute a loop body, returning the final value of each touched cell
+///
+/// Return `None` if the body is not an affine function of the memory, or it's not balanced
+fn loop_effect(body: &Block) -> Option<BTreeMap<isize, Affine>> {
+    let mut state: BTreeMap<isize, Affine> = BTreeMap::new();
+    let read = |state: &BTreeMap<isize, Affine>, cell: isize| {
+        state
+            .get(&cell)
+            .cloned()
+            .unwrap_or_else(|| Affine::cell(cell))
+    };
+    let mut pos = 0;
+    for node in &body.0 {
+        match node {
+            Node::Noop => (),
+            Node::Shift(Shift { amount }) => pos += amount.get(),
+            Node::Add(Add { amount, offset }) => {
+                let mut value = read(&state, pos + offset);
+                value.constant = value.constant.wrapping_add(amount.get());
+                state.insert(pos + offset, value);
+            }
+            Node::Mul(Mul { offset, targets }) => {
+                let n = read(&state, pos + offset);
+                let mut updated = vec![];
+                for (target, expr) in targets {
+                    let expr = expr.clone().shifted(pos).substitute(&state);
+                    // the product must stay affine
+                    let product = if expr.is_constant() {
+                        n.scaled(expr.constant)
+                    } else if n.is_constant() {
+                        expr.scaled(n.constant)
+                    } else {
+                        return None;
+                    };
+                    let mut value = read(&state, pos + target);
+                    value.add_scaled(&product, 1);
+                    updated.push((pos + target, value));
+                }
+                state.extend(updated);
+                state.insert(pos + offset, Affine::constant(0));
+            }
+            Node::Set(Set { value, offset }) => {
+                state.insert(pos + offset, Affine::constant(*value));
+            }
+            Node::Output(_)
+            | Node::Input(_)
+            | Node::InputDiscard
+            | Node::Loop(_)
+            | Node::If(_)
+            | Node::SwitchTape(_)
+            | Node::Print(_)
+            | Node::Diverge => return None,
+        }
+    }
+    if pos != 0 {
+        return None;
+    }
+    // removing cells that ended up unchanged
+    state.retain(|&cell, value| *value != Affine::cell(cell));
+    Some(state)
+}
+
+fn merge_instruction(nodes: [Node; 2], overflow: Overflow) -> Either<[Node; 2], Vec<Node>> {
     match nodes {
         // collating all shifts
         [Node::Shift(Shift { amount: a1 }), Node::Shift(Shift { amount: a2 })] => {
@@ -52,20 +442,71 @@ fn merge_instruction(nodes: [Node; 2]) -> Either<[Node; 2], Vec<Node>> {
         }), Node::Add(Add {
             amount: a2,
             offset: o2,
-        })] if o1 == o2 => Right(match NonZeroU8::new(u8::wrapping_add(a1.get(), a2.get())) {
-            Some(amount) => vec![Node::Add(Add { amount, offset: o1 })],
-            None => vec![],
-        }),
-        // removing consecutive loops with the same offsets
-        [Node::Loop(Loop { body, offset: o1 }), Node::Loop(Loop { offset: o2, .. })]
-            if o1 == o2 =>
+        })] if o1 == o2 && can_merge_adds(a1, a2, overflow) => {
+            Right(match NonZeroU8::new(u8::wrapping_add(a1.get(), a2.get())) {
+                Some(amount) => vec![Node::Add(Add { amount, offset: o1 })],
+                None => vec![],
+            })
+        }
+        // collating constant outputs
+        [Node::Print(Print { bytes: mut b1 }), Node::Print(Print { bytes: b2 })] => {
+            b1.extend(b2);
+            Right(vec![Node::Print(Print { bytes: b1 })])
+        }
+        // collating all tape switches
+        [Node::SwitchTape(SwitchTape { amount: a1 }), Node::SwitchTape(SwitchTape { amount: a2 })] => {
+            Right(match NonZeroIsize::new(a1.get() + a2.get()) {
+                Some(amount) => vec![Node::SwitchTape(SwitchTape { amount })],
+                None => vec![],
+            })
+        }
+        // adding to a cell just set
+        [Node::Set(Set { value, offset: o1 }), Node::Add(Add { amount, offset: o2 })]
+            if o1 == o2 && can_merge_set_add(value, amount, overflow) =>
         {
-            Right(vec![Node::Loop(Loop { body, offset: o1 })])
+            Right(vec![Node::Set(Set {
+                value: value.wrapping_add(amount.get()),
+                offset: o1,
+            })])
+        }
+        // overwriting a cell. Additions are removed only if they cannot trap
+        [Node::Set(Set { offset: o1, .. }), Node::Set(set)] if o1 == set.offset => {
+            Right(vec![Node::Set(set)])
+        }
+        [Node::Add(Add { offset: o1, .. }), Node::Set(set)]
+            if o1 == set.offset && overflow != Overflow::Trap =>
+        {
+            Right(vec![Node::Set(set)])
         }
         nodes => Left(nodes),
     }
 }
-fn defer_shifts(nodes: [Node; 2]) -> Either<[Node; 2], Vec<Node>> {
+/// The cell that is surely zero after the node
+fn zeroed_cell(node: &Node) -> Option<isize> {
+    match node {
+        Node::Loop(Loop { offset, .. })
+        | Node::If(If { offset, .. })
+        | Node::Mul(Mul { offset, .. })
+        | Node::Set(Set { value: 0, offset }) => Some(*offset),
+        _ => None,
+    }
+}
+/// Check if two additions to the same cell can be merged
+///
+/// If the cells do not wrap, an overflow in the middle is not the same as no overflow at all,
+/// so only additions in the same direction are merged, and only if their sum fits in the
+/// signed amount of a single addition.
+fn can_merge_adds(a1: NonZeroU8, a2: NonZeroU8, overflow: Overflow) -> bool {
+    let (a1, a2) = (a1.get() as i8, a2.get() as i8);
+    overflow == Overflow::Wrap || (a1 < 0) == (a2 < 0) && a1.checked_add(a2).is_some()
+}
+/// Check if an addition can be folded in the value just set
+///
+/// If the cells do not wrap, the sum must not overflow as a signed value.
+fn can_merge_set_add(value: u8, amount: NonZeroU8, overflow: Overflow) -> bool {
+    overflow == Overflow::Wrap || (value as i8).checked_add(amount.get() as i8).is_some()
+}
+fn defer_shifts(nodes: [Node; 2], _: Overflow) -> Either<[Node; 2], Vec<Node>> {
     match nodes {
         [Node::Shift(Shift { amount }), node] => Right(vec![
             node.shifted(amount.get()),
@@ -74,76 +515,1119 @@ fn defer_shifts(nodes: [Node; 2]) -> Either<[Node; 2], Vec<Node>> {
         nodes => Left(nodes),
     }
 }
-fn sort_ops([n1, n2]: [Node; 2]) -> Either<[Node; 2], Vec<Node>> {
+fn sort_ops([n1, n2]: [Node; 2], overflow: Overflow) -> Either<[Node; 2], Vec<Node>> {
     // if they commute, and are in the wrong order
-    if Node::commute(&n1, &n2) && n1 > n2 {
+    if Node::commute(&n1, &n2, overflow) && n1 > n2 {
         Right(vec![n2, n1])
     } else {
         Left([n1, n2])
     }
 }
-fn remove_around_diverge([n1, n2]: [Node; 2]) -> Either<[Node; 2], Vec<Node>> {
+fn remove_around_diverge([n1, n2]: [Node; 2], overflow: Overflow) -> Either<[Node; 2], Vec<Node>> {
     if n1.diverge() == Some(true) {
         // nothing to do after diverging
         return Right(vec![n1]);
     }
-    if n2.diverge() == Some(true) && !n1.does_output() {
+    if n2.diverge() == Some(true) && !n1.does_output() && !n1.does_input() && !n1.may_trap(overflow)
+    {
         // remove instruction with no side effect before diverging
         return Right(vec![n2]);
     }
     return Left([n1, n2]);
 }
 
-pub(super) fn optimize(nodes: Vec<Node>, changed: &mut bool) -> Vec<Node> {
-    let nodes = optimize_n(nodes, changed, OPTIMIZATIONS_1);
-    let nodes = optimize_n(nodes, changed, OPTIMIZATIONS_2);
-    nodes
+/// Largest number of nodes a loop is unrolled into
+const UNROLL_LIMIT: usize = 256;
+
+/// Run at compile time the loops on a cell just set, whose body changes it by a constant
+///
+/// The number of iterations is then known. If the body is an affine function of the memory the
+/// iterations are composed into a single multiplication by one, otherwise the short loops are
+/// unrolled. The multiplication wraps around, so this is done only for wrapping cells.
+fn unroll_known_loops(nodes: [Node; 2], overflow: Overflow) -> Either<[Node; 2], Vec<Node>> {
+    let [Node::Set(Set { value, offset }), Node::Loop(Loop {
+        body,
+        offset: counter,
+    })] = &nodes
+    else {
+        return Left(nodes);
+    };
+    if overflow != Overflow::Wrap || offset != counter || *value == 0 {
+        return Left(nodes);
+    }
+    // the outputs do not change the memory
+    let silent = Block(
+        body.0
+            .iter()
+            .filter(|node| !matches!(node, Node::Output(_) | Node::Print(_)))
+            .cloned()
+            .collect(),
+    );
+    let Some(effect) = loop_effect(&silent) else {
+        return Left(nodes);
+    };
+    let Some(trips) = effect
+        .get(counter)
+        .and_then(|step| trip_count(*value, step, *counter))
+    else {
+        return Left(nodes);
+    };
+    if silent.0.len() == body.0.len() {
+        // composing the effect of all the iterations
+        let mut state = BTreeMap::from([(*counter, Affine::constant(*value))]);
+        for _ in 0..trips {
+            let next: Vec<_> = effect
+                .iter()
+                .map(|(&cell, value)| (cell, value.substitute(&state)))
+                .collect();
+            state.extend(next);
+        }
+        let targets: Vec<_> = state
+            .into_iter()
+            .filter(|(cell, _)| cell != counter)
+            .filter_map(|(cell, mut value)| {
+                value.add_scaled(&Affine::cell(cell), u8::MAX);
+                (value != Affine::constant(0)).then_some((cell, value))
+            })
+            .collect();
+        Right(if targets.is_empty() {
+            vec![Node::Set(Set {
+                value: 0,
+                offset: *offset,
+            })]
+        } else {
+            vec![
+                Node::Set(Set {
+                    value: 1,
+                    offset: *offset,
+                }),
+                Node::Mul(Mul {
+                    offset: *offset,
+                    targets,
+                }),
+            ]
+        })
+    } else if trips * body.0.len() <= UNROLL_LIMIT {
+        let mut unrolled = vec![nodes[0].clone()];
+        for _ in 0..trips {
+            unrolled.extend(body.0.iter().cloned())
+        }
+        Right(unrolled)
+    } else {
+        Left(nodes)
+    }
 }
-fn optimize_n<const N: usize>(
-    mut nodes: Vec<Node>,
+/// Resolve the loops and ifs on a cell just set
+///
+/// On a zero cell they are skipped, otherwise the body of an if runs in place. This turns an if
+/// around [`Node::Diverge`] on a nonzero cell into a plain divergence.
+fn enter_known_branches(nodes: [Node; 2], _: Overflow) -> Either<[Node; 2], Vec<Node>> {
+    match nodes {
+        [set @ Node::Set(Set { value: 0, offset }), Node::Loop(Loop {
+            offset: counter, ..
+        })
+        | Node::If(If {
+            offset: counter, ..
+        })] if offset == counter => Right(vec![set]),
+        [set @ Node::Set(Set { offset, .. }), Node::If(If {
+            body,
+            offset: counter,
+        })] if offset == counter => Right(iter::once(set).chain(body.0).collect()),
+        nodes => Left(nodes),
+    }
+}
+/// Number of iterations of a loop on `counter`, starting at `value`, if each one has effect `step`
+///
+/// `None` if the step is not a constant increment, or the loop never ends.
+fn trip_count(value: u8, step: &Affine, counter: isize) -> Option<usize> {
+    if step.terms.len() != 1 || step.coefficient(counter) != 1 || step.constant == 0 {
+        return None;
+    }
+    (1..=256).find(|&trips| value.wrapping_add(step.constant.wrapping_mul(trips as u8)) == 0)
+}
+
+/// Remove the nodes at the ends of the program that have no effect
+///
+/// If the tape is known to start zeroed, the loops before anything is written cannot run. After
+/// the last input or output nothing can be observed, so the nodes that cannot diverge or trap
+/// are removed, unless other code might follow.
+pub(super) fn eliminate_dead_code(
+    block: &mut Block,
+    zeroed_tape: bool,
+    open_end: bool,
+    overflow: Overflow,
+) {
+    if zeroed_tape {
+        let leading = block
+            .0
+            .iter()
+            .take_while(|node| {
+                matches!(
+                    node,
+                    Node::Noop
+                        | Node::Shift(_)
+                        | Node::SwitchTape(_)
+                        | Node::Loop(_)
+                        | Node::If(_)
+                        | Node::Mul(_)
+                        | Node::Set(Set { value: 0, .. })
+                )
+            })
+            .count();
+        let rest = block.0.split_off(leading);
+        // the moves are kept, as the rest of the program depends on them
+        block
+            .0
+            .retain(|node| matches!(node, Node::Shift(_) | Node::SwitchTape(_)));
+        block.0.extend(rest);
+    }
+    if open_end {
+        return;
+    }
+    while let Some(node) = block.0.last() {
+        if node.diverge() != Some(false)
+            || node.does_output()
+            || node.does_input()
+            || can_trap(node, overflow)
+        {
+            break;
+        }
+        block.0.pop();
+    }
+}
+/// Propagate the values of the cells from the start of the program, where they are all zero
+///
+/// The additions to known cells become sets, their outputs become constant, and the loops and ifs
+/// on known cells are removed or inlined. The propagation stops at the first input, at a loop
+/// that runs, or at a cell before the start of the tape, where the program might fail. Only for
+/// wrapping cells.
+///
+/// Return if something changed
+pub(super) fn propagate_constants(block: &mut Block, overflow: Overflow) -> bool {
+    if overflow != Overflow::Wrap {
+        return false;
+    }
+    // the cells not in the map are zero
+    let mut known: BTreeMap<(usize, isize), u8> = BTreeMap::new();
+    let (mut tape, mut pos) = (0usize, 0isize);
+    let mut pending: VecDeque<_> = mem::take(&mut block.0).into();
+    let mut done = vec![];
+    let mut changed = false;
+    while let Some(node) = pending.pop_front() {
+        let cell = |offset: isize| (pos + offset >= 0).then_some((tape, pos + offset));
+        let value = |known: &BTreeMap<_, u8>, cell| known.get(&cell).copied().unwrap_or(0);
+        match node {
+            Node::Noop => changed = true,
+            Node::Shift(Shift { amount }) => {
+                pos += amount.get();
+                done.push(node)
+            }
+            Node::SwitchTape(SwitchTape { amount }) => {
+                match tape.checked_add_signed(amount.get()) {
+                    Some(new) => {
+                        tape = new;
+                        done.push(node)
+                    }
+                    None => {
+                        pending.push_front(node);
+                        break;
+                    }
+                }
+            }
+            Node::Add(Add { amount, offset }) => {
+                let Some(cell) = cell(offset) else {
+                    pending.push_front(node);
+                    break;
+                };
+                let sum = value(&known, cell).wrapping_add(amount.get());
+                known.insert(cell, sum);
+                done.push(Node::Set(Set { value: sum, offset }));
+                changed = true
+            }
+            Node::Set(Set { value, offset }) => {
+                let Some(cell) = cell(offset) else {
+                    pending.push_front(node);
+                    break;
+                };
+                known.insert(cell, value);
+                done.push(node)
+            }
+            Node::Output(Output { offset }) if cell(offset).is_some() => {
+                done.push(Node::Print(Print {
+                    bytes: vec![value(&known, cell(offset).unwrap())],
+                }));
+                changed = true
+            }
+            Node::Print(_) => done.push(node),
+            Node::Mul(Mul {
+                offset,
+                ref targets,
+            }) => {
+                let cells: Option<Vec<_>> = targets
+                    .iter()
+                    .flat_map(|(target, expr)| {
+                        [*target].into_iter().chain(expr.terms.keys().copied())
+                    })
+                    .chain([offset])
+                    .map(cell)
+                    .collect();
+                if cells.is_none() {
+                    pending.push_front(node);
+                    break;
+                }
+                let n = value(&known, cell(offset).unwrap());
+                if n != 0 {
+                    let increments: Vec<_> = targets
+                        .iter()
+                        .map(|(target, expr)| {
+                            let Ok(increment) =
+                                expr.eval(|o| Ok::<_, !>(value(&known, cell(o).unwrap())));
+                            (*target, increment)
+                        })
+                        .collect();
+                    for (target, increment) in increments {
+                        let cell = cell(target).unwrap();
+                        let sum = value(&known, cell).wrapping_add(n.wrapping_mul(increment));
+                        known.insert(cell, sum);
+                        done.push(Node::Set(Set {
+                            value: sum,
+                            offset: target,
+                        }));
+                    }
+                    known.insert(cell(offset).unwrap(), 0);
+                    done.push(Node::Set(Set { value: 0, offset }));
+                }
+                changed = true
+            }
+            Node::Loop(Loop { offset, .. })
+                if cell(offset).is_some_and(|c| value(&known, c) == 0) =>
+            {
+                changed = true
+            }
+            Node::If(If { offset, body }) if cell(offset).is_some() => {
+                if value(&known, cell(offset).unwrap()) != 0 {
+                    for node in body.0.into_iter().rev() {
+                        pending.push_front(node)
+                    }
+                }
+                changed = true
+            }
+            node => {
+                pending.push_front(node);
+                break;
+            }
+        }
+    }
+    done.extend(pending);
+    block.0 = done;
+    changed
+}
+
+/// Steps allowed when running the start of the program at compile time
+const PREFIX_BUDGET: u64 = 1 << 16;
+
+/// State of a program run at compile time, on wrapping cells
+#[derive(Debug, Clone, Default)]
+struct Evaluation {
+    /// The cells not in the map are zero, or unknown if `partial` is set
+    memory: BTreeMap<(usize, isize), u8>,
+    partial: bool,
+    tape: usize,
+    pos: isize,
+    output: Vec<u8>,
+}
+impl Evaluation {
+    fn cell(&self, offset: isize) -> Option<(usize, isize)> {
+        (self.pos + offset >= 0).then_some((self.tape, self.pos + offset))
+    }
+
+    fn get(&self, offset: isize) -> Option<u8> {
+        let cell = self.cell(offset)?;
+        match self.memory.get(&cell) {
+            Some(value) => Some(*value),
+            None if self.partial => None,
+            None => Some(0),
+        }
+    }
+
+    fn set(&mut self, offset: isize, value: u8) -> Option<()> {
+        let cell = self.cell(offset)?;
+        self.memory.insert(cell, value);
+        Some(())
+    }
+
+    /// Run a node, spending a unit of `fuel` for each node executed
+    ///
+    /// Return `None` if the node reads the input or an unknown cell, might fail, or the fuel ran
+    /// out. The state is then left halfway.
+    fn run(&mut self, node: &Node, fuel: &mut u64) -> Option<()> {
+        *fuel = fuel.checked_sub(1)?;
+        match node {
+            Node::Noop => (),
+            Node::Shift(Shift { amount }) => self.pos += amount.get(),
+            Node::Add(Add { amount, offset }) => {
+                let value = self.get(*offset)?.wrapping_add(amount.get());
+                self.set(*offset, value)?
+            }
+            Node::Output(Output { offset }) => {
+                let value = self.get(*offset)?;
+                self.output.push(value)
+            }
+            Node::Print(Print { bytes }) => self.output.extend(bytes),
+            Node::Input(_) | Node::InputDiscard | Node::Diverge => return None,
+            Node::Loop(Loop { body, offset }) => {
+                while self.get(*offset)? != 0 {
+                    *fuel = fuel.checked_sub(1)?;
+                    for node in &body.0 {
+                        self.run(node, fuel)?
+                    }
+                }
+            }
+            Node::Mul(Mul { offset, targets }) => {
+                let n = self.get(*offset)?;
+                if n != 0 {
+                    let increments = targets
+                        .iter()
+                        .map(|(target, expr)| {
+                            Some((*target, expr.eval(|o| self.get(o).ok_or(())).ok()?))
+                        })
+                        .collect::<Option<Vec<_>>>()?;
+                    for (target, increment) in increments {
+                        let value = self.get(target)?.wrapping_add(n.wrapping_mul(increment));
+                        self.set(target, value)?
+                    }
+                    self.set(*offset, 0)?
+                }
+            }
+            Node::SwitchTape(SwitchTape { amount }) => {
+                self.tape = self.tape.checked_add_signed(amount.get())?
+            }
+            Node::Set(Set { value, offset }) => self.set(*offset, *value)?,
+            Node::If(If { body, offset }) => {
+                if self.get(*offset)? != 0 {
+                    for node in &body.0 {
+                        self.run(node, fuel)?
+                    }
+                }
+            }
+        }
+        Some(())
+    }
+
+    /// Nodes that reproduce the outputs and the final state, starting from a zeroed tape
+    fn into_nodes(self) -> Vec<Node> {
+        let mut nodes = vec![Node::Print(Print { bytes: self.output })];
+        let mut tape = 0;
+        for ((cell_tape, offset), value) in self.memory {
+            if value == 0 {
+                continue;
+            }
+            if let Some(amount) = NonZeroIsize::new(cell_tape as isize - tape as isize) {
+                nodes.push(Node::SwitchTape(SwitchTape { amount }));
+                tape = cell_tape;
+            }
+            nodes.push(Node::Set(Set { value, offset }));
+        }
+        if let Some(amount) = NonZeroIsize::new(self.tape as isize - tape as isize) {
+            nodes.push(Node::SwitchTape(SwitchTape { amount }));
+        }
+        if let Some(amount) = NonZeroIsize::new(self.pos) {
+            nodes.push(Node::Shift(Shift { amount }));
+        }
+        nodes
+    }
+}
+
+/// Run the start of the program, up to the first input, and replace it with its outputs and the
+/// resulting state of the tape
+///
+/// Only the nodes that completely run within a budget of steps are replaced, and only if some of
+/// them are loops, ifs or multiplications. Only for wrapping cells.
+///
+/// Return if something changed
+pub(super) fn evaluate_prefix(block: &mut Block, overflow: Overflow) -> bool {
+    if overflow != Overflow::Wrap {
+        return false;
+    }
+    let mut state = Evaluation::default();
+    let mut fuel = PREFIX_BUDGET;
+    let mut evaluated = 0;
+    let mut folded = false;
+    for node in &block.0 {
+        let mut next = state.clone();
+        if next.run(node, &mut fuel).is_none() {
+            break;
+        }
+        state = next;
+        evaluated += 1;
+        folded |= matches!(node, Node::Loop(_) | Node::If(_) | Node::Mul(_));
+    }
+    if !folded {
+        return false;
+    }
+    block.0.splice(..evaluated, state.into_nodes());
+    true
+}
+
+/// Steps allowed when running a loop at compile time
+const FOLD_BUDGET: u64 = 1 << 12;
+
+/// Widest range of cells a loop run at compile time can access
+const FOLD_WIDTH: isize = 64;
+
+/// Run at compile time the loops, ifs and multiplications on known cells, replacing them with
+/// sets
+///
+/// The bodies must do no input or output, and leave the pointer and the tape where they were, so
+/// the cells they might access are known in advance. The node is folded if it only reads cells
+/// with a known value. Unlike the evaluation of the prefix, this works anywhere in the program.
+/// Only for wrapping cells.
+///
+/// Return if something changed
+pub(super) fn fold_pure_loops(block: &mut Block, overflow: Overflow) -> bool {
+    values::rewrite(block, overflow, |node, values, path, _| {
+        let pure = match &node {
+            Node::Mul(_) => true,
+            Node::Loop(Loop { body, .. }) | Node::If(If { body, .. }) => {
+                !body
+                    .0
+                    .iter()
+                    .any(|node| node.does_input() || node.does_output())
+                    && stays_in_place(body)
+            }
+            _ => false,
+        };
+        if !pure {
+            return Left(node);
+        }
+        let Some(Reach { min, max }) = Block(vec![node.clone()]).reach() else {
+            return Left(node);
+        };
+        if max - min >= FOLD_WIDTH {
+            return Left(node);
+        }
+        let before: BTreeMap<_, _> = (min..=max)
+            .filter_map(|offset| Some((offset, values.get(path, offset).known()?)))
+            .collect();
+        // the first cell of the range is put at the start of the tape
+        let mut state = Evaluation {
+            memory: before
+                .iter()
+                .map(|(&offset, &value)| ((0, offset - min), value))
+                .collect(),
+            partial: true,
+            pos: -min,
+            ..Evaluation::default()
+        };
+        let mut fuel = FOLD_BUDGET;
+        if state.run(&node, &mut fuel).is_none() {
+            return Left(node);
+        }
+        Right(
+            (min..=max)
+                .filter_map(|offset| {
+                    let value = state.get(offset)?;
+                    (before.get(&offset) != Some(&value))
+                        .then_some(Node::Set(Set { value, offset }))
+                })
+                .collect(),
+        )
+    })
+}
+
+/// Run the whole block at compile time, and return a block printing its output
+///
+/// Fails if the block reads the input, might fail, or does not halt within `budget` steps.
+/// Only for wrapping cells, starting from a zeroed tape.
+pub(super) fn precompute(block: &Block, budget: u64) -> Option<Block> {
+    let mut state = Evaluation::default();
+    let mut fuel = budget;
+    for node in &block.0 {
+        state.run(node, &mut fuel)?
+    }
+    Some(Block(if state.output.is_empty() {
+        vec![]
+    } else {
+        vec![Node::Print(Print {
+            bytes: state.output,
+        })]
+    }))
+}
+
+/// Check if a node might fail because of an overflow
+fn can_trap(node: &Node, overflow: Overflow) -> bool {
+    overflow == Overflow::Trap
+        && match node {
+            Node::Add(_) => true,
+            node => node
+                .as_block()
+                .is_some_and(|body| body.0.iter().any(|node| can_trap(node, overflow))),
+        }
+}
+
+/// Remove the additions and sets whose value is overwritten before being read
+///
+/// The block is scanned backwards, collecting the cells that are surely set before the next read.
+/// Inputs are not taken as overwriting the cell, as with some end of input policies they leave
+/// it unchanged. Anything that moves the pointer or might read any cell ends the scan.
+pub(super) fn eliminate_dead_stores(
+    nodes: Vec<Node>,
     changed: &mut bool,
-    optimizations: &'static [fn([Node; N]) -> Either<[Node; N], Vec<Node>>],
+    overflow: Overflow,
 ) -> Vec<Node> {
-    for i in 0..N {
-        // fast exit if we emptied the list
-        if nodes.len() < N {
-            return nodes;
+    // cells that are set before being read
+    let mut overwritten = BTreeSet::new();
+    let mut kept = Vec::with_capacity(nodes.len());
+    for node in nodes.into_iter().rev() {
+        let dead = match &node {
+            Node::Set(Set { offset, .. }) => overwritten.contains(offset),
+            Node::Add(Add { offset, .. }) => {
+                overwritten.contains(offset) && overflow != Overflow::Trap
+            }
+            _ => false,
+        };
+        if dead {
+            *changed = true;
+            continue;
+        }
+        track_overwrites(&node, &mut overwritten);
+        kept.push(node)
+    }
+    kept.reverse();
+    kept
+}
+
+/// Drop the value of the inputs overwritten before being read
+///
+/// The byte must still be consumed, so the input becomes a [`Node::InputDiscard`]. The cells are
+/// tracked as in [`eliminate_dead_stores`].
+pub(super) fn discard_dead_inputs(nodes: Vec<Node>, changed: &mut bool, _: Overflow) -> Vec<Node> {
+    let mut overwritten = BTreeSet::new();
+    let mut kept = Vec::with_capacity(nodes.len());
+    for node in nodes.into_iter().rev() {
+        let node = match node {
+            Node::Input(Input { offset }) if overwritten.contains(&offset) => {
+                *changed = true;
+                Node::InputDiscard
+            }
+            node => node,
+        };
+        track_overwrites(&node, &mut overwritten);
+        kept.push(node)
+    }
+    kept.reverse();
+    kept
+}
+
+/// Remove the loops on a cell that an earlier loop left at zero
+///
+/// After a loop, an if or a multiplication the cell they test is zero, so a later loop on it
+/// never runs, and the two are fused into the first one. The nodes in between must do no input
+/// or output, leave the pointer and the tape where they were, and never write the cell. The
+/// clears of that cell are removed in the same way.
+pub(super) fn fuse_loops(nodes: Vec<Node>, changed: &mut bool, _: Overflow) -> Vec<Node> {
+    // cells left at zero, and not written since
+    let mut zeroed = BTreeSet::new();
+    let mut kept = Vec::with_capacity(nodes.len());
+    for node in nodes {
+        if let Node::Loop(Loop { offset, .. })
+        | Node::If(If { offset, .. })
+        | Node::Set(Set { value: 0, offset }) = &node
+        {
+            if zeroed.contains(offset) {
+                *changed = true;
+                continue;
+            }
+        }
+        let pure = match &node {
+            Node::Noop | Node::Add(_) | Node::Set(_) | Node::Mul(_) => true,
+            Node::Loop(Loop { body, .. }) | Node::If(If { body, .. }) => {
+                stays_in_place(body)
+                    && !body
+                        .0
+                        .iter()
+                        .any(|node| node.does_input() || node.does_output())
+            }
+            _ => false,
+        };
+        if pure {
+            zeroed.retain(|cell| !writes(slice::from_ref(&node), *cell))
+        } else {
+            zeroed.clear()
+        }
+        if let Some(cell) = zeroed_cell(&node) {
+            zeroed.insert(cell);
+        }
+        kept.push(node)
+    }
+    kept
+}
+
+/// Update the cells set before being read, going back over `node`
+fn track_overwrites(node: &Node, overwritten: &mut BTreeSet<isize>) {
+    match node {
+        Node::Set(Set { offset, .. }) => {
+            overwritten.insert(*offset);
+        }
+        Node::Add(Add { offset, .. })
+        | Node::Output(Output { offset })
+        | Node::Input(Input { offset }) => {
+            overwritten.remove(offset);
+        }
+        Node::Mul(Mul { offset, targets }) => {
+            overwritten.remove(offset);
+            for (target, expr) in targets {
+                overwritten.remove(target);
+                for cell in expr.terms.keys() {
+                    overwritten.remove(cell);
+                }
+            }
+        }
+        Node::Noop | Node::Print(_) | Node::InputDiscard => (),
+        Node::Shift(_) | Node::Loop(_) | Node::If(_) | Node::SwitchTape(_) | Node::Diverge => {
+            overwritten.clear()
+        }
+    }
+}
+
+/// Move the shifts to the end of the block, and sort the nodes that commute
+///
+/// Of all the orders reachable by exchanging adjacent nodes that commute, the smallest is chosen,
+/// so they all give the same block. The nested blocks are left untouched.
+pub(super) fn canonicalize(nodes: Vec<Node>, changed: &mut bool, overflow: Overflow) -> Vec<Node> {
+    let last = nodes.len().saturating_sub(1);
+    let mut shift = 0;
+    let mut nodes: Vec<_> = nodes
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, node)| match node {
+            Node::Noop => {
+                *changed = true;
+                None
+            }
+            Node::Shift(Shift { amount }) => {
+                // a single shift at the end is already in place
+                *changed |= i != last;
+                shift += amount.get();
+                None
+            }
+            node => Some(node.shifted(shift)),
+        })
+        .collect();
+
+    // each node comes after the previous ones it does not commute with
+    let mut blockers = vec![0usize; nodes.len()];
+    let mut blocked = vec![vec![]; nodes.len()];
+    for i in 0..nodes.len() {
+        for j in 0..i {
+            if !nodes[j].commute(&nodes[i], overflow) {
+                blockers[i] += 1;
+                blocked[j].push(i);
+            }
         }
+    }
+    // always taking the smallest free node gives the smallest order
+    let mut free: BinaryHeap<_> = (0..nodes.len())
+        .filter(|&i| blockers[i] == 0)
+        .map(|i| Reverse((&nodes[i], i)))
+        .collect();
+    let mut order = Vec::with_capacity(nodes.len());
+    while let Some(Reverse((_, i))) = free.pop() {
+        order.push(i);
+        for &next in &blocked[i] {
+            blockers[next] -= 1;
+            if blockers[next] == 0 {
+                free.push(Reverse((&nodes[next], next)))
+            }
+        }
+    }
+    *changed |= order.iter().enumerate().any(|(pos, &i)| pos != i);
 
-        let (prefix, postfix) = nodes.split_at_mut(i);
-        let (chunks, postfix) = postfix.as_chunks_mut::<N>();
-        if chunks.is_empty() {
+    let mut sorted: Vec<_> = order
+        .into_iter()
+        .map(|i| mem::take(&mut nodes[i]))
+        .collect();
+    if let Some(amount) = NonZeroIsize::new(shift) {
+        sorted.push(Node::Shift(Shift { amount }))
+    }
+    sorted
+}
+
+/// Rewrite the windows of `N` consecutive nodes with the optimizations, until none applies
+///
+/// The nodes are scanned left to right. When a window is rewritten the replacement is scanned
+/// again, together with the `N - 1` nodes before it, so the rewrites made possible across the
+/// boundaries of the old window are found in the same sweep.
+pub(super) fn optimize_n<const N: usize>(
+    nodes: Vec<Node>,
+    changed: &mut bool,
+    overflow: Overflow,
+    optimizations: &[Optimization<N>],
+) -> Vec<Node> {
+    let mut done = Vec::with_capacity(nodes.len());
+    let mut todo = VecDeque::from(nodes);
+    while let Some(node) = todo.pop_front() {
+        done.push(node);
+        if done.len() < N {
             continue;
         }
+        let mut window: [Node; N] = array::from_fn(|_| done.pop().unwrap());
+        window.reverse();
 
-        let mut optimizing: Vec<_> = chunks
-            .into_iter()
-            .map(|ch| Left(mem::replace(ch, [(); N].map(|_| Default::default()))))
-            .collect();
+        let mut window = Left(window);
         for opt in optimizations {
-            optimizing = optimizing
-                .into_iter()
-                .map(|ch| match ch {
-                    Left(node) => opt(node),
-                    Right(nodes) => Right(nodes),
-                })
-                .collect()
+            let Left(nodes) = window else { break };
+            window = opt(nodes, overflow);
         }
-
-        // recollecting
-        let mut optimized: Vec<_> = prefix.into_iter().map(mem::take).collect();
-        for ch in optimizing {
-            match ch {
-                Left(nodes) => optimized.extend(nodes.into_iter()),
-                Right(nodes) => {
-                    *changed = true;
-                    optimized.extend(nodes.into_iter())
+        match window {
+            Left(window) => done.extend(window),
+            Right(replacement) => {
+                *changed = true;
+                for node in replacement.into_iter().rev() {
+                    todo.push_front(node)
+                }
+                // the nodes before are scanned again, as they are now next to the replacement
+                for node in done.drain(done.len().saturating_sub(N - 1)..).rev() {
+                    todo.push_front(node)
                 }
             }
         }
-        optimized.extend(postfix.into_iter().map(mem::take));
-        nodes = optimized
+    }
+    done
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        engine::{Engine, ProgrammableEngine, StopState},
+        ir::{
+            passes::{OptLevel, PassManager},
+            Block, If, Node, Print, Program, Set,
+        },
+    };
+
+    #[test]
+    fn clear_loops() {
+        let program: Program = ",[-]>,[+]++.[-]<[--]".parse().unwrap();
+        // the inputs are cleared before being read, so the cells keep the zero they start with,
+        // the additions are printed as a constant, and the loop on the cleared cell is removed
+        assert_eq!(
+            program.0 .0[..],
+            [
+                Node::InputDiscard,
+                Node::InputDiscard,
+                Node::Print(Print { bytes: vec![2] }),
+            ]
+        );
+        // an even amount might never reach zero
+        let program: Program = ",[--]".parse().unwrap();
+        assert!(matches!(program.0 .0[..], [_, Node::Loop(_)]));
+    }
+
+    #[test]
+    fn balanced_loops() {
+        let program: Program = ",[>+>,.<<-]".parse().unwrap();
+        let [_, Node::Loop(body)] = &program.0 .0[..] else {
+            panic!("Expected a single loop")
+        };
+        assert!(body.is_balanced());
+        assert!(!body
+            .body
+            .0
+            .iter()
+            .any(|node| matches!(node, Node::Shift(_))));
+
+        let program: Program = ",[>,.]".parse().unwrap();
+        let [_, Node::Loop(body)] = &program.0 .0[..] else {
+            panic!("Expected a single loop")
+        };
+        assert!(!body.is_balanced());
+        assert_eq!(body.body.net_shift(), Some(1));
+        assert_eq!(program.0.net_shift(), None);
+    }
+
+    #[test]
+    fn dead_code() {
+        for (program, nodes) in [
+            ("", 0),
+            ("[-][+]", 0),
+            ("[-]>[<+>-]+", 0),
+            (">[-]+.", 2),
+            (">[-]<[>+<-]>.", 1),
+            // reading input is observable
+            ("+,", 2),
+            ("+[-],[-]", 1),
+        ] {
+            let program: Program = program.parse().unwrap();
+            assert_eq!(program.0 .0.len(), nodes, "{program}");
+        }
+    }
+
+    #[test]
+    fn dead_stores() {
+        // the addition to the first cell is overwritten
+        let program: Program = ",>,+<+>.<[-]+.".parse().unwrap();
+        assert_eq!(program.stats().adds, 1);
+        assert_eq!(program.stats().sets, 1);
+        // with some end of input policies, the input might leave the cell unchanged
+        let program: Program = "+,.".parse().unwrap();
+        assert_eq!(program.stats().adds + program.stats().sets, 1);
+    }
+
+    #[test]
+    fn dead_inputs() {
+        // the first input is cleared before being read
+        let program: Program = ",[-]+.,.".parse().unwrap();
+        assert_eq!(program.stats().inputs, 2);
+        assert!(program.0 .0.contains(&Node::InputDiscard));
+        let mut engine = crate::engine::ir::Engine::new(program.clone());
+        engine.give_input_slice(b"ab");
+        assert_eq!(engine.run(), Ok(StopState::HasOutput(1)));
+        assert_eq!(engine.run(), Ok(StopState::HasOutput(b'b')));
+        assert_eq!(engine.run(), Ok(StopState::Halted));
+        // the byte is still needed
+        let mut engine = crate::engine::ir::Engine::new(program);
+        engine.close_input();
+        assert_eq!(engine.run(), Ok(StopState::EndOfInput));
+        // the value is read by the output
+        let program: Program = ",.[-]+.".parse().unwrap();
+        assert_eq!(program.stats().inputs, 1);
+        assert!(!program.0 .0.contains(&Node::InputDiscard));
+    }
+
+    #[test]
+    fn propagate_constants() {
+        // the multiplication and the loops run on known values
+        let program: Program = "++[->+++<]>[-<+>]<.>[.],[.]".parse().unwrap();
+        assert_eq!(program.stats().muls, 0);
+        assert_eq!(program.stats().loops, 1);
+        assert!(matches!(
+            program.0 .0[..2],
+            [
+                Node::Set(Set {
+                    value: 6,
+                    offset: 0
+                }),
+                Node::Print(Print { ref bytes })
+            ] if bytes[..] == [6]
+        ));
     }
 
-    nodes
+    #[test]
+    fn evaluate_prefix() {
+        // the loops are run, and only their outputs are left
+        let program: Program = "+++[.-]>++++[-<++>]<.,.".parse().unwrap();
+        assert_eq!(program.stats().loops, 0);
+        assert_eq!(program.stats().prints, 1);
+        assert_eq!(program.stats().outputs, 1);
+        // the loop runs forever, so it is left as it is
+        let program: Program = "+.[.]".parse().unwrap();
+        assert_eq!(program.stats().loops, 1);
+        // this one stops when the cell wraps around
+        let program: Program = "+.[+.]".parse().unwrap();
+        assert_eq!(program.stats().loops, 0);
+    }
+
+    #[test]
+    fn precompute() {
+        let mut program: Program = "++++++++[>++++++++<-]>+.+.>-[.-]".parse().unwrap();
+        assert!(program.precompute(1 << 12));
+        assert_eq!(
+            program.0 .0,
+            [Node::Print(Print {
+                bytes: [b"AB"[..].to_vec(), (1..=255).rev().collect()].concat()
+            })]
+        );
+        // reading the input, or not halting
+        for src in [",.", "+[]"] {
+            let mut program: Program = src.parse().unwrap();
+            assert!(!program.precompute(1 << 12));
+        }
+    }
+
+    #[test]
+    fn unroll_known_loops() {
+        use crate::engine::{Engine, ProgrammableEngine};
+
+        for (src, input, output) in [
+            // the cells depend on each other, so this is not a multiplication
+            (",>++++++[-<[->>+<<]>>[-<<++>>]<]<.", &[3][..], &[192][..]),
+            // the counter goes up by two
+            (",>--------[.++<+>]<.", &[1], &[248, 250, 252, 254, 5]),
+        ] {
+            let program: Program = src.parse().unwrap();
+            assert_eq!(program.stats().loops, 0, "{program}");
+            let mut engine = crate::engine::ir::Engine::new(program);
+            let mut input = input.iter().copied();
+            let mut out = vec![];
+            engine
+                .run_with_io(&mut || input.next(), &mut |ch| out.push(ch))
+                .unwrap();
+            assert_eq!(out, output);
+        }
+    }
+
+    #[test]
+    fn infinite_loops() {
+        // the loop never changes the cell it checks
+        let program: Program = ",[>+<]".parse().unwrap();
+        assert!(matches!(
+            &program.0 .0[1],
+            Node::If(If { body, .. }) if body.0 == [Node::Diverge]
+        ));
+        // the cell is known, so the program always diverges
+        let program: Program = "+.[>+<]".parse().unwrap();
+        assert_eq!(program.0 .0.last(), Some(&Node::Diverge));
+        // the cell is cleared by the input, or the body moves
+        for src in [",[>+<,]", ",[>]"] {
+            let program: Program = src.parse().unwrap();
+            assert_eq!(program.stats().diverges, 0);
+        }
+    }
+
+    #[test]
+    fn known_branches() {
+        // inside the loop the tape is not known, but the cell was just set
+        let program = Program::from_raw_with(",[[-]+[]]".parse().unwrap(), OptLevel::O2);
+        assert_eq!(
+            program.0 .0[1],
+            Node::If(If {
+                body: Block(vec![Node::Diverge]),
+                offset: 0
+            })
+        );
+        // the inner loop is skipped, so the outer one only clears the cells
+        let program = Program::from_raw_with(",[>[-][<+>]<-]".parse().unwrap(), OptLevel::O2);
+        assert_eq!(program.stats().loops + program.stats().ifs, 0);
+    }
+
+    #[test]
+    fn fold_pure_loops() {
+        // the nested multiplication runs on known cells, even after the input
+        let program =
+            Program::from_raw_with(",>+++[>++[>+<-]<-]>>.".parse().unwrap(), OptLevel::O3);
+        assert_eq!(program.stats().muls + program.stats().loops, 0);
+        assert!(program.0 .0.contains(&Node::Set(Set {
+            value: 6,
+            offset: 3
+        })));
+        // the counter is the input, so the loop stays
+        let program = Program::from_raw_with(",[>+++<-]>.".parse().unwrap(), OptLevel::O3);
+        assert_eq!(program.stats().muls, 1);
+    }
+
+    #[test]
+    fn fuse_loops() {
+        let fused = |src: &str| Program::from_raw_with(src.parse().unwrap(), OptLevel::O1);
+        // the nodes in between do not touch the cell
+        assert_eq!(fused(",[>,.<-][>.<-]").stats().loops, 1);
+        assert_eq!(fused(",[>,.<-]>+>[-]<<[>.<-]").stats().loops, 1);
+        // the cell is written, or the pointer moves
+        assert_eq!(fused(",[>,.<-]>[<+>-]<[>.<-]").stats().loops, 3);
+        assert_eq!(fused(",[>,.<-]>[>]<[>.<-]").stats().loops, 3);
+        // the output in between might be the last thing the program does
+        assert_eq!(fused(",[>,.<-].[>.<-]").stats().loops, 2);
+    }
+
+    #[test]
+    fn fuse_loops_differential() {
+        use crate::{
+            analysis::symexec::{check_equivalent, Bounds},
+            ir::check::random::Lcg,
+        };
+
+        let mut rng = Lcg::new(0);
+        // the later levels already remove most of these loops by tracking the zeroed cells
+        let mut unfused = PassManager::for_level(OptLevel::O1);
+        unfused.set_enabled("fuse-loops", false).unwrap();
+        let mut changed = 0;
+        for _ in 0..300 {
+            // loops on the first cell, and pieces that might or might not touch it
+            let source: String = (0..6)
+                .map(|_| {
+                    [
+                        ",", "[>,.<-]", "[>+<-]", "[-]", ">+<", ">[-]<", "+", "[>]", ".",
+                        ">[<+>-]<", "[[-]>.<]",
+                    ][rng.below(11)]
+                })
+                .collect();
+            let raw = Program::from_raw_unoptimized(source.parse().unwrap());
+            let fused = Program::from_raw_with(source.parse().unwrap(), OptLevel::O1);
+            let unfused = Program::from_raw_with_passes(source.parse().unwrap(), &unfused, |_| ());
+            changed += (fused != unfused) as usize;
+            let bounds = Bounds {
+                max_inputs: 3,
+                max_steps: 2000,
+                max_paths: 2000,
+                ..Bounds::default()
+            };
+            match check_equivalent(&raw, &fused, bounds) {
+                Ok(_) => (),
+                Err(err) if err.is_negative_tape_access() => {}
+                Err(err) => panic!("`{source}`: {err}: {err:?}\n{fused}"),
+            }
+        }
+        assert!(
+            changed > 30,
+            "only {changed} programs were changed by the fusion"
+        );
+    }
+
+    #[test]
+    fn if_loops() {
+        // the body clears the counter, but it is not a multiplication
+        let program: Program = ",[>,.<[-]]".parse().unwrap();
+        assert!(matches!(program.0 .0[..], [_, Node::If(_)]));
+        // the counter is changed again after being cleared
+        let program: Program = ",[>,.<[-]+]".parse().unwrap();
+        assert!(matches!(program.0 .0[..], [_, Node::Loop(_)]));
+        // the body does not end where it started
+        let program: Program = ",[[-]>,.]".parse().unwrap();
+        assert!(matches!(program.0 .0[..], [_, Node::Loop(_)]));
+    }
+
+    #[test]
+    fn single_sweep() {
+        // each shift is deferred past all the following additions in the same sweep
+        let mut program = Program::from_raw_unoptimized(",>+>+>+>+>+>+>+>+.".parse().unwrap());
+        assert!(program.0.optimize_once());
+        assert!(!program.0.optimize_once());
+        assert_eq!(program.stats().shifts, 1);
+    }
+
+    #[test]
+    fn canonical_form() {
+        let unoptimized = |src: &str| Program::from_raw_unoptimized(src.parse().unwrap());
+        // independent nodes in a different order, and the shifts in different places
+        assert!(unoptimized("+>+<.>-").equivalent(&unoptimized(">+<+.>-")));
+        assert!(unoptimized("[>+<,]>").equivalent(&unoptimized("[,>+<]>")));
+        assert!(unoptimized(",>+").equivalent(&unoptimized(">+<,>")));
+        // the output reads the cell after the addition
+        assert!(!unoptimized("+.").equivalent(&unoptimized(".+")));
+        assert!(!unoptimized("+>,").equivalent(&unoptimized("+,>")));
+
+        let mut program = unoptimized(">+<[->+<]");
+        program.canonicalize();
+        let mut again = program.clone();
+        again.canonicalize();
+        assert_eq!(program, again);
+    }
+
+    #[test]
+    fn counted_loops() {
+        assert!((1..=u8::MAX)
+            .step_by(2)
+            .all(|n| n.wrapping_mul(super::inverse(n)) == 1));
+
+        let src = ",[--->++<]>.";
+        assert_eq!(Program::from_raw(src.parse().unwrap()).stats().loops, 1);
+        let mut passes = PassManager::new();
+        passes.set_enabled("lower-counted-loops", true).unwrap();
+        let program = Program::from_raw_with_passes(src.parse().unwrap(), &passes, |_| ());
+        assert_eq!(program.stats().loops, 0);
+        // 7 is 173 times 3, modulo 256
+        let mut engine = crate::engine::ir::Engine::new(program);
+        engine.give_input_slice(&[7]);
+        assert_eq!(engine.run(), Ok(StopState::HasOutput(90)));
+    }
 }
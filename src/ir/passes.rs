@@ -0,0 +1,646 @@
+//! Configurable pipeline of optimization passes
+//!
+//! The optimizer is a list of named passes, run in order. The local passes rewrite single nodes,
+//! pairs of adjacent nodes or whole blocks, and are repeated until nothing changes. Then the
+//! global passes run on the whole program, and if they changed something the local passes start
+//! again. Each pass can be disabled by name, to select the optimizations to run.
+//!
+//! The optimizer can be given a budget of fuel or time. When it runs out, the optimizer stops
+//! between two passes, keeping the program as it is.
+
+use std::{
+    fmt::Debug,
+    mem,
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+
+use crate::engine::cell::Overflow;
+
+use super::{
+    nullity,
+    optimizations::{self, Optimization},
+    source_map::{Follow, SourceMap},
+    verify::verify_structure,
+    Annotations, Block, CellValues, Exhausted, If, Loop, Node, NotConverged, Nullity, Phase,
+    Program, Progress,
+};
+
+/// How aggressively the program is optimized
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum OptLevel {
+    /// No optimization, the ir mirrors the source
+    O0,
+    /// Merge the instructions, and lower the simplest loops
+    O1,
+    /// Lower all the loops that can be, and remove the dead code
+    O2,
+    /// Also unroll the loops, and evaluate what is known at compile time
+    #[default]
+    O3,
+}
+
+/// A named optimization pass
+#[derive(Clone, Copy)]
+pub struct Pass {
+    name: &'static str,
+    level: OptLevel,
+    opt_in: bool,
+    kind: Kind,
+}
+impl Pass {
+    pub(super) const fn new(name: &'static str, level: OptLevel, kind: Kind) -> Self {
+        Self {
+            name,
+            level,
+            opt_in: false,
+            kind,
+        }
+    }
+
+    /// Run the pass only if explicitly enabled, at any level
+    pub(super) const fn opt_in(self) -> Self {
+        Self {
+            opt_in: true,
+            ..self
+        }
+    }
+
+    /// Name of the pass, as accepted by [`PassManager::set_enabled`]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Lowest optimization level that runs the pass
+    pub fn level(&self) -> OptLevel {
+        self.level
+    }
+
+    /// Check if the pass is disabled unless enabled with [`PassManager::set_enabled`]
+    ///
+    /// Those passes are aggressive, so no optimization level runs them.
+    pub fn is_opt_in(&self) -> bool {
+        self.opt_in
+    }
+}
+impl Debug for Pass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Pass").field(&self.name).finish()
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(super) enum Kind {
+    /// Run the local passes on the bodies of the loops and ifs
+    Recurse,
+    /// Rewrite each node
+    Single(Optimization<1>),
+    /// Rewrite each pair of adjacent nodes
+    Pair(Optimization<2>),
+    /// Rewrite a whole block, setting the flag if something changed
+    Block(fn(Vec<Node>, &mut bool, Overflow) -> Vec<Node>),
+    /// Trim the program once the local passes converged, given if the tape starts zeroed and if
+    /// other code might follow the program
+    Trim(fn(&mut Block, bool, bool, Overflow)),
+    /// Rewrite the program once the local passes converged, assuming a zeroed tape
+    ///
+    /// Return if something changed, reported as the given phase
+    Global(fn(&mut Block, Overflow) -> bool, Phase),
+    /// As [`Kind::Global`], given which cells are zero before each node
+    ///
+    /// The analysis is sound for any tape, so those passes run even if it is not zeroed.
+    WithNullities(fn(&mut Block, &CellValues<Nullity>) -> bool, Phase),
+}
+
+/// The pass to enable or disable does not exist
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("Unknown optimization pass `{0}`")]
+pub struct UnknownPass(pub String);
+
+/// Pipeline of optimization passes, with the assumptions they can make on the program
+#[derive(Debug, Clone)]
+pub struct PassManager {
+    passes: Vec<(Pass, bool)>,
+    overflow: Overflow,
+    zeroed_tape: bool,
+    open_end: bool,
+    fuel: Option<u64>,
+    timeout: Option<Duration>,
+}
+
+impl Default for PassManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PassManager {
+    /// The full pipeline, for wrapping cells and a zeroed tape
+    pub fn new() -> Self {
+        Self::for_level(OptLevel::default())
+    }
+
+    /// The pipeline with the passes of the given level enabled
+    pub fn for_level(level: OptLevel) -> Self {
+        Self {
+            passes: optimizations::PIPELINE
+                .iter()
+                .map(|&pass| (pass, pass.level <= level && !pass.opt_in))
+                .collect(),
+            overflow: Overflow::Wrap,
+            zeroed_tape: true,
+            open_end: false,
+            fuel: None,
+            timeout: None,
+        }
+    }
+
+    /// The full pipeline, for a fragment of a program
+    ///
+    /// The fragment might start on any tape, and be followed by other code.
+    pub fn fragment() -> Self {
+        Self::new().with_zeroed_tape(false).with_open_end(true)
+    }
+
+    /// Optimize for cells with the given overflow policy
+    ///
+    /// With policies other than [`Overflow::Wrap`] the additions must be run as signed, and
+    /// fewer optimizations apply.
+    pub fn with_overflow(self, overflow: Overflow) -> Self {
+        Self { overflow, ..self }
+    }
+
+    /// Choose if the tape can be assumed zeroed at the start of the program
+    ///
+    /// This must be disabled if the memory is seeded before the program starts.
+    pub fn with_zeroed_tape(self, zeroed_tape: bool) -> Self {
+        Self {
+            zeroed_tape,
+            ..self
+        }
+    }
+
+    /// Choose if other code might run after the end of the program
+    ///
+    /// This must be enabled for the fragments that are joined to other code, as the nodes at the
+    /// end of a whole program that are not observed are removed.
+    pub fn with_open_end(self, open_end: bool) -> Self {
+        Self { open_end, ..self }
+    }
+
+    /// Stop the optimizer once it ran out of fuel
+    ///
+    /// Each pass over the program burns a unit of fuel for each node in it, so this bounds the
+    /// work done independently of the size of the program.
+    pub fn with_fuel(self, fuel: u64) -> Self {
+        Self {
+            fuel: Some(fuel),
+            ..self
+        }
+    }
+
+    /// Stop the optimizer after the given time from when it starts
+    ///
+    /// The time is checked between the passes, so a single long pass can overrun it.
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Self {
+            timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// The passes in the pipeline, in order, and if they are enabled
+    pub fn passes(&self) -> impl Iterator<Item = (Pass, bool)> + '_ {
+        self.passes.iter().copied()
+    }
+
+    /// Enable or disable the pass with the given name
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> Result<(), UnknownPass> {
+        let (_, flag) = self
+            .passes
+            .iter_mut()
+            .find(|(pass, _)| pass.name == name)
+            .ok_or_else(|| UnknownPass(name.to_owned()))?;
+        *flag = enabled;
+        Ok(())
+    }
+
+    fn enabled(&self) -> impl Iterator<Item = Kind> + '_ {
+        self.passes
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(pass, _)| pass.kind)
+    }
+
+    /// Run the local passes once on the block
+    ///
+    /// Return if something changed
+    pub fn sweep(&self, block: &mut Block) -> bool {
+        let kinds: Vec<_> = self.enabled().collect();
+        let mut changed = false;
+        let mut nodes = mem::take(&mut block.0);
+        let mut rest = &kinds[..];
+        while let Some(kind) = rest.first() {
+            // consecutive passes on the same number of nodes run together, node by node
+            match kind {
+                Kind::Recurse => {
+                    for node in &mut nodes {
+                        if let Node::Loop(Loop { body, .. }) | Node::If(If { body, .. }) = node {
+                            changed |= self.sweep(body)
+                        }
+                    }
+                    rest = &rest[1..]
+                }
+                Kind::Single(_) => {
+                    let group: Vec<_> = rest
+                        .iter()
+                        .map_while(|kind| match kind {
+                            Kind::Single(opt) => Some(*opt),
+                            _ => None,
+                        })
+                        .collect();
+                    rest = &rest[group.len()..];
+                    nodes = optimizations::optimize_n(nodes, &mut changed, self.overflow, &group)
+                }
+                Kind::Pair(_) => {
+                    let group: Vec<_> = rest
+                        .iter()
+                        .map_while(|kind| match kind {
+                            Kind::Pair(opt) => Some(*opt),
+                            _ => None,
+                        })
+                        .collect();
+                    rest = &rest[group.len()..];
+                    nodes = optimizations::optimize_n(nodes, &mut changed, self.overflow, &group)
+                }
+                Kind::Block(pass) => {
+                    nodes = pass(nodes, &mut changed, self.overflow);
+                    rest = &rest[1..]
+                }
+                Kind::Trim(_) | Kind::Global(..) | Kind::WithNullities(..) => rest = &rest[1..],
+            }
+        }
+        block.0 = nodes;
+        changed
+    }
+
+    /// Optimize the program, giving up if the fixpoint is not reached in `max_passes` passes
+    ///
+    /// The rounds of global passes are bounded in the same way. `progress` is called at each phase
+    /// boundary. Return the number of passes that changed the program. On failure the program is
+    /// left as the last pass made it, that is still correct.
+    pub fn optimize(
+        &self,
+        program: &mut Program,
+        max_passes: usize,
+        progress: impl FnMut(Progress),
+    ) -> Result<usize, NotConverged> {
+        self.run(program, None, max_passes, progress)
+    }
+
+    /// As [`PassManager::optimize`], keeping `map` aligned with the program
+    ///
+    /// Each phase is compared with the program before it, so this is slower.
+    pub fn optimize_mapped(
+        &self,
+        program: &mut Program,
+        map: &mut SourceMap,
+        max_passes: usize,
+        progress: impl FnMut(Progress),
+    ) -> Result<usize, NotConverged> {
+        self.run(program, Some(map), max_passes, progress)
+    }
+
+    /// As [`PassManager::optimize`], keeping the annotations of the nodes aligned with the program
+    ///
+    /// As with [`PassManager::optimize_mapped`] this is slower, unless there are no annotations.
+    pub fn optimize_annotated(
+        &self,
+        program: &mut Program,
+        annotations: &mut Annotations,
+        max_passes: usize,
+        progress: impl FnMut(Progress),
+    ) -> Result<usize, NotConverged> {
+        let map = (!annotations.is_empty()).then_some(annotations as &mut dyn Follow);
+        self.run(program, map, max_passes, progress)
+    }
+
+    fn run(
+        &self,
+        program: &mut Program,
+        mut map: Option<&mut (dyn Follow + 'static)>,
+        max_passes: usize,
+        mut progress: impl FnMut(Progress),
+    ) -> Result<usize, NotConverged> {
+        let mut budget = Budget {
+            fuel: self.fuel,
+            deadline: self.timeout.map(|timeout| Instant::now() + timeout),
+        };
+        let mut passes = 0;
+        let mut iteration = 0;
+        // the global passes run at least once, even if the local ones change nothing
+        let mut dirty = true;
+        loop {
+            budget.spend(program, passes)?;
+            if mapped(program, map.as_deref_mut(), |block| self.sweep(block)) {
+                check_structure(program, Phase::Pass);
+                passes += 1;
+                if passes > max_passes {
+                    return Err(NotConverged {
+                        passes: max_passes,
+                        cause: Exhausted::Passes,
+                    });
+                }
+                dirty = true;
+                progress(Progress {
+                    phase: Phase::Pass,
+                    passes,
+                    iteration,
+                    program,
+                });
+            } else if dirty {
+                // another round of trimming is needed only if something was folded
+                dirty = false;
+                for kind in self.enabled() {
+                    if let Kind::Trim(_) | Kind::Global(..) | Kind::WithNullities(..) = kind {
+                        budget.spend(program, passes)?
+                    }
+                    match kind {
+                        Kind::Trim(trim) => {
+                            mapped(program, map.as_deref_mut(), |block| {
+                                trim(block, self.zeroed_tape, self.open_end, self.overflow)
+                            });
+                            check_structure(program, Phase::Trim);
+                            progress(Progress {
+                                phase: Phase::Trim,
+                                passes,
+                                iteration,
+                                program,
+                            });
+                        }
+                        Kind::Global(pass, phase) => {
+                            if self.zeroed_tape
+                                && mapped(program, map.as_deref_mut(), |block| {
+                                    pass(block, self.overflow)
+                                })
+                            {
+                                check_structure(program, phase);
+                                dirty = true;
+                                progress(Progress {
+                                    phase,
+                                    passes,
+                                    iteration,
+                                    program,
+                                });
+                            }
+                        }
+                        Kind::WithNullities(pass, phase) => {
+                            let changed = mapped(program, map.as_deref_mut(), |block| {
+                                let nullities =
+                                    nullity::analyze(block, self.zeroed_tape, self.overflow);
+                                pass(block, &nullities)
+                            });
+                            if changed {
+                                check_structure(program, phase);
+                                dirty = true;
+                                progress(Progress {
+                                    phase,
+                                    passes,
+                                    iteration,
+                                    program,
+                                });
+                            }
+                        }
+                        Kind::Recurse | Kind::Single(_) | Kind::Pair(_) | Kind::Block(_) => (),
+                    }
+                }
+                iteration += 1;
+                if dirty && iteration > max_passes {
+                    return Err(NotConverged {
+                        passes,
+                        cause: Exhausted::Passes,
+                    });
+                }
+            } else {
+                progress(Progress {
+                    phase: Phase::Done,
+                    passes,
+                    iteration,
+                    program,
+                });
+                return Ok(passes);
+            }
+        }
+    }
+}
+
+/// Fuel and time left to the optimizer
+struct Budget {
+    fuel: Option<u64>,
+    deadline: Option<Instant>,
+}
+impl Budget {
+    /// Pay for a pass over the program, failing if the budget ran out
+    fn spend(&mut self, program: &Program, passes: usize) -> Result<(), NotConverged> {
+        let cause = if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            Exhausted::Time
+        } else if let Some(fuel) = &mut self.fuel {
+            match fuel.checked_sub(program.stats().nodes as u64) {
+                Some(left) => {
+                    *fuel = left;
+                    return Ok(());
+                }
+                None => Exhausted::Fuel,
+            }
+        } else {
+            return Ok(());
+        };
+        Err(NotConverged { passes, cause })
+    }
+}
+
+/// Run a step on the program, realigning the map if there is one
+fn mapped<R>(
+    program: &mut Program,
+    map: Option<&mut (dyn Follow + 'static)>,
+    step: impl FnOnce(&mut Block) -> R,
+) -> R {
+    let Some(map) = map else {
+        return step(&mut program.0);
+    };
+    let old = program.0.clone();
+    let result = step(&mut program.0);
+    if old != program.0 {
+        map.follow(&old, &program.0);
+    }
+    result
+}
+
+/// In debug builds, check that the phase left the program well formed
+fn check_structure(program: &Program, phase: Phase) {
+    if cfg!(debug_assertions) {
+        if let Err(err) = verify_structure(program) {
+            panic!("The ir is malformed after the {phase:?} phase: {err}")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{OptLevel, PassManager, UnknownPass};
+    use crate::{
+        engine::cell::Overflow,
+        ir::{check::random::arbitrary, Block, Exhausted, Node, NotConverged, Program, PASS_LIMIT},
+    };
+
+    fn optimized(src: &str, passes: &PassManager) -> Program {
+        Program::from_raw_with_passes(src.parse().unwrap(), passes, |_| ())
+    }
+
+    #[test]
+    fn select_passes() {
+        assert!(PassManager::new()
+            .passes()
+            .all(|(pass, enabled)| enabled != pass.is_opt_in() && !pass.name().is_empty()));
+
+        let mut passes = PassManager::new();
+        passes.set_enabled("lower-mul-loops", false).unwrap();
+        let program = optimized(",[->++<]>.", &passes);
+        assert!(matches!(program.0 .0[1], Node::Loop(_)));
+        assert!(matches!(
+            optimized(",[->++<]>.", &PassManager::new()).0 .0[1],
+            Node::Mul(_)
+        ));
+
+        // nothing runs, not even in the loops
+        let mut passes = PassManager::for_level(OptLevel::O0);
+        passes.set_enabled("remove-noops", true).unwrap();
+        let program = optimized("[++]+-", &passes);
+        assert_eq!(program.stats().adds, 4);
+
+        assert_eq!(
+            PassManager::new().set_enabled("nonexistent", false),
+            Err(UnknownPass("nonexistent".to_owned()))
+        );
+    }
+
+    #[test]
+    fn levels() {
+        let src = "+++[>+++[>++<-]<-]>>.,[-]<[>+<-]";
+        let levels = [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3]
+            .map(|level| Program::from_raw_with(src.parse().unwrap(), level).stats());
+        assert_eq!(levels[0].loops, 4);
+        assert_eq!(levels[0].adds, 13);
+        assert_eq!(levels[1].loops, 3);
+        // the last loop is on a cell already cleared
+        assert_eq!((levels[2].loops, levels[2].ifs, levels[2].muls), (0, 0, 2));
+        assert_eq!((levels[3].loops, levels[3].prints), (0, 1));
+        assert_eq!(Program::from_raw(src.parse().unwrap()).stats(), levels[3]);
+    }
+
+    #[test]
+    fn arbitrary_programs() {
+        let mut all = PassManager::new();
+        for (pass, _) in PassManager::new().passes() {
+            all.set_enabled(pass.name(), true).unwrap()
+        }
+        let pipelines = [
+            all,
+            PassManager::for_level(OptLevel::O2).with_zeroed_tape(false),
+            PassManager::new().with_overflow(Overflow::Saturate),
+            PassManager::new().with_overflow(Overflow::Trap),
+        ];
+        // no pipeline panics, or fails to converge
+        for seed in 0..300 {
+            let raw = arbitrary(seed, 48);
+            for passes in &pipelines {
+                let mut program = Program::from_raw_with(raw.clone(), OptLevel::O0);
+                if let Err(err) = passes.optimize(&mut program, PASS_LIMIT, |_| ()) {
+                    panic!("Optimizing `{raw}`: {err}")
+                }
+            }
+            Program::from_raw_with_source_map(raw, &PassManager::new());
+        }
+        assert_eq!(
+            Program::from_raw("".parse().unwrap()),
+            Program(Block(vec![]))
+        );
+    }
+
+    #[test]
+    fn fragments() {
+        // the additions at the end are kept for the code that follows
+        assert_eq!(optimized("+>+", &PassManager::new()).stats().adds, 0);
+        assert_eq!(optimized("+>+", &PassManager::fragment()).stats().adds, 2);
+        // the input is overwritten after the seam
+        let program = Program::fragment(",".parse().unwrap())
+            .concat(Program::fragment("[-]+.".parse().unwrap()));
+        assert!(program.0 .0.contains(&Node::InputDiscard), "{program}");
+        assert_eq!(program.stats().loops, 0);
+    }
+
+    #[test]
+    fn concat() {
+        use crate::{analysis::symexec::check_equivalent, ir::check::random::BOUNDS};
+
+        for seed in 0..100 {
+            let (first, second) = (arbitrary(seed, 12), arbitrary(seed + 1000, 12));
+            let joined = first.clone().concat(second.clone());
+            let raw = Program::from_raw_with(joined.clone(), OptLevel::O0);
+            let fragments = Program::fragment(first).concat(Program::fragment(second));
+            let mut whole = fragments.clone();
+            whole.optimize();
+            for program in [&fragments, &whole] {
+                match check_equivalent(&raw, program, BOUNDS) {
+                    Ok(_) => (),
+                    Err(err) if err.is_negative_tape_access() => {}
+                    Err(err) => panic!("`{joined}`: {err}: {err:?}\n{program}"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pass_limit() {
+        let mut program = Program::from_raw_with(",[->+<]>.".parse().unwrap(), OptLevel::O0);
+        assert_eq!(
+            PassManager::new().optimize(&mut program, 0, |_| ()),
+            Err(NotConverged {
+                passes: 0,
+                cause: Exhausted::Passes
+            })
+        );
+        // the program is left as the first pass made it
+        assert!(matches!(program.0 .0[1], Node::Mul(_)));
+    }
+
+    #[test]
+    fn budget() {
+        let src = ",[->+<]>.";
+        let unoptimized = || Program::from_raw_with(src.parse().unwrap(), OptLevel::O0);
+        let run = |passes: PassManager| {
+            let mut program = unoptimized();
+            let outcome = passes.optimize(&mut program, PASS_LIMIT, |_| ());
+            (program, outcome.map_err(|err| err.cause))
+        };
+        // enough for the first pass only
+        let nodes = unoptimized().stats().nodes as u64;
+        let (program, outcome) = run(PassManager::new().with_fuel(nodes));
+        assert_eq!(outcome, Err(Exhausted::Fuel));
+        assert!(program.stats().nodes < nodes as usize);
+
+        let (program, outcome) = run(PassManager::new().with_fuel(u64::MAX));
+        assert!(outcome.is_ok());
+        assert_eq!(program, run(PassManager::new()).0);
+
+        let (program, outcome) = run(PassManager::new().with_timeout(Duration::ZERO));
+        assert_eq!(outcome, Err(Exhausted::Time));
+        assert_eq!(program, unoptimized());
+    }
+}
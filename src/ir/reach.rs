@@ -0,0 +1,125 @@
+//! Cells a block may access
+//!
+//! The offsets are relative to the pointer at the start of the block. The engine checks them
+//! against the tape once when entering the block, instead of at each access.
+
+use serde::{Deserialize, Serialize};
+
+use super::{Add, Block, If, Input, Loop, Mul, Node, Output, Program, Set, Shift};
+
+/// Range of offsets from the starting pointer that a block may access
+///
+/// It always contains the starting cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Reach {
+    pub min: isize,
+    pub max: isize,
+}
+
+impl Reach {
+    fn include(&mut self, offset: isize) {
+        self.min = self.min.min(offset);
+        self.max = self.max.max(offset);
+    }
+}
+
+impl Block {
+    /// Range of the offsets the block, and the nested ones, may access
+    ///
+    /// `None` if it depends on the memory, because the block contains an unbalanced loop
+    pub fn reach(&self) -> Option<Reach> {
+        let mut reach = Reach { min: 0, max: 0 };
+        let mut shift = 0;
+        for node in &self.0 {
+            match node {
+                Node::Shift(Shift { amount }) => shift += amount.get(),
+                Node::Add(Add { offset, .. })
+                | Node::Output(Output { offset })
+                | Node::Input(Input { offset })
+                | Node::Set(Set { offset, .. }) => reach.include(shift + offset),
+                Node::Mul(Mul { offset, targets }) => {
+                    reach.include(shift + offset);
+                    for (target, expr) in targets {
+                        reach.include(shift + target);
+                        for cell in expr.terms.keys() {
+                            reach.include(shift + cell)
+                        }
+                    }
+                }
+                Node::Loop(Loop { body, offset }) | Node::If(If { body, offset }) => {
+                    if !body.is_balanced() {
+                        return None;
+                    }
+                    let inner = body.reach()?;
+                    reach.include(shift + offset);
+                    reach.include(shift + inner.min);
+                    reach.include(shift + inner.max);
+                }
+                Node::Noop
+                | Node::SwitchTape(_)
+                | Node::Print(_)
+                | Node::InputDiscard
+                | Node::Diverge => (),
+            }
+        }
+        Some(reach)
+    }
+}
+
+impl Program {
+    /// Find the first node that surely accesses a cell before the first one
+    ///
+    /// The program is assumed to start on the first cell. Only the nodes that surely run are
+    /// considered, so the search stops at the first loop, if or input.
+    pub fn first_underflow(&self) -> Option<usize> {
+        let mut shift = 0;
+        for (i, node) in self.0 .0.iter().enumerate() {
+            let offset = match node {
+                Node::Shift(Shift { amount }) => {
+                    shift += amount.get();
+                    continue;
+                }
+                Node::Add(Add { offset, .. })
+                | Node::Output(Output { offset })
+                | Node::Set(Set { offset, .. })
+                // the targets are accessed only if the counter is not zero
+                | Node::Mul(Mul { offset, .. }) => *offset,
+                // those might stop the program, or never end
+                Node::Input(Input { offset })
+                | Node::Loop(Loop { offset, .. })
+                | Node::If(If { offset, .. }) => {
+                    return (shift + offset < 0).then_some(i);
+                }
+                Node::InputDiscard | Node::Diverge => return None,
+                Node::Noop | Node::SwitchTape(_) | Node::Print(_) => continue,
+            };
+            if shift + offset < 0 {
+                return Some(i);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Reach;
+    use crate::ir::Program;
+
+    #[test]
+    fn reach() {
+        let program: Program = ">>,[-<+>>>+<<]<<.".parse().unwrap();
+        assert_eq!(program.0.reach(), Some(Reach { min: 0, max: 4 }));
+        assert_eq!(program.first_underflow(), None);
+
+        let program: Program = ",[>]<<".parse().unwrap();
+        assert_eq!(program.0.reach(), None);
+
+        let program: Program = "+>.<<-.".parse().unwrap();
+        assert_eq!(program.0.reach().unwrap().min, -1);
+        assert!(program.first_underflow().is_some());
+        // the loop might never end
+        let program: Program = ",[>,]<<<<-".parse().unwrap();
+        assert_eq!(program.first_underflow(), None);
+    }
+}
@@ -0,0 +1,299 @@
+//! Slicing of programs
+//!
+//! The slice of a program keeps only the nodes that the output, or the final value of a cell,
+//! depend on. The program is scanned backwards, collecting the cells whose value might still be
+//! observed, as in the elimination of the dead stores. The moves of the pointer and the inputs
+//! are always kept, so the nodes that are left access the same cells and read the same bytes.
+
+use std::collections::BTreeSet;
+
+use super::{Add, Block, If, Input, Loop, Mul, Node, Output, Program, Set, Shift};
+
+/// What the slice of a program must preserve
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Criterion {
+    /// Everything the program outputs
+    Output,
+    /// The value of a cell at the end of the program, given relative to where the pointer starts
+    Cell(isize),
+}
+
+impl Program {
+    /// Remove the nodes that have no influence on the output, or on the final value of a cell
+    ///
+    /// As usual for slices, the removed loops are assumed to end: the slice behaves as the
+    /// program whenever the program halts. With multiple tapes, the cells before the last switch
+    /// of tape are all kept.
+    pub fn slice(&self, criterion: Criterion) -> Program {
+        let mut live = Live::default();
+        if let Criterion::Cell(cell) = criterion {
+            match self.0.net_shift() {
+                Some(shift) => {
+                    live.cells.insert(cell - shift);
+                }
+                None => live.all = true,
+            }
+        }
+        let mut nodes = slice_block(&self.0, &mut live, criterion == Criterion::Output);
+        // the moves after the last node kept do nothing
+        while let Some(Node::Shift(_)) = nodes.last() {
+            nodes.pop();
+        }
+        Program(Block(nodes))
+    }
+}
+
+/// Cells whose value might still be observed, relative to the pointer
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+struct Live {
+    cells: BTreeSet<isize>,
+    /// Every cell is observed, as the position of the pointer is not known
+    all: bool,
+}
+impl Live {
+    fn contains(&self, cell: isize) -> bool {
+        self.all || self.cells.contains(&cell)
+    }
+
+    fn union(mut self, other: &Live) -> Live {
+        self.cells.extend(&other.cells);
+        self.all |= other.all;
+        self
+    }
+}
+
+/// Slice a block, given the cells observed after it
+///
+/// `live` is updated to the cells observed before the block.
+fn slice_block(block: &Block, live: &mut Live, output: bool) -> Vec<Node> {
+    let mut kept = vec![];
+    for node in block.0.iter().rev() {
+        match node {
+            Node::Noop => (),
+            Node::Shift(Shift { amount }) => {
+                live.cells = live.cells.iter().map(|cell| cell + amount.get()).collect();
+                kept.push(node.clone())
+            }
+            Node::SwitchTape(_) => {
+                live.all = true;
+                kept.push(node.clone())
+            }
+            Node::Add(Add { offset, .. }) => {
+                if live.contains(*offset) {
+                    kept.push(node.clone())
+                }
+            }
+            Node::Set(Set { offset, .. }) => {
+                if live.contains(*offset) {
+                    live.cells.remove(offset);
+                    kept.push(node.clone())
+                }
+            }
+            Node::Output(Output { offset }) => {
+                if output {
+                    live.cells.insert(*offset);
+                    kept.push(node.clone())
+                }
+            }
+            Node::Print(_) => {
+                if output {
+                    kept.push(node.clone())
+                }
+            }
+            // with some end of input policies the input leaves the cell unchanged, so it is still
+            // observed before it
+            Node::Input(Input { offset }) => kept.push(if live.contains(*offset) {
+                node.clone()
+            } else {
+                Node::InputDiscard
+            }),
+            Node::InputDiscard => kept.push(node.clone()),
+            Node::Diverge => {
+                *live = Live::default();
+                kept.push(node.clone())
+            }
+            Node::Mul(Mul { offset, targets }) => {
+                let targets: Vec<_> = targets
+                    .iter()
+                    .filter(|(target, _)| live.contains(*target))
+                    .cloned()
+                    .collect();
+                if !targets.is_empty() {
+                    live.cells.insert(*offset);
+                    for (_, expr) in &targets {
+                        live.cells.extend(expr.terms.keys())
+                    }
+                    kept.push(Node::Mul(Mul {
+                        offset: *offset,
+                        targets,
+                    }))
+                } else if live.contains(*offset) {
+                    // only the clearing of the counter is observed
+                    live.cells.remove(offset);
+                    kept.push(Node::Set(Set {
+                        value: 0,
+                        offset: *offset,
+                    }))
+                }
+            }
+            Node::Loop(Loop { body, offset }) => {
+                if let Some(body) = slice_body(body, *offset, live, output, true) {
+                    kept.push(Node::Loop(Loop {
+                        body,
+                        offset: *offset,
+                    }))
+                }
+            }
+            Node::If(If { body, offset }) => {
+                if let Some(body) = slice_body(body, *offset, live, output, false) {
+                    kept.push(Node::If(If {
+                        body,
+                        offset: *offset,
+                    }))
+                }
+            }
+        }
+    }
+    kept.reverse();
+    kept
+}
+
+/// Slice the body of a loop or an if on `offset`, or return `None` if it can be removed
+fn slice_body(
+    body: &Block,
+    offset: isize,
+    live: &mut Live,
+    output: bool,
+    repeat: bool,
+) -> Option<Block> {
+    if !body.is_balanced() {
+        // the cells after it are not known
+        live.all = true;
+        return Some(body.clone());
+    }
+    // ignoring the nodes that are only needed to end the loop
+    let (relevant, _) = slice_fixpoint(body, live.clone(), output, repeat);
+    if relevant.0.iter().all(|node| matches!(node, Node::Shift(_))) {
+        return None;
+    }
+    live.cells.insert(offset);
+    let (body, entry) = slice_fixpoint(body, live.clone(), output, repeat);
+    *live = entry;
+    Some(body)
+}
+
+/// Slice a body that might run again after it ends, until the cells observed are stable
+///
+/// Return the slice, and the cells observed before the loop
+fn slice_fixpoint(body: &Block, exit: Live, output: bool, repeat: bool) -> (Block, Live) {
+    let mut head = exit;
+    loop {
+        let mut entry = head.clone();
+        let kept = slice_block(body, &mut entry, output);
+        let next = head.clone().union(&entry);
+        if !repeat || next == head {
+            return (Block(kept), next);
+        }
+        head = next;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Criterion;
+    use crate::{
+        analysis::symexec::check_equivalent,
+        ir::{
+            check::{
+                random::{self, Lcg},
+                RunEnd,
+            },
+            Block, If, Loop, Node, Output, Program,
+        },
+    };
+
+    /// Remove the outputs of a block
+    fn silence(block: &Block) -> Block {
+        Block(
+            block
+                .0
+                .iter()
+                .filter(|node| !matches!(node, Node::Output(_) | Node::Print(_)))
+                .map(|node| match node {
+                    Node::Loop(Loop { body, offset }) => Node::Loop(Loop {
+                        body: silence(body),
+                        offset: *offset,
+                    }),
+                    Node::If(If { body, offset }) => Node::If(If {
+                        body: silence(body),
+                        offset: *offset,
+                    }),
+                    node => node.clone(),
+                })
+                .collect(),
+        )
+    }
+
+    /// Output only the cell at the end of the program
+    fn observe(program: &Program, cell: isize) -> Program {
+        let mut nodes = silence(&program.0).0;
+        nodes.push(Node::Output(Output {
+            offset: cell - program.0.net_shift().unwrap(),
+        }));
+        Program(Block(nodes))
+    }
+
+    #[test]
+    fn slices() {
+        let program = Program::from_raw(",>,>++++[<+>-]<<.>>>,.".parse().unwrap());
+        // the sum of the second input and 4 is never output
+        let sliced = program.slice(Criterion::Output);
+        assert_eq!(sliced.stats().muls + sliced.stats().adds, 0, "{sliced}");
+        assert_eq!(sliced.stats().inputs, 3, "{sliced}");
+        // but is the final value of the second cell
+        let sliced = program.slice(Criterion::Cell(1));
+        assert_eq!(
+            sliced.stats().outputs + sliced.stats().prints,
+            0,
+            "{sliced}"
+        );
+        assert_eq!(sliced.stats().muls + sliced.stats().adds, 1, "{sliced}");
+        // the loop only moves values that are not observed
+        let program = Program::from_raw(",[>,.<-]>>,[>+<-]<.".parse().unwrap());
+        let sliced = program.slice(Criterion::Output);
+        assert_eq!(sliced.stats().loops, 1, "{sliced}");
+        assert_eq!(sliced.stats().muls, 0, "{sliced}");
+        // the loops that move the pointer keep everything before them
+        let program = Program::from_raw(",>+>+[<]>.".parse().unwrap());
+        assert_eq!(program.slice(Criterion::Output), program);
+    }
+
+    #[test]
+    fn random_programs() {
+        let mut rng = Lcg::new(0);
+        for _ in 0..300 {
+            let source = random::source(&mut rng, 24);
+            let program: Program = source.parse().unwrap();
+            let mut pairs = vec![(program.clone(), program.slice(Criterion::Output))];
+            if program.0.net_shift().is_some() {
+                let cell = rng.below(5) as isize;
+                pairs.push((
+                    observe(&program, cell),
+                    observe(&program.slice(Criterion::Cell(cell)), cell),
+                ))
+            }
+            for (program, sliced) in pairs {
+                assert!(sliced.stats().nodes <= program.stats().nodes);
+                match check_equivalent(&program, &sliced, random::BOUNDS) {
+                    Ok(_) => (),
+                    // the slice might drop the accesses before the start of the tape, or the loops
+                    // that never end
+                    Err(err)
+                        if err.is_negative_tape_access() || err.first.end == RunEnd::OutOfSteps => {
+                    }
+                    Err(err) => panic!("`{source}`: {err}: {err:?}\n{program}\n{sliced}"),
+                }
+            }
+        }
+    }
+}
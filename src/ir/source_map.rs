@@ -0,0 +1,349 @@
+//! Positions in the source of the nodes of the ir
+//!
+//! A [`SourceMap`] follows the structure of a program, giving for each node the span of raw
+//! instructions it was made from. The optimizer keeps it up to date by comparing the program
+//! before and after each step: the nodes left untouched keep their span, while the new nodes
+//! take the span of all the nodes they replaced. The same alignment is used by the other
+//! [`NodeMap`]s, as the annotations.
+
+use std::{mem, ops::Range};
+
+use crate::raw::{self, Instruction};
+
+use super::{Block, If, Loop, Node};
+
+/// Range of indices of the raw instructions a node was made from
+pub type Span = Range<usize>;
+
+/// Largest product of the lengths of the changed parts of a block that are aligned node by node
+///
+/// Bigger changes are taken as a single replacement, and lose precision.
+const ALIGN_LIMIT: usize = 1 << 20;
+
+/// Values attached to the nodes of a block, and of the nested ones
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct NodeMap<T>(Vec<Mapped<T>>);
+
+/// Spans of the nodes of a block, and of the nested ones
+pub type SourceMap = NodeMap<Span>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Mapped<T> {
+    value: T,
+    /// Values of the body, for loops and ifs
+    body: NodeMap<T>,
+}
+
+impl<T> Default for NodeMap<T> {
+    fn default() -> Self {
+        Self(vec![])
+    }
+}
+
+/// Values that follow the nodes through the optimizer
+pub trait Merge: Clone + Default {
+    /// Value of the nodes replacing the ones with the given values, if there are any
+    fn merge<'a>(values: impl Iterator<Item = &'a Self>) -> Option<Self>
+    where
+        Self: 'a;
+
+    /// Value of a node inserted next to the one with value `near`, replacing nothing
+    fn inserted(near: &Self) -> Self;
+}
+
+/// The spans of the replaced nodes are covered, and the inserted nodes take the span of their
+/// neighbours
+impl Merge for Span {
+    fn merge<'a>(spans: impl Iterator<Item = &'a Self>) -> Option<Self> {
+        spans
+            .cloned()
+            .reduce(|a, b| a.start.min(b.start)..a.end.max(b.end))
+    }
+
+    fn inserted(near: &Self) -> Self {
+        near.clone()
+    }
+}
+
+/// Side data that the optimizer keeps aligned with the program
+pub(super) trait Follow {
+    /// Update the data of the block `old` to the one of `new`
+    fn follow(&mut self, old: &Block, new: &Block);
+}
+
+impl<T: Merge> Follow for NodeMap<T> {
+    fn follow(&mut self, old: &Block, new: &Block) {
+        *self = self.realign(old, new)
+    }
+}
+
+impl SourceMap {
+    /// Map of a raw program, translated into ir without optimizations
+    pub fn of_raw(program: &raw::Program) -> Self {
+        // start of each open loop, and the spans of its body
+        let mut stack = vec![(0, vec![])];
+        for (i, instr) in program.iter().enumerate() {
+            match instr {
+                Instruction::OpenLoop => stack.push((i, vec![])),
+                Instruction::CloseLoop => {
+                    let (start, body) = stack.pop().unwrap();
+                    stack.last_mut().unwrap().1.push(Mapped {
+                        value: start..i + 1,
+                        body: NodeMap(body),
+                    })
+                }
+                _ => stack.last_mut().unwrap().1.push(Mapped {
+                    value: i..i + 1,
+                    body: NodeMap::default(),
+                }),
+            }
+        }
+        let [(_, top)] = &mut stack[..] else {
+            unreachable!("The loops of a raw program are balanced")
+        };
+        NodeMap(mem::take(top))
+    }
+
+    /// Span of the node at the given path, as returned by [`crate::engine::Engine::position`]
+    ///
+    /// The positions at the end of a block have no span.
+    pub fn span(&self, path: &[usize]) -> Option<Span> {
+        self.get(path).cloned()
+    }
+}
+
+impl<T> NodeMap<T> {
+    /// Value of the node at the given path
+    pub(super) fn get(&self, path: &[usize]) -> Option<&T> {
+        let (&first, rest) = path.split_first()?;
+        let mapped = self.0.get(first)?;
+        if rest.is_empty() {
+            Some(&mapped.value)
+        } else {
+            mapped.body.get(rest)
+        }
+    }
+
+    /// Map of a block, taking the values from those of the nodes
+    pub(super) fn build(block: &Block, value: &mut impl FnMut(&[usize]) -> T) -> Self {
+        fn walk<T>(
+            block: &Block,
+            path: &mut Vec<usize>,
+            value: &mut impl FnMut(&[usize]) -> T,
+        ) -> NodeMap<T> {
+            NodeMap(
+                block
+                    .0
+                    .iter()
+                    .enumerate()
+                    .map(|(i, node)| {
+                        path.push(i);
+                        let mapped = Mapped {
+                            value: value(path),
+                            body: body(node)
+                                .map_or_else(NodeMap::default, |body| walk(body, path, value)),
+                        };
+                        path.pop();
+                        mapped
+                    })
+                    .collect(),
+            )
+        }
+        walk(block, &mut vec![], value)
+    }
+
+    /// Visit the values of all the nodes, with their path
+    pub(super) fn visit(&self, visit: &mut impl FnMut(&[usize], &T)) {
+        fn walk<T>(map: &NodeMap<T>, path: &mut Vec<usize>, f: &mut impl FnMut(&[usize], &T)) {
+            for (i, mapped) in map.0.iter().enumerate() {
+                path.push(i);
+                f(path, &mapped.value);
+                walk(&mapped.body, path, f);
+                path.pop();
+            }
+        }
+        walk(self, &mut vec![], visit)
+    }
+}
+
+impl<T: Merge> NodeMap<T> {
+    /// Map of the block `new`, if this is the map of `old`
+    pub(super) fn realign(&self, old: &Block, new: &Block) -> Self {
+        let outer = T::merge(self.0.iter().map(|mapped| &mapped.value)).unwrap_or_default();
+        self.realign_within(old, new, &outer)
+    }
+
+    /// As [`NodeMap::realign`], for a block inside a node with value `outer`
+    fn realign_within(&self, old: &Block, new: &Block, outer: &T) -> Self {
+        let (old_nodes, new_nodes) = (&old.0[..], &new.0[..]);
+        let prefix = old_nodes
+            .iter()
+            .zip(new_nodes)
+            .take_while(|(o, n)| o == n)
+            .count();
+        let suffix = old_nodes[prefix..]
+            .iter()
+            .rev()
+            .zip(new_nodes[prefix..].iter().rev())
+            .take_while(|(o, n)| o == n)
+            .count();
+        let old_end = old_nodes.len() - suffix;
+        let new_end = new_nodes.len() - suffix;
+
+        let mut values = self.0[..prefix].to_vec();
+        let (mut i, mut j) = (prefix, prefix);
+        let matches = common(&old_nodes[prefix..old_end], &new_nodes[prefix..new_end])
+            .into_iter()
+            .map(|(oi, nj)| (oi + prefix, nj + prefix));
+        for (oi, nj) in matches.chain([(old_end, new_end)]) {
+            // the inserted nodes might take their value from their neighbours
+            let near = values
+                .last()
+                .or(self.0.get(oi))
+                .map_or(outer, |mapped| &mapped.value);
+            values.extend(replaced(
+                &old_nodes[i..oi],
+                &self.0[i..oi],
+                &new_nodes[j..nj],
+                near,
+            ));
+            if oi < old_end {
+                values.push(self.0[oi].clone())
+            }
+            (i, j) = (oi + 1, nj + 1);
+        }
+        values.extend_from_slice(&self.0[old_end..]);
+        NodeMap(values)
+    }
+
+    /// Map of a block where all the nodes have the same value
+    fn uniform(block: &Block, value: &T) -> Self {
+        NodeMap(
+            block
+                .0
+                .iter()
+                .map(|node| Mapped {
+                    value: value.clone(),
+                    body: body(node)
+                        .map_or_else(NodeMap::default, |body| NodeMap::uniform(body, value)),
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Values of the nodes that replaced `old`
+///
+/// If the loops and ifs are still all there, they keep their value, and their bodies are aligned.
+/// Every other node takes the values of all the replaced nodes, and of their bodies, merged.
+fn replaced<T: Merge>(
+    old: &[Node],
+    old_values: &[Mapped<T>],
+    new: &[Node],
+    near: &T,
+) -> Vec<Mapped<T>> {
+    let mut values = vec![];
+    nested(old_values, &mut values);
+    let value = T::merge(values.into_iter()).unwrap_or_else(|| T::inserted(near));
+    let old_blocks: Vec<_> = old
+        .iter()
+        .zip(old_values)
+        .filter_map(|(node, mapped)| Some((body(node)?, mapped)))
+        .collect();
+    let mut old_blocks =
+        (old_blocks.len() == new.iter().filter_map(body).count()).then_some(old_blocks.into_iter());
+    new.iter()
+        .map(|node| match body(node) {
+            Some(new_body) => match old_blocks.as_mut().and_then(Iterator::next) {
+                Some((old_body, mapped)) => Mapped {
+                    value: mapped.value.clone(),
+                    body: mapped
+                        .body
+                        .realign_within(old_body, new_body, &mapped.value),
+                },
+                None => Mapped {
+                    value: value.clone(),
+                    body: NodeMap::uniform(new_body, &value),
+                },
+            },
+            None => Mapped {
+                value: value.clone(),
+                body: NodeMap::default(),
+            },
+        })
+        .collect()
+}
+
+/// Collect the values of the nodes, and of the nested ones
+fn nested<'a, T>(mapped: &'a [Mapped<T>], values: &mut Vec<&'a T>) {
+    for mapped in mapped {
+        values.push(&mapped.value);
+        nested(&mapped.body.0, values)
+    }
+}
+
+fn body(node: &Node) -> Option<&Block> {
+    match node {
+        Node::Loop(Loop { body, .. }) | Node::If(If { body, .. }) => Some(body),
+        _ => None,
+    }
+}
+
+/// Indices of the nodes in a longest common subsequence of the two slices
+fn common(old: &[Node], new: &[Node]) -> Vec<(usize, usize)> {
+    if old.len() * new.len() > ALIGN_LIMIT {
+        return vec![];
+    }
+    // length of the longest common subsequence of the suffixes
+    let width = new.len() + 1;
+    let mut lengths = vec![0u32; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i * width + j] = if old[i] == new[j] {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+            }
+        }
+    }
+    let mut matches = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            matches.push((i, j));
+            (i, j) = (i + 1, j + 1)
+        } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+            i += 1
+        } else {
+            j += 1
+        }
+    }
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SourceMap;
+    use crate::ir::{passes::PassManager, Node, Program};
+
+    #[test]
+    fn spans() {
+        let (program, map) = Program::from_raw_with_source_map(
+            ",>++<[->+++<]>.[-]+.".parse().unwrap(),
+            &PassManager::new(),
+        );
+        let span_of = |pred: fn(&Node) -> bool| {
+            let i = program.0 .0.iter().position(pred).unwrap();
+            map.span(&[i]).unwrap()
+        };
+        assert_eq!(span_of(|node| matches!(node, Node::Input(_))), 0..1);
+        // the multiplication comes from the loop, and from the moves merged around it
+        let mul = span_of(|node| matches!(node, Node::Mul(_)));
+        assert!(mul.start <= 5 && mul.end == 13, "{mul:?}");
+        assert_eq!(map.span(&[program.0 .0.len()]), None);
+
+        let map = SourceMap::of_raw(&"+[>,.<]".parse().unwrap());
+        assert_eq!(map.span(&[1]), Some(1..7));
+        assert_eq!(map.span(&[1, 1]), Some(3..4));
+    }
+}
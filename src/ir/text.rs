@@ -0,0 +1,310 @@
+//! Reading back the textual form of the ir
+//!
+//! The text is the one given by the [`Display`](std::fmt::Display) of [`Program`]: a node per
+//! line, with the bodies of the loops and ifs between `[` and `]`. The amount of whitespace and
+//! the indentation are not significant, and empty lines are skipped.
+
+use std::{collections::BTreeMap, num::NonZeroU8, str::FromStr};
+
+use thiserror::Error;
+
+use super::{
+    verify::verify_structure, Add, Affine, Block, If, Input, Loop, Mul, Node, Output, Print,
+    Program, Set, Shift, SwitchTape, VerifyError,
+};
+
+/// A line of the textual ir that cannot be read
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Error)]
+pub enum Malformed {
+    #[error("Unknown instruction `{0}`")]
+    UnknownInstruction(String),
+    #[error("Missing {0}")]
+    Missing(&'static str),
+    #[error("Invalid {expected} `{found}`")]
+    Invalid {
+        expected: &'static str,
+        found: String,
+    },
+    #[error("Unexpected `{0}` at the end of the line")]
+    Trailing(String),
+    #[error("A `]` closes no block")]
+    UnmatchedClose,
+    #[error("The block is never closed")]
+    Unclosed,
+}
+
+/// The textual ir cannot be read back
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Error)]
+pub enum ParseTextError {
+    #[error("Malformed ir at line {line}")]
+    Malformed {
+        /// Line of the error, starting from 1
+        line: usize,
+        #[source]
+        malformed: Malformed,
+    },
+    #[error(transparent)]
+    Invalid(#[from] VerifyError),
+}
+
+impl Program {
+    /// Read a program from its textual form, as given by its `Display`
+    ///
+    /// This is not [`FromStr`], that reads brainfuck.
+    pub fn from_text(text: &str) -> Result<Program, ParseTextError> {
+        // the blocks being read, with the node they are the body of
+        let mut open: Vec<(Vec<Node>, Option<Node>)> = vec![(vec![], None)];
+        let mut last = 0;
+        for (idx, line) in text.lines().enumerate() {
+            let error = |malformed| ParseTextError::Malformed {
+                line: idx + 1,
+                malformed,
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            last = idx + 1;
+            if line == "]" {
+                let (body, Some(mut node)) = open.pop().unwrap() else {
+                    return Err(error(Malformed::UnmatchedClose));
+                };
+                if let Node::Loop(Loop { body: slot, .. }) | Node::If(If { body: slot, .. }) =
+                    &mut node
+                {
+                    *slot = Block(body)
+                }
+                open.last_mut().unwrap().0.push(node);
+                continue;
+            }
+            let node = parse_node(line).map_err(error)?;
+            if let Node::Loop(_) | Node::If(_) = node {
+                open.push((vec![], Some(node)))
+            } else {
+                open.last_mut().unwrap().0.push(node)
+            }
+        }
+        let (nodes, None) = open.pop().unwrap() else {
+            return Err(ParseTextError::Malformed {
+                line: last,
+                malformed: Malformed::Unclosed,
+            });
+        };
+        let program = Program(Block(nodes));
+        verify_structure(&program)?;
+        Ok(program)
+    }
+}
+
+/// Parse a line with a single node, or the start of a loop or an if, with an empty body
+fn parse_node(line: &str) -> Result<Node, Malformed> {
+    let (instr, rest) = line
+        .split_once(char::is_whitespace)
+        .map_or((line, ""), |(instr, rest)| (instr, rest.trim()));
+    let mut args = rest.split_whitespace();
+    let node = match instr {
+        "noop" => Node::Noop,
+        "diverge" => Node::Diverge,
+        "shift" => Node::Shift(Shift {
+            amount: number(args.next(), "shift amount")?,
+        }),
+        "tape" => Node::SwitchTape(SwitchTape {
+            amount: number(args.next(), "tape amount")?,
+        }),
+        "add" => Node::Add(Add {
+            amount: number(args.next(), "amount")?,
+            offset: offset(args.next())?,
+        }),
+        "set" => Node::Set(Set {
+            value: number(args.next(), "value")?,
+            offset: offset(args.next())?,
+        }),
+        "output" => Node::Output(Output {
+            offset: offset(args.next())?,
+        }),
+        "input" if rest == "discard" => {
+            args.next();
+            Node::InputDiscard
+        }
+        "input" => Node::Input(Input {
+            offset: offset(args.next())?,
+        }),
+        "loop" | "if" => {
+            let offset = offset(args.next())?;
+            if args.next() != Some("[") {
+                return Err(Malformed::Missing("`[` opening the body"));
+            }
+            let body = Block(vec![]);
+            if instr == "loop" {
+                Node::Loop(Loop { body, offset })
+            } else {
+                Node::If(If { body, offset })
+            }
+        }
+        "print" => {
+            let bytes = rest
+                .strip_prefix('"')
+                .and_then(|rest| rest.strip_suffix('"'))
+                .ok_or(Malformed::Missing("quoted bytes"))?;
+            return Ok(Node::Print(Print {
+                bytes: unescape(bytes)?,
+            }));
+        }
+        "mul" => {
+            let (counter, targets) = rest
+                .split_once('[')
+                .ok_or(Malformed::Missing("`[` opening the targets"))?;
+            let targets = targets
+                .trim_end()
+                .strip_suffix(']')
+                .ok_or(Malformed::Missing("`]` closing the targets"))?;
+            return Ok(Node::Mul(Mul {
+                offset: offset(Some(counter.trim()))?,
+                targets: targets
+                    .split(',')
+                    .filter(|target| !target.trim().is_empty())
+                    .map(|target| {
+                        let (target, expr) = target
+                            .split_once("+=")
+                            .ok_or(Malformed::Missing("`+=` in a target"))?;
+                        Ok((offset(Some(target.trim()))?, affine(expr)?))
+                    })
+                    .collect::<Result<_, Malformed>>()?,
+            }));
+        }
+        _ => return Err(Malformed::UnknownInstruction(instr.to_owned())),
+    };
+    match args.next() {
+        Some(arg) => Err(Malformed::Trailing(arg.to_owned())),
+        None => Ok(node),
+    }
+}
+
+/// Parse a number, naming what was expected in the errors
+fn number<T: FromStr>(arg: Option<&str>, expected: &'static str) -> Result<T, Malformed> {
+    let arg = arg.ok_or(Malformed::Missing(expected))?;
+    arg.parse().map_err(|_| Malformed::Invalid {
+        expected,
+        found: arg.to_owned(),
+    })
+}
+
+/// Parse an offset, as `@-2`
+fn offset(arg: Option<&str>) -> Result<isize, Malformed> {
+    let arg = arg.ok_or(Malformed::Missing("offset"))?;
+    let number = arg.strip_prefix('@').ok_or_else(|| Malformed::Invalid {
+        expected: "offset",
+        found: arg.to_owned(),
+    })?;
+    self::number(Some(number), "offset")
+}
+
+/// Parse an affine expression, as `3 + 2*@1 + @-1`
+fn affine(expr: &str) -> Result<Affine, Malformed> {
+    let mut constant = 0u8;
+    let mut terms = BTreeMap::new();
+    for term in expr.split('+').map(str::trim) {
+        let (coefficient, cell) = match term.split_once('*') {
+            Some((coefficient, cell)) => (number(Some(coefficient.trim()), "coefficient")?, cell),
+            None if term.starts_with('@') => (NonZeroU8::MIN, term),
+            None => {
+                constant = constant.wrapping_add(number::<u8>(Some(term), "constant")?);
+                continue;
+            }
+        };
+        terms.insert(offset(Some(cell.trim()))?, coefficient);
+    }
+    Ok(Affine { constant, terms })
+}
+
+/// Reverse the escaping of [`u8::escape_ascii`]
+fn unescape(text: &str) -> Result<Vec<u8>, Malformed> {
+    let invalid = || Malformed::Invalid {
+        expected: "escape",
+        found: text.to_owned(),
+    };
+    let mut bytes = vec![];
+    let mut chars = text.bytes();
+    while let Some(byte) = chars.next() {
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        bytes.push(match chars.next().ok_or_else(invalid)? {
+            b't' => b'\t',
+            b'r' => b'\r',
+            b'n' => b'\n',
+            b'x' => {
+                let hex = [
+                    chars.next().ok_or_else(invalid)?,
+                    chars.next().ok_or_else(invalid)?,
+                ];
+                std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(invalid)?
+            }
+            escaped @ (b'\\' | b'\'' | b'"') => escaped,
+            _ => return Err(invalid()),
+        })
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Malformed, ParseTextError};
+    use crate::ir::{Invalid, Program};
+
+    #[test]
+    fn round_trip() {
+        for src in [
+            ",[>+<-]>.",
+            "+++[>+++[>++<-]<-]>>.",
+            ",[>,.<[-]]",
+            ",>,[<+>-]<.",
+            "++++++++[>++++++++<-]>+.+.+.[-]++++++++++.",
+            ",[.[-]-]",
+            "+[]",
+        ] {
+            let program: Program = src.parse().unwrap();
+            assert_eq!(
+                Program::from_text(&program.to_string()).as_ref(),
+                Ok(&program),
+                "{program}"
+            );
+        }
+        let program = Program::from_text("add 3 @1\n  mul @1 [ @0 += 2 + 3*@-1 ]\n\n").unwrap();
+        assert_eq!(Program::from_text(&program.to_string()), Ok(program));
+    }
+
+    #[test]
+    fn errors() {
+        let malformed = |text| match Program::from_text(text) {
+            Err(ParseTextError::Malformed { line, malformed }) => (line, malformed),
+            other => panic!("{other:?}"),
+        };
+        assert_eq!(
+            malformed("add 1 @0\njump 3"),
+            (2, Malformed::UnknownInstruction("jump".to_owned()))
+        );
+        assert_eq!(
+            malformed("add 0 @0").1,
+            Malformed::Invalid {
+                expected: "amount",
+                found: "0".to_owned()
+            }
+        );
+        assert_eq!(
+            malformed("set 1 @0 @1").1,
+            Malformed::Trailing("@1".to_owned())
+        );
+        assert_eq!(malformed("loop @0 [\noutput @0"), (2, Malformed::Unclosed));
+        assert_eq!(malformed("]"), (1, Malformed::UnmatchedClose));
+        // the structure of the nodes is checked
+        assert!(matches!(
+            Program::from_text("if @0 [\nshift 1\n]"),
+            Err(ParseTextError::Invalid(err)) if err.invalid == Invalid::UnbalancedIf
+        ));
+    }
+}
@@ -0,0 +1,608 @@
+//! Values the cells might hold
+//!
+//! A forward analysis finds, before each node, the set of values each cell might hold. The loops
+//! are run until their state stops changing, and after a few rounds the cells that still change
+//! are given up on. The sets are exact on 8-bit cells, so they capture both the ranges and the
+//! congruences of the values.
+//!
+//! The analysis is generic over the [`Domain`] abstracting the values of a cell, so coarser
+//! abstractions can be run with the same machinery.
+
+use std::{collections::BTreeMap, fmt::Debug, mem};
+
+use either::Either::{self, Left, Right};
+
+use crate::engine::cell::Overflow;
+
+use super::{Add, Block, If, Input, Loop, Mul, Node, Output, Print, Program, Set, Shift};
+
+/// Rounds a loop is analyzed for before the cells still changing are taken as unknown
+const WIDEN_AFTER: usize = 4;
+
+/// Largest number of combinations of values computed for a multiplication
+const MUL_LIMIT: u32 = 256;
+
+/// Set of values a cell might hold
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Values([u64; 4]);
+
+impl Values {
+    /// The cell might hold any value
+    pub const ANY: Self = Self([u64::MAX; 4]);
+    /// The node is never reached
+    pub const NONE: Self = Self([0; 4]);
+
+    /// The cell surely holds `value`
+    pub const fn only(value: u8) -> Self {
+        let mut words = [0; 4];
+        words[value as usize / 64] = 1 << (value % 64);
+        Self(words)
+    }
+
+    pub fn contains(&self, value: u8) -> bool {
+        self.0[value as usize / 64] & (1 << (value % 64)) != 0
+    }
+
+    /// The value of the cell, if it is known
+    pub fn known(&self) -> Option<u8> {
+        (self.len() == 1).then(|| self.iter().next().unwrap())
+    }
+
+    /// Number of possible values
+    pub fn len(&self) -> u32 {
+        self.0.iter().map(|word| word.count_ones()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::NONE
+    }
+
+    /// Smallest possible value
+    pub fn min(&self) -> Option<u8> {
+        self.iter().next()
+    }
+
+    /// Largest possible value
+    pub fn max(&self) -> Option<u8> {
+        self.iter().next_back()
+    }
+
+    /// The possible values, in increasing order
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = u8> + '_ {
+        (0..=u8::MAX).filter(|value| self.contains(*value))
+    }
+
+    /// The values after adding `amount`, wrapping around
+    fn wrapping_added(self, amount: u8) -> Self {
+        self.iter().fold(Self::NONE, |sum, value| {
+            sum.union(Self::only(value.wrapping_add(amount)))
+        })
+    }
+}
+
+impl Domain for Values {
+    const ANY: Self = Self::ANY;
+    const NONE: Self = Self::NONE;
+
+    fn only(value: u8) -> Self {
+        Self::only(value)
+    }
+
+    fn known(&self) -> Option<u8> {
+        Values::known(self)
+    }
+
+    fn union(self, other: Self) -> Self {
+        Self(std::array::from_fn(|i| self.0[i] | other.0[i]))
+    }
+
+    fn without_zero(self) -> Self {
+        let mut words = self.0;
+        words[0] &= !1;
+        Self(words)
+    }
+
+    fn zero_only(self) -> Self {
+        if self.contains(0) {
+            Self::only(0)
+        } else {
+            Self::NONE
+        }
+    }
+
+    fn added(self, amount: u8, overflow: Overflow) -> Self {
+        self.iter().fold(Self::NONE, |sum, value| {
+            match overflow.add_signed(value, amount as i8) {
+                Some(value) => sum.union(Self::only(value)),
+                // the program stops there
+                None => sum,
+            }
+        })
+    }
+
+    fn mul_added(self, n: Self, factor: Option<u8>, overflow: Overflow) -> Self {
+        match factor {
+            Some(factor)
+                if (overflow == Overflow::Wrap || n.without_zero().is_empty())
+                    && n.len() * self.len() <= MUL_LIMIT =>
+            {
+                n.iter().fold(Values::NONE, |values, n| {
+                    values.union(self.wrapping_added(n.wrapping_mul(factor)))
+                })
+            }
+            _ => Values::ANY,
+        }
+    }
+}
+
+impl Debug for Values {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if *self == Self::ANY {
+            return write!(f, "any");
+        }
+        // runs of consecutive values are shown as ranges
+        let mut set = f.debug_set();
+        let mut values = self.iter().peekable();
+        while let Some(start) = values.next() {
+            let mut end = start;
+            while values.next_if(|next| *next == end + 1).is_some() {
+                end += 1
+            }
+            if start == end {
+                set.entry(&start);
+            } else {
+                set.entry(&(start..=end));
+            }
+        }
+        set.finish()
+    }
+}
+
+/// Abstraction of the values a cell might hold
+pub trait Domain: Copy + Eq + Debug {
+    /// The cell might hold any value
+    const ANY: Self;
+    /// The node is never reached
+    const NONE: Self;
+
+    /// The closest abstraction of `value`
+    fn only(value: u8) -> Self;
+    /// The value of the cell, if it is known
+    fn known(&self) -> Option<u8>;
+    fn union(self, other: Self) -> Self;
+    /// The values left knowing that the cell is not zero
+    fn without_zero(self) -> Self;
+    /// The values left knowing that the cell is zero
+    fn zero_only(self) -> Self;
+    /// The values after adding `amount`, taken as signed, with the given overflow policy
+    fn added(self, amount: u8, overflow: Overflow) -> Self;
+    /// The values after adding `n` times `factor`, or an unknown expression if `factor` is `None`
+    fn mul_added(self, n: Self, factor: Option<u8>, overflow: Overflow) -> Self;
+
+    fn is_empty(&self) -> bool {
+        *self == Self::NONE
+    }
+}
+
+/// Values of the cells, relative to the pointer
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct State<D> {
+    cells: BTreeMap<isize, D>,
+    /// Values of the cells not in `cells`
+    rest: D,
+}
+
+impl<D: Domain> State<D> {
+    fn any() -> Self {
+        Self {
+            cells: BTreeMap::new(),
+            rest: D::ANY,
+        }
+    }
+
+    fn get(&self, offset: isize) -> D {
+        self.cells.get(&offset).copied().unwrap_or(self.rest)
+    }
+
+    fn set(&mut self, offset: isize, values: D) {
+        self.cells.insert(offset, values);
+    }
+
+    fn shifted(self, amount: isize) -> Self {
+        Self {
+            cells: self
+                .cells
+                .into_iter()
+                .map(|(offset, values)| (offset - amount, values))
+                .collect(),
+            rest: self.rest,
+        }
+    }
+
+    /// Restrict the cell at `offset`, returning `None` if it cannot hold any of the values
+    fn restricted(mut self, offset: isize, restrict: fn(D) -> D) -> Option<Self> {
+        let values = restrict(self.get(offset));
+        if values.is_empty() {
+            return None;
+        }
+        self.set(offset, values);
+        Some(self)
+    }
+
+    fn union(self, other: Self) -> Self {
+        let mut cells = self.cells;
+        for (&offset, values) in &mut cells {
+            *values = values.union(other.get(offset))
+        }
+        for (offset, values) in other.cells {
+            cells
+                .entry(offset)
+                .or_insert_with(|| self.rest.union(values));
+        }
+        Self {
+            cells,
+            rest: self.rest.union(other.rest),
+        }
+    }
+
+    /// Give up on the cells that changed from `old`
+    fn widened(self, old: &Self) -> Self {
+        let rest = if self.rest == old.rest {
+            self.rest
+        } else {
+            D::ANY
+        };
+        Self {
+            cells: self
+                .cells
+                .into_iter()
+                .map(|(offset, values)| {
+                    if values == old.get(offset) {
+                        (offset, values)
+                    } else {
+                        (offset, D::ANY)
+                    }
+                })
+                .collect(),
+            rest,
+        }
+    }
+}
+
+fn union<D: Domain>(a: Option<State<D>>, b: Option<State<D>>) -> Option<State<D>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.union(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Values of the cells before each node of a program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CellValues<D = Values> {
+    /// State before each node, or at the end of each block, by path
+    states: BTreeMap<Vec<usize>, Option<State<D>>>,
+}
+
+impl<D: Domain> CellValues<D> {
+    /// Analyze a block, starting from the given values of all the cells
+    ///
+    /// The additions are assumed to wrap around.
+    pub fn of_block(block: &Block, start: D) -> Self {
+        Self::of_block_with(block, start, Overflow::Wrap)
+    }
+
+    /// Analyze a block, for cells with the given overflow policy
+    pub fn of_block_with(block: &Block, start: D, overflow: Overflow) -> Self {
+        let mut values = CellValues {
+            states: BTreeMap::new(),
+        };
+        let start = State {
+            cells: BTreeMap::new(),
+            rest: start,
+        };
+        analyze(
+            block,
+            Some(start),
+            &mut Some((&mut values, &mut vec![])),
+            overflow,
+        );
+        values
+    }
+
+    /// Values the cell at `offset` from the pointer might hold before the node at `path`
+    ///
+    /// The path is the one given by [`crate::engine::Engine::position`], and can point to the
+    /// end of a block. Nodes that are never reached have no possible values.
+    pub fn get(&self, path: &[usize], offset: isize) -> D {
+        match self.states.get(path) {
+            Some(Some(state)) => state.get(offset),
+            Some(None) => D::NONE,
+            None => D::ANY,
+        }
+    }
+}
+
+impl Program {
+    /// Find the values the cells might hold before each node
+    ///
+    /// The tape can be assumed zeroed at the start of the program. The additions are assumed to
+    /// wrap around.
+    pub fn cell_values(&self, zeroed_tape: bool) -> CellValues {
+        CellValues::of_block(
+            &self.0,
+            if zeroed_tape {
+                Values::only(0)
+            } else {
+                Values::ANY
+            },
+        )
+    }
+}
+
+/// Where to record the states of the nodes, and the path of the block
+type Record<'r, D> = Option<(&'r mut CellValues<D>, &'r mut Vec<usize>)>;
+
+/// Run the analysis on a block, returning the state at the end
+fn analyze<D: Domain>(
+    block: &Block,
+    mut state: Option<State<D>>,
+    record: &mut Record<D>,
+    overflow: Overflow,
+) -> Option<State<D>> {
+    for (i, node) in block.0.iter().enumerate() {
+        if let Some((values, path)) = record {
+            path.push(i);
+            values.states.insert(path.clone(), state.clone());
+        }
+        state = step(node, state, record, overflow);
+        if let Some((_, path)) = record {
+            path.pop();
+        }
+    }
+    if let Some((values, path)) = record {
+        path.push(block.0.len());
+        values.states.insert(path.clone(), state.clone());
+        path.pop();
+    }
+    state
+}
+
+/// The state after a node. The path of the node is the last element of the recorded one
+fn step<D: Domain>(
+    node: &Node,
+    state: Option<State<D>>,
+    record: &mut Record<D>,
+    overflow: Overflow,
+) -> Option<State<D>> {
+    let Some(mut state) = state else {
+        // the nested blocks are never reached either
+        if let Some(body) = node.as_block() {
+            analyze(body, None, record, overflow);
+        }
+        return None;
+    };
+    match node {
+        Node::Noop | Node::Output(_) | Node::Print(_) | Node::InputDiscard => (),
+        Node::Shift(Shift { amount }) => state = state.shifted(amount.get()),
+        Node::Add(Add { amount, offset }) => {
+            state.set(*offset, state.get(*offset).added(amount.get(), overflow))
+        }
+        Node::Set(Set { value, offset }) => state.set(*offset, D::only(*value)),
+        Node::Input(Input { offset }) => state.set(*offset, D::ANY),
+        Node::Mul(Mul { offset, targets }) => {
+            let n = state.get(*offset);
+            let products: Vec<_> = targets
+                .iter()
+                .map(|(target, expr)| {
+                    let factor = expr.is_constant().then_some(expr.constant);
+                    let values = state.get(*target).mul_added(n, factor, overflow);
+                    (*target, values)
+                })
+                .collect();
+            for (target, values) in products {
+                state.set(target, values)
+            }
+            state.set(*offset, D::only(0))
+        }
+        // the other tapes are not tracked
+        Node::SwitchTape(_) => state = State::any(),
+        Node::Diverge => return None,
+        Node::If(If { body, offset }) => {
+            let taken = state.clone().restricted(*offset, D::without_zero);
+            let skipped = state.restricted(*offset, D::zero_only);
+            let end = with_body(record, |record| analyze(body, taken, record, overflow));
+            return union(end, skipped);
+        }
+        Node::Loop(Loop { body, offset }) => {
+            if !body.is_balanced() {
+                // after the first round the pointer is unknown
+                let entry = State::any().restricted(*offset, D::without_zero);
+                with_body(record, |record| analyze(body, entry, record, overflow));
+                return Some(State::any());
+            }
+            // find the state at the start of the body, once it stopped changing
+            let mut entry = state.clone().restricted(*offset, D::without_zero);
+            for round in 0.. {
+                let end = analyze(body, entry.clone(), &mut None, overflow);
+                let again = union(
+                    state.clone().restricted(*offset, D::without_zero),
+                    end.and_then(|end| end.restricted(*offset, D::without_zero)),
+                );
+                let again = match (again, &entry) {
+                    (Some(again), Some(entry)) if round >= WIDEN_AFTER => {
+                        Some(again.widened(entry))
+                    }
+                    (again, _) => again,
+                };
+                if again == entry {
+                    break;
+                }
+                entry = again;
+            }
+            let end = with_body(record, |record| analyze(body, entry, record, overflow));
+            return union(
+                state.restricted(*offset, D::zero_only),
+                end.and_then(|end| end.restricted(*offset, D::zero_only)),
+            );
+        }
+    }
+    Some(state)
+}
+
+/// Analyze the body of the node whose path is the recorded one
+fn with_body<D, R>(record: &mut Record<D>, analyze: impl FnOnce(&mut Record<D>) -> R) -> R {
+    analyze(
+        &mut record
+            .as_mut()
+            .map(|(values, path)| (&mut **values, &mut **path)),
+    )
+}
+
+/// Simplify the loops, ifs and sets using the possible values of the cells
+///
+/// Loops and ifs on a cell that is always zero are removed, and ifs on a cell that is never zero
+/// are replaced by their body. Loops whose body always leaves the cell zero run at most once, and
+/// become ifs. Sets of the value the cell already holds are removed. Only for wrapping cells.
+///
+/// Return if something changed
+pub(super) fn narrow_values(block: &mut Block, overflow: Overflow) -> bool {
+    rewrite(block, overflow, narrow_node)
+}
+
+/// Replace the outputs of cells with a known value with prints of that value
+///
+/// Unlike the propagation of the constants, this also works inside the loops, after an input, or
+/// where the pointer is not known. Only for wrapping cells.
+///
+/// Return if something changed
+pub(super) fn fold_outputs(block: &mut Block, overflow: Overflow) -> bool {
+    rewrite(block, overflow, |node, values, path, _| match node {
+        Node::Output(Output { offset }) => match values.get(path, offset).known() {
+            Some(byte) => Right(vec![Node::Print(Print { bytes: vec![byte] })]),
+            None => Left(node),
+        },
+        node => Left(node),
+    })
+}
+
+/// Rewrite of a node, given the values before it and the path of the end of its body
+pub(super) type Rewrite<D = Values> =
+    fn(Node, &CellValues<D>, &[usize], Option<Vec<usize>>) -> Either<Node, Vec<Node>>;
+
+/// Rewrite each node of the program, starting from a zeroed tape
+pub(super) fn rewrite(block: &mut Block, overflow: Overflow, rewrite: Rewrite) -> bool {
+    if overflow != Overflow::Wrap {
+        return false;
+    }
+    let values = CellValues::of_block(block, Values::only(0));
+    rewrite_with(block, &values, rewrite)
+}
+
+/// Rewrite each node of the block, with the values found by an analysis of it
+pub(super) fn rewrite_with<D: Domain>(
+    block: &mut Block,
+    values: &CellValues<D>,
+    rewrite: Rewrite<D>,
+) -> bool {
+    let mut changed = false;
+    rewrite_block(block, values, &mut vec![], &mut changed, rewrite);
+    changed
+}
+
+fn rewrite_block<D: Domain>(
+    block: &mut Block,
+    values: &CellValues<D>,
+    path: &mut Vec<usize>,
+    changed: &mut bool,
+    rewrite: Rewrite<D>,
+) {
+    let nodes = mem::take(&mut block.0);
+    for (i, mut node) in nodes.into_iter().enumerate() {
+        path.push(i);
+        // the end of the body, before it is rewritten
+        let mut end = None;
+        if let Node::Loop(Loop { body, .. }) | Node::If(If { body, .. }) = &mut node {
+            end = Some([&path[..], &[body.0.len()]].concat());
+            rewrite_block(body, values, path, changed, rewrite)
+        }
+        match rewrite(node, values, path, end) {
+            Left(node) => block.0.push(node),
+            Right(nodes) => {
+                *changed = true;
+                block.0.extend(nodes)
+            }
+        }
+        path.pop();
+    }
+}
+
+pub(super) fn narrow_node<D: Domain>(
+    node: Node,
+    values: &CellValues<D>,
+    path: &[usize],
+    end: Option<Vec<usize>>,
+) -> Either<Node, Vec<Node>> {
+    match node {
+        Node::Set(Set { value, offset }) if values.get(path, offset).known() == Some(value) => {
+            Right(vec![])
+        }
+        Node::Loop(Loop { offset, .. }) | Node::If(If { offset, .. })
+            if values.get(path, offset).without_zero().is_empty() =>
+        {
+            Right(vec![])
+        }
+        Node::If(If { body, offset }) if values.get(path, offset).zero_only().is_empty() => {
+            Right(body.0)
+        }
+        Node::Loop(Loop { body, offset }) if body.is_balanced() => {
+            let end = values.get(&end.expect("Loops have a body"), offset);
+            if end.without_zero().is_empty() {
+                Right(vec![Node::If(If { body, offset })])
+            } else {
+                Left(Node::Loop(Loop { body, offset }))
+            }
+        }
+        node => Left(node),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Domain, Values};
+    use crate::ir::{Node, Program};
+
+    #[test]
+    fn values() {
+        let program = Program::from_raw_unoptimized(",>++[-<+>]+<[>[-]+<-]".parse().unwrap());
+        let values = program.cell_values(true);
+        // after the first loop
+        assert_eq!(values.get(&[5], 0), Values::only(0));
+        assert_eq!(values.get(&[5], -1), Values::ANY);
+        // in the first loop the counter goes down from two
+        assert_eq!(
+            values.get(&[4, 0], 0),
+            Values::only(1).union(Values::only(2))
+        );
+        assert_eq!(format!("{:?}", values.get(&[4, 0], 0)), "{1..=2}");
+        // the second loop always sets the cell back to one
+        assert_eq!(values.get(&[7, 1], 0).known(), Some(1));
+    }
+
+    #[test]
+    fn narrow() {
+        // the body always clears the cell
+        let program: Program = ",[.[-]+.-]".parse().unwrap();
+        assert!(matches!(program.0 .0[..], [_, Node::If(_)]));
+        // the cell is always zero after the multiplication
+        let program: Program = ",[->+<]>[<+>-]<[-]>>,<<[-]".parse().unwrap();
+        assert_eq!(program.stats().sets, 0, "{program}");
+    }
+
+    #[test]
+    fn fold_outputs() {
+        // the output in the loop always prints a three
+        let program: Program = ",[>[-]+++.<-]".parse().unwrap();
+        assert_eq!(program.stats().outputs, 0, "{program}");
+        assert_eq!(program.stats().prints, 1);
+    }
+}
@@ -0,0 +1,131 @@
+//! Structural invariants of the ir
+//!
+//! Some invariants hold for any program, and are checked between the passes in debug builds.
+//! Others are only reached at the end of the optimization, and are checked by [`verify`].
+
+use std::collections::BTreeSet;
+
+use thiserror::Error;
+
+use super::{Block, If, Loop, Mul, Node, Print, Program};
+
+/// A broken invariant of the ir
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Error)]
+pub enum Invalid {
+    #[error("A noop was left in the program")]
+    Noop,
+    #[error("A print has no bytes")]
+    EmptyPrint,
+    #[error("A multiplication has no targets")]
+    EmptyMul,
+    #[error("A multiplication targets its own counter")]
+    MulTargetsCounter,
+    #[error("A multiplication reads its own counter")]
+    MulReadsCounter,
+    #[error("A multiplication targets the cell {0} twice")]
+    DuplicateTarget(isize),
+    #[error("The body of an if moves the pointer")]
+    UnbalancedIf,
+}
+
+/// A node of the program breaks an invariant
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Error)]
+#[error("Invalid node at {path:?}: {invalid}")]
+pub struct VerifyError {
+    /// Index of the node in each nested block, from the outermost
+    pub path: Vec<usize>,
+    #[source]
+    pub invalid: Invalid,
+}
+
+/// Check the invariants of an optimized program
+pub fn verify(program: &Program) -> Result<(), VerifyError> {
+    verify_block(&program.0, true, &mut vec![])
+}
+
+/// Check the invariants that hold at any point of the optimization
+pub(super) fn verify_structure(program: &Program) -> Result<(), VerifyError> {
+    verify_block(&program.0, false, &mut vec![])
+}
+
+fn verify_block(block: &Block, optimized: bool, path: &mut Vec<usize>) -> Result<(), VerifyError> {
+    for (i, node) in block.0.iter().enumerate() {
+        path.push(i);
+        verify_node(node, optimized).map_err(|invalid| VerifyError {
+            path: path.clone(),
+            invalid,
+        })?;
+        if let Node::Loop(Loop { body, .. }) | Node::If(If { body, .. }) = node {
+            verify_block(body, optimized, path)?
+        }
+        path.pop();
+    }
+    Ok(())
+}
+
+fn verify_node(node: &Node, optimized: bool) -> Result<(), Invalid> {
+    match node {
+        Node::Noop if optimized => Err(Invalid::Noop),
+        Node::Print(Print { bytes }) if optimized && bytes.is_empty() => Err(Invalid::EmptyPrint),
+        Node::Mul(Mul { targets, .. }) if optimized && targets.is_empty() => Err(Invalid::EmptyMul),
+        Node::Mul(Mul { offset, targets }) => {
+            let mut seen = BTreeSet::new();
+            for (target, expr) in targets {
+                if target == offset {
+                    return Err(Invalid::MulTargetsCounter);
+                }
+                if expr.coefficient(*offset) != 0 {
+                    return Err(Invalid::MulReadsCounter);
+                }
+                if !seen.insert(*target) {
+                    return Err(Invalid::DuplicateTarget(*target));
+                }
+            }
+            Ok(())
+        }
+        Node::If(If { body, .. }) if !body.is_balanced() => Err(Invalid::UnbalancedIf),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU8;
+
+    use super::{verify, Invalid, VerifyError};
+    use crate::ir::{Add, Affine, Block, If, Mul, Node, Print, Program};
+
+    #[test]
+    fn invariants() {
+        let program: Program = ",[->++<]>.[-]+[>+<+]".parse().unwrap();
+        assert_eq!(verify(&program), Ok(()));
+
+        let add = Node::Add(Add {
+            amount: NonZeroU8::new(1).unwrap(),
+            offset: 0,
+        });
+        let program = Program(Block(vec![
+            add.clone(),
+            Node::If(If {
+                body: Block(vec![add, Node::Print(Print { bytes: vec![] })]),
+                offset: 0,
+            }),
+        ]));
+        assert_eq!(
+            verify(&program),
+            Err(VerifyError {
+                path: vec![1, 1],
+                invalid: Invalid::EmptyPrint
+            })
+        );
+
+        let program = Program(Block(vec![Node::Mul(Mul {
+            offset: 0,
+            targets: vec![(1, Affine::constant(2)), (1, Affine::constant(3))],
+        })]));
+        assert_eq!(
+            verify(&program).map_err(|err| err.invalid),
+            Err(Invalid::DuplicateTarget(1))
+        );
+    }
+}
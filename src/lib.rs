@@ -3,7 +3,10 @@
 #![feature(split_array)]
 #![feature(array_windows)]
 #![feature(assert_matches)]
+#![feature(associated_type_defaults)]
 
+pub mod analysis;
+pub mod emit;
 pub mod engine;
 pub mod ir;
 pub mod raw;
@@ -1,45 +1,137 @@
 use std::{
     collections::VecDeque,
-    fs::File,
-    io::{self, stdin, stdout, Write},
+    fmt::Display,
+    fs::{self, File},
+    io::{self, stdin, stdout, IsTerminal, Read, Write},
+    ops::Range,
     path::PathBuf,
+    str::{from_utf8, FromStr},
+    time::{Duration, Instant},
 };
 
 use anyhow::{bail, Context};
+use base64::{prelude::BASE64_STANDARD, Engine as _};
 use bf::{
-    engine::{self, Engine, ProgrammableEngine},
-    save::Payload,
+    engine::{self, limit::Deadline, mem::Memory, Engine, ProgrammableEngine},
+    ir::{passes::PassManager, Annotations, SourceMap},
+    raw,
+    save::{Content, Header, Payload},
 };
 use clap::{Parser, ValueEnum};
+use serde::Serialize;
 
 /// Brainfuck optimizer and runner
 #[derive(Debug, Clone, Parser)]
 #[clap(name="bf", about = "Brainfuck optimizer and runner", long_about = None,version)]
-enum Cli {
+struct Cli {
+    /// Format of the error messages
+    #[clap(long, global = true, default_value = "text")]
+    error_format: ErrorFormat,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+enum Command {
     /// Run the program
     Run {
         /// Run the program directly with no optimizations
         #[clap(long)]
         raw: bool,
         /// Input stream type
-        #[clap(short, long, default_value = "bytes")]
-        input: StreamType,
+        #[clap(short, long, alias = "input-encoding", default_value = "bytes")]
+        input: InputType,
         /// Output stream type
         #[clap(short, long, default_value = "bytes")]
-        output: StreamType,
+        output: OutputType,
+        /// Also write the output to this file, as `[ENCODING:]PATH`. Can be repeated
+        #[clap(long)]
+        tee: Vec<Tee>,
+        /// Byte appended to each input line in `lines` mode
+        #[clap(long, default_value_t = b'\n')]
+        eol: u8,
+        /// Newline translation
+        #[clap(long, default_value = "raw")]
+        newline: Newline,
+        /// Prompt shown when the program waits for input on a terminal.
+        /// Defaults to the `prompt` in the program metadata
+        #[clap(long)]
+        prompt: Option<String>,
+        /// Preload the tape with the content of this file
+        #[clap(long)]
+        seed_memory: Option<PathBuf>,
+        /// Starting position of the memory pointer
+        #[clap(long, default_value_t = 0)]
+        seed_pointer: isize,
+        /// Stop before executing the given step, and dump the state of the engine
+        #[clap(long, conflicts_with = "check")]
+        break_at_step: Option<u64>,
+        /// Stop the program if it runs for longer than this number of seconds
+        #[clap(long, conflicts_with = "check")]
+        timeout: Option<f64>,
+        /// Print execution statistics on stderr at the end of the run
+        #[clap(long, conflicts_with = "check")]
+        stats: bool,
+        /// Print on stderr the instructions that were never executed
+        #[clap(long, conflicts_with = "check")]
+        coverage: bool,
+        /// Width of the memory cells, in bits. Wider cells run with no optimizations
+        #[clap(long, default_value = "8", conflicts_with = "check")]
+        cell_width: CellWidth,
+        /// How the cells are read and written
+        #[clap(long, default_value = "truncate", conflicts_with = "check")]
+        cell_io: CellIo,
+        /// Make the cells signed, so they are read and written as negative numbers
+        #[clap(long, conflicts_with = "check")]
+        signed: bool,
+        /// What `+` and `-` do when a cell overflows
+        #[clap(long, default_value = "wrap", conflicts_with = "check")]
+        overflow: Overflow,
+        /// What happens when the memory pointer moves before the first cell
+        #[clap(long, default_value = "right-unbounded", conflicts_with = "check")]
+        tape_mode: TapeMode,
+        /// Number of cells of a fixed tape
+        #[clap(long, default_value_t = 30_000, value_parser = clap::value_parser!(u64).range(1..))]
+        tape_len: u64,
+        /// Stop the program if a tape grows past this number of cells
+        #[clap(long, conflicts_with = "check")]
+        memory_limit: Option<usize>,
+        /// What the program reads after the end of the input
+        #[clap(long, default_value = "stop", conflicts_with = "check")]
+        eof: Eof,
+        /// Dialect of the source. Defaults to the one recorded in the file
+        #[clap(long)]
+        dialect: Option<Dialect>,
+        /// Run the raw and the optimized program in lockstep, stopping at the first divergent output
+        #[clap(long, conflicts_with = "raw")]
+        check: bool,
+        /// Also check that the inputs are requested at the same points of the output
+        #[clap(long, requires = "check")]
+        check_io_order: bool,
+        /// Optimization level
+        #[clap(short = 'O', long, default_value = "3")]
+        opt_level: OptLevel,
         /// Program to run
         program: PathBuf,
     },
     /// Inspect a file, showing its header
     Inspect {
+        /// Print a machine readable json report instead of the yaml header
+        #[clap(long)]
+        json: bool,
+        /// Print the structure of the program as a Graphviz graph, compiling it if needed
+        #[clap(long, conflicts_with = "json")]
+        dot: bool,
         /// File to inspect. Defaults to read stdin
         file: Option<PathBuf>,
     },
     /// Compile a file
     Compile {
         /// Source file. Defaults to read stdin
-        #[clap(short, long)]
+        #[clap(short, long, conflicts_with = "sources")]
         input: Option<PathBuf>,
+        /// Source files to link together, in order
+        sources: Vec<PathBuf>,
         /// Output file. Defaults to write stdout
         #[clap(short, long)]
         output: Option<PathBuf>,
@@ -49,9 +141,81 @@ enum Cli {
         /// Use a compressed representation
         #[clap(short, long)]
         compress: bool,
+        /// Store the source alongside the compiled program
+        #[clap(long)]
+        keep_source: bool,
+        /// Keep the comments of the source as annotations of the nodes
+        #[clap(long)]
+        annotate: bool,
+        /// Dialect of the source. Defaults to the one recorded in the file
+        #[clap(long)]
+        dialect: Option<Dialect>,
+        /// Show the progress of the optimizer
+        #[clap(long)]
+        progress: bool,
+        /// Check the memory accesses in the generated code
+        #[clap(long)]
+        bounds_checks: bool,
+        /// Run programs that read no input for up to this many steps, keeping only their output
+        #[clap(long, value_name = "STEPS")]
+        precompute: Option<u64>,
+        /// Optimization level
+        #[clap(short = 'O', long, default_value = "3")]
+        opt_level: OptLevel,
+        /// Optimization passes to skip
+        #[clap(long, value_name = "PASS", value_delimiter = ',')]
+        disable_pass: Vec<String>,
+        /// Optimization passes to run, even if the level would skip them
+        #[clap(long, value_name = "PASS", value_delimiter = ',')]
+        enable_pass: Vec<String>,
+        /// Stop optimizing after visiting this many nodes, keeping the best program so far
+        #[clap(long, value_name = "NODES")]
+        opt_fuel: Option<u64>,
+        /// Stop optimizing after this number of seconds, keeping the best program so far
+        #[clap(long, value_name = "SECONDS")]
+        opt_timeout: Option<f64>,
+    },
+    /// Run the current optimizer on an already compiled file
+    Reoptimize {
+        /// File to reoptimize
+        file: PathBuf,
+        /// Output file. Defaults to overwrite the reoptimized file
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+        /// Stop optimizing after this number of passes if the optimizer does not converge,
+        /// keeping the program so far
+        #[clap(long, default_value_t = bf::ir::PASS_LIMIT)]
+        max_passes: usize,
+        /// Show the progress of the optimizer
+        #[clap(long)]
+        progress: bool,
+        /// Optimization level
+        #[clap(short = 'O', long, default_value = "3")]
+        opt_level: OptLevel,
+    },
+    /// Remove all the metadata from a file, keeping only the payload
+    Strip {
+        /// File to strip
+        file: PathBuf,
+        /// Output file. Defaults to overwrite the stripped file
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+        /// Metadata to keep
+        #[clap(long, value_delimiter = ',')]
+        keep: Vec<Keep>,
     },
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, Eq, PartialEq)]
+enum Keep {
+    /// The description of the program
+    Description,
+    /// The notes on the nodes of the compiled program
+    Annotations,
+    /// The source embedded in a compiled program
+    Source,
+}
+
 #[derive(ValueEnum, Clone, Copy, Debug, Eq, PartialEq)]
 enum Format {
     /// Raw brainfuck
@@ -60,6 +224,19 @@ enum Format {
     Binary,
     /// Human readable json
     Json,
+    /// Textual ir, that can be edited and read back
+    Text,
+    /// Standalone C source
+    C,
+    /// Standalone Rust source
+    Rust,
+    /// x86-64 assembly for Linux
+    Asm,
+    /// WebAssembly module
+    Wasm,
+    /// Textual LLVM-IR, to be compiled by `llc` or `clang`
+    #[cfg(feature = "llvm")]
+    Llvm,
 }
 
 impl Format {
@@ -72,60 +249,466 @@ impl Format {
     }
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug, Eq, PartialEq)]
+enum Dialect {
+    /// Plain brainfuck
+    Standard,
+    /// Multiple tapes, selected with `{` and `}`
+    MultiTape,
+}
+impl From<Dialect> for raw::Dialect {
+    fn from(value: Dialect) -> Self {
+        match value {
+            Dialect::Standard => raw::Dialect::Standard,
+            Dialect::MultiTape => raw::Dialect::MultiTape,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Eq, PartialEq)]
+enum CellWidth {
+    #[value(name = "8")]
+    W8,
+    #[value(name = "16")]
+    W16,
+    #[value(name = "32")]
+    W32,
+    /// Unbounded integers, that can also go below zero
+    #[cfg(feature = "bignum")]
+    Unbounded,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Eq, PartialEq)]
+enum CellIo {
+    /// One byte, truncating the output
+    Truncate,
+    /// All the bytes of the cell, least significant first
+    LittleEndian,
+    /// All the bytes of the cell, most significant first
+    BigEndian,
+    /// Decimal numbers, one for each line
+    Numeric,
+}
+impl From<CellIo> for engine::cell::CellIo {
+    fn from(value: CellIo) -> Self {
+        match value {
+            CellIo::Truncate => engine::cell::CellIo::Truncate,
+            CellIo::LittleEndian => engine::cell::CellIo::LittleEndian,
+            CellIo::BigEndian => engine::cell::CellIo::BigEndian,
+            CellIo::Numeric => engine::cell::CellIo::Numeric,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Eq, PartialEq)]
+enum Overflow {
+    /// Wrap around to the other end
+    Wrap,
+    /// Stop at the largest or smallest value
+    Saturate,
+    /// Stop with an error
+    Trap,
+}
+impl From<Overflow> for engine::cell::Overflow {
+    fn from(value: Overflow) -> Self {
+        match value {
+            Overflow::Wrap => engine::cell::Overflow::Wrap,
+            Overflow::Saturate => engine::cell::Overflow::Saturate,
+            Overflow::Trap => engine::cell::Overflow::Trap,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Eq, PartialEq)]
+enum OptLevel {
+    /// No optimization
+    #[value(name = "0")]
+    O0,
+    /// Merge the instructions, and lower the simplest loops
+    #[value(name = "1")]
+    O1,
+    /// Lower all the loops that can be, and remove the dead code
+    #[value(name = "2")]
+    O2,
+    /// Also unroll the loops, and evaluate what is known at compile time
+    #[value(name = "3")]
+    O3,
+}
+impl From<OptLevel> for bf::ir::passes::OptLevel {
+    fn from(value: OptLevel) -> Self {
+        match value {
+            OptLevel::O0 => bf::ir::passes::OptLevel::O0,
+            OptLevel::O1 => bf::ir::passes::OptLevel::O1,
+            OptLevel::O2 => bf::ir::passes::OptLevel::O2,
+            OptLevel::O3 => bf::ir::passes::OptLevel::O3,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Eq, PartialEq)]
+enum Eof {
+    /// Stop the program
+    Stop,
+    /// Read a zero
+    Zero,
+    /// Read a `-1`, the largest value for unsigned cells
+    MinusOne,
+    /// Leave the cell unchanged
+    Unchanged,
+}
+impl From<Eof> for engine::Eof {
+    fn from(value: Eof) -> Self {
+        match value {
+            Eof::Stop => engine::Eof::Stop,
+            Eof::Zero => engine::Eof::Zero,
+            Eof::MinusOne => engine::Eof::MinusOne,
+            Eof::Unchanged => engine::Eof::Unchanged,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Eq, PartialEq)]
+enum TapeMode {
+    /// Moving before the first cell is an error
+    RightUnbounded,
+    /// The tape extends in both directions
+    Bidirectional,
+    /// `--tape-len` cells, moving past the ends is an error
+    Fixed,
+    /// `--tape-len` cells, moving past an end wraps around to the other
+    FixedWrapping,
+}
+impl TapeMode {
+    fn with_len(self, len: u64) -> engine::mem::TapeMode {
+        let len = len as usize;
+        match self {
+            TapeMode::RightUnbounded => engine::mem::TapeMode::RightUnbounded,
+            TapeMode::Bidirectional => engine::mem::TapeMode::Bidirectional,
+            TapeMode::Fixed => engine::mem::TapeMode::Fixed { len, wrap: false },
+            TapeMode::FixedWrapping => engine::mem::TapeMode::Fixed { len, wrap: true },
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Eq, PartialEq)]
+enum ErrorFormat {
+    /// Human readable chain of errors
+    Text,
+    /// A single json object on stderr
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum InputType {
+    Bytes,
+    Ascii,
+    /// Whole lines, terminated by the `--eol` byte
+    Lines,
+    /// Hexadecimal digits, two for each byte
+    Hex,
+    /// Base64 encoded lines
+    Base64,
+    /// Lines containing escape sequences like `\n` and `\x0a`
+    Escaped,
+}
+
 #[derive(Debug, Clone, Copy, ValueEnum)]
-enum StreamType {
+enum OutputType {
     Bytes,
     Ascii,
+    /// Line buffered bytes
+    Lines,
+    /// Offset, hexadecimal bytes and printable characters, 16 bytes a line
+    Hexdump,
+    /// Rust style escaped string
+    Escaped,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Newline {
+    /// Translate `\r\n` to `\n` on input
+    Lf,
+    /// Translate `\r\n` to `\n` on input and `\n` to `\r\n` on output
+    Crlf,
+    /// No translation
+    Raw,
 }
 
 struct InputStream {
     buf: VecDeque<u8>,
-    typ: StreamType,
+    typ: InputType,
+    eol: u8,
+    newline: Newline,
+    /// Shown before reading from stdin
+    prompt: Option<String>,
 }
 impl InputStream {
-    fn read(&mut self) -> anyhow::Result<u8> {
+    /// Read the next byte. `None` at the end of the input
+    fn read(&mut self) -> anyhow::Result<Option<u8>> {
         while self.buf.is_empty() {
             log::trace!("Filling input buffer");
+            if let Some(prompt) = &self.prompt {
+                eprint!("{prompt}");
+                io::stderr().flush()?;
+            }
             let mut buf = String::new();
-            stdin().read_line(&mut buf)?;
+            let read = stdin().read_line(&mut buf)?;
+            if read == 0 {
+                log::trace!("End of input");
+                return Ok(None);
+            }
+            if self.newline != Newline::Raw && buf.ends_with("\r\n") {
+                buf.truncate(buf.len() - 2);
+                buf.push('\n');
+            }
             match self.typ {
-                StreamType::Bytes => self.buf.extend(buf.as_bytes()),
-                StreamType::Ascii => {
+                InputType::Bytes => self.buf.extend(buf.as_bytes()),
+                InputType::Lines => {
+                    let line = buf.strip_suffix('\n').unwrap_or(&buf);
+                    let line = line.strip_suffix('\r').unwrap_or(line);
+                    self.buf.extend(line.as_bytes());
+                    self.buf.push_back(self.eol)
+                }
+                InputType::Ascii => {
                     for num in buf.split_whitespace() {
                         let num = num.parse().context("Cannot parse integer")?;
                         self.buf.push_back(num)
                     }
                 }
+                InputType::Hex => {
+                    let digits: Vec<_> =
+                        buf.bytes().filter(|ch| !ch.is_ascii_whitespace()).collect();
+                    if digits.len() % 2 != 0 {
+                        bail!("Odd number of hexadecimal digits")
+                    }
+                    for pair in digits.chunks(2) {
+                        let byte = from_utf8(pair)
+                            .ok()
+                            .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                            .context("Cannot parse hexadecimal byte")?;
+                        self.buf.push_back(byte)
+                    }
+                }
+                InputType::Base64 => self.buf.extend(
+                    BASE64_STANDARD
+                        .decode(buf.trim())
+                        .context("Cannot decode base64")?,
+                ),
+                InputType::Escaped => {
+                    let line = buf.strip_suffix('\n').unwrap_or(&buf);
+                    let line = line.strip_suffix('\r').unwrap_or(line);
+                    self.buf.extend(unescape(line)?)
+                }
             }
         }
-        Ok(self.buf.pop_front().unwrap())
+        Ok(self.buf.pop_front())
     }
 }
-impl From<StreamType> for InputStream {
-    fn from(value: StreamType) -> Self {
+impl InputStream {
+    fn new(typ: InputType, eol: u8, newline: Newline, prompt: Option<String>) -> Self {
         Self {
             buf: VecDeque::new(),
-            typ: value,
+            typ,
+            eol,
+            newline,
+            // prompting only interactive users
+            prompt: prompt.filter(|_| stdin().is_terminal()),
         }
     }
 }
 
-struct OutputStream {
-    typ: StreamType,
+/// Decode a string containing rust style escape sequences
+fn unescape(line: &str) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut chars = line.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend(ch.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        bytes.push(match chars.next() {
+            Some('n') => b'\n',
+            Some('r') => b'\r',
+            Some('t') => b'\t',
+            Some('0') => b'\0',
+            Some('\\') => b'\\',
+            Some('\'') => b'\'',
+            Some('"') => b'"',
+            Some('x') => {
+                let digits: String = chars.by_ref().take(2).collect();
+                u8::from_str_radix(&digits, 16)
+                    .with_context(|| format!("Invalid escape `\\x{digits}`"))?
+            }
+            Some(ch) => bail!("Unknown escape `\\{ch}`"),
+            None => bail!("Unterminated escape at the end of the line"),
+        })
+    }
+    Ok(bytes)
 }
-impl OutputStream {
-    fn write(&self, value: u8) -> io::Result<()> {
+
+/// Bytes in a line of hexdump
+const HEXDUMP_WIDTH: usize = 16;
+
+/// Destination of the program output, with its own encoding
+struct OutputSink {
+    typ: OutputType,
+    writer: Box<dyn Write>,
+    /// Bytes written before the pending ones
+    offset: usize,
+    /// Bytes waiting to fill a line of hexdump
+    pending: Vec<u8>,
+}
+impl OutputSink {
+    fn new(typ: OutputType, writer: impl Write + 'static) -> Self {
+        Self {
+            typ,
+            writer: Box::new(writer),
+            offset: 0,
+            pending: vec![],
+        }
+    }
+    fn write(&mut self, value: u8) -> io::Result<()> {
         match self.typ {
-            StreamType::Bytes => stdout().write_all(&[value])?,
-            StreamType::Ascii => writeln!(stdout(), "{value}")?,
+            OutputType::Bytes => self.writer.write_all(&[value])?,
+            OutputType::Ascii => writeln!(self.writer, "{value}")?,
+            OutputType::Lines => {
+                // flushing only at the end of the line
+                self.writer.write_all(&[value])?;
+                if value != b'\n' {
+                    return Ok(());
+                }
+            }
+            OutputType::Hexdump => {
+                self.pending.push(value);
+                if self.pending.len() < HEXDUMP_WIDTH {
+                    return Ok(());
+                }
+                self.dump_pending()?
+            }
+            OutputType::Escaped => write!(self.writer, "{}", value.escape_ascii())?,
         }
-        stdout().flush()?;
+        self.writer.flush()?;
+        Ok(())
+    }
+    /// Write many bytes, flushing once
+    fn write_all(&mut self, values: &[u8]) -> io::Result<()> {
+        match self.typ {
+            OutputType::Bytes => {
+                self.writer.write_all(values)?;
+                self.writer.flush()
+            }
+            _ => values.iter().try_for_each(|value| self.write(*value)),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.dump_pending()?;
+        self.writer.flush()
+    }
+    /// Flush and terminate the output
+    fn finish(&mut self) -> io::Result<()> {
+        if matches!(self.typ, OutputType::Escaped) {
+            writeln!(self.writer)?;
+        }
+        self.flush()
+    }
+    fn dump_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let writer = &mut self.writer;
+        write!(writer, "{:08x} ", self.offset)?;
+        for i in 0..HEXDUMP_WIDTH {
+            if i % 8 == 0 {
+                write!(writer, " ")?;
+            }
+            match self.pending.get(i) {
+                Some(byte) => write!(writer, "{byte:02x} ")?,
+                None => write!(writer, "   ")?,
+            }
+        }
+        write!(writer, " |")?;
+        for byte in &self.pending {
+            let ch = if byte.is_ascii_graphic() || *byte == b' ' {
+                *byte as char
+            } else {
+                '.'
+            };
+            write!(writer, "{ch}")?;
+        }
+        writeln!(writer, "|")?;
+        self.offset += self.pending.len();
+        self.pending.clear();
         Ok(())
     }
 }
-impl From<StreamType> for OutputStream {
-    fn from(value: StreamType) -> Self {
-        Self { typ: value }
+
+/// File receiving a copy of the output, as `[ENCODING:]PATH`
+#[derive(Debug, Clone)]
+struct Tee {
+    typ: OutputType,
+    path: PathBuf,
+}
+impl FromStr for Tee {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((typ, path)) = s.split_once(':') {
+            if let Ok(typ) = OutputType::from_str(typ, true) {
+                return Ok(Self {
+                    typ,
+                    path: path.into(),
+                });
+            }
+        }
+        Ok(Self {
+            typ: OutputType::Bytes,
+            path: s.into(),
+        })
+    }
+}
+
+/// Output of the program, copied to all the sinks
+struct OutputStream {
+    sinks: Vec<OutputSink>,
+    newline: Newline,
+}
+impl OutputStream {
+    fn new(typ: OutputType, tee: Vec<Tee>, newline: Newline) -> anyhow::Result<Self> {
+        let mut sinks = vec![OutputSink::new(typ, stdout())];
+        for Tee { typ, path } in tee {
+            let file = File::create(&path)
+                .with_context(|| format!("Cannot create tee file {}", path.display()))?;
+            sinks.push(OutputSink::new(typ, io::BufWriter::new(file)))
+        }
+        Ok(Self { sinks, newline })
+    }
+    fn write(&mut self, value: u8) -> io::Result<()> {
+        if self.newline == Newline::Crlf && value == b'\n' {
+            self.sinks
+                .iter_mut()
+                .try_for_each(|sink| sink.write(b'\r'))?;
+        }
+        self.sinks.iter_mut().try_for_each(|sink| sink.write(value))
+    }
+    fn write_all(&mut self, values: &[u8]) -> io::Result<()> {
+        let mut expanded = Vec::with_capacity(values.len());
+        for &value in values {
+            if self.newline == Newline::Crlf && value == b'\n' {
+                expanded.push(b'\r')
+            }
+            expanded.push(value)
+        }
+        self.sinks
+            .iter_mut()
+            .try_for_each(|sink| sink.write_all(&expanded))
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.sinks.iter_mut().try_for_each(OutputSink::flush)
+    }
+    /// Flush and terminate all the outputs
+    fn finish(&mut self) -> io::Result<()> {
+        self.sinks.iter_mut().try_for_each(OutputSink::finish)
     }
 }
 
@@ -136,107 +719,529 @@ fn main() -> anyhow::Result<()> {
         .env()
         .init()
         .context("Cannot init logging")?;
-    match Cli::parse() {
-        Cli::Run {
+    let Cli {
+        error_format,
+        command,
+    } = Cli::parse();
+    match error_format {
+        ErrorFormat::Text => execute(command),
+        ErrorFormat::Json => {
+            if let Err(err) = execute(command) {
+                serde_json::to_writer(io::stderr(), &ErrorReport::new(&err))
+                    .context("While printing error")?;
+                eprintln!();
+                std::process::exit(1)
+            }
+            Ok(())
+        }
+    }
+}
+
+fn execute(command: Command) -> anyhow::Result<()> {
+    match command {
+        Command::Run {
             mut raw,
             input,
             output,
+            tee,
+            eol,
+            newline,
+            prompt,
+            seed_memory,
+            seed_pointer,
+            break_at_step,
+            timeout,
+            stats,
+            coverage,
+            cell_width,
+            cell_io,
+            signed,
+            overflow,
+            tape_mode,
+            tape_len,
+            memory_limit,
+            eof,
+            dialect,
+            check,
+            check_io_order,
+            opt_level,
             program,
         } => {
             log::info!("Reading file");
-            let program = bf::save::parse(File::open(program).context("Cannot open program file")?)
-                .context("Cannot parse program file")?;
-            if raw && program.payload.is_ir() {
+            let mut program =
+                bf::save::parse(File::open(program).context("Cannot open program file")?)
+                    .context("Cannot parse program file")?;
+            if let Some(dialect) = dialect {
+                program.header.dialect = dialect.into()
+            }
+            if raw && program.payload.as_source().is_none() {
                 log::warn!(
                     "The program in the file is already optimized, running with optimization on"
                 );
                 raw = false;
             }
+            if !raw && (cell_width != CellWidth::W8 || cell_io != CellIo::Truncate || signed) {
+                if program.payload.as_source().is_none() {
+                    bail!("Cannot change the cells of a compiled program, as its source is not available")
+                }
+                log::info!("The optimizations assume byte cells, running with optimization off");
+                raw = true;
+            }
+            if !raw && matches!(tape_mode, TapeMode::Fixed | TapeMode::FixedWrapping) {
+                if program.payload.as_source().is_none() {
+                    bail!("Cannot run a compiled program on a fixed tape, as its source is not available")
+                }
+                // distinct offsets might be the same cell, or past the end of the tape
+                log::info!(
+                    "The optimizations assume an unbounded tape, running with optimization off"
+                );
+                raw = true;
+            }
+            if !raw && memory_limit.is_some() {
+                if program.payload.as_source().is_none() {
+                    bail!("Cannot limit the memory of a compiled program, as its source is not available")
+                }
+                // the optimizer drops or precomputes the accesses to the cells that are not read
+                log::info!(
+                    "The optimizations do not keep the memory used, running with optimization off"
+                );
+                raw = true;
+            }
+            if !raw && overflow != Overflow::Wrap {
+                // the compiled program was optimized for wrapping cells
+                program.payload = match program.payload {
+                    Payload::Ir(_) => bail!(
+                        "Cannot change the overflow of a compiled program, as its source is not available"
+                    ),
+                    Payload::Source(source) | Payload::Dual { source, .. } => {
+                        Payload::Source(source)
+                    }
+                };
+            }
+            let overflow = overflow.into();
+            let tape_mode = tape_mode.with_len(tape_len);
+            let memory_limit = memory_limit.unwrap_or(usize::MAX);
+            let eof = eof.into();
+            let seed = Seed {
+                memory: seed_memory
+                    .map(|path| fs::read(path).context("Cannot read memory seed"))
+                    .transpose()?
+                    .map(Memory::from),
+                pointer: seed_pointer,
+            };
+            let deadline = timeout.map(|secs| Instant::now() + Duration::from_secs_f64(secs));
+            let output = OutputStream::new(output, tee, newline)?;
+            let input = InputStream::new(
+                input,
+                eol,
+                newline,
+                prompt.or_else(|| program.header.meta.prompt.clone()),
+            );
+            if check {
+                let (raw, ir) = match program.payload {
+                    Payload::Source(src) => {
+                        let raw: raw::Program = parse_source(&src, &program.header)?;
+                        let ir = bf::ir::Program::from_raw_with_passes(
+                            raw.clone(),
+                            &PassManager::for_level(opt_level.into())
+                                .with_zeroed_tape(seed.memory.is_none()),
+                            |_| (),
+                        );
+                        (raw, ir)
+                    }
+                    Payload::Dual { source, ir } => {
+                        if seed.memory.is_some() {
+                            bail!("Cannot seed the memory of a compiled program, as it was optimized assuming a zeroed tape")
+                        }
+                        (parse_source(&source, &program.header)?, ir)
+                    }
+                    Payload::Ir(_) => {
+                        bail!("Cannot check a compiled program, as its source is not available")
+                    }
+                };
+                return run_checked(raw, ir, seed, input, output, check_io_order);
+            }
+            let collect = Collect { stats, coverage };
             match (raw, program.payload) {
                 (true, bf::save::Payload::Ir(_)) => unreachable!(),
-                (true, bf::save::Payload::Source(src)) => {
-                    let raw = src.parse().context("While parsing raw brainfuck")?;
-                    run::<engine::raw::Engine>(raw, input.into(), output.into())?
-                }
-                (false, bf::save::Payload::Source(src)) => {
-                    let ir = src.parse().context("While parsing raw brainfuck")?;
-                    run::<engine::ir::Engine>(ir, input.into(), output.into())?
+                (
+                    true,
+                    bf::save::Payload::Source(src) | bf::save::Payload::Dual { source: src, .. },
+                ) => {
+                    let raw = parse_source(&src, &program.header)?;
+                    let locator = Locator::new(&src, &program.header, None);
+                    let io = cell_io.into();
+                    macro_rules! run_raw {
+                        ($cell:ty) => {
+                            run(
+                                Box::new(
+                                    start::<engine::raw::Engine<$cell>>(raw, seed)
+                                        .with_cell_io(io)
+                                        .with_overflow(overflow)
+                                        .with_tape_mode(tape_mode)
+                                        .with_memory_limit(memory_limit)
+                                        .with_eof(eof),
+                                ),
+                                input,
+                                output,
+                                break_at_step,
+                                deadline,
+                                collect,
+                                Some(&locator),
+                            )?
+                        };
+                    }
+                    match (cell_width, signed) {
+                        (CellWidth::W8, false) => run_raw!(u8),
+                        (CellWidth::W16, false) => run_raw!(u16),
+                        (CellWidth::W32, false) => run_raw!(u32),
+                        (CellWidth::W8, true) => run_raw!(i8),
+                        (CellWidth::W16, true) => run_raw!(i16),
+                        (CellWidth::W32, true) => run_raw!(i32),
+                        #[cfg(feature = "bignum")]
+                        (CellWidth::Unbounded, _) => run_raw!(num_bigint::BigInt),
+                    }
                 }
-                (false, bf::save::Payload::Ir(ir)) => {
-                    run::<engine::ir::Engine>(ir, input.into(), output.into())?
+                (false, payload) => {
+                    let mut locator = None;
+                    let engine: Box<dyn Engine<Cell = u8>> = match payload {
+                        bf::save::Payload::Source(src) => {
+                            let (ir, map) = bf::ir::Program::from_raw_with_source_map(
+                                parse_source(&src, &program.header)?,
+                                &PassManager::for_level(opt_level.into())
+                                    .with_overflow(overflow)
+                                    .with_zeroed_tape(seed.memory.is_none()),
+                            );
+                            locator = Some(Locator::new(&src, &program.header, Some(map)));
+                            Box::new(
+                                start::<engine::ir::Engine>(ir, seed)
+                                    .with_overflow(overflow)
+                                    .with_tape_mode(tape_mode)
+                                    .with_memory_limit(memory_limit)
+                                    .with_eof(eof),
+                            )
+                        }
+                        bf::save::Payload::Ir(ir) | bf::save::Payload::Dual { ir, .. } => {
+                            if seed.memory.is_some() {
+                                bail!("Cannot seed the memory of a compiled program, as it was optimized assuming a zeroed tape")
+                            }
+                            Box::new(
+                                start::<engine::ir::Engine>(ir, seed)
+                                    .with_tape_mode(tape_mode)
+                                    .with_memory_limit(memory_limit)
+                                    .with_eof(eof),
+                            )
+                        }
+                    };
+                    run(
+                        engine,
+                        input,
+                        output,
+                        break_at_step,
+                        deadline,
+                        collect,
+                        locator.as_ref(),
+                    )?
                 }
             }
         }
-        Cli::Inspect { file } => {
+        Command::Inspect { json, dot, file } => {
             log::info!("Reading file");
-            let header = if let Some(file) = file {
-                bf::save::parse(File::open(file).context("Cannot open program file")?)
+            let content = if let Some(file) = file {
+                fs::read(file).context("Cannot read program file")?
             } else {
-                bf::save::parse(stdin())
+                let mut buf = vec![];
+                stdin()
+                    .read_to_end(&mut buf)
+                    .context("Cannot read program from stdin")?;
+                buf
+            };
+            let file = bf::save::parse(content.as_slice()).context("Cannot parse program file")?;
+            if dot {
+                let ir = match &file.payload {
+                    Payload::Source(src) => {
+                        bf::ir::Program::from_raw(parse_source(src, &file.header)?)
+                    }
+                    Payload::Ir(ir) | Payload::Dual { ir, .. } => ir.clone(),
+                };
+                print!("{}", ir.to_dot());
+            } else if json {
+                let report = InspectReport::new(&file, content.len());
+                serde_json::to_writer_pretty(stdout(), &report).context("While printing report")?;
+                println!();
+            } else {
+                serde_yaml::to_writer(stdout(), &file.header).context("While printing header")?;
             }
-            .context("Cannot parse program file")?
-            .header;
-            serde_yaml::to_writer(stdout(), &header).context("While printing header")?;
         }
-        Cli::Compile {
+        Command::Reoptimize {
+            file,
+            output,
+            max_passes,
+            progress,
+            opt_level,
+        } => {
+            log::info!("Reading file");
+            let bf::save::File { header, payload } =
+                bf::save::parse(File::open(&file).context("Cannot open program file")?)
+                    .context("Cannot parse program file")?;
+            let Content::Ir {
+                format, optimizer, ..
+            } = header.content
+            else {
+                bail!("The file is not compiled, use `compile` instead")
+            };
+            let (mut ir, source) = match payload {
+                Payload::Ir(ir) => (ir, None),
+                Payload::Dual { source, ir } => (ir, Some(source)),
+                Payload::Source(_) => unreachable!(),
+            };
+            log::info!(
+                "Reoptimizing from optimizer version {}",
+                optimizer.map_or("unknown".to_owned(), |v| v.to_string())
+            );
+            let mut annotations = header.annotations.clone();
+            let passes = PassManager::for_level(opt_level.into());
+            let outcome = if progress {
+                let mut bar = ProgressBar::new();
+                passes.optimize_annotated(&mut ir, &mut annotations, max_passes, |p| bar.update(p))
+            } else {
+                passes.optimize_annotated(&mut ir, &mut annotations, max_passes, |_| ())
+            };
+            match outcome {
+                Ok(passes) => log::info!("Optimization changed the program in {passes} passes"),
+                Err(err) => log::warn!("{err}, the program is not fully optimized"),
+            }
+            let dest = File::create(output.unwrap_or(file)).context("Creating file")?;
+            let header = Header {
+                content: Content::Ir {
+                    format,
+                    source_len: None,
+                    optimizer: Some(bf::ir::OPTIMIZER_VERSION),
+                    schema: Some(bf::save::SCHEMA_VERSION),
+                },
+                annotations,
+                ..header
+            };
+            bf::save::write_ir(dest, &ir, source.as_deref(), &header)
+                .context("While writing to file")?
+        }
+        Command::Strip { file, output, keep } => {
+            log::info!("Reading file");
+            let bf::save::File { header, payload } =
+                bf::save::parse(File::open(&file).context("Cannot open program file")?)
+                    .context("Cannot parse program file")?;
+            let description = header
+                .description
+                .clone()
+                .filter(|_| keep.contains(&Keep::Description));
+            let annotations = if keep.contains(&Keep::Annotations) {
+                header.annotations.clone()
+            } else {
+                Annotations::default()
+            };
+            let mut stripped = Header {
+                sources: vec![],
+                annotations,
+                ..header
+            };
+            stripped.describe(description);
+            let dest = File::create(output.unwrap_or(file)).context("Creating file")?;
+            match payload {
+                Payload::Source(source) => bf::save::write_source(dest, source, &stripped),
+                Payload::Ir(ir) => bf::save::write_ir(dest, &ir, None, &stripped),
+                Payload::Dual { source, ir } => {
+                    let source = keep.contains(&Keep::Source).then_some(&*source);
+                    bf::save::write_ir(dest, &ir, source, &stripped)
+                }
+            }
+            .context("While writing to file")?
+        }
+        Command::Compile {
             input,
+            mut sources,
             output,
             compress,
             format,
+            keep_source,
+            annotate,
+            dialect,
+            progress,
+            bounds_checks,
+            precompute,
+            opt_level,
+            disable_pass,
+            enable_pass,
+            opt_fuel,
+            opt_timeout,
         } => {
-            let bf::save::File { header, payload } = if let Some(input) = input {
+            let bf::save::File { header, payload } = if sources.len() > 1 {
+                link(&sources, dialect.map_or(raw::Dialect::Standard, Into::into))?
+            } else if let Some(input) = input.or(sources.pop()) {
                 log::info!("Reading file");
                 bf::save::parse(File::open(input).context("Cannot open program file")?)
+                    .context("Cannot parse program file")?
             } else {
                 log::info!("Reading input");
-                bf::save::parse(stdin())
-            }
-            .context("Cannot parse program file")?;
+                bf::save::parse(stdin()).context("Cannot parse program file")?
+            };
+            let header = Header {
+                compressed: compress,
+                dialect: dialect.map_or(header.dialect, Into::into),
+                ..header
+            };
             if format.is_raw() {
-                let Payload::Source(source) = payload else {bail!("Cannot conver compiled back into source brainfuck")};
+                let (source, header) = match &payload {
+                    Payload::Source(source) | Payload::Dual { source, .. } => {
+                        (source.clone(), header)
+                    }
+                    Payload::Ir(ir) => {
+                        log::info!("Lowering the compiled program back to brainfuck");
+                        let lowered = bf::emit::raw::emit(ir);
+                        let header = Header {
+                            dialect: lowered.dialect(),
+                            ..header
+                        };
+                        (lowered.to_string(), header)
+                    }
+                };
                 if let Some(output) = output {
                     bf::save::write_source(
                         File::create(output).context("Creating file")?,
                         source,
-                        compress,
-                        header.description,
+                        &header,
                     )
                     .context("While writing to file")?
                 } else {
-                    bf::save::write_source(stdout(), source, compress, header.description)
+                    bf::save::write_source(stdout(), source, &header)
                         .context("While writing to file")?
                 }
             } else {
-                let payload = match payload {
-                    Payload::Source(src) => src.parse().context("Error doring compiling")?,
-                    Payload::Ir(ir) => ir,
+                let source = payload.as_source().filter(|_| keep_source);
+                let (mut ir, mut annotations) = match &payload {
+                    Payload::Source(src) => {
+                        let raw = parse_source(src, &header).context("Error doring compiling")?;
+                        let mut passes = PassManager::for_level(opt_level.into());
+                        for name in &enable_pass {
+                            passes
+                                .set_enabled(name, true)
+                                .context("While selecting the passes")?;
+                        }
+                        for name in &disable_pass {
+                            passes
+                                .set_enabled(name, false)
+                                .context("While selecting the passes")?;
+                        }
+                        if let Some(fuel) = opt_fuel {
+                            passes = passes.with_fuel(fuel)
+                        }
+                        if let Some(secs) = opt_timeout {
+                            passes = passes.with_timeout(Duration::from_secs_f64(secs))
+                        }
+                        let mut annotations = if annotate {
+                            Annotations::of_source(src, header.dialect)
+                        } else {
+                            Annotations::default()
+                        };
+                        let (ir, outcome) = if progress {
+                            let mut bar = ProgressBar::new();
+                            bf::ir::Program::from_raw_annotated(
+                                raw,
+                                &passes,
+                                &mut annotations,
+                                |p| bar.update(p),
+                            )
+                        } else {
+                            bf::ir::Program::from_raw_annotated(
+                                raw,
+                                &passes,
+                                &mut annotations,
+                                |_| (),
+                            )
+                        };
+                        match outcome {
+                            Ok(passes) => log::info!("The optimizer converged in {passes} passes"),
+                            Err(err) => log::warn!("{err}, the program is not fully optimized"),
+                        }
+                        (ir, annotations)
+                    }
+                    Payload::Ir(ir) | Payload::Dual { ir, .. } => {
+                        (ir.clone(), header.annotations.clone())
+                    }
                 };
-                if let Some(output) = output {
-                    bf::save::write_ir(
-                        File::create(output).context("Creating file")?,
-                        &payload,
-                        compress,
-                        header.description,
-                        match format {
-                            Format::Raw => unreachable!(),
-                            Format::Binary => bf::save::Format::Binary,
-                            Format::Json => bf::save::Format::Json,
-                        },
+                if let Some(budget) = precompute {
+                    if ir.precompute(budget) {
+                        // none of the nodes are left
+                        annotations = Annotations::default();
+                        log::info!("The program was run at compile time")
+                    } else {
+                        log::info!("The program could not be run at compile time")
+                    }
+                }
+                if let Some(node) = ir.first_underflow() {
+                    log::warn!(
+                        "The program accesses a cell before the first one at node {node} (`{}`), and will fail on a right-unbounded tape",
+                        ir.0[node]
                     )
-                    .context("While writing to file")?
-                } else {
-                    bf::save::write_ir(
-                        stdout(),
-                        &payload,
-                        compress,
-                        header.description,
-                        match format {
-                            Format::Raw => unreachable!(),
+                }
+                let code = match format {
+                    Format::C => Some(
+                        bf::emit::c::emit(
+                            &ir,
+                            &bf::emit::c::Options {
+                                bounds_checks,
+                                ..Default::default()
+                            },
+                        )
+                        .into_bytes(),
+                    ),
+                    Format::Rust => Some(bf::emit::rust::emit(&ir).into_bytes()),
+                    Format::Asm => Some(bf::emit::x86_64::emit(&ir).into_bytes()),
+                    Format::Wasm => Some(bf::emit::wasm::emit(&ir)),
+                    #[cfg(feature = "llvm")]
+                    Format::Llvm => Some(bf::emit::llvm::emit(&ir).into_bytes()),
+                    Format::Raw | Format::Binary | Format::Json | Format::Text => None,
+                };
+                if let Some(code) = code {
+                    if let Some(output) = output {
+                        fs::write(output, code).context("While writing to file")?
+                    } else {
+                        stdout().write_all(&code).context("While writing to file")?
+                    }
+                    return Ok(());
+                }
+                let header = Header {
+                    content: Content::Ir {
+                        format: match format {
+                            Format::Raw | Format::C | Format::Rust | Format::Asm | Format::Wasm => {
+                                unreachable!()
+                            }
+                            #[cfg(feature = "llvm")]
+                            Format::Llvm => unreachable!(),
                             Format::Binary => bf::save::Format::Binary,
                             Format::Json => bf::save::Format::Json,
+                            Format::Text => bf::save::Format::Text,
                         },
+                        source_len: None,
+                        optimizer: match header.content {
+                            Content::Source => Some(bf::ir::OPTIMIZER_VERSION),
+                            Content::Ir { optimizer, .. } => optimizer,
+                        },
+                        schema: Some(bf::save::SCHEMA_VERSION),
+                    },
+                    annotations,
+                    ..header
+                };
+                if let Some(output) = output {
+                    bf::save::write_ir(
+                        File::create(output).context("Creating file")?,
+                        &ir,
+                        source,
+                        &header,
                     )
                     .context("While writing to file")?
+                } else {
+                    bf::save::write_ir(stdout(), &ir, source, &header)
+                        .context("While writing to file")?
                 }
             }
         }
@@ -244,27 +1249,555 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn run<E>(program: E::Program, mut input: InputStream, output: OutputStream) -> anyhow::Result<()>
-where
-    E: Engine + ProgrammableEngine,
-{
+/// Parse brainfuck source, locating the error in case of failure
+fn parse_source(src: &str, header: &Header) -> anyhow::Result<raw::Program> {
+    raw::Program::parse_dialect(src, header.dialect).map_err(|err| {
+        let err = anyhow::Error::new(err);
+        match raw::UnmatchedParentheses::locate(src) {
+            Some(start) => at_span(
+                err,
+                Span {
+                    start,
+                    end: start + 1,
+                },
+                header,
+            ),
+            None => err,
+        }
+        .context("While parsing raw brainfuck")
+    })
+}
+
+/// Add to an error the span of source it refers to, and the file it comes from
+fn at_span(err: anyhow::Error, span: Span, header: &Header) -> anyhow::Error {
+    let err = err.context(span);
+    match header.source_at(span.start) {
+        Some((file, pos)) => err.context(format!("In file {file} at byte {pos}")),
+        None => err,
+    }
+}
+
+/// Position in the source of the instructions an engine runs
+struct Locator<'h> {
+    header: &'h Header,
+    /// Bytes of each instruction in the source
+    instructions: Vec<Range<usize>>,
+    /// Spans of the nodes, if the engine runs the optimized ir
+    map: Option<SourceMap>,
+}
+impl<'h> Locator<'h> {
+    fn new(src: &str, header: &'h Header, map: Option<SourceMap>) -> Self {
+        Self {
+            header,
+            instructions: src
+                .char_indices()
+                .filter(|(_, ch)| raw::Instruction::parse(*ch, header.dialect).is_some())
+                .map(|(pos, ch)| pos..pos + ch.len_utf8())
+                .collect(),
+            map,
+        }
+    }
+
+    /// Span of source of the instruction at the given position of the engine
+    fn locate(&self, position: &[usize]) -> Option<Span> {
+        let instructions = match &self.map {
+            Some(map) => map.span(position)?,
+            None => match position {
+                &[ip] => ip..ip + 1,
+                _ => return None,
+            },
+        };
+        let first = self.instructions.get(instructions.start)?;
+        let last = self.instructions.get(instructions.end.checked_sub(1)?)?;
+        Some(Span {
+            start: first.start,
+            end: last.end,
+        })
+    }
+
+    /// Add to an error the span of the instruction at the given position
+    fn context(&self, err: anyhow::Error, position: &[usize]) -> anyhow::Error {
+        match self.locate(position) {
+            Some(span) => at_span(err, span, self.header),
+            None => err,
+        }
+    }
+}
+
+/// Minimum time between two updates of the progress bar
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Progress of the optimizer, shown on stderr
+struct ProgressBar {
+    last: Option<Instant>,
+}
+impl ProgressBar {
+    fn new() -> Self {
+        Self { last: None }
+    }
+    fn update(&mut self, progress: bf::ir::Progress) {
+        let done = progress.phase == bf::ir::Phase::Done;
+        if !done
+            && self
+                .last
+                .is_some_and(|last| last.elapsed() < PROGRESS_INTERVAL)
+        {
+            return;
+        }
+        self.last = Some(Instant::now());
+        eprint!(
+            "\rOptimizing: pass {}, iteration {}, {} nodes",
+            progress.passes,
+            progress.iteration,
+            progress.nodes()
+        );
+        if done {
+            eprintln!()
+        }
+    }
+}
+
+/// Link multiple source files into a single program
+fn link(paths: &[PathBuf], dialect: raw::Dialect) -> anyhow::Result<bf::save::File> {
+    let mut description = None;
+    let mut sources = vec![];
+    for path in paths {
+        log::info!("Reading file {}", path.display());
+        let bf::save::File {
+            mut header,
+            payload,
+        } = bf::save::parse(File::open(path).context("Cannot open program file")?)
+            .context("Cannot parse program file")
+            .with_context(|| format!("In file {}", path.display()))?;
+        header.dialect = dialect;
+        let Payload::Source(source) = payload else {
+            bail!("Cannot link the compiled file {}", path.display())
+        };
+        parse_source(&source, &header).with_context(|| format!("In file {}", path.display()))?;
+        description = description.or(header.description);
+        sources.push((path.display().to_string(), source));
+    }
+    let (source, sources) = bf::save::link(
+        sources
+            .iter()
+            .map(|(path, source)| (path.clone(), source.as_str())),
+    );
+    Ok(bf::save::File {
+        header: Header {
+            description,
+            sources,
+            dialect,
+            ..Header::of_plain_source()
+        },
+        payload: Payload::Source(source),
+    })
+}
+
+/// Span of source an error refers to
+#[derive(Debug, Clone, Copy, Serialize)]
+struct Span {
+    start: usize,
+    end: usize,
+}
+impl Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "At bytes {}..{}", self.start, self.end)
+    }
+}
+
+/// Step an engine error happened at
+#[derive(Debug, Clone, Copy)]
+struct AtStep(u64);
+impl Display for AtStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "At step {}", self.0)
+    }
+}
+
+/// Machine readable error, printed with `--error-format json`
+#[derive(Debug, Serialize)]
+struct ErrorReport {
+    kind: &'static str,
+    message: String,
+    causes: Vec<String>,
+    span: Option<Span>,
+    step: Option<u64>,
+}
+impl ErrorReport {
+    fn new(err: &anyhow::Error) -> Self {
+        let kind = if err.downcast_ref::<engine::RTError>().is_some() {
+            "runtime"
+        } else if err.downcast_ref::<raw::UnmatchedParentheses>().is_some() {
+            "parse"
+        } else if err.downcast_ref::<bf::save::ParseFileError>().is_some() {
+            "file"
+        } else if err.downcast_ref::<io::Error>().is_some() {
+            "io"
+        } else {
+            "other"
+        };
+        Self {
+            kind,
+            message: err.to_string(),
+            causes: err.chain().skip(1).map(ToString::to_string).collect(),
+            span: err.downcast_ref::<Span>().copied(),
+            step: err.downcast_ref::<AtStep>().map(|AtStep(step)| *step),
+        }
+    }
+}
+
+/// Machine readable description of a file, printed by `inspect --json`
+#[derive(Debug, Serialize)]
+struct InspectReport<'f> {
+    header: &'f Header,
+    compressed: bool,
+    /// Size of the file on disk, in bytes
+    file_size: usize,
+    checksum: ChecksumStatus,
+    payload: PayloadReport,
+}
+impl<'f> InspectReport<'f> {
+    fn new(file: &'f bf::save::File, file_size: usize) -> Self {
+        Self {
+            header: &file.header,
+            compressed: file.header.compressed,
+            file_size,
+            checksum: ChecksumStatus::Absent,
+            payload: match &file.payload {
+                Payload::Source(src) => PayloadReport::Source {
+                    bytes: src.len(),
+                    instructions: src
+                        .chars()
+                        .filter(|ch| raw::Instruction::parse(*ch, file.header.dialect).is_some())
+                        .count(),
+                },
+                Payload::Ir(ir) => PayloadReport::Ir { stats: ir.stats() },
+                Payload::Dual { source, ir } => PayloadReport::Dual {
+                    bytes: source.len(),
+                    instructions: source
+                        .chars()
+                        .filter(|ch| raw::Instruction::parse(*ch, file.header.dialect).is_some())
+                        .count(),
+                    stats: ir.stats(),
+                },
+            },
+        }
+    }
+}
+
+/// Whether the payload matches the checksum of the file
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ChecksumStatus {
+    /// The format has no checksum
+    Absent,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum PayloadReport {
+    Source {
+        bytes: usize,
+        instructions: usize,
+    },
+    Ir {
+        stats: bf::ir::Stats,
+    },
+    Dual {
+        bytes: usize,
+        instructions: usize,
+        stats: bf::ir::Stats,
+    },
+}
+
+/// Initial state of the engine
+#[derive(Clone)]
+struct Seed {
+    memory: Option<Memory>,
+    pointer: isize,
+}
+
+fn run<C: engine::cell::Cell>(
+    engine: Box<dyn Engine<Cell = C>>,
+    mut input: InputStream,
+    mut output: OutputStream,
+    break_at: Option<u64>,
+    deadline: Option<Instant>,
+    collect: Collect,
+    locator: Option<&Locator>,
+) -> anyhow::Result<()> {
     log::info!("Running raw brainfuck");
-    let mut engine = E::new(program);
+    let mut engine = Deadline::new(engine, deadline);
+    if collect.stats && !engine.collect_stats() {
+        log::warn!("The engine cannot collect statistics")
+    }
+    if collect.coverage && !engine.collect_coverage() {
+        log::warn!("The engine cannot record the coverage")
+    }
+    let mut step = 0u64;
     'l: loop {
-        match engine.run().context("Runtime error")? {
+        let stop = loop {
+            if break_at == Some(step) {
+                output.flush()?;
+                dump_state(&engine, step);
+                if let Some(span) = locator.and_then(|locator| locator.locate(&engine.position())) {
+                    eprintln!("source: {span}")
+                }
+                return Ok(());
+            }
+            let state = engine.step().map_err(|err| {
+                let err = anyhow::Error::new(err).context(AtStep(step));
+                match locator {
+                    Some(locator) => locator.context(err, &engine.position()),
+                    None => err,
+                }
+            });
+            match state.context("Runtime error")? {
+                engine::State::Running => step += 1,
+                engine::State::Stopped(stop @ engine::StopState::HasOutput(_)) => {
+                    step += 1;
+                    break stop;
+                }
+                // the engine did not advance
+                engine::State::Stopped(stop) => break stop,
+            }
+        };
+        match stop {
             engine::StopState::Halted => {
                 log::trace!("Engine halted");
                 break 'l;
             }
             engine::StopState::NeedInput => {
                 log::trace!("Engine requested input");
-                engine.give_input(input.read()?);
+                output.flush()?;
+                match input.read()? {
+                    Some(ch) => {
+                        engine.give_input(ch);
+                    }
+                    None => {
+                        if !engine.close_input() {
+                            bail!("The engine cannot be told that the input ended")
+                        }
+                    }
+                }
             }
             engine::StopState::HasOutput(ch) => {
                 log::trace!("Engine emitted output");
+                // stepping through the rest of a print would only count the steps
+                let rest = if break_at.is_none() {
+                    engine.take_output()
+                } else {
+                    vec![]
+                };
+                if rest.is_empty() {
+                    output.write(ch)?;
+                } else {
+                    step += rest.len() as u64;
+                    output.write_all(&[&[ch][..], &rest].concat())?;
+                }
+            }
+            engine::StopState::Breakpoint => unreachable!("No breakpoint is set"),
+            engine::StopState::EndOfInput => {
+                log::info!("The program read past the end of the input");
+                break 'l;
+            }
+            engine::StopState::Diverged => {
+                output.finish()?;
+                bail!("The program entered a loop that never ends")
+            }
+        }
+    }
+    output.finish()?;
+    if let Some(stats) = engine.stats() {
+        eprint!(
+            "{}",
+            serde_yaml::to_string(stats).context("While printing statistics")?
+        );
+    }
+    if let Some(coverage) = engine.coverage() {
+        eprintln!(
+            "executed {} of {} instructions",
+            coverage.count(),
+            coverage.len()
+        );
+        let never: Vec<_> = coverage.never_executed().map(|i| i.to_string()).collect();
+        if !never.is_empty() {
+            eprintln!("never executed: {}", never.join(", "));
+        }
+    }
+    Ok(())
+}
+
+/// What to collect while running
+#[derive(Debug, Clone, Copy)]
+struct Collect {
+    /// Execution statistics
+    stats: bool,
+    /// Coverage of the instructions
+    coverage: bool,
+}
+
+/// Create an engine in the seeded state
+fn start<E>(program: E::Program, seed: Seed) -> E
+where
+    E: Engine + ProgrammableEngine,
+{
+    let mut engine = E::new(program);
+    if let Some(memory) = seed.memory {
+        *engine.memory_mut() = memory.widen();
+    }
+    engine.set_pointer(seed.pointer);
+    engine
+}
+
+/// Step the engine until it stops, counting the steps
+fn next_stop(engine: &mut impl Engine, step: &mut u64) -> anyhow::Result<engine::StopState> {
+    loop {
+        match engine
+            .step()
+            .context(AtStep(*step))
+            .context("Runtime error")?
+        {
+            engine::State::Running => *step += 1,
+            engine::State::Stopped(stop @ engine::StopState::HasOutput(_)) => {
+                *step += 1;
+                return Ok(stop);
+            }
+            engine::State::Stopped(stop) => return Ok(stop),
+        }
+    }
+}
+
+/// Engine running in a lockstep check, with its own position in the input
+struct Checked<E> {
+    engine: E,
+    step: u64,
+    /// Inputs already consumed
+    consumed: usize,
+}
+impl<E: Engine> Checked<E> {
+    /// Run until the engine emits an output or halts, feeding it the input
+    ///
+    /// If `stop_at_input` is set, input requests are also returned
+    fn next_event(
+        &mut self,
+        history: &mut Vec<u8>,
+        input: &mut InputStream,
+        output: &mut OutputStream,
+        stop_at_input: bool,
+    ) -> anyhow::Result<engine::StopState> {
+        loop {
+            let stop = next_stop(&mut self.engine, &mut self.step)?;
+            if stop != engine::StopState::NeedInput {
+                return Ok(stop);
+            }
+            if self.consumed == history.len() {
+                output.flush()?;
+                match input.read()? {
+                    Some(ch) => history.push(ch),
+                    None => {
+                        self.engine.close_input();
+                        continue;
+                    }
+                }
+            }
+            self.engine.give_input(history[self.consumed]);
+            self.consumed += 1;
+            if stop_at_input {
+                return Ok(stop);
+            }
+        }
+    }
+
+    fn describe(&self, event: engine::StopState) -> String {
+        format!(
+            "{event:?} at step {} (position {:?}, pointer {})",
+            self.step,
+            self.engine.position(),
+            self.engine.pointer()
+        )
+    }
+}
+
+/// Run the raw and the optimized program in lockstep, checking that they produce the same output
+fn run_checked(
+    raw: raw::Program,
+    ir: bf::ir::Program,
+    seed: Seed,
+    mut input: InputStream,
+    mut output: OutputStream,
+    check_io_order: bool,
+) -> anyhow::Result<()> {
+    log::info!("Running raw and optimized brainfuck in lockstep");
+    let mut raw = Checked {
+        engine: start::<engine::raw::Engine>(raw, seed.clone()),
+        step: 0,
+        consumed: 0,
+    };
+    let mut ir = Checked {
+        engine: start::<engine::ir::Engine>(ir, seed),
+        step: 0,
+        consumed: 0,
+    };
+    let mut history = vec![];
+    let mut emitted = 0usize;
+    loop {
+        let raw_event = raw
+            .next_event(&mut history, &mut input, &mut output, check_io_order)
+            .context("In the raw engine")?;
+        let ir_event = ir
+            .next_event(&mut history, &mut input, &mut output, check_io_order)
+            .context("In the optimized engine")?;
+        if raw_event != ir_event || raw.consumed != ir.consumed && check_io_order {
+            output.flush()?;
+            bail!(
+                "Divergence after {emitted} bytes of output and {} of input:\n  raw:       {}\n  optimized: {}",
+                raw.consumed.max(ir.consumed),
+                raw.describe(raw_event),
+                ir.describe(ir_event)
+            )
+        }
+        match raw_event {
+            engine::StopState::Halted => break,
+            engine::StopState::NeedInput => (),
+            engine::StopState::HasOutput(ch) => {
                 output.write(ch)?;
+                emitted += 1
+            }
+            engine::StopState::Breakpoint => unreachable!("No breakpoint is set"),
+            engine::StopState::EndOfInput => {
+                log::info!("The program read past the end of the input");
+                break;
+            }
+            engine::StopState::Diverged => {
+                unreachable!("The raw engine does not detect divergence")
             }
         }
     }
+    output.finish()?;
     Ok(())
 }
+
+/// Cells shown on each side of the pointer when dumping the state
+const DUMP_WINDOW: isize = 8;
+
+/// Print the state of an engine on stderr
+fn dump_state(engine: &impl Engine, step: u64) {
+    let pointer = engine.pointer();
+    eprintln!("Stopped at step {step}");
+    eprintln!("position: {:?}", engine.position());
+    eprintln!("tape: {}", engine.tape());
+    eprintln!("pointer: {pointer}");
+    // cells before the first one are shown only if they were used
+    let start = (pointer - DUMP_WINDOW).max(-(engine.memory().negative_slice().len() as isize));
+    eprint!("tape from {start}:");
+    for pos in start..=pointer + DUMP_WINDOW {
+        let value = engine.memory().get_signed(pos);
+        if pos == pointer {
+            eprint!(" [{value}]")
+        } else {
+            eprint!(" {value}")
+        }
+    }
+    eprintln!();
+}
@@ -9,6 +9,7 @@ use std::{
     vec,
 };
 
+use serde::{Deserialize, Serialize};
 use static_assertions::const_assert_eq;
 use thiserror::Error;
 
@@ -23,8 +24,46 @@ pub enum Instruction {
     Input = b',',
     OpenLoop = b'[',
     CloseLoop = b']',
+    /// Select the previous tape. Only in the [`Dialect::MultiTape`] dialect
+    PrevTape = b'{',
+    /// Select the next tape. Only in the [`Dialect::MultiTape`] dialect
+    NextTape = b'}',
 }
 
+/// Set of instructions recognized in a source
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Dialect {
+    /// Plain brainfuck
+    #[default]
+    Standard,
+    /// Multiple tapes sharing the same pointer, selected with `{` and `}`
+    MultiTape,
+}
+impl Dialect {
+    /// Returns `true` if the dialect is [`Standard`].
+    ///
+    /// [`Standard`]: Dialect::Standard
+    #[must_use]
+    pub fn is_standard(&self) -> bool {
+        matches!(self, Self::Standard)
+    }
+}
+
+impl Instruction {
+    /// Parse an instruction in the given dialect
+    pub fn parse(value: char, dialect: Dialect) -> Option<Self> {
+        match (value, dialect) {
+            ('{', Dialect::MultiTape) => Some(Self::PrevTape),
+            ('}', Dialect::MultiTape) => Some(Self::NextTape),
+            (value, _) => Self::try_from(value).ok(),
+        }
+    }
+}
+
+/// Parse the instructions of plain brainfuck
 impl TryFrom<u8> for Instruction {
     type Error = u8;
 
@@ -76,6 +115,25 @@ pub struct Program {
     code: Box<[Instruction]>,
 }
 
+/// Serialized as its source, so any dialect can be stored
+impl Serialize for Program {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+impl<'de> Deserialize<'de> for Program {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let source = String::deserialize(deserializer)?;
+        Self::parse_dialect(&source, Dialect::MultiTape).map_err(serde::de::Error::custom)
+    }
+}
+
 impl Program {
     /// Get the program as a byte slice
     pub fn as_bytes(&self) -> &[u8] {
@@ -108,10 +166,38 @@ impl Program {
         self.code.iter_mut()
     }
 
+    /// Parse a source in the given dialect
+    pub fn parse_dialect(source: &str, dialect: Dialect) -> Result<Self, UnmatchedParentheses> {
+        Self::from_instrs(
+            source
+                .chars()
+                .filter_map(|ch| Instruction::parse(ch, dialect)),
+        )
+    }
+
+    /// The minimal dialect needed to run this program
+    pub fn dialect(&self) -> Dialect {
+        if self
+            .iter()
+            .any(|instr| matches!(instr, Instruction::PrevTape | Instruction::NextTape))
+        {
+            Dialect::MultiTape
+        } else {
+            Dialect::Standard
+        }
+    }
+
     pub fn len(&self) -> usize {
         self.code.len()
     }
 
+    /// Run `other` after this program
+    pub fn concat(self, other: Program) -> Program {
+        Self {
+            code: self.into_iter().chain(other).collect(),
+        }
+    }
+
     pub fn from_chars(code: impl IntoIterator<Item = char>) -> Result<Self, UnmatchedParentheses> {
         Self::from_instrs(
             code.into_iter()
@@ -213,9 +299,27 @@ impl FromStr for Program {
 #[error("The brainfuck program has unmatched parentheses")]
 pub struct UnmatchedParentheses;
 
+impl UnmatchedParentheses {
+    /// Find the byte offset of the first unmatched parenthesis in a source
+    ///
+    /// A closing parenthesis with no opening one is reported as soon as it is found,
+    /// otherwise the outermost unclosed opening parenthesis is reported
+    pub fn locate(source: &str) -> Option<usize> {
+        let mut open = vec![];
+        for (pos, ch) in source.bytes().enumerate() {
+            match ch {
+                b'[' => open.push(pos),
+                b']' if open.pop().is_none() => return Some(pos),
+                _ => (),
+            }
+        }
+        open.first().copied()
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Program;
+    use super::{Dialect, Program, UnmatchedParentheses};
 
     #[test]
     fn empty() {
@@ -225,4 +329,24 @@ mod tests {
     fn parentheses() {
         let _: Program = "[]".parse().unwrap();
     }
+    #[test]
+    fn dialects() {
+        let standard: Program = "+{>}-".parse().unwrap();
+        assert_eq!(standard.as_str(), "+>-");
+        assert_eq!(standard.dialect(), Dialect::Standard);
+        let multi = Program::parse_dialect("+{>}-", Dialect::MultiTape).unwrap();
+        assert_eq!(multi.as_str(), "+{>}-");
+        assert_eq!(multi.dialect(), Dialect::MultiTape);
+    }
+    #[test]
+    fn concat() {
+        let first: Program = "+[->+<]".parse().unwrap();
+        assert_eq!(first.concat(">.".parse().unwrap()).as_str(), "+[->+<]>.");
+    }
+    #[test]
+    fn locate_unmatched() {
+        assert_eq!(UnmatchedParentheses::locate("[[]]"), None);
+        assert_eq!(UnmatchedParentheses::locate("+[[]"), Some(1));
+        assert_eq!(UnmatchedParentheses::locate("[]]["), Some(2));
+    }
 }
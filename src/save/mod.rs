@@ -7,21 +7,42 @@ use std::{
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::ir;
+use crate::{
+    ir,
+    raw::{Dialect, Instruction},
+};
 
 /// Magic value to recognize compiled files
 /// it starts with ']' so it's never valid bf
 const MAGIC: [u8; 3] = *b"]bf";
 
+/// Version of the layout of the serialized ir
+///
+/// To be bumped every time a change to the ir types changes their json or binary form, keeping a
+/// way to decode the previous version in [`decode_ir`].
+pub const SCHEMA_VERSION: u32 = 1;
+
 /// Header of a compiled file
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
 pub struct Header {
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
+    /// Structured informations found in the description
+    #[serde(flatten)]
+    pub meta: Metadata,
     #[serde(skip)]
     pub compressed: bool,
     #[serde(flatten)]
     pub content: Content,
+    /// Files the program was linked from
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<SourceSpan>,
+    /// Dialect the program is written in
+    #[serde(default, skip_serializing_if = "Dialect::is_standard")]
+    pub dialect: Dialect,
+    /// Notes on the nodes of the compiled program
+    #[serde(default, skip_serializing_if = "ir::Annotations::is_empty")]
+    pub annotations: ir::Annotations,
 }
 impl Header {
     pub fn of_plain_source() -> Header {
@@ -29,8 +50,86 @@ impl Header {
             content: Content::Source,
             compressed: false,
             description: None,
+            meta: Metadata::default(),
+            sources: vec![],
+            dialect: Dialect::Standard,
+            annotations: ir::Annotations::default(),
         }
     }
+
+    /// Set the description, extracting the structured metadata from it
+    pub fn describe(&mut self, description: Option<String>) {
+        self.meta = description
+            .as_deref()
+            .map(Metadata::parse)
+            .unwrap_or_default();
+        self.description = description;
+    }
+
+    /// Find the file and the position in it of a byte of the linked source
+    pub fn source_at(&self, pos: usize) -> Option<(&str, usize)> {
+        self.sources
+            .iter()
+            .find(|span| span.start <= pos && pos < span.end)
+            .map(|span| (span.file.as_str(), span.origin + (pos - span.start)))
+    }
+}
+
+/// Metadata given in the description as `key: value` pairs, separated by `;` or newlines
+///
+/// E.g. `[title: Hello world; author: Someone; cells: 8-bit]`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Deserialize, Serialize)]
+pub struct Metadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// Size of the cells the program expects
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cells: Option<String>,
+    /// Prompt to show when the program waits for input
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+}
+impl Metadata {
+    /// Parse the metadata from a description
+    ///
+    /// If the description is not entirely made of `key: value` pairs it's considered free text,
+    /// and no metadata is extracted. Unknown keys are ignored.
+    pub fn parse(description: &str) -> Self {
+        let mut meta = Self::default();
+        for pair in description
+            .split([';', '\n'])
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+        {
+            let Some((key, value)) = pair.split_once(':') else {
+                return Self::default();
+            };
+            let value = Some(value.trim().to_owned());
+            match key.trim().to_lowercase().as_str() {
+                "title" => meta.title = value,
+                "author" => meta.author = value,
+                "cells" => meta.cells = value,
+                "prompt" => meta.prompt = value,
+                _ => (),
+            }
+        }
+        meta
+    }
+}
+
+/// Part of a linked source coming from a single file
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+pub struct SourceSpan {
+    pub file: String,
+    /// Start of the span in the linked source
+    pub start: usize,
+    /// End of the span in the linked source
+    pub end: usize,
+    /// Position in the file corresponding to the start of the span
+    #[serde(default)]
+    pub origin: usize,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
@@ -40,6 +139,16 @@ pub enum Content {
     Ir {
         #[serde(default)]
         format: Format,
+        /// Length of the source stored before the ir, if any
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        source_len: Option<usize>,
+        /// Version of the optimizer that produced the ir. Missing for files that predate it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        optimizer: Option<u32>,
+        /// Version of the layout of the ir. Missing for files that predate it, that are laid out
+        /// as the first version
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        schema: Option<u32>,
     },
 }
 
@@ -68,30 +177,37 @@ pub enum Format {
     #[default]
     Json,
     Binary,
+    /// The textual form of the ir, that can be edited by hand
+    Text,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Payload {
     Source(String),
     Ir(ir::Program),
+    /// Optimized program, together with the source it was compiled from
+    Dual {
+        source: String,
+        ir: ir::Program,
+    },
 }
 
 impl Payload {
+    /// The ir in the payload, if present
     #[must_use]
     pub fn as_ir(&self) -> Option<&ir::Program> {
-        if let Self::Ir(v) = self {
-            Some(v)
-        } else {
-            None
+        match self {
+            Self::Ir(v) | Self::Dual { ir: v, .. } => Some(v),
+            Self::Source(_) => None,
         }
     }
 
+    /// The source in the payload, if present
     #[must_use]
     pub fn as_source(&self) -> Option<&str> {
-        if let Self::Source(v) = self {
-            Some(v)
-        } else {
-            None
+        match self {
+            Self::Source(v) | Self::Dual { source: v, .. } => Some(v),
+            Self::Ir(_) => None,
         }
     }
 
@@ -103,7 +219,6 @@ impl Payload {
         matches!(self, Self::Source(..))
     }
 
-    #[must_use]
     pub fn try_into_source(self) -> Result<String, Self> {
         if let Self::Source(v) = self {
             Ok(v)
@@ -120,7 +235,6 @@ impl Payload {
         matches!(self, Self::Ir(..))
     }
 
-    #[must_use]
     pub fn try_into_ir(self) -> Result<ir::Program, Self> {
         if let Self::Ir(v) = self {
             Ok(v)
@@ -128,6 +242,14 @@ impl Payload {
             Err(self)
         }
     }
+
+    /// Returns `true` if the payload is [`Dual`].
+    ///
+    /// [`Dual`]: Payload::Dual
+    #[must_use]
+    pub fn is_dual(&self) -> bool {
+        matches!(self, Self::Dual { .. })
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -156,6 +278,14 @@ pub enum ParseFileError {
     InvalidBinaryIr(#[source] bincode::error::DecodeError),
     #[error("Error while parsing Json ir representation")]
     InvalidJsonIr(#[source] serde_json::Error),
+    #[error("The textual ir representation is not valid utf8")]
+    TextIrNotUtf8(#[source] std::str::Utf8Error),
+    #[error("Error while parsing textual ir representation")]
+    InvalidTextIr(#[source] ir::ParseTextError),
+    #[error("The source length {0} is over the end of the file")]
+    SourceTooLong(usize),
+    #[error("The ir is in the layout version {0}, but only versions up to {SCHEMA_VERSION} are supported")]
+    UnsupportedSchema(u32),
 }
 
 /// Parse a file from the bytes
@@ -215,16 +345,28 @@ pub fn parse(mut source: impl io::Read) -> Result<File, ParseFileError> {
         // parsing the payload
         let payload = match header.content {
             Content::Source => Payload::Source(String::from_utf8_lossy(payload).into_owned()),
-            Content::Ir { format } => Payload::Ir(match format {
-                Format::Json => {
-                    serde_json::from_slice(payload).map_err(ParseFileError::InvalidJsonIr)?
-                }
-                Format::Binary => {
-                    bincode::decode_from_slice(payload, bincode::config::standard())
-                        .map_err(ParseFileError::InvalidBinaryIr)?
-                        .0
+            Content::Ir {
+                format,
+                source_len,
+                schema,
+                ..
+            } => {
+                let (source, payload) = match source_len {
+                    Some(len) if len > payload.len() => {
+                        return Err(ParseFileError::SourceTooLong(len))
+                    }
+                    Some(len) => {
+                        let (source, payload) = payload.split_at(len);
+                        (Some(String::from_utf8_lossy(source).into_owned()), payload)
+                    }
+                    None => (None, payload),
+                };
+                let ir = decode_ir(payload, format, schema.unwrap_or(1))?;
+                match source {
+                    Some(source) => Payload::Dual { source, ir },
+                    None => Payload::Ir(ir),
                 }
-            }),
+            }
         };
 
         Ok(File { header, payload })
@@ -234,36 +376,7 @@ pub fn parse(mut source: impl io::Read) -> Result<File, ParseFileError> {
         let mut header = Header::of_plain_source();
 
         // searching for beginner comment to include as a description
-        header.description = {
-            let source = source.trim_start();
-            if source.starts_with('[') {
-                let end = source
-                    .char_indices()
-                    .skip(1)
-                    .scan(1usize, |depth, (idx, ch)| {
-                        if *depth == 0 {
-                            return None;
-                        }
-                        match ch {
-                            '[' => {
-                                *depth += 1;
-                                Some(None)
-                            }
-                            ']' => {
-                                *depth -= 1;
-                                Some(Some(idx))
-                            }
-                            _ => Some(None),
-                        }
-                    })
-                    .last()
-                    .flatten()
-                    .unwrap_or(source.len());
-                Some(source[1..end].to_owned())
-            } else {
-                None
-            }
-        };
+        header.describe(split_leading_comment(&source).map(|(descr, _)| descr.to_owned()));
 
         let payload = Payload::Source(source);
 
@@ -271,17 +384,107 @@ pub fn parse(mut source: impl io::Read) -> Result<File, ParseFileError> {
     }
 }
 
+/// Decode the ir, laid out as the given version of the schema
+///
+/// The node types only ever gained new variants at the end, so the files that predate the
+/// versioning are all laid out as the first version.
+fn decode_ir(payload: &[u8], format: Format, schema: u32) -> Result<ir::Program, ParseFileError> {
+    match schema {
+        1 => Ok(match format {
+            Format::Json => {
+                serde_json::from_slice(payload).map_err(ParseFileError::InvalidJsonIr)?
+            }
+            Format::Binary => {
+                bincode::decode_from_slice(payload, bincode::config::standard())
+                    .map_err(ParseFileError::InvalidBinaryIr)?
+                    .0
+            }
+            Format::Text => {
+                ir::Program::from_text(from_utf8(payload).map_err(ParseFileError::TextIrNotUtf8)?)
+                    .map_err(ParseFileError::InvalidTextIr)?
+            }
+        }),
+        schema => Err(ParseFileError::UnsupportedSchema(schema)),
+    }
+}
+
+/// Split the leading comment of a source, returning its content and the rest of the source
+///
+/// An unterminated comment extends to the end of the source. A leading loop holding only
+/// instructions, of any dialect, and whitespace is code and not a comment.
+fn split_leading_comment(source: &str) -> Option<(&str, &str)> {
+    let source = source.trim_start();
+    if !source.starts_with('[') {
+        return None;
+    }
+    let end = source
+        .char_indices()
+        .skip(1)
+        .scan(1usize, |depth, (idx, ch)| {
+            if *depth == 0 {
+                return None;
+            }
+            match ch {
+                '[' => {
+                    *depth += 1;
+                    Some(None)
+                }
+                ']' => {
+                    *depth -= 1;
+                    Some(Some(idx))
+                }
+                _ => Some(None),
+            }
+        })
+        .last()
+        .flatten()
+        .unwrap_or(source.len());
+    let comment = &source[1..end];
+    comment
+        .chars()
+        .any(|ch| !ch.is_whitespace() && Instruction::parse(ch, Dialect::MultiTape).is_none())
+        .then(|| (comment, source.get(end + 1..).unwrap_or("")))
+}
+
+/// Concatenate multiple sources, given with their file names, into a single one
+///
+/// The leading comments of all files but the first are removed, as they could be
+/// executed once the tape is no longer zeroed.
+/// Return the linked source and the spans of each file in it.
+pub fn link<'s>(sources: impl IntoIterator<Item = (String, &'s str)>) -> (String, Vec<SourceSpan>) {
+    let mut linked = String::new();
+    let mut spans = vec![];
+    for (i, (file, source)) in sources.into_iter().enumerate() {
+        let code = match split_leading_comment(source) {
+            Some((_, rest)) if i > 0 => rest,
+            _ => source,
+        };
+        let start = linked.len();
+        linked.push_str(code);
+        spans.push(SourceSpan {
+            file,
+            start,
+            end: linked.len(),
+            origin: source.len() - code.len(),
+        })
+    }
+    (linked, spans)
+}
+
 /// Dump a source to file
-pub fn write_source<'d>(
+///
+/// The content of the header is ignored, and set to [`Content::Source`]
+pub fn write_source(
     mut dest: impl io::Write,
     source: impl AsRef<str>,
-    compressed: bool,
-    description: Option<impl Into<Cow<'d, str>>>,
+    header: &Header,
 ) -> io::Result<()> {
+    let compressed = header.compressed;
+    // the annotations refer to the nodes of the ir
     let header = serde_yaml::to_string(&Header {
-        description: description.map(|d| d.into().into_owned()),
-        compressed,
         content: Content::Source,
+        annotations: ir::Annotations::default(),
+        ..header.clone()
     })
     .unwrap();
     assert!(header.ends_with('\n'));
@@ -302,17 +505,31 @@ pub fn write_source<'d>(
 }
 
 /// Dump the intermediate representation to file
-pub fn write_ir<'d>(
+///
+/// The header content must be [`Content::Ir`].
+/// If `source` is given, it's stored alongside the ir. The ir is always written in the current
+/// [`SCHEMA_VERSION`].
+pub fn write_ir(
     mut dest: impl io::Write,
     ir: &ir::Program,
-    compressed: bool,
-    description: Option<impl Into<Cow<'d, str>>>,
-    format: Format,
+    source: Option<&str>,
+    header: &Header,
 ) -> io::Result<()> {
+    let Content::Ir {
+        format, optimizer, ..
+    } = header.content
+    else {
+        panic!("The header of an ir file must have ir content")
+    };
+    let compressed = header.compressed;
     let header = serde_yaml::to_string(&Header {
-        description: description.map(|d| d.into().into_owned()),
-        compressed,
-        content: Content::Ir { format },
+        content: Content::Ir {
+            format,
+            source_len: source.map(str::len),
+            optimizer,
+            schema: Some(SCHEMA_VERSION),
+        },
+        ..header.clone()
     })
     .unwrap();
     assert!(header.ends_with('\n'));
@@ -322,6 +539,7 @@ pub fn write_ir<'d>(
         write!(dest, "c")?;
         let mut dest = flate2::write::DeflateEncoder::new(dest, flate2::Compression::best());
         write!(dest, "\n---\n{header}...\n")?;
+        dest.write_all(source.unwrap_or_default().as_bytes())?;
         match format {
             Format::Json => serde_json::to_writer(&mut dest, ir)?,
             Format::Binary => {
@@ -331,11 +549,13 @@ pub fn write_ir<'d>(
                         _ => panic!("ir tree should always be dumpable"),
                     })?;
             }
+            Format::Text => write!(dest, "{ir}")?,
         }
         dest.finish()?;
     } else {
         write!(dest, "p")?;
         write!(dest, "\n---\n{header}...\n")?;
+        dest.write_all(source.unwrap_or_default().as_bytes())?;
         match format {
             Format::Json => {
                 serde_json::to_writer_pretty(&mut dest, ir)?;
@@ -348,6 +568,7 @@ pub fn write_ir<'d>(
                         _ => panic!("ir tree should always be dumpable"),
                     })?;
             }
+            Format::Text => write!(dest, "{ir}")?,
         }
     }
     Ok(())
@@ -356,8 +577,20 @@ pub fn write_ir<'d>(
 #[cfg(test)]
 mod tests {
     use std::assert_matches::assert_matches;
+    use std::num::{NonZeroIsize, NonZeroU8};
+
+    use crate::{
+        ir::{
+            self, Add, Affine, Block, If, Input, Loop, Mul, Node, Output, Print, Set, Shift,
+            SwitchTape,
+        },
+        raw::Dialect,
+    };
 
-    use super::{parse, Content, File, Header, Payload};
+    use super::{
+        decode_ir, link, parse, write_ir, write_source, Content, File, Format, Header, Metadata,
+        ParseFileError, Payload,
+    };
 
     #[test]
     fn parse_source() {
@@ -368,11 +601,15 @@ mod tests {
             File {
                 header: Header {
                     description: None,
+                    meta,
                     compressed: false,
                     content: Content::Source,
+                    sources,
+                    dialect: Dialect::Standard,
+                    annotations,
                 },
                 payload: Payload::Source(src)
-            } if src == "Some brainfuck: ++--"
+            } if src == "Some brainfuck: ++--" && meta == Metadata::default() && sources.is_empty() && annotations.is_empty()
         )
     }
     #[test]
@@ -384,11 +621,236 @@ mod tests {
             File {
                 header: Header {
                     description: Some(descr),
+                    meta,
                     compressed: false,
                     content: Content::Source,
+                    sources,
+                    dialect: Dialect::Standard,
+                    annotations,
                 },
                 payload: Payload::Source(src)
-            } if src == "[Some brainfuck] ++--" && descr == "Some brainfuck"
+            } if src == "[Some brainfuck] ++--" && descr == "Some brainfuck" && meta == Metadata::default() && sources.is_empty() && annotations.is_empty()
         )
     }
+    #[test]
+    fn parse_source_metadata() {
+        let src = "[title: Hello; author: Someone\ncells: 16-bit] ++--";
+        let file = parse(src.as_bytes()).expect("The file should be recognized as a source file");
+        assert_eq!(
+            file.header.description.as_deref(),
+            Some("title: Hello; author: Someone\ncells: 16-bit")
+        );
+        assert_eq!(
+            file.header.meta,
+            Metadata {
+                title: Some("Hello".to_owned()),
+                author: Some("Someone".to_owned()),
+                cells: Some("16-bit".to_owned()),
+                prompt: None,
+            }
+        );
+        // free text is not parsed
+        let file = parse("[Note: this is a comment. Nothing more]".as_bytes()).unwrap();
+        assert_eq!(file.header.meta, Metadata::default());
+    }
+    #[test]
+    fn dual_payload_roundtrip() {
+        let source = "[cat] +[,.----------]";
+        let ir: crate::ir::Program = source.parse().unwrap();
+        for format in [Format::Json, Format::Binary, Format::Text] {
+            for compressed in [false, true] {
+                let mut buf = vec![];
+                let header = Header {
+                    compressed,
+                    content: Content::Ir {
+                        format,
+                        source_len: None,
+                        optimizer: Some(crate::ir::OPTIMIZER_VERSION),
+                        schema: Some(super::SCHEMA_VERSION),
+                    },
+                    ..Header::of_plain_source()
+                };
+                write_ir(&mut buf, &ir, Some(source), &header).unwrap();
+                let file = parse(buf.as_slice()).unwrap();
+                assert_eq!(
+                    file.payload,
+                    Payload::Dual {
+                        source: source.to_owned(),
+                        ir: ir.clone()
+                    }
+                );
+            }
+        }
+    }
+    #[test]
+    fn annotations_roundtrip() {
+        let source = "read , twice [->++<]";
+        let ir: ir::Program = source.parse().unwrap();
+        let mut annotations = ir::Annotations::of_source(source, Dialect::Standard);
+        annotations.annotate(&[1, 0], ir::Annotation::Label("double".to_owned()));
+        let header = Header {
+            content: Content::Ir {
+                format: Format::Binary,
+                source_len: None,
+                optimizer: None,
+                schema: None,
+            },
+            annotations: annotations.clone(),
+            ..Header::of_plain_source()
+        };
+        let mut buf = vec![];
+        write_ir(&mut buf, &ir, None, &header).unwrap();
+        assert_eq!(
+            parse(buf.as_slice()).unwrap().header.annotations,
+            annotations
+        );
+        // the sources have no nodes to annotate
+        let mut buf = vec![];
+        write_source(&mut buf, source, &header).unwrap();
+        assert!(parse(buf.as_slice()).unwrap().header.annotations.is_empty());
+    }
+    /// A program with every kind of node
+    fn every_node() -> ir::Program {
+        let nz = |n: u8| NonZeroU8::new(n).unwrap();
+        let shift = |n: isize| NonZeroIsize::new(n).unwrap();
+        ir::Program(Block(vec![
+            Node::Noop,
+            Node::Shift(Shift { amount: shift(-1) }),
+            Node::Add(Add {
+                amount: nz(3),
+                offset: 1,
+            }),
+            Node::Output(Output { offset: 0 }),
+            Node::Input(Input { offset: -2 }),
+            Node::Loop(Loop {
+                body: Block(vec![Node::Add(Add {
+                    amount: nz(255),
+                    offset: 0,
+                })]),
+                offset: 0,
+            }),
+            Node::Mul(Mul {
+                offset: 0,
+                targets: vec![(
+                    1,
+                    Affine {
+                        constant: 2,
+                        terms: [(2, nz(3))].into(),
+                    },
+                )],
+            }),
+            Node::SwitchTape(SwitchTape { amount: shift(1) }),
+            Node::Set(Set {
+                value: 4,
+                offset: 1,
+            }),
+            Node::If(If {
+                body: Block(vec![Node::Set(Set {
+                    value: 0,
+                    offset: 0,
+                })]),
+                offset: 0,
+            }),
+            Node::Print(Print {
+                bytes: b"hi".to_vec(),
+            }),
+            Node::Diverge,
+            Node::InputDiscard,
+        ]))
+    }
+
+    #[test]
+    fn schema_is_stable() {
+        // if this fails, the serialized form of the ir changed: bump `SCHEMA_VERSION`, and keep
+        // decoding the previous one
+        let json = r#"[{"action":"Noop"},{"action":"Shift","amount":-1},{"action":"Add","amount":3,"offset":1},{"action":"Output","offset":0},{"action":"Input","offset":-2},{"action":"Loop","body":[{"action":"Add","amount":255,"offset":0}],"offset":0},{"action":"Mul","offset":0,"targets":[[1,{"constant":2,"terms":{"2":3}}]]},{"action":"SwitchTape","amount":1},{"action":"Set","value":4,"offset":1},{"action":"If","body":[{"action":"Set","value":0,"offset":0}],"offset":0},{"action":"Print","bytes":[104,105]},{"action":"Diverge"},{"action":"InputDiscard"}]"#;
+        let binary = [
+            13, 0, 1, 1, 2, 3, 2, 3, 0, 4, 3, 5, 1, 2, 255, 0, 0, 6, 0, 1, 2, 2, 1, 4, 3, 7, 2, 8,
+            4, 2, 9, 1, 8, 0, 0, 0, 10, 2, 104, 105, 11, 12,
+        ];
+        let text = concat!(
+            "noop\nshift\t-1\nadd\t3\t@1\noutput\t\t@0\ninput\t\t@-2\n",
+            "loop\t@0 [\n    add\t255\t@0\n]\n",
+            "mul\t\t@0\t[@1 += 2 + 3*@2]\ntape\t1\nset\t4\t@1\n",
+            "if\t@0 [\n    set\t0\t@0\n]\n",
+            "print\t\"hi\"\ndiverge\ninput\t\tdiscard\n",
+        );
+        let program = every_node();
+        assert_eq!(serde_json::to_string(&program).unwrap(), json);
+        assert_eq!(program.to_string(), text);
+        assert_eq!(
+            bincode::encode_to_vec(&program, bincode::config::standard()).unwrap(),
+            binary
+        );
+        assert_eq!(
+            decode_ir(json.as_bytes(), Format::Json, 1).unwrap(),
+            program
+        );
+        assert_eq!(decode_ir(&binary, Format::Binary, 1).unwrap(), program);
+        assert_eq!(
+            decode_ir(text.as_bytes(), Format::Text, 1).unwrap(),
+            program
+        );
+    }
+
+    #[test]
+    fn schema_versions() {
+        let ir = every_node();
+        let mut buf = vec![];
+        let header = Header {
+            content: Content::Ir {
+                format: Format::Json,
+                source_len: None,
+                optimizer: None,
+                schema: None,
+            },
+            ..Header::of_plain_source()
+        };
+        write_ir(&mut buf, &ir, None, &header).unwrap();
+        let file = String::from_utf8(buf).unwrap();
+        assert!(file.contains("\nschema: 1\n"), "{file}");
+        // files that predate the versioning are read as the first version
+        let legacy = file.replace("\nschema: 1\n", "\n");
+        assert_eq!(parse(legacy.as_bytes()).unwrap().payload, Payload::Ir(ir));
+        let future = file.replace("\nschema: 1\n", "\nschema: 2\n");
+        assert_matches!(
+            parse(future.as_bytes()),
+            Err(ParseFileError::UnsupportedSchema(2))
+        );
+    }
+
+    #[test]
+    fn link_sources() {
+        let (linked, spans) = link([
+            ("a.b".to_owned(), "[first] +++"),
+            ("b.b".to_owned(), "  [second [nested]]>."),
+        ]);
+        assert_eq!(linked, "[first] +++>.");
+        assert_eq!(spans.len(), 2);
+        let header = Header {
+            sources: spans,
+            ..Header::of_plain_source()
+        };
+        assert_eq!(header.source_at(8), Some(("a.b", 8)));
+        assert_eq!(header.source_at(11), Some(("b.b", 19)));
+        assert_eq!(header.source_at(13), None);
+    }
+
+    #[test]
+    fn link_leading_loops() {
+        use crate::engine::{raw, Engine, ProgrammableEngine};
+
+        let first = "++++++++[>++++++++<-]>+.+.";
+        let second = "[-]++++++++++.";
+        let (linked, _) = link([("a.b".to_owned(), first), ("b.b".to_owned(), second)]);
+        assert_eq!(linked, format!("{first}{second}"));
+        let mut engine = <raw::Engine>::new_from_str(&linked).unwrap();
+        let mut output = vec![];
+        engine
+            .run_with_io(&mut || None, &mut |ch| output.push(ch))
+            .unwrap();
+        assert_eq!(output, b"AB\n");
+        // the loop is not taken as a description either
+        assert_eq!(parse(second.as_bytes()).unwrap().header.description, None);
+    }
 }
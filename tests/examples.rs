@@ -9,6 +9,8 @@ use bf::{
 struct IOExample {
     input: &'static [u8],
     output: &'static [u8],
+    /// Steps after which the program is considered non halting
+    max_steps: u64,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -23,7 +25,7 @@ impl IO {
             Mutex::new(BTreeMap::new());
         let mut cache = CACHE.lock().expect("The lock should never be poisoned");
         *cache.entry((program, input)).or_insert_with(|| {
-            let mut engine = bf::engine::raw::Engine::new_from_str(program).unwrap();
+            let mut engine = <bf::engine::raw::Engine>::new_from_str(program).unwrap();
             let mut input = input;
             let mut fingerprint = vec![];
             'l: loop {
@@ -36,6 +38,11 @@ impl IO {
                         fingerprint.push(IO::Input)
                     }
                     bf::engine::StopState::HasOutput(_) => fingerprint.push(IO::Output),
+                    bf::engine::StopState::Breakpoint => unreachable!("No breakpoint is set"),
+                    bf::engine::StopState::EndOfInput => unreachable!("The input is never closed"),
+                    bf::engine::StopState::Diverged => {
+                        unreachable!("The raw engine does not detect divergence")
+                    }
                 }
             }
             // truncate the inputs after the last output
@@ -60,6 +67,7 @@ fn test_engine<E>(
     IOExample {
         input: full_input,
         output: expected,
+        max_steps,
     }: IOExample,
 ) where
     E: Engine + ProgrammableEngine,
@@ -71,11 +79,24 @@ fn test_engine<E>(
     let mut output = vec![];
     let mut fingerprints = vec![];
     let mut input = full_input;
+    let mut steps = 0u64;
     'l: loop {
-        match engine
-            .run()
-            .expect("The engine should not error on the example programs")
-        {
+        let stop = loop {
+            match engine
+                .step()
+                .expect("The engine should not error on the example programs")
+            {
+                bf::engine::State::Running => {
+                    steps += 1;
+                    assert!(
+                        steps <= max_steps,
+                        "Possible miscompile: program did not halt in {max_steps} steps"
+                    )
+                }
+                bf::engine::State::Stopped(stop) => break stop,
+            }
+        };
+        match stop {
             bf::engine::StopState::Halted => break 'l,
             bf::engine::StopState::NeedInput => {
                 let (ch, remainder) = input
@@ -91,6 +112,9 @@ fn test_engine<E>(
                 output.push(ch);
                 fingerprints.push(IO::Output);
             }
+            bf::engine::StopState::Breakpoint => unreachable!("No breakpoint is set"),
+            bf::engine::StopState::EndOfInput => unreachable!("The input is never closed"),
+            bf::engine::StopState::Diverged => panic!("Possible miscompile: the program diverged"),
         }
     }
     // converting into strings to make nice errors
@@ -108,4 +132,92 @@ fn test_engine<E>(
     )
 }
 
+/// State of the wasm runner
+struct WasmIO {
+    input: &'static [u8],
+    output: Vec<u8>,
+}
+
+/// WebAssembly backend testing, running the emitted module with wasmi
+fn test_wasm(
+    program: &'static str,
+    IOExample {
+        input,
+        output: expected,
+        ..
+    }: IOExample,
+) {
+    let program = bf::ir::Program::from_raw(
+        program
+            .parse()
+            .expect("The example programs should be valid brainfuck"),
+    );
+    let module = bf::emit::wasm::emit(&program);
+
+    let engine = wasmi::Engine::default();
+    let module = wasmi::Module::new(&engine, &module[..]).expect("The module should be valid");
+    let mut store = wasmi::Store::new(
+        &engine,
+        WasmIO {
+            input,
+            output: vec![],
+        },
+    );
+    let mut linker = wasmi::Linker::<WasmIO>::new(&engine);
+    linker
+        .func_wrap(
+            "env",
+            "read_byte",
+            |mut caller: wasmi::Caller<'_, WasmIO>| -> i32 {
+                let io = caller.data_mut();
+                match io.input.split_first() {
+                    Some((ch, remainder)) => {
+                        io.input = remainder;
+                        *ch as i32
+                    }
+                    None => -1,
+                }
+            },
+        )
+        .unwrap();
+    linker
+        .func_wrap(
+            "env",
+            "write_byte",
+            |mut caller: wasmi::Caller<'_, WasmIO>, ch: i32| {
+                caller.data_mut().output.push(ch as u8)
+            },
+        )
+        .unwrap();
+    let instance = linker
+        .instantiate(&mut store, &module)
+        .and_then(|instance| instance.start(&mut store))
+        .expect("The module should instantiate");
+    instance
+        .get_typed_func::<(), ()>(&store, "run")
+        .expect("The module should export `run`")
+        .call(&mut store, ())
+        .expect("The module should not trap on the example programs");
+
+    let output = &store.data().output;
+    match [output, expected].map(from_utf8) {
+        [Ok(out), Ok(expected)] => assert_eq!(out, expected),
+        [Err(_), Ok(expected)] => panic!("Expected string {expected:?}, got bytes {output:?}"),
+        [_, Err(_)] => assert_eq!(output, expected),
+    }
+}
+
+/// Passes the optimizer is allowed to take on the example programs
+const MAX_OPTIMIZER_PASSES: usize = 1000;
+
+/// Optimizer testing
+fn test_optimizer(program: &'static str) {
+    let program = program
+        .parse()
+        .expect("The example programs should be valid brainfuck");
+    if let Err(err) = bf::ir::check::check_optimizer(program, MAX_OPTIMIZER_PASSES) {
+        panic!("{err}")
+    }
+}
+
 include!(env!("TEST_EXAMPLES"));